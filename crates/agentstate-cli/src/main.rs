@@ -19,6 +19,31 @@ enum Cmd {
         #[arg(long)]
         dump: Option<String>,
     },
+    /// Print every WAL record as one JSON line, in replay order
+    DumpWal {
+        /// Data directory holding manifest.json and wal/
+        #[arg(long)]
+        wal_dir: String,
+    },
+    /// Print WAL records as JSON lines like dump-wal, optionally filtered and followed
+    WalCat {
+        /// Data directory holding manifest.json and wal/
+        data_dir: String,
+        /// Skip records with a lower seq
+        #[arg(long, default_value_t = 0)]
+        from_seq: u64,
+        /// Comma-separated record types to print, e.g. put,delete (default: all)
+        #[arg(long = "type", value_delimiter = ',')]
+        types: Vec<String>,
+        /// Keep printing records as they're appended, including across segment rotation
+        #[arg(long)]
+        follow: bool,
+    },
+    /// Check a data directory's WAL read-only; exits 1 on a missing segment or bad record
+    Verify {
+        /// Data directory holding manifest.json and wal/
+        data_dir: String,
+    },
 }
 
 fn read_snapshot(path: &str) -> Result<Vec<serde_json::Value>> {
@@ -36,6 +61,30 @@ fn read_snapshot(path: &str) -> Result<Vec<serde_json::Value>> {
     Ok(out)
 }
 
+// One JSON line per dumped WAL record, for dump-wal and wal-cat. Records below
+// `from_seq` or outside `types` (empty = all) are skipped; unreadable segments and
+// undecodable records have no seq or type and are always shown.
+fn print_records(
+    out: &mut impl std::io::Write,
+    recs: Vec<serde_json::Value>,
+    from_seq: u64,
+    types: &[String],
+) -> Result<()> {
+    for rec in recs {
+        let seq_ok = rec.get("seq").and_then(|v| v.as_u64()).unwrap_or(u64::MAX) >= from_seq;
+        let type_ok = types.is_empty()
+            || rec
+                .get("type")
+                .and_then(|v| v.as_str())
+                .is_none_or(|t| types.iter().any(|want| want == t));
+        if seq_ok && type_ok {
+            writeln!(out, "{}", serde_json::to_string(&rec)?)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.cmd {
@@ -46,21 +95,21 @@ fn main() -> Result<()> {
             dump,
         } => {
             let mut objs = read_snapshot(&snapshot)?;
-            // replay WAL tail
-            let recs = walbin::replay(&wal_dir).unwrap_or_default();
-            for r in recs {
-                match r {
-                    walbin::RecBody::Put { ns: _, obj } => {
-                        objs.push(obj);
-                    }
-                    walbin::RecBody::Delete { ns, id } => {
-                        objs.retain(|o| {
-                            !(o.get("ns").and_then(|v| v.as_str()) == Some(&ns)
-                                && o.get("id").and_then(|v| v.as_str()) == Some(&id))
-                        });
-                    }
-                    _ => {}
+            // replay WAL tail, one record at a time
+            let replay = walbin::replay_with(&wal_dir, |r| match r {
+                walbin::RecBody::Put { ns: _, obj } => {
+                    objs.push(obj);
+                }
+                walbin::RecBody::Delete { ns, id, .. } => {
+                    objs.retain(|o| {
+                        !(o.get("ns").and_then(|v| v.as_str()) == Some(&ns)
+                            && o.get("id").and_then(|v| v.as_str()) == Some(&id))
+                    });
                 }
+                _ => {}
+            })?;
+            for issue in &replay.issues {
+                eprintln!("warning: {}", issue);
             }
             let last_seq = objs
                 .iter()
@@ -75,9 +124,135 @@ fn main() -> Result<()> {
                 }
                 std::fs::write(path, s)?;
             }
-            let report = serde_json::json!({ "last_seq": last_seq, "objects": objs.len(), "crc_ok": true, "index_consistent": true });
+            let report = serde_json::json!({
+                "last_seq": last_seq,
+                "objects": objs.len(),
+                "crc_ok": replay.crc_failures == 0 && replay.issues.is_empty(),
+                "records_applied": replay.records_applied,
+                "crc_failures": replay.crc_failures,
+                "truncated_at_offset": replay.truncated_at_offset,
+                "skipped": replay.issues,
+                "index_consistent": true
+            });
             std::fs::write(out, serde_json::to_vec_pretty(&report)?)?;
         }
+        Cmd::Verify { data_dir } => {
+            let report = walbin::verify(&data_dir)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.last_seq_ok {
+                eprintln!(
+                    "manifest last_seq {} but highest seq found is {}",
+                    report.last_seq, report.max_seq_seen
+                );
+            }
+            if !report.ok {
+                std::process::exit(1);
+            }
+        }
+        Cmd::WalCat {
+            data_dir,
+            from_seq,
+            types,
+            follow,
+        } => {
+            let mut cursor = walbin::WalCursor::default();
+            loop {
+                let (recs, next) = walbin::dump_since(&data_dir, &cursor)?;
+                print_records(&mut std::io::stdout().lock(), recs, from_seq, &types)?;
+                if !follow {
+                    break;
+                }
+                cursor = next;
+                std::thread::sleep(std::time::Duration::from_millis(250));
+            }
+        }
+        Cmd::DumpWal { wal_dir } => {
+            let recs = walbin::dump_wal(&wal_dir)?;
+            print_records(&mut std::io::stdout().lock(), recs, 0, &[])?
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use walbin::{RecBody, WalWriter};
+
+    fn put(id: &str) -> RecBody {
+        RecBody::Put {
+            ns: "ns".into(),
+            obj: json!({ "id": id }),
+        }
+    }
+
+    fn delete(id: &str, commit_seq: u64) -> RecBody {
+        RecBody::Delete {
+            ns: "ns".into(),
+            id: id.into(),
+            commit_seq,
+        }
+    }
+
+    // A WAL that rotates after every batch, so each record sits in a segment of its own
+    async fn write_wal(dir: &std::path::Path) {
+        let wal = WalWriter::open(dir, 1, 0).unwrap();
+        wal.append(1, 0, &put("a")).await.unwrap();
+        wal.append(2, 0, &put("b")).await.unwrap();
+        wal.append(3, 0, &delete("a", 3)).await.unwrap();
+        wal.append(4, 0, &put("c")).await.unwrap();
+        assert_eq!(wal.manifest().segments.len(), 5);
+    }
+
+    fn printed(recs: Vec<serde_json::Value>, from_seq: u64, types: &[&str]) -> Vec<(u64, String)> {
+        let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+        let mut out = Vec::new();
+        print_records(&mut out, recs, from_seq, &types).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let v: serde_json::Value = serde_json::from_str(l).unwrap();
+                (v["seq"].as_u64().unwrap(), v["type"].as_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn dump_wal_prints_records_in_replay_order_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        write_wal(dir.path()).await;
+        let recs = walbin::dump_wal(dir.path()).unwrap();
+        let segments: std::collections::BTreeSet<&str> =
+            recs.iter().filter_map(|r| r["segment"].as_str()).collect();
+        assert_eq!(segments.len(), 4);
+        let mut replayed = Vec::new();
+        walbin::replay_with(dir.path(), |rec| replayed.push(serde_json::to_value(rec).unwrap()))
+            .unwrap();
+        let bodies: Vec<_> = recs.iter().map(|r| r["body"].clone()).collect();
+        assert_eq!(bodies, replayed);
+        let seqs: Vec<u64> = printed(recs, 0, &[]).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(seqs, [1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn wal_cat_filters_on_seq_and_type() {
+        let dir = tempfile::tempdir().unwrap();
+        write_wal(dir.path()).await;
+        let recs = || walbin::dump_wal(dir.path()).unwrap();
+        let put = |seq: u64| (seq, "put".to_string());
+        assert_eq!(printed(recs(), 2, &[]).len(), 3);
+        assert_eq!(printed(recs(), 0, &["put"]), [put(1), put(2), put(4)]);
+        assert_eq!(printed(recs(), 2, &["put"]), [put(2), put(4)]);
+        assert_eq!(
+            printed(recs(), 0, &["delete", "lease_acquire"]),
+            [(3, "delete".to_string())]
+        );
+        // An unreadable segment has neither seq nor type, so it's shown whatever the filters
+        let broken = json!({"segment": "seg-x", "error": "unreadable"});
+        let mut out = Vec::new();
+        print_records(&mut out, vec![broken], 9, &["put".into()]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1);
+    }
+}
@@ -12,6 +12,11 @@ pub enum StateError {
     Invalid(String),
     #[error("internal error: {0}")]
     Internal(String),
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    // The store can't take writes right now (e.g. its WAL is failing); retry later
+    #[error("unavailable: {0}")]
+    Unavailable(String),
 }
 
 pub type Result<T> = std::result::Result<T, StateError>;
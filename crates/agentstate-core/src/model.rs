@@ -16,9 +16,52 @@ pub struct Tags(pub BTreeMap<String, String>);
 pub struct VecField {
     pub name: String,
     pub dims: usize,
+    #[serde(default)]
+    pub mode: VecDimsMode,
+}
+
+/// How a put handles an embedding whose length differs from `VecField.dims`.
+///
+/// `PadOrTruncate` keeps legacy vectors queryable, but zero-padding and
+/// truncation change the geometry: cosine scores between a coerced vector and
+/// a native one are only an approximation of what the original model would
+/// have produced, so rankings across mixed model versions are best-effort.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VecDimsMode {
+    /// Reject puts whose embedding length differs from `dims`.
+    #[default]
+    Strict,
+    /// Zero-pad shorter embeddings and truncate longer ones to `dims`.
+    PadOrTruncate,
 }
 
+/// A write-time normalization of one body path, e.g. `{"path":"body.email","op":"lowercase"}`.
+/// A namespace's rules run in registration order on every put.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformRule {
+    pub path: String,
+    pub op: TransformOp,
+}
+
+/// What a `TransformRule` does to the value at its path. Puts without the path are left
+/// alone, and `lowercase`/`trim` skip non-string values.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformOp {
+    /// Lowercase a string.
+    Lowercase,
+    /// Strip leading and trailing whitespace from a string.
+    Trim,
+    /// Remove the field.
+    Redact,
+    /// Replace the value with the blake3 hex digest of the string (or of the JSON text
+    /// of any other non-null value).
+    Hash,
+}
+
+// Serialize is written out below to add the id-derived `created_at`
+#[derive(Debug, Clone, Deserialize)]
 pub struct Object {
     pub id: ObjectId,
     pub ns: Namespace,
@@ -33,6 +76,9 @@ pub struct Object {
     pub commit: CommitId,
     pub ts: DateTime<Utc>,
     pub commit_seq: u64, // monotonic per-namespace
+    // None is application/json
+    #[serde(default)]
+    pub content_type: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -47,12 +93,155 @@ pub struct PutRequest {
     pub id: Option<ObjectId>,
     #[serde(default)]
     pub parents: Vec<CommitId>,
+    // Client event-time; defaults to server time. Bounded by MAX_CLOCK_SKEW_SECS
+    // against the object's previous version.
+    #[serde(default)]
+    pub ts: Option<DateTime<Utc>>,
+    // Skip the write, returning the latest version, when its body and tags already
+    // equal this request's (after write transforms)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub only_if_changed: bool,
+    // MIME type of the body, application/json when unset; see `BodyEncoding`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// How ids are made for puts that don't bring one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    #[default]
+    Ulid,
+    UuidV4,
+    UuidV7,
+    /// First 128 bits of the blake3 hash of the body, as 32 hex chars: identical bodies
+    /// get the same id, so re-putting content overwrites instead of duplicating it.
+    ContentHash,
+}
+
+impl IdStrategy {
+    /// ID_STRATEGY: ulid (default), uuidv4, uuidv7 or content_hash.
+    pub fn from_env() -> std::result::Result<Self, String> {
+        match std::env::var("ID_STRATEGY").ok().as_deref() {
+            None | Some("") | Some("ulid") => Ok(IdStrategy::Ulid),
+            Some("uuidv4") => Ok(IdStrategy::UuidV4),
+            Some("uuidv7") => Ok(IdStrategy::UuidV7),
+            Some("content_hash") => Ok(IdStrategy::ContentHash),
+            Some(other) => Err(format!(
+                "ID_STRATEGY must be ulid, uuidv4, uuidv7 or content_hash, got {:?}",
+                other
+            )),
+        }
+    }
+
+    /// The strategy ID_STRATEGY picked for this process.
+    pub fn configured() -> Self {
+        *ID_STRATEGY
+    }
+
+    pub fn generate(self, now: DateTime<Utc>, body: &JsonValue) -> ObjectId {
+        match self {
+            IdStrategy::Ulid => Ulid::from_datetime(now.into()).to_string(),
+            IdStrategy::UuidV4 => uuid::Uuid::new_v4().to_string(),
+            IdStrategy::UuidV7 => {
+                let ts = uuid::Timestamp::from_unix(
+                    uuid::NoContext,
+                    now.timestamp() as u64,
+                    now.timestamp_subsec_nanos(),
+                );
+                uuid::Uuid::new_v7(ts).to_string()
+            }
+            IdStrategy::ContentHash => {
+                // serde_json keeps object keys sorted, so equal bodies serialize equally
+                let mut h = blake3_hex(&serde_json::to_vec(body).unwrap_or_default());
+                h.truncate(32);
+                h
+            }
+        }
+    }
+}
+
+// Read once; the server checks ID_STRATEGY at startup, so a bad value never gets here
+static ID_STRATEGY: std::sync::LazyLock<IdStrategy> =
+    std::sync::LazyLock::new(|| IdStrategy::from_env().unwrap_or_default());
+
+/// How a body holds content of a given type. JSON types (`application/json`, `*/*+json`)
+/// are the body itself; `text/*` is a JSON string; anything else is the base64 of the
+/// raw bytes, as a JSON string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyEncoding {
+    Json,
+    Text,
+    Base64,
+}
+
+impl BodyEncoding {
+    pub fn of(content_type: &str) -> Self {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        let mime = mime.to_ascii_lowercase();
+        if mime == "application/json" || mime.ends_with("+json") {
+            BodyEncoding::Json
+        } else if mime.starts_with("text/") {
+            BodyEncoding::Text
+        } else {
+            BodyEncoding::Base64
+        }
+    }
+}
+
+impl PutRequest {
+    /// Checks the body can hold content of the request's `content_type`.
+    pub fn check_content(&self) -> crate::Result<()> {
+        let Some(ct) = self.content_type.as_deref() else {
+            return Ok(());
+        };
+        let invalid = |m: &str| Err(crate::StateError::Invalid(format!("{}: {}", ct, m)));
+        // Served back as the Content-Type header, so it has to be a valid header value
+        if !ct.contains('/') || !ct.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+            return invalid("content_type must be a MIME type");
+        }
+        match (BodyEncoding::of(ct), &self.body) {
+            (BodyEncoding::Json, _) => Ok(()),
+            (BodyEncoding::Text, JsonValue::String(_)) => Ok(()),
+            (BodyEncoding::Base64, JsonValue::String(s)) => {
+                use base64::{engine::general_purpose::STANDARD, Engine};
+                match STANDARD.decode(s) {
+                    Ok(_) => Ok(()),
+                    Err(_) => invalid("body must be a base64 string"),
+                }
+            }
+            (BodyEncoding::Text, _) => invalid("body must be a string"),
+            (BodyEncoding::Base64, _) => invalid("body must be a base64 string"),
+        }
+    }
+}
+
+/// One step of a multi-object transaction. Ops apply in order and later ops see
+/// the effect of earlier ones; if any op fails, none are applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TxnOp {
+    Put(PutRequest),
+    Delete {
+        id: ObjectId,
+    },
+    /// RFC 7386 merge patch over the current body; type, tags and TTL are kept.
+    Patch {
+        id: ObjectId,
+        patch: JsonValue,
+    },
 }
 
 impl Object {
     pub fn new_with_seq(ns: Namespace, mut req: PutRequest, commit_seq: u64) -> Self {
-        let id = req.id.take().unwrap_or_else(|| Ulid::new().to_string());
-        let ts = Utc::now();
+        let now = Utc::now();
+        // Generated ULID and UUIDv7 ids carry the same instant as the default ts
+        let id = req
+            .id
+            .take()
+            .unwrap_or_else(|| ID_STRATEGY.generate(now, &req.body));
+        let ts = req.ts.take().unwrap_or(now);
         let mut seed = format!("{}:{}:{}:{}", &ns, &id, req.r#type, ts.to_rfc3339());
         seed.push_str(&serde_json::to_string(&req.body).unwrap_or_default());
         let commit = blake3_hex(seed.as_bytes());
@@ -67,6 +256,51 @@ impl Object {
             commit,
             ts,
             commit_seq,
+            content_type: req.content_type,
+        }
+    }
+
+    /// The body's MIME type.
+    pub fn content_type(&self) -> &str {
+        self.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE)
+    }
+
+    /// Creation time embedded in the id, when the id is a ULID or UUIDv7 (millisecond
+    /// precision).
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        crate::util::ulid_timestamp(&self.id).or_else(|| crate::util::uuidv7_timestamp(&self.id))
+    }
+
+    /// When this version stops being visible, if it has a TTL.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.ttl_seconds
+            .map(|ttl| self.ts + chrono::Duration::seconds(ttl as i64))
+    }
+}
+
+impl Serialize for Object {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let created_at = self.created_at();
+        let extra = created_at.is_some() as usize + self.content_type.is_some() as usize;
+        let mut st = s.serialize_struct("Object", 10 + extra)?;
+        st.serialize_field("id", &self.id)?;
+        st.serialize_field("ns", &self.ns)?;
+        st.serialize_field("type", &self.r#type)?;
+        st.serialize_field("body", &self.body)?;
+        st.serialize_field("tags", &self.tags)?;
+        st.serialize_field("ttl_seconds", &self.ttl_seconds)?;
+        st.serialize_field("parents", &self.parents)?;
+        st.serialize_field("commit", &self.commit)?;
+        st.serialize_field("ts", &self.ts)?;
+        st.serialize_field("commit_seq", &self.commit_seq)?;
+        if let Some(ct) = &self.content_type {
+            st.serialize_field("content_type", ct)?;
+        }
+        // Derived, never read back: deserializing ignores it
+        if let Some(at) = created_at {
+            st.serialize_field("created_at", &at)?;
         }
+        st.end()
     }
 }
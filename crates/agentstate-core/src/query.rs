@@ -1,5 +1,10 @@
+use crate::errors::{Result, StateError};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering as CmpOrdering;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct TagFilter(pub BTreeMap<String, String>);
@@ -8,7 +13,47 @@ pub struct TagFilter(pub BTreeMap<String, String>);
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct JsonPathFilter {
     // path -> exact value match
+    #[serde(default)]
     pub equals: BTreeMap<String, serde_json::Value>,
+    // path -> bounds, checked by scanning the candidates (not served from an index)
+    #[serde(default)]
+    pub ranges: BTreeMap<String, Range>,
+}
+
+/// Bounds on the value at a path. Numbers compare numerically and strings
+/// lexicographically; a value of any other type, or of a different type than a
+/// bound, doesn't match.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Range {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gt: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gte: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lt: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lte: Option<serde_json::Value>,
+}
+
+impl Range {
+    pub fn matches(&self, v: &serde_json::Value) -> bool {
+        let holds = |bound: &Option<serde_json::Value>, ok: fn(CmpOrdering) -> bool| {
+            bound.as_ref().is_none_or(|b| compare(v, b).is_some_and(ok))
+        };
+        holds(&self.gt, CmpOrdering::is_gt)
+            && holds(&self.gte, CmpOrdering::is_ge)
+            && holds(&self.lt, CmpOrdering::is_lt)
+            && holds(&self.lte, CmpOrdering::is_le)
+    }
+}
+
+fn compare(a: &serde_json::Value, b: &serde_json::Value) -> Option<CmpOrdering> {
+    use serde_json::Value;
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        _ => None,
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -21,9 +66,109 @@ pub struct VectorQuery {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct QueryRequest {
     pub tag_filter: Option<TagFilter>,
+    // Key-existence filter: objects carrying every listed tag key, any value
+    #[serde(default)]
+    pub has_tag_keys: Vec<String>,
+    // Object type equality, served from a (ns, type) index
+    #[serde(default)]
+    pub r#type: Option<String>,
     pub jsonpath: Option<JsonPathFilter>,
     pub vector: Option<VectorQuery>,
     pub limit: Option<usize>,
     // Projection for body fields: e.g., ["text","status"]
     pub fields: Option<Vec<String>>,
+    // Incremental pull: only objects whose latest commit_seq is greater, ordered by commit_seq
+    #[serde(default)]
+    pub since_commit_seq: Option<u64>,
+    // Keep only the first result per distinct value at this body path, e.g. "$.dedup_id"
+    #[serde(default)]
+    pub distinct_by: Option<String>,
+    // Advisory lock state: true keeps only objects with a live lock, false only unlocked ones
+    #[serde(default)]
+    pub locked: Option<bool>,
+    // On deadline, return what was matched so far instead of failing
+    #[serde(default)]
+    pub partial_on_timeout: bool,
+    // OR groups: objects matching any sub-query (and the filters above); only the
+    // sub-queries' filters are used, limit/fields/vector/distinct_by apply out here
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub any_of: Vec<QueryRequest>,
+    // Set by the transport from X-Deadline / grpc-timeout and client disconnects
+    #[serde(skip)]
+    pub deadline: Deadline,
+}
+
+pub const MAX_ANY_OF_DEPTH: usize = 4;
+
+impl QueryRequest {
+    /// Rejects `any_of` groups nested more than MAX_ANY_OF_DEPTH levels deep.
+    pub fn check_any_of_depth(&self) -> Result<()> {
+        fn within(q: &QueryRequest, depth: usize) -> bool {
+            q.any_of.is_empty()
+                || (depth < MAX_ANY_OF_DEPTH && q.any_of.iter().all(|s| within(s, depth + 1)))
+        }
+        if within(self, 0) {
+            Ok(())
+        } else {
+            Err(StateError::Invalid(format!(
+                "any_of nested more than {} levels deep",
+                MAX_ANY_OF_DEPTH
+            )))
+        }
+    }
+}
+
+/// Deadline and cancellation flag for one request, checked inside storage scans.
+#[derive(Debug, Clone, Default)]
+pub struct Deadline {
+    pub at: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+    partial_ok: bool,
+    truncated: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            at: Some(Instant::now() + budget),
+            ..Default::default()
+        }
+    }
+
+    /// Marks the request abandoned, e.g. because the client disconnected.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Lets scans stop early with what they have once the deadline passes.
+    /// Cancellation still fails the request: nobody is waiting for the result.
+    pub fn allow_partial(&mut self) {
+        self.partial_ok = true;
+    }
+
+    /// Ok(false) when the deadline passed and partial results are allowed; the
+    /// caller stops scanning and the request is marked truncated.
+    pub fn keep_going(&self) -> Result<bool> {
+        match self.check() {
+            Ok(()) => Ok(true),
+            Err(_) if self.partial_ok && !self.cancelled.load(Ordering::Relaxed) => {
+                self.truncated.store(true, Ordering::Relaxed);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether a scan stopped early under `allow_partial`.
+    pub fn truncated(&self) -> bool {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) || self.at.is_some_and(|at| Instant::now() >= at)
+        {
+            return Err(StateError::DeadlineExceeded);
+        }
+        Ok(())
+    }
 }
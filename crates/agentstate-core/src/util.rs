@@ -1,4 +1,5 @@
 use blake3::Hasher;
+use chrono::{DateTime, Utc};
 
 pub fn blake3_hex(data: &[u8]) -> String {
     let mut hasher = Hasher::new();
@@ -6,3 +7,79 @@ pub fn blake3_hex(data: &[u8]) -> String {
     let hash = hasher.finalize();
     hash.to_hex().to_string()
 }
+
+/// The timestamp embedded in a ULID id, or None if the id isn't a ULID.
+pub fn ulid_timestamp(id: &str) -> Option<DateTime<Utc>> {
+    ulid::Ulid::from_string(id)
+        .ok()
+        .map(|u| DateTime::<Utc>::from(u.datetime()))
+}
+
+/// The timestamp embedded in a UUIDv7 id, or None for any other id.
+pub fn uuidv7_timestamp(id: &str) -> Option<DateTime<Utc>> {
+    let u = uuid::Uuid::parse_str(id).ok()?;
+    if u.get_version_num() != 7 {
+        return None;
+    }
+    let (secs, nanos) = u.get_timestamp()?.to_unix();
+    DateTime::from_timestamp(secs as i64, nanos)
+}
+
+/// Applies an RFC 7386 JSON Merge Patch: object members merge recursively, `null`
+/// removes a member, anything else replaces the target whole.
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    use serde_json::Value;
+    let Value::Object(p) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(t) = target else {
+        return;
+    };
+    for (k, v) in p.iter() {
+        if v.is_null() {
+            t.remove(k);
+        } else {
+            merge_patch(t.entry(k.clone()).or_insert(Value::Null), v);
+        }
+    }
+}
+
+/// RFC 6902 JSON Patch turning `from` into `to`. Objects are diffed per key;
+/// arrays and scalars that differ are replaced whole.
+pub fn json_diff(from: &serde_json::Value, to: &serde_json::Value) -> Vec<serde_json::Value> {
+    let mut ops = Vec::new();
+    diff_at(String::new(), from, to, &mut ops);
+    ops
+}
+
+fn diff_at(
+    path: String,
+    from: &serde_json::Value,
+    to: &serde_json::Value,
+    ops: &mut Vec<serde_json::Value>,
+) {
+    use serde_json::{json, Value};
+    match (from, to) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, av) in a.iter() {
+                let p = format!("{}/{}", path, k.replace('~', "~0").replace('/', "~1"));
+                match b.get(k) {
+                    Some(bv) => diff_at(p, av, bv, ops),
+                    None => ops.push(json!({"op": "remove", "path": p})),
+                }
+            }
+            for (k, bv) in b.iter() {
+                if !a.contains_key(k) {
+                    let p = format!("{}/{}", path, k.replace('~', "~0").replace('/', "~1"));
+                    ops.push(json!({"op": "add", "path": p, "value": bv}));
+                }
+            }
+        }
+        _ if from != to => ops.push(json!({"op": "replace", "path": path, "value": to})),
+        _ => {}
+    }
+}
@@ -0,0 +1,206 @@
+// Read-only follower: tails a leader's /admin/wal/stream and applies records locally.
+use agentstate_storage::walbin::{RecBody, WalCursor, WalEntry};
+use agentstate_storage::Storage;
+use futures::StreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+pub fn spawn(store: Arc<dyn Storage>, leader: String, data_dir: Option<PathBuf>) {
+    tokio::spawn(async move {
+        // Resume position survives restarts when the follower has a data dir
+        let cursor_path = data_dir.map(|d| d.join("follow.cursor"));
+        let mut cursor: WalCursor = cursor_path
+            .as_ref()
+            .and_then(|p| std::fs::read(p).ok())
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default();
+        let token = std::env::var("FOLLOW_TOKEN").ok();
+        let client = reqwest::Client::new();
+        let mut backoff = BACKOFF_MIN;
+        loop {
+            if let Err(e) = stream_once(
+                &client,
+                &leader,
+                token.as_deref(),
+                &store,
+                &mut cursor,
+                cursor_path.as_deref(),
+                &mut backoff,
+            )
+            .await
+            {
+                tracing::warn!(
+                    "follower stream from {} failed at {}:{}: {}",
+                    leader,
+                    cursor.segment,
+                    cursor.offset,
+                    e
+                );
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+        }
+    });
+}
+
+async fn stream_once(
+    client: &reqwest::Client,
+    leader: &str,
+    token: Option<&str>,
+    store: &Arc<dyn Storage>,
+    cursor: &mut WalCursor,
+    cursor_path: Option<&Path>,
+    backoff: &mut Duration,
+) -> anyhow::Result<()> {
+    let url = format!("{}/admin/wal/stream", leader.trim_end_matches('/'));
+    let mut req = client.get(url).query(&[
+        ("segment", cursor.segment.clone()),
+        ("offset", cursor.offset.to_string()),
+    ]);
+    if let Some(t) = token {
+        req = req.bearer_auth(t);
+    }
+    let resp = req.send().await?.error_for_status()?;
+    *backoff = BACKOFF_MIN;
+    tracing::info!("following {} from {}:{}", leader, cursor.segment, cursor.offset);
+    let mut body = resp.bytes_stream();
+    let mut buf: Vec<u8> = Vec::new();
+    // Records of a transaction still waiting for its commit marker
+    let mut txn: Option<Vec<RecBody>> = None;
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        let mut applied = false;
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            if line.len() <= 1 {
+                continue;
+            }
+            let entry: WalEntry = serde_json::from_slice(&line)?;
+            // A transaction is applied whole at its commit, and the cursor only moves past
+            // it then, so a reconnect mid-transaction reads it again from the start
+            let recs = match (txn.as_mut(), entry.body) {
+                (Some(recs), body @ RecBody::TxnCommit { .. }) => {
+                    recs.push(body);
+                    txn.take().unwrap_or_default()
+                }
+                (Some(recs), body) => {
+                    recs.push(body);
+                    continue;
+                }
+                (None, body @ RecBody::TxnBegin { .. }) => {
+                    txn = Some(vec![body]);
+                    continue;
+                }
+                (None, body) => vec![body],
+            };
+            store.apply_replicated(entry.ts, recs).await?;
+            *cursor = entry.next;
+            applied = true;
+        }
+        if applied {
+            if let Some(p) = cursor_path {
+                std::fs::write(p, serde_json::to_vec(&*cursor)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agentstate_core::{PutRequest, StateError, TxnOp};
+    use agentstate_storage::traits::{GetOptions, LeaseStore, ObjectStore};
+    use agentstate_storage::{InMemoryStore, PersistentStore};
+
+    fn note(id: &str) -> PutRequest {
+        PutRequest {
+            r#type: "note".into(),
+            body: serde_json::json!({"id": id}),
+            id: Some(id.into()),
+            ..Default::default()
+        }
+    }
+
+    async fn has(store: &Arc<dyn Storage>, id: &str) -> bool {
+        store
+            .get("ns", id, GetOptions { at_ts: None })
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn follower_converges_on_the_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = Arc::new(PersistentStore::open(dir.path().to_path_buf()).unwrap());
+        leader.put("ns", note("a")).await.unwrap();
+        leader.put("ns", note("b")).await.unwrap();
+        let ops = vec![TxnOp::Put(note("c")), TxnOp::Delete { id: "b".into() }];
+        leader.txn("ns", ops).await.unwrap();
+        leader
+            .lease_acquire("ns", "job", "leader-side", 600)
+            .await
+            .unwrap();
+
+        let mut state = crate::tests::app_state(leader.clone());
+        state.data_dir = Some(dir.path().to_path_buf());
+        let app = axum::Router::new()
+            .route(
+                "/admin/wal/stream",
+                axum::routing::get(crate::admin_wal_stream),
+            )
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let follower: Arc<dyn Storage> = Arc::new(InMemoryStore::new());
+        let token = crate::tests::token(serde_json::json!({}));
+        let replica = follower.clone();
+        tokio::spawn(async move {
+            let bearer = token.trim_start_matches("Bearer ");
+            let mut cursor = WalCursor::default();
+            let mut backoff = BACKOFF_MIN;
+            let client = reqwest::Client::new();
+            let stream = stream_once(
+                &client,
+                &url,
+                Some(bearer),
+                &replica,
+                &mut cursor,
+                None,
+                &mut backoff,
+            );
+            stream.await
+        });
+
+        for _ in 0..100 {
+            if has(&follower, "c").await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(has(&follower, "a").await);
+        assert!(has(&follower, "c").await);
+        assert!(!has(&follower, "b").await);
+        let err = follower
+            .lease_acquire("ns", "job", "follower-side", 30)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StateError::Conflict(_)), "{err:?}");
+
+        // Later writes keep streaming in
+        leader.put("ns", note("d")).await.unwrap();
+        for _ in 0..100 {
+            if has(&follower, "d").await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(has(&follower, "d").await);
+    }
+}
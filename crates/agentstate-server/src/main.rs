@@ -1,4 +1,6 @@
-use agentstate_core::{PutRequest, QueryRequest};
+use agentstate_core::{PutRequest, QueryRequest, StateError};
+use agentstate_storage::crypt;
+use agentstate_storage::mem::{body_path, project_body};
 use agentstate_storage::{InMemoryStore, PersistentStore, Storage};
 use axum::http::StatusCode;
 use axum::{
@@ -9,18 +11,18 @@ use axum::{
     Json, Router,
 };
 use once_cell::sync::Lazy;
-use opentelemetry_sdk;
 use opentelemetry_otlp::WithExportConfig;
 use tracing_subscriber::prelude::*;
-use prometheus::{
-    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Registry, TextEncoder,
-};
+use prometheus::{Encoder, TextEncoder};
 use serde_json::json;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{info, Level};
+mod follower;
 mod metrics;
+mod tls;
+mod webhook;
 use metrics::{WATCH_CLIENTS, WATCH_EVENTS_TOTAL, WATCH_RESUMES_TOTAL};
 use futures::Stream;
 use hmac::{Hmac, Mac};
@@ -30,24 +32,93 @@ use std::path::Path as StdPath;
 use std::pin::Pin;
 use tonic::{transport::Server as GrpcServer, Request, Response as TonicResponse, Status};
 
+// token bucket state: (tokens, last refill, burst)
+type Buckets = std::collections::HashMap<String, (f64, std::time::Instant, u64)>;
+
 #[derive(Clone)]
 struct AppState {
     store: Arc<dyn Storage>,
-    // simple in-memory idempotency cache: (ns, key)-> (ts, response json)
-    idem: Arc<
-        parking_lot::RwLock<
-            std::collections::HashMap<(String, String), (std::time::Instant, serde_json::Value)>,
-        >,
-    >,
     // rate limiters keyed by cap token identity (kid+jti)
-    qps:
-        Arc<parking_lot::RwLock<std::collections::HashMap<String, (f64, std::time::Instant, u64)>>>,
+    qps: Arc<parking_lot::RwLock<Buckets>>,
+    // follower mode (leader base URL): writes are rejected, reads served locally
+    // unless they ask for strong consistency
+    leader: Option<String>,
+    // MAX_INFLIGHT_WRITES admission control; None when unlimited
+    writes: Option<Arc<tokio::sync::Semaphore>>,
+    regions: Arc<Regions>,
+    keys: Arc<CapKeys>,
+    // DATA_DIR, for the admin endpoints that read WAL segments off disk
+    data_dir: Option<std::path::PathBuf>,
+    // Set once the startup ANN warm-up has run (immediately when ANN_WARMUP is off)
+    warmed: Arc<std::sync::atomic::AtomicBool>,
+}
+
+// REGION pins this server; writes from a token whose `region` claim names another
+// region go to that region's REGION_ENDPOINTS entry (comma-separated region=url pairs,
+// e.g. "eu-west-1=https://eu.example.com")
+#[derive(Debug, Clone, Default)]
+struct Regions {
+    local: Option<String>,
+    endpoints: std::collections::HashMap<String, String>,
+}
+
+impl Regions {
+    fn from_env() -> Self {
+        Self {
+            local: std::env::var("REGION").ok().filter(|s| !s.is_empty()),
+            endpoints: std::env::var("REGION_ENDPOINTS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(r, u)| (r.trim().to_string(), u.trim().trim_end_matches('/').to_string()))
+                .collect(),
+        }
+    }
+
+    // The token's home region, when that isn't this server's
+    fn elsewhere<'a>(&self, claims: &'a serde_json::Value) -> Option<&'a str> {
+        let reg = claims.get("region")?.as_str()?;
+        let local = self.local.as_deref()?;
+        (local != reg).then_some(reg)
+    }
+}
+
+// Capability token keys as (kid, secret): CAP_KEY_ACTIVE and CAP_KEY_NEXT, with kids
+// CAP_KEY_ACTIVE_ID and CAP_KEY_NEXT_ID (default "active" and "next"). With neither
+// key set, tokens aren't checked.
+#[derive(Debug, Clone, Default)]
+struct CapKeys {
+    active: Option<(String, String)>,
+    next: Option<(String, String)>,
+}
+
+impl CapKeys {
+    fn from_env() -> Self {
+        let key = |var: &str, kid: &str| {
+            let secret = std::env::var(var).ok()?;
+            let kid = std::env::var(format!("{}_ID", var)).unwrap_or_else(|_| kid.into());
+            Some((kid, secret))
+        };
+        Self {
+            active: key("CAP_KEY_ACTIVE", "active"),
+            next: key("CAP_KEY_NEXT", "next"),
+        }
+    }
+
+    fn secret(&self, kid: &str) -> Option<&str> {
+        [&self.active, &self.next]
+            .into_iter()
+            .flatten()
+            .find(|(k, _)| k == kid)
+            .map(|(_, secret)| secret.as_str())
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Tracing + optional OTLP
     let otlp = std::env::var("OTLP_ENDPOINT").ok();
+    let tracing_enabled = otlp.is_some();
     if let Some(endpoint) = otlp {
         let tracer = opentelemetry_otlp::new_pipeline()
             .tracing()
@@ -77,7 +148,9 @@ async fn main() -> anyhow::Result<()> {
             .init();
     }
 
-    let store: Arc<dyn Storage> = if let Ok(dir) = std::env::var("DATA_DIR") {
+    let store: Arc<dyn Storage> = if std::env::var("STORAGE_ENGINE").as_deref() == Ok("rocksdb") {
+        open_rocks()
+    } else if let Ok(dir) = std::env::var("DATA_DIR") {
         match PersistentStore::open(std::path::PathBuf::from(dir)) {
             Ok(p) => Arc::new(p),
             Err(e) => {
@@ -88,118 +161,183 @@ async fn main() -> anyhow::Result<()> {
     } else {
         Arc::new(InMemoryStore::new())
     };
+    crypt::validate_config();
+    if let Err(e) = agentstate_core::IdStrategy::from_env() {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+    let tls_min = match tls::min_version() {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = agentstate_storage::validator::init() {
+        tracing::error!("wasm validator: {}", e);
+        std::process::exit(1);
+    }
+    let leader = std::env::var("FOLLOW_LEADER_URL")
+        .ok()
+        .filter(|s| !s.is_empty());
     let state = AppState {
         store,
-        idem: Arc::new(parking_lot::RwLock::new(std::collections::HashMap::new())),
         qps: Arc::new(parking_lot::RwLock::new(std::collections::HashMap::new())),
+        leader: leader.clone(),
+        writes: std::env::var("MAX_INFLIGHT_WRITES")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .map(|n| Arc::new(tokio::sync::Semaphore::new(n))),
+        regions: Arc::new(Regions::from_env()),
+        keys: Arc::new(CapKeys::from_env()),
+        data_dir: std::env::var("DATA_DIR").ok().map(std::path::PathBuf::from),
+        warmed: Arc::new(std::sync::atomic::AtomicBool::new(false)),
     };
+    if let Some(leader) = leader {
+        info!("follower mode: replicating from {}", leader);
+        follower::spawn(state.store.clone(), leader, state.data_dir.clone());
+    }
     
     let store_for_backlog = state.store.clone();
     let sweeper_state = state.clone();
-    let snapshot_state = state.clone();
+    let checker_store = state.store.clone();
+    let snapshot_store = state.store.clone();
+    let warm_store = state.store.clone();
+    let warmed = state.warmed.clone();
     let grpc_state = state.clone();
 
-    // Metrics registry (MVP)
-    static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
-    static OPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
-        IntCounterVec::new(prometheus::opts!("agentstate_ops_total", "ops"), &["op"]).unwrap()
-    });
-    static OP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
-        HistogramVec::new(
-            prometheus::opts!("op_duration_seconds", "op durations").into(),
-            &["op"],
-        )
-        .unwrap()
-    });
-    static WAL_FSYNC_TOTAL: Lazy<IntCounter> =
-        Lazy::new(|| IntCounter::new("wal_fsync_total", "wal fsyncs").unwrap());
-    static WATCH_CLIENTS: Lazy<IntGaugeVec> = Lazy::new(|| {
-        IntGaugeVec::new(
-            prometheus::opts!("watch_clients", "active watch clients"),
-            &["proto"],
-        )
-        .unwrap()
-    });
-    static WATCH_EVENTS_TOTAL: Lazy<IntCounter> =
-        Lazy::new(|| IntCounter::new("watch_events_total", "watch events emitted").unwrap());
-    static WATCH_RESUMES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
-        IntCounterVec::new(
-            prometheus::opts!("watch_resumes_total", "watch resumes"),
-            &["proto"],
+    metrics::init();
+
+    let app = router(state);
+    // Per-request spans carry the trace ids used as op_duration_seconds exemplars
+    let app = if tracing_enabled {
+        app.layer(
+            tower_http::trace::TraceLayer::new_for_http()
+                .make_span_with(tower_http::trace::DefaultMakeSpan::new().level(Level::INFO)),
         )
-        .unwrap()
-    });
-    let _ = REGISTRY.register(Box::new(OPS_TOTAL.clone()));
-    let _ = REGISTRY.register(Box::new(OP_DURATION.clone()));
-    let _ = REGISTRY.register(Box::new(WAL_FSYNC_TOTAL.clone()));
-    let _ = REGISTRY.register(Box::new(WATCH_CLIENTS.clone()));
-    let _ = REGISTRY.register(Box::new(WATCH_EVENTS_TOTAL.clone()));
-    let _ = REGISTRY.register(Box::new(WATCH_RESUMES_TOTAL.clone()));
-    // Register dashboard metrics
-    let _ = prometheus::default_registry().register(Box::new(metrics::WAL_ACTIVE_SEGMENTS.clone()));
-    let _ = prometheus::default_registry().register(Box::new(metrics::STORAGE_BYTES_TOTAL.clone()));
-    let _ = prometheus::default_registry().register(Box::new(metrics::WATCH_DROPS_TOTAL.clone()));
-    let _ =
-        prometheus::default_registry().register(Box::new(metrics::WATCH_BACKLOG_EVENTS.clone()));
-    let _ = prometheus::default_registry().register(Box::new(metrics::WATCH_EMIT_LAG_SEC.clone()));
-    let _ = prometheus::default_registry().register(Box::new(metrics::SNAPSHOT_TOTAL.clone()));
-    let _ =
-        prometheus::default_registry().register(Box::new(metrics::SNAPSHOT_DURATION_SEC.clone()));
-    let _ =
-        prometheus::default_registry().register(Box::new(metrics::VECTOR_QUERY_SECONDS.clone()));
-    let _ =
-        prometheus::default_registry().register(Box::new(metrics::QUERY_PLANNER_MICROS.clone()));
-
-    let app = Router::new()
-        .route("/health", get(health))
-        .route("/v1/:ns/objects", post(put_objects))
-        .route("/v1/:ns/objects/:id", get(get_object).delete(delete_object))
-        .route("/v1/:ns/query", post(query))
-        .route("/v1/:ns/watch", get(watch_sse))
-        .route("/v1/:ns/lease/acquire", post(lease_acquire))
-        .route("/v1/:ns/lease/renew", post(lease_renew))
-        .route("/v1/:ns/lease/release", post(lease_release))
-        .route("/admin/snapshot", post(admin_snapshot))
-        .route("/admin/manifest", get(admin_manifest))
-        .route("/admin/trim-wal", post(admin_trim_wal))
-        .route("/admin/explain-query", post(admin_explain_query))
-        .route("/admin/dump", get(admin_dump))
-        .route("/metrics", get(metrics))
-        .with_state(state)
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        );
+    } else {
+        app
+    };
+    // DEFAULT_NAMESPACE: ns-less /v1 routes are rewritten before routing
+    let app = if DEFAULT_NAMESPACE.is_some() {
+        Router::new()
+            .fallback_service(app)
+            .layer(axum::middleware::map_request(default_ns_rewrite))
+    } else {
+        app
+    };
 
     let http_addr: SocketAddr = "0.0.0.0:8080".parse().unwrap();
     let grpc_addr: SocketAddr = "0.0.0.0:9090".parse().unwrap();
     info!("http listening on {}", http_addr);
     info!("grpc listening on {}", grpc_addr);
 
+    // ANN warm-up (ANN_WARMUP=1): /ready waits until the store's graphs are paged in
+    tokio::spawn(async move {
+        let ran = tokio::task::spawn_blocking(move || warm_store.warm_ann())
+            .await
+            .unwrap_or(0);
+        if ran > 0 {
+            info!("ann warm-up ran {} synthetic searches", ran);
+        }
+        warmed.store(true, std::sync::atomic::Ordering::SeqCst);
+    });
+
     // TTL sweeper
+    webhook::spawn();
     tokio::spawn(async move {
         loop {
-            let _ = sweeper_state.store.sweep_expired(0).await; // retention window unused in mem engine
+            if let Ok(expired) = sweeper_state.store.sweep_expired(0).await {
+                webhook::notify_expired(&expired);
+            }
             tokio::time::sleep(std::time::Duration::from_secs(30)).await;
         }
     });
 
-    // Snapshotter (if persistent store)
-    if std::env::var("DATA_DIR").is_ok() {
+    // Index consistency checker (INDEX_CHECK_INTERVAL_SECS, default off), verifying
+    // INDEX_CHECK_SAMPLE index entries per run (default 1000)
+    let check_interval = std::env::var("INDEX_CHECK_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(0);
+    if check_interval > 0 {
+        let sample = std::env::var("INDEX_CHECK_SAMPLE")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(1000);
         tokio::spawn(async move {
             loop {
-                // downcast to PersistentStore via Any is non-trivial; call via HTTP admin in future.
-                // For now, do nothing; placeholder for snapshot scheduling.
-                tokio::time::sleep(std::time::Duration::from_secs(300)).await;
+                tokio::time::sleep(std::time::Duration::from_secs(check_interval)).await;
+                let store = checker_store.clone();
+                let Ok(found) =
+                    tokio::task::spawn_blocking(move || store.check_indexes(sample)).await
+                else {
+                    continue;
+                };
+                for m in found {
+                    metrics::INDEX_INCONSISTENCY_TOTAL
+                        .with_label_values(&[m.index])
+                        .inc();
+                    tracing::warn!(
+                        "index inconsistency in {} index: {}/{}: {}",
+                        m.index,
+                        m.ns,
+                        m.id,
+                        m.detail
+                    );
+                }
             }
         });
+    }
+
+    // Snapshotter (if persistent store)
+    if std::env::var("DATA_DIR").is_ok() {
+        // SNAPSHOT_INTERVAL_SECS: how often to consider a snapshot (default 300, 0 = off);
+        // one is only taken once SNAPSHOT_MIN_WAL_BYTES (default 64 MiB) of WAL has been
+        // written since the last, so an idle server isn't snapshotted over and over. With
+        // WAL_DISABLED snapshots are the only durability: any write since the last is enough.
+        let interval = std::env::var("SNAPSHOT_INTERVAL_SECS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(300);
+        let min_bytes = std::env::var("SNAPSHOT_MIN_WAL_BYTES")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(64 * 1024 * 1024);
+        if interval > 0 {
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+                    if !snapshot_due(min_bytes) {
+                        continue;
+                    }
+                    // A background job already running covers this round
+                    if SNAPSHOT_JOBS.lock().iter().any(|j| j.status == "running") {
+                        continue;
+                    }
+                    match run_snapshot(&snapshot_store).await {
+                        Ok((id, last_seq)) => {
+                            tracing::info!("scheduled snapshot {} at seq {}", id, last_seq)
+                        }
+                        // In-memory fallback (DATA_DIR unusable): nothing to snapshot
+                        Err(agentstate_core::StateError::Invalid(e)) => {
+                            tracing::info!("snapshot scheduler stopped: {}", e);
+                            break;
+                        }
+                        Err(e) => tracing::warn!("scheduled snapshot failed: {}", e),
+                    }
+                }
+            });
+        }
         // Data dir scanner for storage_bytes_total
         let data_dir = std::env::var("DATA_DIR").unwrap();
         tokio::spawn(async move {
             loop {
-                let wal = dir_size(std::path::Path::new(&data_dir).join("wal"));
+                let wal = agentstate_storage::walbin::wal_dirs(std::path::Path::new(&data_dir))
+                    .iter()
+                    .map(dir_size)
+                    .sum::<u64>();
                 let snaps = dir_size(std::path::Path::new(&data_dir).join("snapshots"));
                 metrics::STORAGE_BYTES_TOTAL
                     .with_label_values(&["wal"])
@@ -228,9 +366,7 @@ async fn main() -> anyhow::Result<()> {
     let http = if use_tls {
         let cert = std::fs::read(std::env::var("TLS_CERT_PATH").unwrap()).expect("read cert");
         let key = std::fs::read(std::env::var("TLS_KEY_PATH").unwrap()).expect("read key");
-        let config = axum_server::tls_rustls::RustlsConfig::from_pem(cert, key)
-            .await
-            .expect("tls");
+        let config = tls::http_config(cert, key, tls_min).await.expect("tls");
         tokio::spawn(async move {
             axum_server::bind_rustls(http_addr, config)
                 .serve(app.into_make_service())
@@ -250,27 +386,39 @@ async fn main() -> anyhow::Result<()> {
             state: grpc_state,
         };
         let mut builder = GrpcServer::builder();
+        // tonic's TLS config can't limit versions, so 1.3-only terminates TLS itself
+        let mut acceptor = None;
         if use_tls {
             let cert = std::fs::read(std::env::var("TLS_CERT_PATH").unwrap()).expect("read cert");
             let key = std::fs::read(std::env::var("TLS_KEY_PATH").unwrap()).expect("read key");
-            let identity = tonic::transport::Identity::from_pem(cert, key);
-            let mut tls = tonic::transport::ServerTlsConfig::new().identity(identity);
-            if let Ok(ca) = std::env::var("TLS_CLIENT_CA_PATH").map(std::fs::read) {
-                if let Ok(ca_bytes) = ca {
+            let ca = std::env::var("TLS_CLIENT_CA_PATH")
+                .ok()
+                .and_then(|p| std::fs::read(p).ok());
+            if tls_min == tls::MinVersion::Tls13 {
+                acceptor = Some(tls::grpc_acceptor(&cert, &key, ca.as_deref()).expect("tls"));
+            } else {
+                let identity = tonic::transport::Identity::from_pem(cert, key);
+                let mut tls = tonic::transport::ServerTlsConfig::new().identity(identity);
+                if let Some(ca_bytes) = ca {
                     let ca = tonic::transport::Certificate::from_pem(ca_bytes);
                     tls = tls.client_ca_root(ca);
                 }
+                builder = builder.tls_config(tls).expect("tls config");
             }
-            builder = builder.tls_config(tls).expect("tls config");
         }
         tokio::spawn(async move {
-            builder
-                .add_service(agentstate_v1::agent_state_server::AgentStateServer::new(
-                    svc,
-                ))
-                .serve(grpc_addr)
-                .await
-                .unwrap();
+            let router = builder.add_service(
+                agentstate_v1::agent_state_server::AgentStateServer::new(svc),
+            );
+            match acceptor {
+                Some(acceptor) => {
+                    let incoming = tls::grpc_incoming(grpc_addr, acceptor)
+                        .await
+                        .expect("grpc listen");
+                    router.serve_with_incoming(incoming).await.unwrap();
+                }
+                None => router.serve(grpc_addr).await.unwrap(),
+            }
         })
     };
 
@@ -278,6 +426,48 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+// SLOW_OP_THRESHOLD_MS: log ops at least this slow (default unset = disabled)
+static SLOW_OP_THRESHOLD_MS: Lazy<Option<u64>> = Lazy::new(|| {
+    std::env::var("SLOW_OP_THRESHOLD_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+});
+
+// Structured warning for ops slower than SLOW_OP_THRESHOLD_MS; queries also pass
+// how many objects their metadata stage scanned
+fn log_if_slow(
+    op: &str,
+    ns: &str,
+    started: std::time::Instant,
+    results: Option<usize>,
+    candidates: Option<usize>,
+) {
+    if let Some(threshold_ms) = *SLOW_OP_THRESHOLD_MS {
+        warn_if_slow(threshold_ms, op, ns, started, results, candidates);
+    }
+}
+
+fn warn_if_slow(
+    threshold_ms: u64,
+    op: &str,
+    ns: &str,
+    started: std::time::Instant,
+    results: Option<usize>,
+    candidates: Option<usize>,
+) {
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if duration_ms >= threshold_ms {
+        tracing::warn!(
+            ns,
+            op,
+            duration_ms,
+            results = ?results,
+            candidates = ?candidates,
+            "slow operation"
+        );
+    }
+}
+
 fn dir_size(path: impl AsRef<StdPath>) -> u64 {
     fn walk(p: &StdPath, acc: &mut u64) {
         if let Ok(md) = std::fs::metadata(p) {
@@ -297,50 +487,94 @@ fn dir_size(path: impl AsRef<StdPath>) -> u64 {
     total
 }
 
+// Checks the bearer token the way every endpoint does, minus ns/verb scoping, and returns
+// its claims (never the key) so clients can debug scoped tokens without an operation.
+async fn token_introspect(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    match verify_token(&app.keys, &headers) {
+        Ok(claims) => {
+            let caps_enabled = claims.get("kid").is_some();
+            (
+                StatusCode::OK,
+                Json(json!({"valid": true, "caps_enabled": caps_enabled, "claims": claims})),
+            )
+                .into_response()
+        }
+        Err((code, Json(mut body))) => {
+            body["valid"] = json!(false);
+            (code, Json(body)).into_response()
+        }
+    }
+}
+
 async fn health() -> impl IntoResponse {
     (StatusCode::OK, "ok")
 }
 
+// 503 until the startup ANN warm-up is done, and again while a vec field registration
+// warms its new graph, so load balancers hold traffic off cold indexes
+async fn ready(State(app): State<AppState>) -> impl IntoResponse {
+    if !app.warmed.load(std::sync::atomic::Ordering::SeqCst) || app.store.ann_warming() {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"status":"warming"})));
+    }
+    (StatusCode::OK, Json(json!({"status":"ready"})))
+}
+
+// Fails while the WAL can't persist writes (e.g. disk full), unlike /health.
+// A store that came up without some of its data stays ready but reports "degraded".
+async fn health_deep(State(app): State<AppState>) -> impl IntoResponse {
+    match app.store.wal_health().await {
+        Ok(()) => {
+            let issues = app.store.recovery_issues();
+            if issues.is_empty() {
+                (StatusCode::OK, Json(json!({"status":"ok"}))).into_response()
+            } else {
+                let body = json!({"status":"degraded","recovery": issues});
+                (StatusCode::OK, Json(body)).into_response()
+            }
+        }
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status":"wal_failed","error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
 async fn put_objects(
     State(app): State<AppState>,
     Path(ns): Path<String>,
     headers: HeaderMap,
     Json(req): Json<PutRequest>,
 ) -> impl IntoResponse {
-    let claims = match enforce_caps(&headers, &ns, "put") {
+    let claims = match enforce_caps(&app, &headers, &ns, "put") {
         Ok(c) => c,
         Err(resp) => return resp.into_response(),
     };
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
     if let Err(resp) = rate_limit(&app, &claims) {
         return resp.into_response();
     }
-    // Region pin
-    if let Some(reg) = claims.get("region").and_then(|v| v.as_str()) {
-        if let Ok(srv) = std::env::var("REGION") {
-            if !srv.is_empty() && srv != reg {
-                return (
-                    StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
-                    Json(json!({"error":"region_mismatch"})),
-                )
-                    .into_response();
-            }
-        }
+    let _permit = match admit_write(&app) {
+        Ok(p) => p,
+        Err(resp) => return resp.into_response(),
+    };
+    let path = format!("/v1/{}/objects", ns);
+    let post = reqwest::Method::POST;
+    if let Some(resp) = route_write(&app, &claims, &headers, post, &path, || to_body(&req)).await {
+        return resp;
     }
-    // Size limits
-    if let Some(maxb) = claims.get("max_bytes").and_then(|v| v.as_u64()) {
-        if let Some(cl) = headers
-            .get("content-length")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|s| s.parse::<u64>().ok())
-        {
-            if cl > maxb {
-                return (
-                    StatusCode::PAYLOAD_TOO_LARGE,
-                    Json(json!({"error":"too_large"})),
-                )
-                    .into_response();
-            }
-        }
+    if let Err(resp) = reject_if_too_large(&claims, &headers) {
+        return resp.into_response();
+    }
+    // Sealed fields get a fresh nonce per put, so they never compare equal
+    if req.only_if_changed && crypt::encrypts(&ns) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "only_if_changed is not supported in encrypted namespaces"})),
+        )
+            .into_response();
     }
     // Optional lease fencing
     if let Some(resource) = headers.get("If-Resource").and_then(|v| v.to_str().ok()) {
@@ -361,28 +595,28 @@ async fn put_objects(
             }
         }
     }
-    let _timer = {
-        // local static
-        static OP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
-            HistogramVec::new(
-                prometheus::opts!("op_duration_seconds", "op durations").into(),
-                &["op"],
-            )
-            .unwrap()
-        });
-        OP_DURATION.with_label_values(&["put"]).start_timer()
-    };
+    let _timer = metrics::OpTimer::new("put");
     // Idempotency key support
     if let Some(key) = headers.get("Idempotency-Key").and_then(|v| v.to_str().ok()) {
         // persisted idempotency
         let body_hash =
             agentstate_core::util::blake3_hex(serde_json::to_vec(&req).unwrap().as_slice());
-        if let Ok(Some(rec)) = app.store.idempotency_lookup(&ns, key, &body_hash).await {
+        if let Ok(Some(mut rec)) = app.store.idempotency_lookup(&ns, key, &body_hash).await {
+            crypt::open_value(&claims, &mut rec.response);
+            if let Some(body) = rec.response.get_mut("body") {
+                redact_body(&claims, body);
+            }
             return (StatusCode::OK, Json(rec.response)).into_response();
         }
-        match app.store.put(&ns, req).await {
-            Ok(obj) => {
-                let val = serde_json::to_value(&obj).unwrap_or(json!({"id": obj.id}));
+        let t0 = std::time::Instant::now();
+        let res = put_or_skip(&app, &ns, req).await;
+        log_if_slow("put", &ns, t0, None, None);
+        match res {
+            Ok((obj, changed)) => {
+                let mut val = serde_json::to_value(&obj).unwrap_or(json!({"id": obj.id}));
+                if let Some(changed) = changed {
+                    val["changed"] = json!(changed);
+                }
                 let _ = app
                     .store
                     .idempotency_commit(
@@ -394,735 +628,4711 @@ async fn put_objects(
                         chrono::Utc::now() + chrono::Duration::minutes(10),
                     )
                     .await;
-                {
-                    static OPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
-                        IntCounterVec::new(
-                            prometheus::opts!("agentstate_ops_total", "ops"),
-                            &["op"],
-                        )
-                        .unwrap()
-                    });
-                    OPS_TOTAL.with_label_values(&["put"]).inc();
+                metrics::OPS_TOTAL.with_label_values(&["put"]).inc();
+                crypt::open_value(&claims, &mut val);
+                if let Some(body) = val.get_mut("body") {
+                    redact_body(&claims, body);
                 }
                 (StatusCode::OK, Json(val)).into_response()
             }
-            Err(e) => (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": e.to_string()})),
-            )
-                .into_response(),
+            Err(e) => put_error(e),
         }
     } else {
-        match app.store.put(&ns, req).await {
-            Ok(obj) => {
-                static OPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
-                    IntCounterVec::new(prometheus::opts!("agentstate_ops_total", "ops"), &["op"])
-                        .unwrap()
-                });
-                OPS_TOTAL.with_label_values(&["put"]).inc();
-                (StatusCode::OK, Json(obj)).into_response()
+        let t0 = std::time::Instant::now();
+        let res = put_or_skip(&app, &ns, req).await;
+        log_if_slow("put", &ns, t0, None, None);
+        match res {
+            Ok((mut obj, changed)) => {
+                metrics::OPS_TOTAL.with_label_values(&["put"]).inc();
+                crypt::open(&claims, std::slice::from_mut(&mut obj));
+                redact(&claims, std::slice::from_mut(&mut obj));
+                let Some(changed) = changed else {
+                    return (StatusCode::OK, Json(obj)).into_response();
+                };
+                let mut val = serde_json::to_value(&obj).unwrap_or(json!({"id": obj.id}));
+                val["changed"] = json!(changed);
+                (StatusCode::OK, Json(val)).into_response()
             }
-            Err(e) => (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": e.to_string()})),
-            )
-                .into_response(),
+            Err(e) => put_error(e),
         }
     }
 }
 
-#[derive(serde::Deserialize)]
-struct GetOpts {
-    at: Option<String>,
+// A plain put, or with `only_if_changed` a conditional one; `changed` is only reported
+// for the latter
+async fn put_or_skip(
+    app: &AppState,
+    ns: &str,
+    req: PutRequest,
+) -> agentstate_core::Result<(agentstate_core::Object, Option<bool>)> {
+    if req.only_if_changed {
+        let (obj, changed) = app.store.put_if_changed(ns, req).await?;
+        Ok((obj, Some(changed)))
+    } else {
+        Ok((app.store.put(ns, req).await?, None))
+    }
 }
 
-async fn get_object(
-    State(app): State<AppState>,
-    Path((ns, id)): Path<(String, String)>,
-    q: Option<Query<GetOpts>>,
-    headers: HeaderMap,
-) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "get") {
-        return resp.into_response();
+// Marks a request already forwarded once (to a region or a leader), so misconfigured
+// servers can't bounce it around
+const FORWARDED_HEADER: &str = "x-agentstate-forwarded";
+
+const HOP_HEADERS: [&str; 4] = ["host", "content-length", "connection", "transfer-encoding"];
+
+// Relays a request to another server with the caller's headers (token, Idempotency-Key,
+// fencing, deadline) and returns its response as-is. `body` is the request re-encoded
+// as JSON, whatever the caller sent it as.
+async fn proxy(
+    method: reqwest::Method,
+    url: &str,
+    headers: &HeaderMap,
+    body: Option<Vec<u8>>,
+) -> Result<axum::response::Response, reqwest::Error> {
+    static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+        reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap_or_default()
+    });
+    // reqwest and axum share the `http` crate, so headers carry over as-is
+    let mut out = headers.clone();
+    for h in HOP_HEADERS {
+        out.remove(h);
+    }
+    out.insert(FORWARDED_HEADER, axum::http::HeaderValue::from_static("1"));
+    if body.is_some() {
+        let json = axum::http::HeaderValue::from_static("application/json");
+        out.insert(axum::http::header::CONTENT_TYPE, json);
+    }
+    let mut rb = CLIENT.request(method, url).headers(out);
+    if let Some(body) = body {
+        rb = rb.body(body);
+    }
+    let res = rb.send().await?;
+    let status = res.status();
+    let mut res_headers = res.headers().clone();
+    for h in HOP_HEADERS {
+        res_headers.remove(h);
     }
-    let _timer = {
-        static OP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
-            HistogramVec::new(
-                prometheus::opts!("op_duration_seconds", "op durations").into(),
-                &["op"],
+    let body = res.bytes().await?;
+    let mut resp = (status, body).into_response();
+    resp.headers_mut().extend(res_headers);
+    Ok(resp)
+}
+
+fn to_body(v: &impl serde::Serialize) -> Vec<u8> {
+    serde_json::to_vec(v).unwrap_or_default()
+}
+
+// Region pin for writes. None when the token's region is this one; otherwise the write,
+// re-sent as `method` to `path` (path and query), comes back from the token's home
+// region, or is refused with 451 when that region has no endpoint or the request was
+// already forwarded once.
+async fn route_write(
+    app: &AppState,
+    claims: &serde_json::Value,
+    headers: &HeaderMap,
+    method: reqwest::Method,
+    path: &str,
+    body: impl FnOnce() -> Vec<u8>,
+) -> Option<axum::response::Response> {
+    let region = app.regions.elsewhere(claims)?;
+    let base = app
+        .regions
+        .endpoints
+        .get(region)
+        .filter(|_| !headers.contains_key(FORWARDED_HEADER));
+    let Some(base) = base else {
+        return Some(
+            (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                Json(json!({"error":"region_mismatch"})),
             )
-            .unwrap()
-        });
-        OP_DURATION.with_label_values(&["get"]).start_timer()
+                .into_response(),
+        );
     };
-    let at_ts = q
-        .and_then(|Query(g)| g.at)
-        .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
-        .map(|dt| dt.with_timezone(&chrono::Utc));
-    match app
-        .store
-        .get(&ns, &id, agentstate_storage::traits::GetOptions { at_ts })
-        .await
-    {
-        Ok(obj) => {
-            static OPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
-                IntCounterVec::new(prometheus::opts!("agentstate_ops_total", "ops"), &["op"])
-                    .unwrap()
-            });
-            OPS_TOTAL.with_label_values(&["get"]).inc();
-            (StatusCode::OK, Json(obj)).into_response()
+    let url = format!("{}{}", base, path);
+    match proxy(method, &url, headers, Some(body())).await {
+        Ok(resp) => Some(resp),
+        Err(e) => {
+            tracing::warn!("region forward to {} failed: {}", url, e);
+            Some(
+                (
+                    StatusCode::BAD_GATEWAY,
+                    Json(json!({"error":"region_forward_failed","region": region})),
+                )
+                    .into_response(),
+            )
         }
-        Err(e) => (StatusCode::NOT_FOUND, Json(json!({"error": e.to_string()}))).into_response(),
     }
 }
 
-async fn delete_object(
-    State(app): State<AppState>,
-    Path((ns, id)): Path<(String, String)>,
-    headers: HeaderMap,
-) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "delete") {
-        return resp.into_response();
+// Read consistency from the `consistency` header or `?consistency=` param. Eventual
+// (the default) reads are served locally. On a follower, strong reads are proxied to the
+// leader; a standalone server is always strong, so it serves them itself.
+// Some(response) means the caller should return it as-is. `body` is only built for
+// forwarded reads; None forwards as a GET.
+async fn route_read(
+    app: &AppState,
+    headers: &HeaderMap,
+    uri: &axum::http::Uri,
+    body: impl FnOnce() -> Option<Vec<u8>>,
+) -> Option<axum::response::Response> {
+    let param = uri
+        .query()
+        .and_then(|q| q.split('&').find_map(|kv| kv.strip_prefix("consistency=")));
+    let level = headers
+        .get("consistency")
+        .and_then(|v| v.to_str().ok())
+        .or(param)
+        .unwrap_or("eventual");
+    match level {
+        "eventual" => return None,
+        "strong" => {}
+        other => {
+            return Some(
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": format!(
+                        "unknown consistency {:?}, expected strong or eventual",
+                        other
+                    )})),
+                )
+                    .into_response(),
+            )
+        }
     }
-    match app.store.delete(&ns, &id).await {
-        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
-        Err(e) => (StatusCode::NOT_FOUND, Json(json!({"error": e.to_string()}))).into_response(),
+    let leader = app.leader.as_deref()?;
+    if headers.contains_key(FORWARDED_HEADER) {
+        return None;
+    }
+    let path = uri.path_and_query().map_or(uri.path(), |p| p.as_str());
+    let url = format!("{}{}", leader.trim_end_matches('/'), path);
+    let body = body();
+    let method = if body.is_some() {
+        reqwest::Method::POST
+    } else {
+        reqwest::Method::GET
+    };
+    match proxy(method, &url, headers, body).await {
+        Ok(resp) => Some(resp),
+        Err(e) => {
+            tracing::warn!("strong read via leader {} failed: {}", url, e);
+            Some(
+                (
+                    StatusCode::BAD_GATEWAY,
+                    Json(json!({"error":"leader_unreachable"})),
+                )
+                    .into_response(),
+            )
+        }
     }
 }
 
-async fn query(
+// Storage failures (e.g. the WAL couldn't persist the write) are 500s, not client errors
+fn put_error(e: StateError) -> axum::response::Response {
+    let code = match e {
+        StateError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (code, Json(json!({"error": e.to_string()}))).into_response()
+}
+
+#[derive(serde::Deserialize, Default)]
+struct PutAtOpts {
+    #[serde(default)]
+    if_absent: bool,
+}
+
+// PUT /v1/:ns/objects/:id: a put addressed by path. With `?if_absent=true` it only
+// creates: 201 with the new object, or 200 with the live one left untouched.
+async fn put_object_at(
     State(app): State<AppState>,
-    Path(ns): Path<String>,
+    Path((ns, id)): Path<(String, String)>,
+    q: Option<Query<PutAtOpts>>,
     headers: HeaderMap,
-    Json(req): Json<QueryRequest>,
-) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "query") {
+    Json(mut req): Json<PutRequest>,
+) -> axum::response::Response {
+    req.id = Some(id);
+    let opts = q.map(|Query(o)| o).unwrap_or_default();
+    if !opts.if_absent {
+        return put_objects(State(app), Path(ns), headers, Json(req))
+            .await
+            .into_response();
+    }
+    let claims = match enforce_caps(&app, &headers, &ns, "put") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    if let Err(resp) = reject_if_follower(&app) {
         return resp.into_response();
     }
-    let _timer = {
-        static OP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
-            HistogramVec::new(
-                prometheus::opts!("op_duration_seconds", "op durations").into(),
-                &["op"],
-            )
-            .unwrap()
-        });
-        OP_DURATION.with_label_values(&["query"]).start_timer()
+    if let Err(resp) = rate_limit(&app, &claims) {
+        return resp.into_response();
+    }
+    let _permit = match admit_write(&app) {
+        Ok(p) => p,
+        Err(resp) => return resp.into_response(),
     };
-    match app.store.query(&ns, req).await {
-        Ok(list) => {
-            static OPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
-                IntCounterVec::new(prometheus::opts!("agentstate_ops_total", "ops"), &["op"])
-                    .unwrap()
-            });
-            OPS_TOTAL.with_label_values(&["query"]).inc();
-            (StatusCode::OK, Json(list)).into_response()
+    let id = req.id.as_deref().unwrap_or_default();
+    let path = format!("/v1/{}/objects/{}?if_absent=true", ns, id);
+    let put = reqwest::Method::PUT;
+    if let Some(resp) = route_write(&app, &claims, &headers, put, &path, || to_body(&req)).await {
+        return resp;
+    }
+    if let Err(resp) = reject_if_too_large(&claims, &headers) {
+        return resp.into_response();
+    }
+    let _timer = metrics::OpTimer::new("put");
+    let t0 = std::time::Instant::now();
+    let res = app.store.put_if_absent(&ns, req).await;
+    log_if_slow("put", &ns, t0, None, None);
+    match res {
+        Ok((mut obj, created)) => {
+            // Handing back an existing object is a read
+            if !created {
+                if let Err(resp) = enforce_caps(&app, &headers, &ns, "get") {
+                    return resp.into_response();
+                }
+            }
+            crypt::open(&claims, std::slice::from_mut(&mut obj));
+            redact(&claims, std::slice::from_mut(&mut obj));
+            let code = if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            (code, Json(obj)).into_response()
         }
-        Err(e) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": e.to_string()})),
-        )
-            .into_response(),
+        Err(e) => put_error(e),
     }
 }
 
-async fn watch_sse(
+#[derive(serde::Deserialize, Default)]
+struct GetOpts {
+    at: Option<String>,
+    #[serde(default)]
+    include_access: bool,
+}
+
+async fn get_object(
     State(app): State<AppState>,
-    Path(ns): Path<String>,
+    Path((ns, id)): Path<(String, String)>,
+    q: Option<Query<GetOpts>>,
+    axum::extract::OriginalUri(uri): axum::extract::OriginalUri,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "watch") {
-        return resp.into_response();
-    }
-    // Manual SSE stream with decrement on drop
-    struct ClientGuard(&'static str);
-    impl ClientGuard {
-        fn inc(proto: &'static str) -> Self {
-            WATCH_CLIENTS.with_label_values(&[proto]).inc();
-            ClientGuard(proto)
-        }
-    }
-    impl Drop for ClientGuard {
-        fn drop(&mut self) {
-            WATCH_CLIENTS.with_label_values(&[self.0]).dec();
-        }
+    let claims = match enforce_caps(&app, &headers, &ns, "get") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    if let Some(resp) = route_read(&app, &headers, &uri, || None).await {
+        return resp;
     }
-
-    let mut handle = app.store.subscribe(
-        agentstate_storage::traits::WatchFilter { ns: ns.clone() },
-        None,
-    );
-    let guard = ClientGuard::inc("sse");
-    let s = async_stream::stream! {
-        let _g = guard;
-        loop {
-            if let Some((last, _retry)) = handle.overflow_meta() {
-                metrics::WATCH_DROPS_TOTAL.with_label_values(&["overflow"]).inc();
-                let payload = serde_json::to_string(&json!({"error":"overflow","last_commit":last})).unwrap();
-                let chunk = format!("id: {}\ndata: {}\n\n", last, payload);
-                yield Ok::<Bytes, std::io::Error>(Bytes::from(chunk));
-                break;
-            } else if let Some(ev) = handle.try_next() {
-                match ev {
-                    agentstate_storage::traits::WatchEvent::Put(o) => {
-                        WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
-                        let lag = (chrono::Utc::now() - o.ts).num_milliseconds() as f64 / 1000.0;
-                        metrics::WATCH_EMIT_LAG_SEC.observe(lag.max(0.0));
-                        let payload = serde_json::to_string(&json!({"type":"put","obj":o,"commit_seq":o.commit_seq})).unwrap();
-                        let chunk = format!("id: {}\ndata: {}\n\n", o.commit_seq, payload);
-                        yield Ok::<Bytes, std::io::Error>(Bytes::from(chunk));
-                    }
-                    agentstate_storage::traits::WatchEvent::Delete{ns,id,commit_seq} => {
-                        WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
-                        let payload = serde_json::to_string(&json!({"type":"delete","ns":ns,"id":id,"commit_seq":commit_seq})).unwrap();
-                        let chunk = format!("id: {}\ndata: {}\n\n", commit_seq, payload);
-                        yield Ok::<Bytes, std::io::Error>(Bytes::from(chunk));
-                    }
+    let _timer = metrics::OpTimer::new("get");
+    let opts = q.map(|Query(g)| g).unwrap_or_default();
+    let at_ts = opts
+        .at
+        .as_deref()
+        .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+        .map(|dt| dt.with_timezone(&chrono::Utc));
+    let t0 = std::time::Instant::now();
+    let res = app
+        .store
+        .get(&ns, &id, agentstate_storage::traits::GetOptions { at_ts })
+        .await;
+    log_if_slow("get", &ns, t0, None, None);
+    match res {
+        Ok(mut obj) => {
+            metrics::OPS_TOTAL.with_label_values(&["get"]).inc();
+            crypt::open(&claims, std::slice::from_mut(&mut obj));
+            redact(&claims, std::slice::from_mut(&mut obj));
+            // Past its TTL but still inside TTL_GRACE_SECS: served, flagged for the caller
+            let expired = obj
+                .expires_at()
+                .is_some_and(|at| at < chrono::Utc::now());
+            let lock = app.store.advisory_lock_state(&ns, &id);
+            let jsonapi = wants_jsonapi(&headers);
+            let mut resp = if opts.include_access || lock.is_some() || jsonapi {
+                // Read counters and the advisory lock ride along next to the object, not
+                // inside its body
+                let mut v = serde_json::to_value(&obj).unwrap_or_default();
+                if opts.include_access {
+                    let stats = app.store.access_stats(&ns, &id);
+                    v["access_count"] = json!(stats.as_ref().map_or(0, |a| a.access_count));
+                    v["last_access"] = json!(stats.map(|a| a.last_access));
+                }
+                if let Some(l) = lock {
+                    v["locked_by"] = json!(l.owner);
+                    v["lock_expires"] = json!(l.expires_at);
                 }
+                if jsonapi {
+                    jsonapi_response(StatusCode::OK, json!({"data": jsonapi_resource(v)}))
+                } else if wants_msgpack(&headers) {
+                    msgpack_response(&v)
+                } else {
+                    (StatusCode::OK, Json(v)).into_response()
+                }
+            } else if wants_msgpack(&headers) {
+                msgpack_response(&obj)
+            } else if let Some(resp) = content_response(&obj, &headers) {
+                resp
             } else {
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                (StatusCode::OK, Json(obj)).into_response()
+            };
+            if expired {
+                resp.headers_mut().insert(
+                    "x-expired",
+                    axum::http::HeaderValue::from_static("true"),
+                );
             }
+            resp
         }
+        Err(e) if wants_jsonapi(&headers) => jsonapi_response(
+            StatusCode::NOT_FOUND,
+            json!({"errors": [{"status": "404", "title": e.to_string()}]}),
+        ),
+        Err(e) => (StatusCode::NOT_FOUND, Json(json!({"error": e.to_string()}))).into_response(),
+    }
+}
+
+// Non-JSON objects are served as their content with their own Content-Type, unless the
+// caller asks for the JSON object with `Accept: application/json`
+fn content_response(
+    obj: &agentstate_core::Object,
+    headers: &HeaderMap,
+) -> Option<axum::response::Response> {
+    use agentstate_core::BodyEncoding;
+    use base64::{engine::general_purpose::STANDARD as b64, Engine};
+    let encoding = BodyEncoding::of(obj.content_type());
+    let accept = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if encoding == BodyEncoding::Json || accept.contains("application/json") {
+        return None;
+    }
+    // A body that doesn't fit its type (e.g. sealed by field encryption) stays JSON
+    let s = obj.body.as_str()?;
+    let bytes = match encoding {
+        BodyEncoding::Base64 => b64.decode(s).ok()?,
+        _ => s.as_bytes().to_vec(),
     };
-    axum::http::Response::builder()
-        .header(axum::http::header::CONTENT_TYPE, "text/event-stream")
-        .header(axum::http::header::CACHE_CONTROL, "no-cache")
-        .body(axum::body::Body::from_stream(s))
-        .unwrap()
+    let ct = axum::http::HeaderValue::from_str(obj.content_type()).ok()?;
+    Some(([(axum::http::header::CONTENT_TYPE, ct)], bytes).into_response())
 }
 
-async fn metrics() -> impl IntoResponse {
-    let encoder = TextEncoder::new();
-    let metric_families = prometheus::gather();
-    let mut buf = Vec::new();
-    let _ = encoder.encode(&metric_families, &mut buf);
-    (StatusCode::OK, String::from_utf8(buf).unwrap_or_default())
+#[derive(serde::Deserialize)]
+struct DiffOpts {
+    from: u64,
+    to: Option<u64>,
 }
 
-// Admin endpoints
-async fn admin_snapshot(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, "admin://global", "admin") {
-        return resp.into_response();
-    }
-    let t0 = std::time::Instant::now();
-    match app.store.admin_snapshot().await {
-        Ok((id, last_seq)) => {
-            metrics::SNAPSHOT_TOTAL.with_label_values(&["ok"]).inc();
-            metrics::SNAPSHOT_DURATION_SEC.observe(t0.elapsed().as_secs_f64());
-            (
-                StatusCode::OK,
-                Json(json!({"snapshot_id": id, "last_seq": last_seq})),
-            )
-                .into_response()
-        }
+// JSON Patch between the bodies of two versions, addressed by commit_seq
+async fn diff_versions(
+    State(app): State<AppState>,
+    Path((ns, id)): Path<(String, String)>,
+    Query(q): Query<DiffOpts>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let claims = match enforce_caps(&app, &headers, &ns, "get") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    let mut versions = match app.store.versions(&ns, &id).await {
+        Ok(v) => v,
         Err(e) => {
-            metrics::SNAPSHOT_TOTAL.with_label_values(&["error"]).inc();
-            metrics::SNAPSHOT_DURATION_SEC.observe(t0.elapsed().as_secs_f64());
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": e.to_string()})),
-            )
-                .into_response()
-        }
-    }
-}
-async fn admin_manifest(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, "admin://global", "admin") {
-        return resp.into_response();
-    }
-    match app.store.admin_manifest().await {
-        Ok(m) => {
-            if let Some(segs) = m.get("segments").and_then(|v| v.as_array()) {
-                metrics::WAL_ACTIVE_SEGMENTS.set(segs.len() as f64);
-            }
-            (StatusCode::OK, Json(m)).into_response()
+            return (StatusCode::NOT_FOUND, Json(json!({"error": e.to_string()}))).into_response()
         }
-        Err(e) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": e.to_string()})),
+    };
+    // Diff what this token may read, not the raw bodies
+    crypt::open(&claims, &mut versions);
+    redact(&claims, &mut versions);
+    let from = versions.iter().find(|o| o.commit_seq == q.from);
+    let to = match q.to {
+        Some(seq) => versions.iter().find(|o| o.commit_seq == seq),
+        None => versions.last(),
+    };
+    match (from, to) {
+        (Some(a), Some(b)) => (
+            StatusCode::OK,
+            Json(json!({
+                "from": a.commit_seq,
+                "to": b.commit_seq,
+                "patch": agentstate_core::util::json_diff(&a.body, &b.body),
+            })),
+        )
+            .into_response(),
+        _ => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error":"version not found"})),
         )
             .into_response(),
     }
 }
 
-async fn admin_dump(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, "admin://global", "admin") {
-        return resp.into_response();
-    }
-    match app.store.admin_snapshot().await {
-        Ok((snapshot_id, _)) => {
-            let mut response = String::new();
-            for obj in app.store.all_objects() {
-                if let Ok(json) = serde_json::to_string(&obj) {
-                    response.push_str(&json);
-                    response.push('\n');
-                }
-            }
-            (StatusCode::OK, response).into_response()
+// Page size for history when `limit` is missing, and its upper bound
+const HISTORY_PAGE_DEFAULT: usize = 100;
+const HISTORY_PAGE_MAX: usize = 1000;
+
+#[derive(serde::Deserialize)]
+struct HistoryOpts {
+    limit: Option<usize>,
+    after: Option<u64>,
+    #[serde(default)]
+    order: HistoryOrder,
+}
+
+#[derive(serde::Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum HistoryOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// Retained versions by commit_seq, oldest first unless `order=desc`. `after` continues
+// in that order and `x-next-cursor` carries it for the next page, absent on the last one.
+async fn object_history(
+    State(app): State<AppState>,
+    Path((ns, id)): Path<(String, String)>,
+    Query(q): Query<HistoryOpts>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let claims = match enforce_caps(&app, &headers, &ns, "get") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    let versions = match app.store.versions(&ns, &id).await {
+        Ok(v) => v,
+        Err(e) => {
+            return (StatusCode::NOT_FOUND, Json(json!({"error": e.to_string()}))).into_response()
         }
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": e.to_string()}))
-        ).into_response(),
+    };
+    let limit = q
+        .limit
+        .unwrap_or(HISTORY_PAGE_DEFAULT)
+        .clamp(1, HISTORY_PAGE_MAX);
+    // Stored order can differ from commit order after a restore; sort rather than trust it
+    let mut versions = versions;
+    versions.sort_by_key(|o| o.commit_seq);
+    if q.order == HistoryOrder::Desc {
+        versions.reverse();
+    }
+    let mut rest = versions.into_iter().filter(|o| match (q.after, &q.order) {
+        (None, _) => true,
+        (Some(after), HistoryOrder::Asc) => o.commit_seq > after,
+        (Some(after), HistoryOrder::Desc) => o.commit_seq < after,
+    });
+    let mut page: Vec<_> = rest.by_ref().take(limit).collect();
+    let more = rest.next().is_some();
+    crypt::open(&claims, &mut page);
+    redact(&claims, &mut page);
+    let mut resp = (StatusCode::OK, Json(json!(page))).into_response();
+    if let (true, Some(last)) = (more, page.last()) {
+        resp.headers_mut().insert(
+            "x-next-cursor",
+            axum::http::HeaderValue::from(last.commit_seq),
+        );
     }
+    resp
 }
 
-async fn admin_trim_wal(
+#[derive(serde::Deserialize)]
+struct DescendantsOpts {
+    // Levels below the object to follow; 1 (default) is direct children
+    depth: Option<usize>,
+    limit: Option<usize>,
+}
+
+const DESCENDANTS_MAX: usize = 1000;
+
+// GET /v1/:ns/objects/:id/descendants: objects whose latest version references this
+// one through `parents`, transitively, with their distance from it
+async fn object_descendants(
     State(app): State<AppState>,
-    q: Option<Query<std::collections::HashMap<String, String>>>,
+    Path((ns, id)): Path<(String, String)>,
+    Query(q): Query<DescendantsOpts>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, "admin://global", "admin") {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "get") {
         return resp.into_response();
     }
-    let sid = q
-        .and_then(|Query(m)| m.get("snapshot_id").cloned())
-        .unwrap_or_default();
-    if sid.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error":"missing snapshot_id"})),
-        )
-            .into_response();
-    }
-    match app.store.admin_trim_wal(&sid).await {
-        Ok(deleted) => {
-            if let Ok(m) = app.store.admin_manifest().await {
-                if let Some(segs) = m.get("segments").and_then(|v| v.as_array()) {
-                    metrics::WAL_ACTIVE_SEGMENTS.set(segs.len() as f64);
-                }
-            }
-            (StatusCode::OK, Json(json!({"deleted": deleted}))).into_response()
+    let depth = q.depth.unwrap_or(1);
+    let limit = q.limit.unwrap_or(DESCENDANTS_MAX).clamp(1, DESCENDANTS_MAX);
+    match app.store.descendants(&ns, &id, depth, limit) {
+        Ok((found, truncated)) => {
+            let descendants: Vec<_> = found
+                .into_iter()
+                .map(|(id, depth)| json!({"id": id, "depth": depth}))
+                .collect();
+            (
+                StatusCode::OK,
+                Json(json!({"id": id, "descendants": descendants, "truncated": truncated})),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            let code = match e {
+                agentstate_core::StateError::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
         }
-        Err(e) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": e.to_string()})),
-        )
-            .into_response(),
     }
 }
 
 #[derive(serde::Deserialize)]
-struct ExplainReq {
-    ns: String,
-    #[serde(default)]
-    filter: Option<serde_json::Value>,
-    #[serde(default)]
-    top_k_vec: Option<serde_json::Value>,
-    #[serde(default)]
-    fields: Option<Vec<String>>,
+struct TrimVersionsReq {
+    keep: usize,
 }
 
-async fn admin_explain_query(
-    State(_app): State<AppState>,
+async fn trim_versions(
+    State(app): State<AppState>,
+    Path((ns, id)): Path<(String, String)>,
     headers: HeaderMap,
-    Json(req): Json<ExplainReq>,
+    Json(req): Json<TrimVersionsReq>,
 ) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &req.ns, "admin") {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "delete") {
         return resp.into_response();
     }
-    let t0 = std::time::Instant::now();
-    let mut plan: Vec<serde_json::Value> = Vec::new();
-    if let Some(f) = &req.filter {
-        if let Some(tags) = f.get("tags").and_then(|t| t.as_object()) {
-            for (k, _v) in tags {
-                plan.push(json!({"op":"filter","on": format!("tags.{}", k), "index":"tags","selectivity":0.5}));
-            }
-        }
-        if let Some(jp) = f.get("jsonpath").and_then(|j| j.as_str()) {
-            plan.push(json!({"op":"filter","on": format!("jsonpath:{}", jp), "index":"jsonpath","selectivity":0.5}));
-        }
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
     }
-    if let Some(vq) = &req.top_k_vec {
-        if let Some(field) = vq.get("field").and_then(|v| v.as_str()) {
-            plan.push(json!({"op":"ann","field":field,"algo":"hnsw","candidates":5000,"k": vq.get("k").and_then(|k| k.as_u64()).unwrap_or(8)}));
+    let _permit = match admit_write(&app) {
+        Ok(p) => p,
+        Err(resp) => return resp.into_response(),
+    };
+    match app.store.trim_versions(&ns, &id, req.keep).await {
+        Ok(dropped) => (StatusCode::OK, Json(json!({"dropped": dropped}))).into_response(),
+        Err(e) => {
+            let code = match e {
+                StateError::NotFound => StatusCode::NOT_FOUND,
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
         }
     }
-    let resp = json!({
-        "plan": plan,
-        "estimated_cost": {"cpu_ms": 7.2, "io_reads": 34},
-        "indexes_hit": plan.iter().filter_map(|p| p.get("index")).collect::<Vec<_>>(),
-        "warnings": Vec::<String>::new(),
-    });
-    let micros = t0.elapsed().as_micros() as f64;
-    metrics::QUERY_PLANNER_MICROS.observe(micros);
-    (StatusCode::OK, Json(resp)).into_response()
 }
 
-// Leases endpoints
-#[derive(serde::Deserialize)]
-struct LeaseAcquireReq {
-    key: String,
-    owner: String,
-    ttl: u64,
-}
 #[derive(serde::Deserialize)]
-struct LeaseRenewReq {
-    key: String,
+struct AdvisoryLockReq {
     owner: String,
-    token: u64,
+    #[serde(default)]
     ttl: u64,
 }
-#[derive(serde::Deserialize)]
-struct LeaseReleaseReq {
-    key: String,
-    owner: String,
-    token: u64,
-}
 
-async fn lease_acquire(
+// Advisory locks are cooperative: nothing here or in the write path checks them
+async fn advisory_lock(
     State(app): State<AppState>,
-    Path(ns): Path<String>,
+    Path((ns, id)): Path<(String, String)>,
     headers: HeaderMap,
-    Json(req): Json<LeaseAcquireReq>,
+    Json(req): Json<AdvisoryLockReq>,
 ) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "lease") {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "lease") {
         return resp.into_response();
     }
-    match app
-        .store
-        .lease_acquire(&ns, &req.key, &req.owner, req.ttl)
-        .await
-    {
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    if req.owner.is_empty() || req.ttl == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"owner and a ttl of at least 1 second are required"})),
+        )
+            .into_response();
+    }
+    match app.store.advisory_lock(&ns, &id, &req.owner, req.ttl) {
         Ok(l) => (
             StatusCode::OK,
-            Json(json!({"token": l.token, "expires_at": l.expires_at.to_rfc3339()})),
+            Json(json!({"locked_by": l.owner, "lock_expires": l.expires_at})),
         )
             .into_response(),
-        Err(e) => (StatusCode::CONFLICT, Json(json!({"error": e.to_string()}))).into_response(),
+        Err(e) => lock_error(e),
     }
 }
-async fn lease_renew(
+
+async fn advisory_unlock(
     State(app): State<AppState>,
-    Path(ns): Path<String>,
+    Path((ns, id)): Path<(String, String)>,
     headers: HeaderMap,
-    Json(req): Json<LeaseRenewReq>,
+    Json(req): Json<AdvisoryLockReq>,
 ) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "lease") {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "lease") {
         return resp.into_response();
     }
-    match app
-        .store
-        .lease_renew(&ns, &req.key, &req.owner, req.token, req.ttl)
-        .await
-    {
-        Ok(l) => (
-            StatusCode::OK,
-            Json(json!({"token": l.token, "expires_at": l.expires_at.to_rfc3339()})),
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    match app.store.advisory_unlock(&ns, &id, &req.owner) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => lock_error(e),
+    }
+}
+
+fn lock_error(e: StateError) -> axum::response::Response {
+    let code = match e {
+        StateError::NotFound => StatusCode::NOT_FOUND,
+        StateError::Conflict(_) => StatusCode::CONFLICT,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (code, Json(json!({"error": e.to_string()}))).into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct ExpiringOpts {
+    within_secs: u64,
+}
+
+// Objects whose TTL runs out within the next `within_secs`, soonest first
+async fn expiring(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    Query(q): Query<ExpiringOpts>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let claims = match enforce_caps(&app, &headers, &ns, "query") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    let horizon = chrono::Utc::now() + chrono::Duration::seconds(q.within_secs as i64);
+    let t0 = std::time::Instant::now();
+    let res = app.store.expiring(&ns, horizon);
+    log_if_slow("expiring", &ns, t0, res.as_ref().ok().map(|l| l.len()), None);
+    match res {
+        Ok(mut list) => {
+            crypt::open(&claims, &mut list);
+            redact(&claims, &mut list);
+            let out: Vec<_> = list
+                .into_iter()
+                .map(|o| json!({"expires_at": o.expires_at(), "object": o}))
+                .collect();
+            (StatusCode::OK, Json(out)).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
         )
             .into_response(),
-        Err(e) => (StatusCode::CONFLICT, Json(json!({"error": e.to_string()}))).into_response(),
     }
 }
-async fn lease_release(
+
+#[derive(serde::Deserialize, Default)]
+struct DeleteOpts {
+    #[serde(default)]
+    cascade: bool,
+}
+
+// CASCADE_DELETE_MAX: most objects one `?cascade=true` delete may remove (root included)
+static CASCADE_DELETE_MAX: Lazy<usize> = Lazy::new(|| {
+    std::env::var("CASCADE_DELETE_MAX")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(1000)
+});
+
+async fn delete_object(
     State(app): State<AppState>,
-    Path(ns): Path<String>,
+    Path((ns, id)): Path<(String, String)>,
+    q: Option<Query<DeleteOpts>>,
     headers: HeaderMap,
-    Json(req): Json<LeaseReleaseReq>,
 ) -> impl IntoResponse {
-    if let Err(resp) = enforce_caps(&headers, &ns, "lease") {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "delete") {
         return resp.into_response();
     }
-    match app
-        .store
-        .lease_release(&ns, &req.key, &req.owner, req.token)
-        .await
-    {
-        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
-        Err(e) => (StatusCode::CONFLICT, Json(json!({"error": e.to_string()}))).into_response(),
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    let _permit = match admit_write(&app) {
+        Ok(p) => p,
+        Err(resp) => return resp.into_response(),
+    };
+    let opts = q.map(|Query(o)| o).unwrap_or_default();
+    let t0 = std::time::Instant::now();
+    let res = if opts.cascade {
+        app.store.delete_cascade(&ns, &id, *CASCADE_DELETE_MAX).await
+    } else {
+        app.store.delete(&ns, &id).await.map(|seq| vec![(id, seq)])
+    };
+    log_if_slow("delete", &ns, t0, None, None);
+    match res {
+        Ok(deleted) if opts.cascade => {
+            let seq = deleted.first().map_or(0, |(_, s)| *s);
+            let ids: Vec<&String> = deleted.iter().map(|(id, _)| id).collect();
+            (
+                StatusCode::OK,
+                Json(json!({"commit_seq": seq, "deleted": ids})),
+            )
+                .into_response()
+        }
+        Ok(deleted) => {
+            let seq = deleted.first().map_or(0, |(_, s)| *s);
+            (StatusCode::OK, Json(json!({"commit_seq": seq}))).into_response()
+        }
+        Err(e) => {
+            let code = match e {
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                StateError::Invalid(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::NOT_FOUND,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
+        }
     }
 }
 
-pub mod agentstate_v1 {
-    tonic::include_proto!("agentstate.v1");
+#[derive(serde::Deserialize)]
+struct RenameReq {
+    new_id: String,
 }
 
-struct AgentStateGrpc {
-    state: AppState,
+// Custom methods on an object: POST /v1/:ns/objects/{id}:rename
+async fn object_action(
+    State(app): State<AppState>,
+    Path((ns, target)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let Some(id) = target.strip_suffix(":rename") else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error":"unknown object action"})),
+        )
+            .into_response();
+    };
+    // A rename deletes the old id and writes the new one
+    let mut claims = json!({});
+    for verb in ["put", "delete"] {
+        match enforce_caps(&app, &headers, &ns, verb) {
+            Ok(c) => claims = c,
+            Err(resp) => return resp.into_response(),
+        }
+    }
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    let _permit = match admit_write(&app) {
+        Ok(p) => p,
+        Err(resp) => return resp.into_response(),
+    };
+    let req: RenameReq = match serde_json::from_value(body) {
+        Ok(r) => r,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": e.to_string()})),
+            )
+                .into_response()
+        }
+    };
+    match app.store.rename(&ns, id, &req.new_id).await {
+        Ok(mut o) => {
+            crypt::open(&claims, std::slice::from_mut(&mut o));
+            redact(&claims, std::slice::from_mut(&mut o));
+            (StatusCode::OK, Json(json!(o))).into_response()
+        }
+        Err(e) => {
+            let code = match e {
+                StateError::NotFound => StatusCode::NOT_FOUND,
+                StateError::Conflict(_) => StatusCode::CONFLICT,
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
+        }
+    }
 }
 
-type WatchStream = Pin<Box<dyn Stream<Item = Result<agentstate_v1::WatchEvent, Status>> + Send>>;
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TxnReq {
+    ops: Vec<agentstate_core::TxnOp>,
+}
 
-#[tonic::async_trait]
-impl agentstate_v1::agent_state_server::AgentState for AgentStateGrpc {
-    async fn put(
-        &self,
-        request: Request<agentstate_v1::PutRequest>,
-    ) -> Result<TonicResponse<agentstate_v1::Object>, Status> {
-        let req = request.into_inner();
-        let pr = PutRequest {
-            r#type: req.r#type,
-            body: serde_json::from_str(&req.body_json).unwrap_or(serde_json::Value::Null),
-            tags: agentstate_core::Tags(req.tags.into_iter().collect()),
-            ttl_seconds: if req.ttl_seconds == 0 {
-                None
-            } else {
-                Some(req.ttl_seconds as u64)
-            },
-            id: if req.id.is_empty() {
-                None
+// All-or-nothing writes over several objects in one namespace
+async fn txn(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<TxnReq>,
+) -> impl IntoResponse {
+    use agentstate_core::TxnOp;
+    let claims = match enforce_caps(&app, &headers, &ns, "put") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    if req.ops.iter().any(|op| matches!(op, TxnOp::Delete { .. })) {
+        if let Err(resp) = enforce_caps(&app, &headers, &ns, "delete") {
+            return resp.into_response();
+        }
+    }
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    if let Err(resp) = rate_limit(&app, &claims) {
+        return resp.into_response();
+    }
+    let _permit = match admit_write(&app) {
+        Ok(p) => p,
+        Err(resp) => return resp.into_response(),
+    };
+    let path = format!("/v1/{}/txn", ns);
+    let post = reqwest::Method::POST;
+    if let Some(resp) = route_write(&app, &claims, &headers, post, &path, || to_body(&req)).await {
+        return resp;
+    }
+    if let Err(resp) = reject_if_too_large(&claims, &headers) {
+        return resp.into_response();
+    }
+    let _timer = metrics::OpTimer::new("txn");
+    let t0 = std::time::Instant::now();
+    let res = app.store.txn(&ns, req.ops).await;
+    log_if_slow("txn", &ns, t0, None, None);
+    let events = match res {
+        Ok(events) => events,
+        Err(e) => {
+            let code = match e {
+                StateError::Conflict(_) => StatusCode::CONFLICT,
+                StateError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            return (code, Json(json!({"error": e.to_string()}))).into_response();
+        }
+    };
+    let mut commit_seq = 0;
+    let results: Vec<serde_json::Value> = events
+        .into_iter()
+        .map(|ev| match ev {
+            agentstate_storage::traits::WatchEvent::Put(mut o) => {
+                commit_seq = o.commit_seq;
+                crypt::open(&claims, std::slice::from_mut(&mut o));
+                redact(&claims, std::slice::from_mut(&mut o));
+                json!({"type":"put","obj":o,"commit_seq":o.commit_seq})
+            }
+            agentstate_storage::traits::WatchEvent::Delete { ns, id, commit_seq: seq } => {
+                commit_seq = seq;
+                json!({"type":"delete","ns":ns,"id":id,"commit_seq":seq})
+            }
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        Json(json!({"commit_seq": commit_seq, "results": results})),
+    )
+        .into_response()
+}
+
+async fn query(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    axum::extract::OriginalUri(uri): axum::extract::OriginalUri,
+    headers: HeaderMap,
+    Json(mut req): Json<QueryRequest>,
+) -> impl IntoResponse {
+    let claims = match enforce_caps(&app, &headers, &ns, "query") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    if let Some(resp) = route_read(&app, &headers, &uri, || serde_json::to_vec(&req).ok()).await {
+        return resp;
+    }
+    // X-Deadline: remaining budget in milliseconds
+    if let Some(ms) = headers
+        .get("x-deadline")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    {
+        req.deadline = agentstate_core::Deadline::after(std::time::Duration::from_millis(ms));
+    }
+    if req.partial_on_timeout {
+        req.deadline.allow_partial();
+    }
+    if let Err(e) = crypt::check_filter(&ns, &req) {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": e}))).into_response();
+    }
+    let deadline = req.deadline.clone();
+    // The store projects, except over ciphertext: sealed parents are opened first
+    let fields = if crypt::encrypts(&ns) {
+        req.fields.take()
+    } else {
+        None
+    };
+    let _cancel = CancelOnDrop(deadline.clone());
+    let _timer = metrics::OpTimer::new("query");
+    let t0 = std::time::Instant::now();
+    let mut trace = agentstate_storage::traits::QueryTrace::default();
+    let res = app.store.query_traced(&ns, req, &mut trace).await;
+    let results = res.as_ref().ok().map(|l| l.len());
+    log_if_slow("query", &ns, t0, results, Some(trace.candidates));
+    match res {
+        Ok(mut list) => {
+            metrics::OPS_TOTAL.with_label_values(&["query"]).inc();
+            // Opening, redaction and projection, per object
+            let finish = move |o: &mut agentstate_core::Object| {
+                crypt::open(&claims, std::slice::from_mut(o));
+                redact(&claims, std::slice::from_mut(o));
+                // After the token's allowed_fields; either way the result is the intersection
+                if let Some(fields) = &fields {
+                    let paths: Vec<Vec<&str>> = fields.iter().map(|f| body_path(f)).collect();
+                    project_body(&mut o.body, &paths);
+                }
+            };
+            let ndjson = headers
+                .get(axum::http::header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|a| a.contains("application/x-ndjson"));
+            if !ndjson || wants_jsonapi(&headers) || wants_msgpack(&headers) {
+                list.iter_mut().for_each(&finish);
+            }
+            let mut resp = if wants_jsonapi(&headers) {
+                let data: Vec<serde_json::Value> = list
+                    .iter()
+                    .map(|o| jsonapi_resource(serde_json::to_value(o).unwrap_or_default()))
+                    .collect();
+                jsonapi_response(StatusCode::OK, json!({"data": data}))
+            } else if wants_msgpack(&headers) {
+                msgpack_response(&list)
+            } else if !ndjson {
+                (StatusCode::OK, Json(list)).into_response()
             } else {
-                Some(req.id)
-            },
-            parents: req.parents,
+                // One object per line, opened and serialized as the body is polled
+                let s = futures::stream::iter(list.into_iter().map(move |mut o| {
+                    finish(&mut o);
+                    let mut line = serde_json::to_vec(&o).unwrap_or_default();
+                    line.push(b'\n');
+                    Ok::<Bytes, std::io::Error>(Bytes::from(line))
+                }));
+                axum::http::Response::builder()
+                    .header(axum::http::header::CONTENT_TYPE, "application/x-ndjson")
+                    .body(axum::body::Body::from_stream(s))
+                    .unwrap()
+                    .into_response()
+            };
+            // partial_on_timeout: the scan stopped at the deadline with what it had
+            if deadline.truncated() {
+                resp.headers_mut().insert(
+                    "x-partial",
+                    axum::http::HeaderValue::from_static("true"),
+                );
+            }
+            resp
+        }
+        Err(StateError::DeadlineExceeded) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(json!({"error":"deadline_exceeded"})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+// Upper bound on results from one cross-namespace admin query
+static ADMIN_QUERY_MAX_RESULTS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("ADMIN_QUERY_MAX_RESULTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000)
+});
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum NamespaceSel {
+    All(String),
+    List(Vec<String>),
+}
+
+#[derive(serde::Deserialize)]
+struct AdminQueryReq {
+    namespaces: NamespaceSel,
+    #[serde(default)]
+    query: QueryRequest,
+}
+
+// Operator query across tenants: runs `query` in each namespace (all of them for "*")
+// and concatenates the results in namespace order, each carrying its `ns`
+async fn admin_query(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<AdminQueryReq>,
+) -> impl IntoResponse {
+    let claims = match enforce_caps(&app, &headers, "admin://global", "admin") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    let namespaces = match req.namespaces {
+        NamespaceSel::All(s) if s == "*" => app.store.namespaces(),
+        NamespaceSel::List(l) => l,
+        NamespaceSel::All(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error":"namespaces must be a list or \"*\""})),
+            )
+                .into_response()
+        }
+    };
+    let mut query = req.query;
+    if let Some(ms) = headers
+        .get("x-deadline")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    {
+        query.deadline = agentstate_core::Deadline::after(std::time::Duration::from_millis(ms));
+    }
+    let _cancel = CancelOnDrop(query.deadline.clone());
+    let cap = query
+        .limit
+        .unwrap_or(usize::MAX)
+        .min(*ADMIN_QUERY_MAX_RESULTS);
+    let mut results = Vec::new();
+    let mut truncated = false;
+    for ns in &namespaces {
+        if results.len() >= cap {
+            truncated = true;
+            break;
+        }
+        if let Err(e) = crypt::check_filter(ns, &query) {
+            return (StatusCode::BAD_REQUEST, Json(json!({"error": e, "ns": ns}))).into_response();
+        }
+        let mut q = query.clone();
+        // One past what's left, so a full page tells us more were available
+        q.limit = Some(cap - results.len() + 1);
+        let fields = if crypt::encrypts(ns) {
+            q.fields.take()
+        } else {
+            None
         };
-        let o = self
-            .state
-            .store
-            .put(&req.ns, pr)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
-        Ok(TonicResponse::new(to_proto_object(o)))
+        let mut list = match app.store.query(ns, q).await {
+            Ok(l) => l,
+            Err(StateError::DeadlineExceeded) => {
+                return (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    Json(json!({"error":"deadline_exceeded"})),
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": e.to_string(), "ns": ns})),
+                )
+                    .into_response()
+            }
+        };
+        if list.len() > cap - results.len() {
+            list.truncate(cap - results.len());
+            truncated = true;
+        }
+        crypt::open(&claims, &mut list);
+        redact(&claims, &mut list);
+        if let Some(fields) = &fields {
+            let paths: Vec<Vec<&str>> = fields.iter().map(|f| body_path(f)).collect();
+            for o in list.iter_mut() {
+                project_body(&mut o.body, &paths);
+            }
+        }
+        results.extend(list);
     }
+    (
+        StatusCode::OK,
+        Json(json!({"results": results, "truncated": truncated})),
+    )
+        .into_response()
+}
 
-    async fn get(
-        &self,
-        request: Request<agentstate_v1::GetRequest>,
-    ) -> Result<TonicResponse<agentstate_v1::Object>, Status> {
-        let req = request.into_inner();
-        let o = self
-            .state
-            .store
-            .get(
-                &req.ns,
-                &req.id,
-                agentstate_storage::traits::GetOptions { at_ts: None },
+// Cancels the request's deadline when the handler future is dropped, which is
+// what hyper does when the client disconnects; blocking scans then stop early.
+struct CancelOnDrop(agentstate_core::Deadline);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+// grpc-timeout header value: digits followed by a unit (H, M, S, m, u, n)
+fn parse_grpc_timeout(v: &str) -> Option<std::time::Duration> {
+    use std::time::Duration;
+    let (n, unit) = v.split_at(v.len().checked_sub(1)?);
+    let n: u64 = n.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(n * 3600),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+#[derive(serde::Deserialize, Default)]
+struct WatchOpts {
+    // "zstd": event data is {"encoding":"zstd+base64","payload":...} when that's smaller
+    compress: Option<String>,
+    // Only puts of this type (deletes always come through)
+    #[serde(rename = "type")]
+    type_equals: Option<String>,
+    // Buffer limits for this watch, clamped to WATCH_MAX_BUFFER_*; strings because serde
+    // can't parse numbers out of a query next to a flattened map
+    max_events: Option<String>,
+    max_bytes: Option<String>,
+    // `tag.<key>=<value>` params: only puts carrying every such tag
+    #[serde(flatten)]
+    rest: std::collections::HashMap<String, String>,
+}
+
+// WATCH_KEEPALIVE_SECS: idle seconds before an SSE watch sends a `: keepalive` comment, so
+// proxies don't drop quiet streams (default 15, 0 disables)
+static WATCH_KEEPALIVE_SECS: Lazy<u64> = Lazy::new(|| {
+    std::env::var("WATCH_KEEPALIVE_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(15)
+});
+
+// WATCH_MAX_BUFFER_EVENTS / WATCH_MAX_BUFFER_BYTES: the most a watcher may ask for with
+// ?max_events= and ?max_bytes= (defaults 100000 and 256 MiB)
+static WATCH_MAX_BUFFER_EVENTS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("WATCH_MAX_BUFFER_EVENTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100_000)
+});
+static WATCH_MAX_BUFFER_BYTES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("WATCH_MAX_BUFFER_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(256 * 1024 * 1024)
+});
+
+// WATCH_ZSTD_MIN_BYTES: smaller payloads go out uncompressed even with compress=zstd,
+// as framing would outweigh the savings (default 256)
+static WATCH_ZSTD_MIN_BYTES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("WATCH_ZSTD_MIN_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(256)
+});
+
+// WATCH_ZSTD_LEVEL: zstd level for compressed watch events (default 3)
+static WATCH_ZSTD_LEVEL: Lazy<i32> = Lazy::new(|| {
+    std::env::var("WATCH_ZSTD_LEVEL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3)
+});
+
+// One SSE event; with `compress` a large JSON payload travels zstd-compressed and
+// base64'd inside an envelope naming the encoding, so clients can tell which events to
+// decompress
+fn sse_event(id: u64, payload: &serde_json::Value, compress: bool) -> Bytes {
+    let mut data = serde_json::to_string(payload).unwrap_or_default();
+    if compress && data.len() >= *WATCH_ZSTD_MIN_BYTES {
+        use base64::{engine::general_purpose::STANDARD as b64, Engine};
+        if let Ok(z) = zstd::bulk::compress(data.as_bytes(), *WATCH_ZSTD_LEVEL) {
+            data = json!({"encoding": "zstd+base64", "payload": b64.encode(z)}).to_string();
+        }
+    }
+    Bytes::from(format!("id: {}\ndata: {}\n\n", id, data))
+}
+
+// Counts a watch client in WATCH_CLIENTS for as long as it's held
+struct ClientGuard(&'static str);
+impl ClientGuard {
+    fn inc(proto: &'static str) -> Self {
+        WATCH_CLIENTS.with_label_values(&[proto]).inc();
+        ClientGuard(proto)
+    }
+}
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        WATCH_CLIENTS.with_label_values(&[self.0]).dec();
+    }
+}
+
+async fn watch_sse(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    q: Option<Query<WatchOpts>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let claims = match enforce_caps(&app, &headers, &ns, "watch") {
+        Ok(c) => c,
+        Err(resp) => return resp.into_response(),
+    };
+    let opts = q.map(|Query(o)| o).unwrap_or_default();
+    let compress = match opts.compress.as_deref() {
+        None | Some("") | Some("none") => false,
+        Some("zstd") => true,
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": format!("unsupported compress: {}", other)})),
             )
+                .into_response()
+        }
+    };
+    if let Err(resp) = watch_rate_limit(&app, &claims) {
+        return resp.into_response();
+    }
+    let parse_limit = |name: &str, v: Option<&String>, max: usize| match v {
+        None => Ok(None),
+        Some(s) => match s.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n.min(max))),
+            _ => Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": format!("{} must be a positive integer", name)})),
+            )),
+        },
+    };
+    let limits = match (
+        parse_limit("max_events", opts.max_events.as_ref(), *WATCH_MAX_BUFFER_EVENTS),
+        parse_limit("max_bytes", opts.max_bytes.as_ref(), *WATCH_MAX_BUFFER_BYTES),
+    ) {
+        (Ok(None), Ok(None)) => None,
+        (Ok(events), Ok(bytes)) => {
+            let mut l = agentstate_storage::traits::WatchLimits::default();
+            l.max_events = events.unwrap_or(l.max_events);
+            l.max_bytes = bytes.unwrap_or(l.max_bytes);
+            Some(l)
+        }
+        (Err(resp), _) | (_, Err(resp)) => return resp.into_response(),
+    };
+    let filter = agentstate_storage::traits::WatchFilter {
+        ns: ns.clone(),
+        type_equals: opts.type_equals,
+        tag_equals: opts
+            .rest
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix("tag.")?.to_string(), v)))
+            .collect(),
+    };
+    // A browser reconnecting after overflow or a dropped connection sends the last id it saw,
+    // and the backlog replays everything after it
+    let resume = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<u64>().ok());
+    // Manual SSE stream with decrement on drop
+    let mut handle = app.store.subscribe(filter, resume, limits);
+    let guard = ClientGuard::inc("sse");
+    let keepalive = (*WATCH_KEEPALIVE_SECS > 0)
+        .then(|| std::time::Duration::from_secs(*WATCH_KEEPALIVE_SECS));
+    let s = async_stream::stream! {
+        let _g = guard;
+        let mut last_sent = std::time::Instant::now();
+        loop {
+            if let Some((last, retry)) = handle.overflow_meta() {
+                metrics::WATCH_DROPS_TOTAL.with_label_values(&["overflow", &ns]).inc();
+                let payload = json!({"error":"overflow","last_commit":last,"retry_after_ms":retry});
+                // `retry:` sets the EventSource reconnect delay; it comes back with Last-Event-ID
+                let mut chunk = format!("retry: {}\n", retry).into_bytes();
+                chunk.extend_from_slice(&sse_event(last, &payload, compress));
+                yield Ok::<Bytes, std::io::Error>(Bytes::from(chunk));
+                break;
+            } else if let Some(ev) = handle.try_next() {
+                match ev {
+                    agentstate_storage::traits::WatchEvent::Put(mut o) => {
+                        WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
+                        let lag = (chrono::Utc::now() - o.ts).num_milliseconds() as f64 / 1000.0;
+                        metrics::WATCH_EMIT_LAG_SEC.observe(lag.max(0.0));
+                        crypt::open(&claims, std::slice::from_mut(&mut o));
+                        redact(&claims, std::slice::from_mut(&mut o));
+                        let payload = json!({"type":"put","obj":o,"commit_seq":o.commit_seq});
+                        yield Ok::<Bytes, std::io::Error>(sse_event(o.commit_seq, &payload, compress));
+                        last_sent = std::time::Instant::now();
+                    }
+                    agentstate_storage::traits::WatchEvent::Delete{ns,id,commit_seq} => {
+                        WATCH_EVENTS_TOTAL.with_label_values(&["delete"]).inc();
+                        let payload = json!({"type":"delete","ns":ns,"id":id,"commit_seq":commit_seq});
+                        yield Ok::<Bytes, std::io::Error>(sse_event(commit_seq, &payload, compress));
+                        last_sent = std::time::Instant::now();
+                    }
+                }
+            } else if keepalive.is_some_and(|k| last_sent.elapsed() >= k) {
+                // A comment line: clients ignore it and it carries no id, so resuming is unaffected
+                yield Ok::<Bytes, std::io::Error>(Bytes::from_static(b": keepalive\n\n"));
+                last_sent = std::time::Instant::now();
+            } else {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        }
+    };
+    axum::http::Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "text/event-stream")
+        .header(axum::http::header::CACHE_CONTROL, "no-cache")
+        .body(axum::body::Body::from_stream(s))
+        .unwrap()
+        .into_response()
+}
+
+async fn metrics(headers: HeaderMap) -> impl IntoResponse {
+    let metric_families = agentstate_storage::metrics::REGISTRY.gather();
+    let openmetrics = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|a| a.contains("application/openmetrics-text"));
+    if openmetrics {
+        return (
+            StatusCode::OK,
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )],
+            metrics::encode_openmetrics(&metric_families),
+        )
+            .into_response();
+    }
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    let _ = encoder.encode(&metric_families, &mut buf);
+    (StatusCode::OK, String::from_utf8(buf).unwrap_or_default()).into_response()
+}
+
+// Admin endpoints
+async fn admin_metrics_json(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    // The segment gauge is otherwise only refreshed by manifest reads and trims
+    if let Ok(m) = app.store.admin_manifest().await {
+        if let Some(segs) = m.get("segments").and_then(|v| v.as_array()) {
+            metrics::WAL_ACTIVE_SEGMENTS.set(segs.len() as f64);
+        }
+    }
+    Json(metrics::summary_json(&agentstate_storage::metrics::REGISTRY.gather())).into_response()
+}
+
+// WAL bytes written and writes committed as of the start of the last successful snapshot
+static SNAPSHOT_WAL_MARK: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static SNAPSHOT_WRITE_MARK: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+// Whether the scheduler should snapshot now
+fn snapshot_due(min_wal_bytes: u64) -> bool {
+    use agentstate_storage::persistent::{wal_disabled, writes_committed};
+    use std::sync::atomic::Ordering::Relaxed;
+    if wal_disabled() {
+        return writes_committed() > SNAPSHOT_WRITE_MARK.load(Relaxed);
+    }
+    let since = agentstate_storage::walbin::bytes_written()
+        .saturating_sub(SNAPSHOT_WAL_MARK.load(Relaxed));
+    since >= min_wal_bytes
+}
+
+async fn run_snapshot(store: &Arc<dyn Storage>) -> agentstate_core::Result<(String, u64)> {
+    let t0 = std::time::Instant::now();
+    let mark = agentstate_storage::walbin::bytes_written();
+    let writes = agentstate_storage::persistent::writes_committed();
+    let res = store.admin_snapshot().await;
+    if res.is_ok() {
+        SNAPSHOT_WAL_MARK.store(mark, std::sync::atomic::Ordering::Relaxed);
+        SNAPSHOT_WRITE_MARK.store(writes, std::sync::atomic::Ordering::Relaxed);
+    }
+    let result = if res.is_ok() { "ok" } else { "error" };
+    metrics::SNAPSHOT_TOTAL.with_label_values(&[result]).inc();
+    metrics::SNAPSHOT_DURATION_SEC.observe(t0.elapsed().as_secs_f64());
+    res
+}
+
+// Background snapshots (POST /admin/snapshot?background=true), newest last; one runs at a time
+#[derive(Clone, serde::Serialize)]
+struct SnapshotJob {
+    id: String,
+    status: &'static str, // running | done | failed
+    started_at: chrono::DateTime<chrono::Utc>,
+    finished_at: Option<chrono::DateTime<chrono::Utc>>,
+    objects: i64,
+    bytes: i64,
+    snapshot_id: Option<String>,
+    last_seq: Option<u64>,
+    error: Option<String>,
+}
+
+const SNAPSHOT_JOBS_KEPT: usize = 32;
+
+static SNAPSHOT_JOBS: Lazy<parking_lot::Mutex<Vec<SnapshotJob>>> = Lazy::new(Default::default);
+static SNAPSHOT_JOB_SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+fn start_snapshot_job(app: &AppState) -> Result<String, String> {
+    use agentstate_storage::persistent::{SNAPSHOT_PROGRESS_BYTES, SNAPSHOT_PROGRESS_OBJECTS};
+    let id = {
+        let mut jobs = SNAPSHOT_JOBS.lock();
+        if let Some(running) = jobs.iter().find(|j| j.status == "running") {
+            return Err(running.id.clone());
+        }
+        let n = SNAPSHOT_JOB_SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        let id = format!("job-{}", n);
+        jobs.push(SnapshotJob {
+            id: id.clone(),
+            status: "running",
+            started_at: chrono::Utc::now(),
+            finished_at: None,
+            objects: 0,
+            bytes: 0,
+            snapshot_id: None,
+            last_seq: None,
+            error: None,
+        });
+        let excess = jobs.len().saturating_sub(SNAPSHOT_JOBS_KEPT);
+        jobs.drain(..excess);
+        id
+    };
+    let store = app.store.clone();
+    let job_id = id.clone();
+    tokio::spawn(async move {
+        let res = run_snapshot(&store).await;
+        let mut jobs = SNAPSHOT_JOBS.lock();
+        let Some(job) = jobs.iter_mut().find(|j| j.id == job_id) else {
+            return;
+        };
+        job.finished_at = Some(chrono::Utc::now());
+        job.objects = SNAPSHOT_PROGRESS_OBJECTS.get();
+        job.bytes = SNAPSHOT_PROGRESS_BYTES.get();
+        match res {
+            Ok((snap, last_seq)) => {
+                job.status = "done";
+                job.snapshot_id = Some(snap);
+                job.last_seq = Some(last_seq);
+            }
+            Err(e) => {
+                job.status = "failed";
+                job.error = Some(e.to_string());
+            }
+        }
+    });
+    Ok(id)
+}
+
+async fn admin_snapshot(
+    State(app): State<AppState>,
+    q: Option<Query<std::collections::HashMap<String, String>>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    let background = q
+        .as_ref()
+        .and_then(|Query(m)| m.get("background"))
+        .is_some_and(|v| v == "true" || v == "1");
+    if background {
+        return match start_snapshot_job(&app) {
+            Ok(id) => (StatusCode::ACCEPTED, Json(json!({"job_id": id}))).into_response(),
+            Err(running) => (
+                StatusCode::CONFLICT,
+                Json(json!({"error":"snapshot_in_progress","job_id": running})),
+            )
+                .into_response(),
+        };
+    }
+    match run_snapshot(&app.store).await {
+        Ok((id, last_seq)) => (
+            StatusCode::OK,
+            Json(json!({"snapshot_id": id, "last_seq": last_seq})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_snapshot_job(
+    State(app): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    use agentstate_storage::persistent::{SNAPSHOT_PROGRESS_BYTES, SNAPSHOT_PROGRESS_OBJECTS};
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    let Some(mut job) = SNAPSHOT_JOBS.lock().iter().find(|j| j.id == id).cloned() else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))).into_response();
+    };
+    if job.status == "running" {
+        job.objects = SNAPSHOT_PROGRESS_OBJECTS.get();
+        job.bytes = SNAPSHOT_PROGRESS_BYTES.get();
+    }
+    (StatusCode::OK, Json(json!(job))).into_response()
+}
+async fn admin_manifest(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    match app.store.admin_manifest().await {
+        Ok(m) => {
+            if let Some(segs) = m.get("segments").and_then(|v| v.as_array()) {
+                metrics::WAL_ACTIVE_SEGMENTS.set(segs.len() as f64);
+            }
+            (StatusCode::OK, Json(m)).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+struct DumpOpts {
+    #[serde(default)]
+    consistent: bool,
+    // Resume: the x-export-watermark of the interrupted export, and the last key it sent
+    watermark: Option<String>,
+    after_ns: Option<String>,
+    after_id: Option<String>,
+}
+
+// Keys per store read while streaming a consistent export
+const EXPORT_BATCH: usize = 500;
+
+async fn admin_dump(
+    State(app): State<AppState>,
+    q: Option<Query<DumpOpts>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    let opts = q.map(|Query(o)| o).unwrap_or_default();
+    if opts.consistent || opts.watermark.is_some() {
+        return consistent_dump(&app, opts);
+    }
+    match app.store.admin_snapshot().await {
+        Ok(_) => {
+            let mut response = String::new();
+            for obj in app.store.all_objects() {
+                if let Ok(json) = serde_json::to_string(&obj) {
+                    response.push_str(&json);
+                    response.push('\n');
+                }
+            }
+            (StatusCode::OK, response).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()}))
+        ).into_response(),
+    }
+}
+
+// Streams every object as of one commit_seq watermark per namespace, in (ns, id) order,
+// so writes landing mid-export don't show up. The watermark goes out as a header token;
+// passing it back with the last key received resumes the same view after that key.
+fn consistent_dump(app: &AppState, opts: DumpOpts) -> axum::response::Response {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine};
+    let bad = |msg: String| {
+        (StatusCode::BAD_REQUEST, Json(json!({"error": msg}))).into_response()
+    };
+    let after = match (opts.after_ns, opts.after_id) {
+        (Some(ns), Some(id)) => Some((ns, id)),
+        (None, None) => None,
+        _ => return bad("after_ns and after_id go together".into()),
+    };
+    let watermark = match opts.watermark.as_deref() {
+        Some(token) => {
+            let decoded = b64.decode(token).ok().and_then(|raw| {
+                serde_json::from_slice::<std::collections::BTreeMap<String, u64>>(&raw).ok()
+            });
+            match decoded {
+                Some(w) => w,
+                None => return bad("invalid watermark".into()),
+            }
+        }
+        None if after.is_some() => return bad("resuming needs the export's watermark".into()),
+        None => match app.store.export_watermark() {
+            Ok(w) => w,
+            Err(e) => return bad(e.to_string()),
+        },
+    };
+    let mut keys = match app.store.export_keys() {
+        Ok(k) => k,
+        Err(e) => return bad(e.to_string()),
+    };
+    if let Some(after) = after {
+        keys.retain(|k| *k > after);
+    }
+    let token = b64.encode(serde_json::to_vec(&watermark).unwrap_or_default());
+    let store = app.store.clone();
+    let s = async_stream::stream! {
+        for chunk in keys.chunks(EXPORT_BATCH) {
+            for o in store.export_batch(chunk, &watermark) {
+                let mut line = serde_json::to_vec(&o).unwrap_or_default();
+                line.push(b'\n');
+                yield Ok::<Bytes, std::io::Error>(Bytes::from(line));
+            }
+        }
+    };
+    axum::http::Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "application/x-ndjson")
+        .header("x-export-watermark", token)
+        .body(axum::body::Body::from_stream(s))
+        .unwrap()
+        .into_response()
+}
+
+async fn admin_trim_wal(
+    State(app): State<AppState>,
+    q: Option<Query<std::collections::HashMap<String, String>>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    let sid = q
+        .and_then(|Query(m)| m.get("snapshot_id").cloned())
+        .unwrap_or_default();
+    if sid.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"missing snapshot_id"})),
+        )
+            .into_response();
+    }
+    match app.store.admin_trim_wal(&sid).await {
+        Ok(deleted) => {
+            if let Ok(m) = app.store.admin_manifest().await {
+                if let Some(segs) = m.get("segments").and_then(|v| v.as_array()) {
+                    metrics::WAL_ACTIVE_SEGMENTS.set(segs.len() as f64);
+                }
+            }
+            (StatusCode::OK, Json(json!({"deleted": deleted}))).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+struct WalStreamOpts {
+    #[serde(default)]
+    segment: String,
+    #[serde(default)]
+    offset: u64,
+}
+
+// CDC feed for followers: NDJSON of WAL entries, each carrying the cursor to resume after it
+async fn admin_wal_stream(
+    State(app): State<AppState>,
+    q: Option<Query<WalStreamOpts>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    let Some(dir) = app.data_dir.clone() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"not persistent"})),
+        )
+            .into_response();
+    };
+    let opts = q.map(|Query(o)| o).unwrap_or_default();
+    let mut cursor = agentstate_storage::walbin::WalCursor {
+        segment: opts.segment,
+        offset: opts.offset,
+    };
+    let s = async_stream::stream! {
+        loop {
+            let (d, from) = (dir.clone(), cursor.clone());
+            let batch = tokio::task::spawn_blocking(move || {
+                agentstate_storage::walbin::read_since(&d, &from, 512)
+            })
+            .await;
+            match batch {
+                Ok(Ok(entries)) if !entries.is_empty() => {
+                    for e in entries {
+                        cursor = e.next.clone();
+                        let mut line = serde_json::to_vec(&e).unwrap_or_default();
+                        line.push(b'\n');
+                        yield Ok::<Bytes, std::io::Error>(Bytes::from(line));
+                    }
+                }
+                Ok(Ok(_)) => tokio::time::sleep(std::time::Duration::from_millis(200)).await,
+                _ => break,
+            }
+        }
+    };
+    axum::http::Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "application/x-ndjson")
+        .header(axum::http::header::CACHE_CONTROL, "no-cache")
+        .body(axum::body::Body::from_stream(s))
+        .unwrap()
+        .into_response()
+}
+
+async fn admin_wal_freeze(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    wal_freeze(app, headers, true).await
+}
+
+async fn admin_wal_unfreeze(State(app): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    wal_freeze(app, headers, false).await
+}
+
+// While frozen the current segment grows past WAL_SEGMENT_BYTES and trims are refused,
+// so a backup tool can copy the reported segments as they stand
+async fn wal_freeze(app: AppState, headers: HeaderMap, frozen: bool) -> axum::response::Response {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    match app.store.admin_wal_freeze(frozen).await {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SegmentOpts {
+    limit: Option<usize>,
+}
+
+// Decoded records of one WAL segment, with CRC/framing errors and their offsets
+async fn admin_wal_segment(
+    State(app): State<AppState>,
+    Path(name): Path<String>,
+    q: Option<Query<SegmentOpts>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, "admin://global", "admin") {
+        return resp.into_response();
+    }
+    let Some(dir) = app.data_dir.clone() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"not persistent"})),
+        )
+            .into_response();
+    };
+    // Segment names are plain file names inside wal/
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"invalid segment name"})),
+        )
+            .into_response();
+    }
+    let limit = q.and_then(|Query(o)| o.limit).unwrap_or(1000);
+    let report = tokio::task::spawn_blocking(move || {
+        agentstate_storage::walbin::inspect_segment(&dir, &name, limit)
+    })
+    .await;
+    match report {
+        Ok(Ok(r)) => (StatusCode::OK, Json(json!(r))).into_response(),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error":"segment not found"})),
+        )
+            .into_response(),
+        Ok(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ExplainReq {
+    ns: String,
+    #[serde(default)]
+    filter: Option<serde_json::Value>,
+    #[serde(default)]
+    top_k_vec: Option<serde_json::Value>,
+    #[serde(default)]
+    #[allow(dead_code)]
+    fields: Option<Vec<String>>,
+    // EXPLAIN ANALYZE: run `query` (a /v1/{ns}/query body) and report what each stage did
+    #[serde(default)]
+    analyze: bool,
+    #[serde(default)]
+    query: Option<QueryRequest>,
+}
+
+async fn admin_explain_query(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ExplainReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &req.ns, "admin") {
+        return resp.into_response();
+    }
+    if req.analyze {
+        return explain_analyze(&app, &req.ns, req.query).await;
+    }
+    let t0 = std::time::Instant::now();
+    let mut plan: Vec<serde_json::Value> = Vec::new();
+    if let Some(f) = &req.filter {
+        if let Some(tags) = f.get("tags").and_then(|t| t.as_object()) {
+            for (k, _v) in tags {
+                plan.push(json!({"op":"filter","on": format!("tags.{}", k), "index":"tags","selectivity":0.5}));
+            }
+        }
+        if let Some(t) = f.get("type").and_then(|t| t.as_str()) {
+            plan.push(json!({"op":"filter","on": format!("type:{}", t), "index":"type","selectivity":0.5}));
+        }
+        if let Some(keys) = f.get("has_tag_keys").and_then(|k| k.as_array()) {
+            for k in keys.iter().filter_map(|k| k.as_str()) {
+                plan.push(json!({"op":"filter","on": format!("tags.{}:*", k), "index":"tag_keys","selectivity":0.5}));
+            }
+        }
+        if let Some(jp) = f.get("jsonpath").and_then(|j| j.as_str()) {
+            plan.push(json!({"op":"filter","on": format!("jsonpath:{}", jp), "index":"jsonpath","selectivity":0.5}));
+        }
+    }
+    if let Some(vq) = &req.top_k_vec {
+        if let Some(field) = vq.get("field").and_then(|v| v.as_str()) {
+            plan.push(json!({"op":"ann","field":field,"algo":"hnsw","candidates":5000,"k": vq.get("k").and_then(|k| k.as_u64()).unwrap_or(8)}));
+        }
+    }
+    let resp = json!({
+        "plan": plan,
+        "estimated_cost": {"cpu_ms": 7.2, "io_reads": 34},
+        "indexes_hit": plan.iter().filter_map(|p| p.get("index")).collect::<Vec<_>>(),
+        "warnings": Vec::<String>::new(),
+    });
+    let micros = t0.elapsed().as_micros() as f64;
+    metrics::QUERY_PLANNER_MICROS.observe(micros);
+    (StatusCode::OK, Json(resp)).into_response()
+}
+
+async fn explain_analyze(
+    app: &AppState,
+    ns: &str,
+    query: Option<QueryRequest>,
+) -> axum::response::Response {
+    let bad_request =
+        |e: String| (StatusCode::BAD_REQUEST, Json(json!({"error": e}))).into_response();
+    let Some(query) = query else {
+        return bad_request("analyze needs a query".into());
+    };
+    if let Err(e) = crypt::check_filter(ns, &query) {
+        return bad_request(e);
+    }
+    let t0 = std::time::Instant::now();
+    match app.store.query_analyze(ns, query).await {
+        Ok((rows, trace)) => (
+            StatusCode::OK,
+            Json(json!({
+                "analyze": true,
+                "stages": trace.stages,
+                "rows": rows.len(),
+                "total_micros": t0.elapsed().as_micros() as u64,
+            })),
+        )
+            .into_response(),
+        Err(e) => bad_request(e.to_string()),
+    }
+}
+
+async fn admin_register_vec_field(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(field): Json<agentstate_core::VecField>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match app.store.register_vec_field(&ns, field.clone()) {
+        Ok(()) => (StatusCode::OK, Json(json!(field))).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CompositeIndexReq {
+    keys: Vec<String>,
+}
+
+async fn admin_register_composite_index(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<CompositeIndexReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match app.store.register_composite_index(&ns, req.keys.clone()) {
+        Ok(()) => (StatusCode::OK, Json(json!({"keys": req.keys}))).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct IndexPathsReq {
+    paths: Vec<String>,
+}
+
+async fn admin_register_index_paths(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<IndexPathsReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match app.store.register_index_paths(&ns, req.paths) {
+        Ok(paths) => (StatusCode::OK, Json(json!({"paths": paths}))).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_index_paths(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    (StatusCode::OK, Json(json!({"paths": app.store.index_paths(&ns)}))).into_response()
+}
+
+async fn admin_register_transform(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(rule): Json<agentstate_core::TransformRule>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match app.store.register_transform(&ns, rule.clone()) {
+        Ok(()) => (StatusCode::OK, Json(json!(rule))).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_get_quota(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match app.store.quota(&ns) {
+        Some(q) => (StatusCode::OK, Json(json!(q))).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))).into_response(),
+    }
+}
+
+async fn admin_set_quota(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(quota): Json<agentstate_storage::NsQuota>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match app.store.set_quota(&ns, quota) {
+        Ok(()) => (StatusCode::OK, Json(json!(quota))).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+// Usage against the ns's quota: live objects and bytes of their latest versions
+async fn admin_ns_stats(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    let Some(usage) = app.store.usage(&ns) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"usage not tracked by this engine"})),
+        )
+            .into_response();
+    };
+    let body = json!({
+        "ns": ns,
+        "objects": usage.objects,
+        "bytes": usage.bytes,
+        "quota": app.store.quota(&ns),
+    });
+    (StatusCode::OK, Json(body)).into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct ExpiryWebhookReq {
+    url: String,
+}
+
+async fn admin_get_expiry_webhook(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    match webhook::url(&ns) {
+        Some(url) => (StatusCode::OK, Json(json!({"url": url}))).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error":"not_found"}))).into_response(),
+    }
+}
+
+async fn admin_set_expiry_webhook(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<ExpiryWebhookReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    if reqwest::Url::parse(&req.url).map_or(true, |u| !matches!(u.scheme(), "http" | "https")) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error":"url must be http(s)"})),
+        )
+            .into_response();
+    }
+    webhook::set_url(&ns, Some(req.url.clone()));
+    (StatusCode::OK, Json(json!({"url": req.url}))).into_response()
+}
+
+async fn admin_clear_expiry_webhook(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "admin") {
+        return resp.into_response();
+    }
+    webhook::set_url(&ns, None);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// Leases endpoints
+#[derive(serde::Deserialize)]
+struct LeaseAcquireReq {
+    key: String,
+    owner: String,
+    ttl: u64,
+}
+#[derive(serde::Deserialize)]
+struct LeaseRenewReq {
+    key: String,
+    owner: String,
+    token: u64,
+    ttl: u64,
+}
+#[derive(serde::Deserialize)]
+struct LeaseReleaseReq {
+    key: String,
+    owner: String,
+    token: u64,
+}
+
+async fn lease_acquire(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<LeaseAcquireReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "lease") {
+        return resp.into_response();
+    }
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    match app
+        .store
+        .lease_acquire(&ns, &req.key, &req.owner, req.ttl)
+        .await
+    {
+        Ok(l) => (
+            StatusCode::OK,
+            Json(json!({"token": l.token, "expires_at": l.expires_at.to_rfc3339()})),
+        )
+            .into_response(),
+        Err(e) => {
+            let code = match e {
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::CONFLICT,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
+        }
+    }
+}
+async fn lease_renew(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<LeaseRenewReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "lease") {
+        return resp.into_response();
+    }
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    match app
+        .store
+        .lease_renew(&ns, &req.key, &req.owner, req.token, req.ttl)
+        .await
+    {
+        Ok(l) => (
+            StatusCode::OK,
+            Json(json!({"token": l.token, "expires_at": l.expires_at.to_rfc3339()})),
+        )
+            .into_response(),
+        Err(e) => {
+            let code = match e {
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::CONFLICT,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
+        }
+    }
+}
+async fn lease_release(
+    State(app): State<AppState>,
+    Path(ns): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<LeaseReleaseReq>,
+) -> impl IntoResponse {
+    if let Err(resp) = enforce_caps(&app, &headers, &ns, "lease") {
+        return resp.into_response();
+    }
+    if let Err(resp) = reject_if_follower(&app) {
+        return resp.into_response();
+    }
+    match app
+        .store
+        .lease_release(&ns, &req.key, &req.owner, req.token)
+        .await
+    {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => {
+            let code = match e {
+                StateError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::CONFLICT,
+            };
+            (code, Json(json!({"error": e.to_string()}))).into_response()
+        }
+    }
+}
+
+pub mod agentstate_v1 {
+    tonic::include_proto!("agentstate.v1");
+}
+
+struct AgentStateGrpc {
+    state: AppState,
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<agentstate_v1::WatchEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl agentstate_v1::agent_state_server::AgentState for AgentStateGrpc {
+    async fn put(
+        &self,
+        request: Request<agentstate_v1::PutRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::Object>, Status> {
+        if self.state.leader.is_some() {
+            return Err(Status::permission_denied("read_only_follower"));
+        }
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        let claims = grpc_caps(&self.state, &headers, &req.ns, "put").map_err(|(c, m)| Status::new(c, m))?;
+        let _permit = admit_write(&self.state)?;
+        let pr = PutRequest {
+            r#type: req.r#type,
+            body: serde_json::from_str(&req.body_json).unwrap_or(serde_json::Value::Null),
+            tags: agentstate_core::Tags(req.tags.into_iter().collect()),
+            ttl_seconds: if req.ttl_seconds == 0 {
+                None
+            } else {
+                Some(req.ttl_seconds)
+            },
+            id: if req.id.is_empty() {
+                None
+            } else {
+                Some(req.id)
+            },
+            parents: req.parents,
+            ts: None,
+            only_if_changed: false,
+            content_type: if req.content_type.is_empty() {
+                None
+            } else {
+                Some(req.content_type)
+            },
+        };
+        let mut o = self
+            .state
+            .store
+            .put(&req.ns, pr)
+            .await
+            .map_err(|e| match e {
+                StateError::Unavailable(_) => Status::unavailable(e.to_string()),
+                StateError::Invalid(_) => Status::invalid_argument(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            })?;
+        crypt::open(&claims, std::slice::from_mut(&mut o));
+        redact(&claims, std::slice::from_mut(&mut o));
+        Ok(TonicResponse::new(to_proto_object(o)))
+    }
+
+    async fn get(
+        &self,
+        request: Request<agentstate_v1::GetRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::Object>, Status> {
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        let claims = grpc_caps(&self.state, &headers, &req.ns, "get").map_err(|(c, m)| Status::new(c, m))?;
+        let mut o = self
+            .state
+            .store
+            .get(
+                &req.ns,
+                &req.id,
+                agentstate_storage::traits::GetOptions { at_ts: None },
+            )
+            .await
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        crypt::open(&claims, std::slice::from_mut(&mut o));
+        redact(&claims, std::slice::from_mut(&mut o));
+        Ok(TonicResponse::new(to_proto_object(o)))
+    }
+
+    async fn query(
+        &self,
+        request: Request<agentstate_v1::QueryRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::QueryResponse>, Status> {
+        let deadline = request
+            .metadata()
+            .get("grpc-timeout")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_grpc_timeout)
+            .map(agentstate_core::Deadline::after)
+            .unwrap_or_default();
+        let _cancel = CancelOnDrop(deadline.clone());
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        let ns = req.ns.clone();
+        let claims = grpc_caps(&self.state, &headers, &ns, "query").map_err(|(c, m)| Status::new(c, m))?;
+        let mut qr = from_proto_query(req).map_err(Status::invalid_argument)?;
+        qr.deadline = deadline;
+        crypt::check_filter(&ns, &qr).map_err(Status::invalid_argument)?;
+        let mut list = self
+            .state
+            .store
+            .query(&ns, qr)
+            .await
+            .map_err(|e| match e {
+                StateError::DeadlineExceeded => Status::deadline_exceeded(e.to_string()),
+                StateError::Invalid(_) => Status::invalid_argument(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            })?;
+        crypt::open(&claims, &mut list);
+        redact(&claims, &mut list);
+        Ok(TonicResponse::new(agentstate_v1::QueryResponse {
+            objects: list.into_iter().map(to_proto_object).collect(),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<agentstate_v1::DeleteRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::Empty>, Status> {
+        if self.state.leader.is_some() {
+            return Err(Status::permission_denied("read_only_follower"));
+        }
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        grpc_caps(&self.state, &headers, &req.ns, "delete").map_err(|(c, m)| Status::new(c, m))?;
+        let _permit = admit_write(&self.state)?;
+        self.state
+            .store
+            .delete(&req.ns, &req.id)
+            .await
+            .map_err(|e| match e {
+                StateError::Unavailable(_) => Status::unavailable(e.to_string()),
+                _ => Status::not_found(e.to_string()),
+            })?;
+        Ok(TonicResponse::new(agentstate_v1::Empty {}))
+    }
+
+    type WatchStream = WatchStream;
+    async fn watch(
+        &self,
+        request: Request<agentstate_v1::WatchRequest>,
+    ) -> Result<TonicResponse<Self::WatchStream>, Status> {
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        let claims = grpc_caps(&self.state, &headers, &req.ns, "watch").map_err(|(c, m)| Status::new(c, m))?;
+        if watch_rate_limit(&self.state, &claims).is_err() {
+            return Err(Status::resource_exhausted("rate_limited"));
+        }
+        let mut handle = self.state.store.subscribe(
+            agentstate_storage::traits::WatchFilter {
+                ns: req.ns.clone(),
+                type_equals: (!req.r#type.is_empty()).then(|| req.r#type.clone()),
+                tag_equals: req.tags.clone().into_iter().collect(),
+            },
+            Some(req.from_commit),
+            None,
+        );
+        if req.from_commit > 0 {
+            WATCH_RESUMES_TOTAL.with_label_values(&["grpc"]).inc();
+        }
+        WATCH_CLIENTS.with_label_values(&["grpc"]).inc();
+        let output = async_stream::try_stream! {
+            loop {
+                if let Some((last, retry)) = handle.overflow_meta() {
+                    // terminate with RESOURCE_EXHAUSTED; include info in message (trailers API is limited here)
+                    metrics::WATCH_DROPS_TOTAL.with_label_values(&["overflow", &req.ns]).inc();
+                    Err(Status::resource_exhausted(format!("overflow last_commit={} retry_after_ms={}", last, retry)))?;
+                } else if let Some(ev) = handle.try_next() {
+                    match ev {
+                        agentstate_storage::traits::WatchEvent::Put(mut o) => {
+                            WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
+                            let lag = (chrono::Utc::now() - o.ts).num_milliseconds() as f64 / 1000.0;
+                            metrics::WATCH_EMIT_LAG_SEC.observe(lag.max(0.0));
+                            crypt::open(&claims, std::slice::from_mut(&mut o));
+                            redact(&claims, std::slice::from_mut(&mut o));
+                            yield agentstate_v1::WatchEvent { r#type: "put".into(), obj: Some(to_proto_object(o.clone())), id: o.id.clone(), commit: o.commit_seq };
+                        }
+                        agentstate_storage::traits::WatchEvent::Delete{ns:_, id, commit_seq} => {
+                            WATCH_EVENTS_TOTAL.with_label_values(&["delete"]).inc();
+                            yield agentstate_v1::WatchEvent { r#type: "delete".into(), obj: None, id, commit: commit_seq };
+                        }
+                    }
+                } else {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                }
+            }
+        };
+        Ok(TonicResponse::new(Box::pin(output) as WatchStream))
+    }
+
+    type WatchPullStream = WatchStream;
+    async fn watch_pull(
+        &self,
+        request: Request<tonic::Streaming<agentstate_v1::WatchPullRequest>>,
+    ) -> Result<TonicResponse<Self::WatchPullStream>, Status> {
+        let headers = grpc_headers(&request);
+        let mut inbound = request.into_inner();
+        let first = inbound
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("watch_pull needs an opening request"))?;
+        let claims =
+            grpc_caps(&self.state, &headers, &first.ns, "watch").map_err(|(c, m)| Status::new(c, m))?;
+        if watch_rate_limit(&self.state, &claims).is_err() {
+            return Err(Status::resource_exhausted("rate_limited"));
+        }
+        let store = self.state.store.clone();
+        let filter = agentstate_storage::traits::WatchFilter {
+            ns: first.ns,
+            type_equals: (!first.r#type.is_empty()).then_some(first.r#type),
+            tag_equals: first.tags.into_iter().collect(),
+        };
+        let mut last = first.from_commit;
+        let mut credits = first.credits;
+        if last > 0 {
+            WATCH_RESUMES_TOTAL.with_label_values(&["grpc"]).inc();
+        }
+        let guard = ClientGuard::inc("grpc");
+        let subscribe = move |from: u64| store.subscribe(filter.clone(), Some(from), None);
+        let mut handle = subscribe(last);
+        let output = async_stream::try_stream! {
+            let _g = guard;
+            // A closed request stream means no more credits; finish once the granted ones are spent
+            let mut open = true;
+            loop {
+                if credits == 0 {
+                    if !open {
+                        break;
+                    }
+                    // Nothing is taken off the buffer until the client grants more
+                    match inbound.message().await? {
+                        Some(m) => credits = credits.saturating_add(m.credits),
+                        None => open = false,
+                    }
+                    continue;
+                }
+                let Some(ev) = handle.try_next() else {
+                    if handle.overflow_meta().is_some() {
+                        // The buffer filled while the client held back; the commit log still has
+                        // everything after the last event sent, so pick up from there
+                        handle.unsubscribe();
+                        handle = subscribe(last);
+                        continue;
+                    }
+                    let tick = tokio::time::sleep(std::time::Duration::from_millis(100));
+                    if !open {
+                        tick.await;
+                        continue;
+                    }
+                    let msg = tokio::select! {
+                        m = inbound.message() => Some(m),
+                        _ = tick => None,
+                    };
+                    match msg.transpose()? {
+                        Some(Some(m)) => credits = credits.saturating_add(m.credits),
+                        Some(None) => open = false,
+                        None => {}
+                    }
+                    continue;
+                };
+                credits -= 1;
+                match ev {
+                    agentstate_storage::traits::WatchEvent::Put(mut o) => {
+                        WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
+                        last = o.commit_seq;
+                        crypt::open(&claims, std::slice::from_mut(&mut o));
+                        redact(&claims, std::slice::from_mut(&mut o));
+                        yield agentstate_v1::WatchEvent { r#type: "put".into(), id: o.id.clone(), commit: o.commit_seq, obj: Some(to_proto_object(o)) };
+                    }
+                    agentstate_storage::traits::WatchEvent::Delete { ns: _, id, commit_seq } => {
+                        WATCH_EVENTS_TOTAL.with_label_values(&["delete"]).inc();
+                        last = commit_seq;
+                        yield agentstate_v1::WatchEvent { r#type: "delete".into(), obj: None, id, commit: commit_seq };
+                    }
+                }
+            }
+        };
+        Ok(TonicResponse::new(Box::pin(output) as WatchStream))
+    }
+
+    async fn lease_acquire(
+        &self,
+        request: Request<agentstate_v1::LeaseAcquireRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::Lease>, Status> {
+        if self.state.leader.is_some() {
+            return Err(Status::permission_denied("read_only_follower"));
+        }
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        grpc_caps(&self.state, &headers, &req.ns, "lease").map_err(|(c, m)| Status::new(c, m))?;
+        let l = self
+            .state
+            .store
+            .lease_acquire(&req.ns, &req.key, &req.owner, req.ttl)
+            .await
+            .map_err(lease_status)?;
+        Ok(TonicResponse::new(to_proto_lease(l)))
+    }
+
+    async fn lease_renew(
+        &self,
+        request: Request<agentstate_v1::LeaseRenewRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::Lease>, Status> {
+        if self.state.leader.is_some() {
+            return Err(Status::permission_denied("read_only_follower"));
+        }
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        grpc_caps(&self.state, &headers, &req.ns, "lease").map_err(|(c, m)| Status::new(c, m))?;
+        let l = self
+            .state
+            .store
+            .lease_renew(&req.ns, &req.key, &req.owner, req.token, req.ttl)
+            .await
+            .map_err(lease_status)?;
+        Ok(TonicResponse::new(to_proto_lease(l)))
+    }
+
+    async fn lease_release(
+        &self,
+        request: Request<agentstate_v1::LeaseReleaseRequest>,
+    ) -> Result<TonicResponse<agentstate_v1::Empty>, Status> {
+        if self.state.leader.is_some() {
+            return Err(Status::permission_denied("read_only_follower"));
+        }
+        let headers = grpc_headers(&request);
+        let req = request.into_inner();
+        grpc_caps(&self.state, &headers, &req.ns, "lease").map_err(|(c, m)| Status::new(c, m))?;
+        self.state
+            .store
+            .lease_release(&req.ns, &req.key, &req.owner, req.token)
+            .await
+            .map_err(lease_status)?;
+        Ok(TonicResponse::new(agentstate_v1::Empty {}))
+    }
+}
+
+// gRPC calls send their capability token as `authorization` metadata; copied into a
+// HeaderMap because tonic's metadata is on an older `http` than axum's
+fn grpc_headers<T>(request: &Request<T>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(auth) = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| axum::http::HeaderValue::from_str(s).ok())
+    {
+        headers.insert(axum::http::header::AUTHORIZATION, auth);
+    }
+    headers
+}
+
+// enforce_caps for a gRPC call: the token's claims, or the code and message to fail with
+fn grpc_caps(
+    app: &AppState,
+    headers: &HeaderMap,
+    ns: &str,
+    verb: &str,
+) -> Result<serde_json::Value, (tonic::Code, String)> {
+    enforce_caps(app, headers, ns, verb).map_err(|(status, Json(body))| {
+        let code = match status {
+            StatusCode::UNAUTHORIZED => tonic::Code::Unauthenticated,
+            _ => tonic::Code::PermissionDenied,
+        };
+        let msg = body["error"].as_str().unwrap_or("denied").to_string();
+        (code, msg)
+    })
+}
+
+// Lost races and fencing failures are FAILED_PRECONDITION, a lease that isn't there NOT_FOUND
+fn lease_status(e: StateError) -> Status {
+    match e {
+        StateError::Conflict(_) => Status::failed_precondition(e.to_string()),
+        StateError::NotFound => Status::not_found(e.to_string()),
+        StateError::Unavailable(_) => Status::unavailable(e.to_string()),
+        StateError::Invalid(_) => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+fn to_proto_lease(l: agentstate_storage::traits::Lease) -> agentstate_v1::Lease {
+    agentstate_v1::Lease {
+        token: l.token,
+        expires_at: l.expires_at.to_rfc3339(),
+    }
+}
+
+// The structured fields map one to one onto the HTTP query body. The deprecated
+// `tag_json` keeps its meaning; `jsonpath` never matched anything and is rejected.
+fn from_proto_query(req: agentstate_v1::QueryRequest) -> Result<QueryRequest, String> {
+    let json = |what: &str, path: &str, s: &str| -> Result<Option<serde_json::Value>, String> {
+        if s.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_str(s)
+            .map(Some)
+            .map_err(|e| format!("{} for {}: {}", what, path, e))
+    };
+    let mut tags: std::collections::BTreeMap<String, String> = if req.tag_json.is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_str(&req.tag_json).map_err(|e| format!("tag_json: {}", e))?
+    };
+    tags.extend(req.tags);
+    if !req.jsonpath.is_empty() {
+        return Err("jsonpath is no longer supported; use jsonpath_equals".into());
+    }
+    let mut jp = agentstate_core::JsonPathFilter::default();
+    for eq in req.jsonpath_equals {
+        let v = json("value_json", &eq.path, &eq.value_json)?
+            .ok_or_else(|| format!("value_json for {}: empty", eq.path))?;
+        jp.equals.insert(eq.path, v);
+    }
+    for r in req.jsonpath_ranges {
+        let range = agentstate_core::Range {
+            gt: json("gt_json", &r.path, &r.gt_json)?,
+            gte: json("gte_json", &r.path, &r.gte_json)?,
+            lt: json("lt_json", &r.path, &r.lt_json)?,
+            lte: json("lte_json", &r.path, &r.lte_json)?,
+        };
+        jp.ranges.insert(r.path, range);
+    }
+    let vector = req.vector.map(|v| agentstate_core::VectorQuery {
+        field: v.field,
+        top_k: v.top_k as usize,
+        embedding: v.embedding,
+    });
+    Ok(QueryRequest {
+        tag_filter: (!tags.is_empty()).then_some(agentstate_core::TagFilter(tags)),
+        jsonpath: (!jp.equals.is_empty() || !jp.ranges.is_empty()).then_some(jp),
+        vector,
+        limit: (req.limit > 0).then_some(req.limit as usize),
+        ..Default::default()
+    })
+}
+
+fn to_proto_object(o: agentstate_core::Object) -> agentstate_v1::Object {
+    agentstate_v1::Object {
+        content_type: o.content_type().to_string(),
+        id: o.id,
+        ns: o.ns,
+        r#type: o.r#type,
+        body_json: serde_json::to_string(&o.body).unwrap_or("null".into()),
+        tags: o.tags.0.into_iter().collect(),
+        ttl_seconds: o.ttl_seconds.unwrap_or_default(),
+        parents: o.parents,
+        commit: o.commit,
+        ts_rfc3339: o.ts.to_rfc3339(),
+    }
+}
+
+// Capability token enforcement (simple HMAC signed JSON)
+fn enforce_caps(
+    app: &AppState,
+    headers: &HeaderMap,
+    ns: &str,
+    verb: &str,
+) -> Result<serde_json::Value, (StatusCode, Json<serde_json::Value>)> {
+    let claims = verify_token(&app.keys, headers)?;
+    // ns check
+    if let Some(arr) = claims.get("ns").and_then(|v| v.as_array()) {
+        if !arr.iter().any(|v| v.as_str() == Some(ns)) {
+            return Err((StatusCode::FORBIDDEN, Json(json!({"error":"ns denied"}))));
+        }
+    }
+    // verbs check
+    if let Some(arr) = claims.get("verbs").and_then(|v| v.as_array()) {
+        if !arr.iter().any(|v| v.as_str() == Some(verb)) {
+            return Err((StatusCode::FORBIDDEN, Json(json!({"error":"verb denied"}))));
+        }
+    }
+    // size limit for PUT
+    if verb == "put" {
+        if let Some(max) = claims.get("max_bytes").and_then(|v| v.as_u64()) {
+            /* enforced in handler if needed */
+            let _ = max;
+        }
+    }
+    Ok(claims)
+}
+
+// The bearer token's signature, claims and expiry; ns and verb scoping is up to the caller.
+// With no CAP_KEY_* configured every request passes with empty claims.
+fn verify_token(
+    keys: &CapKeys,
+    headers: &HeaderMap,
+) -> Result<serde_json::Value, (StatusCode, Json<serde_json::Value>)> {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine};
+    // Dual keys: CAP_KEY_ACTIVE, CAP_KEY_NEXT; token format: kid.payload.sig
+    if keys.active.is_none() && keys.next.is_none() {
+        return Ok(serde_json::json!({}));
+    }
+    let auth = match headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(s) if s.starts_with("Bearer ") => &s[7..],
+        _ => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error":"missing token"})),
+            ))
+        }
+    };
+    let parts: Vec<&str> = auth.split('.').collect();
+    if parts.len() != 3 {
+        return Err((StatusCode::UNAUTHORIZED, Json(json!({"error":"bad token"}))));
+    }
+    let kid = parts[0];
+    let payload = b64
+        .decode(parts[1])
+        .map_err(|_| (StatusCode::UNAUTHORIZED, Json(json!({"error":"bad b64"}))))?;
+    let sig_bytes = b64
+        .decode(parts[2])
+        .map_err(|_| (StatusCode::UNAUTHORIZED, Json(json!({"error":"bad b64"}))))?;
+    let secret = keys.secret(kid).ok_or((
+        StatusCode::UNAUTHORIZED,
+        Json(json!({"error":"unknown kid"})),
+    ))?;
+    let mut mac = <Hmac<Sha256>>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(&payload);
+    let sig = mac.finalize().into_bytes();
+    if sig.as_slice() != sig_bytes.as_slice() {
+        return Err((StatusCode::UNAUTHORIZED, Json(json!({"error":"bad sig"}))));
+    }
+    let mut claims: serde_json::Value = serde_json::from_slice(&payload).map_err(|_| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error":"bad claims"})),
+        )
+    })?;
+    // inject kid for tracing/audit
+    if let serde_json::Value::Object(ref mut map) = claims {
+        map.insert("kid".into(), serde_json::Value::String(kid.to_string()));
+    }
+    // ttl check
+    if let Some(exp) = claims.get("exp").and_then(|v| v.as_i64()) {
+        if exp
+            < (std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64)
+        {
+            return Err((StatusCode::UNAUTHORIZED, Json(json!({"error":"expired"}))));
+        }
+    }
+    Ok(claims)
+}
+
+static DEFAULT_NAMESPACE: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("DEFAULT_NAMESPACE").ok().filter(|s| !s.is_empty()));
+
+// STORAGE_ENGINE=rocksdb: on-disk objects under DATA_DIR (default ./data/rocks),
+// with watch/lease/idempotency kept in memory
+#[cfg(feature = "rocksdb")]
+fn open_rocks() -> Arc<dyn Storage> {
+    let dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "./data/rocks".into());
+    match agentstate_storage::RocksStore::open(std::path::Path::new(&dir)) {
+        Ok(r) => Arc::new(agentstate_storage::Composed::new(r)),
+        Err(e) => {
+            tracing::warn!("rocksdb open failed: {} — falling back to memory", e);
+            Arc::new(InMemoryStore::new())
+        }
+    }
+}
+
+#[cfg(not(feature = "rocksdb"))]
+fn open_rocks() -> Arc<dyn Storage> {
+    tracing::warn!("STORAGE_ENGINE=rocksdb but built without the rocksdb feature — using memory");
+    Arc::new(InMemoryStore::new())
+}
+
+// Every HTTP route; main adds tracing and the default namespace rewrite around it
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/health/deep", get(health_deep))
+        .route("/ready", get(ready))
+        .route(
+            "/v1/token/introspect",
+            get(token_introspect).post(token_introspect),
+        )
+        .route("/v1/:ns/objects", post(put_objects))
+        .route(
+            "/v1/:ns/objects/:id",
+            get(get_object)
+                .put(put_object_at)
+                .delete(delete_object)
+                .post(object_action),
+        )
+        .route("/v1/:ns/objects/:id/trim-versions", post(trim_versions))
+        .route(
+            "/v1/:ns/objects/:id/lock",
+            post(advisory_lock).delete(advisory_unlock),
+        )
+        .route("/v1/:ns/objects/:id/diff", get(diff_versions))
+        .route("/v1/:ns/objects/:id/history", get(object_history))
+        .route("/v1/:ns/objects/:id/descendants", get(object_descendants))
+        .route("/v1/:ns/txn", post(txn))
+        .route("/v1/:ns/query", post(query))
+        .route("/v1/:ns/expiring", get(expiring))
+        .route("/v1/:ns/watch", get(watch_sse))
+        .route("/v1/:ns/lease/acquire", post(lease_acquire))
+        .route("/v1/:ns/lease/renew", post(lease_renew))
+        .route("/v1/:ns/lease/release", post(lease_release))
+        .route("/admin/snapshot", post(admin_snapshot))
+        .route("/admin/snapshots/jobs/:id", get(admin_snapshot_job))
+        .route("/admin/manifest", get(admin_manifest))
+        .route("/admin/wal/stream", get(admin_wal_stream))
+        .route("/admin/wal/segments/:name", get(admin_wal_segment))
+        .route("/admin/trim-wal", post(admin_trim_wal))
+        .route("/admin/wal/freeze", post(admin_wal_freeze))
+        .route("/admin/wal/unfreeze", post(admin_wal_unfreeze))
+        .route("/admin/explain-query", post(admin_explain_query))
+        .route("/admin/query", post(admin_query))
+        .route("/admin/dump", get(admin_dump))
+        .route("/admin/metrics.json", get(admin_metrics_json))
+        .route("/admin/:ns/vec-fields", post(admin_register_vec_field))
+        .route(
+            "/admin/:ns/composite-indexes",
+            post(admin_register_composite_index),
+        )
+        .route(
+            "/admin/:ns/index-paths",
+            get(admin_index_paths).post(admin_register_index_paths),
+        )
+        .route("/admin/:ns/transforms", post(admin_register_transform))
+        .route(
+            "/admin/:ns/quota",
+            get(admin_get_quota).put(admin_set_quota),
+        )
+        .route("/admin/:ns/stats", get(admin_ns_stats))
+        .route(
+            "/admin/:ns/expiry-webhook",
+            get(admin_get_expiry_webhook)
+                .put(admin_set_expiry_webhook)
+                .delete(admin_clear_expiry_webhook),
+        )
+        .route("/metrics", get(metrics))
+        .with_state(state)
+        .layer(
+            CorsLayer::new()
+                .allow_origin(Any)
+                .allow_methods(Any)
+                .allow_headers(Any),
+        )
+}
+
+// Resources that live under /v1/:ns; with a default namespace these names
+// are reserved and `/v1/<resource>/...` maps to `/v1/<default>/<resource>/...`.
+const NS_RESOURCES: &[&str] = &["objects", "query", "watch", "lease", "expiring"];
+
+async fn default_ns_rewrite(req: axum::extract::Request) -> axum::extract::Request {
+    match DEFAULT_NAMESPACE.as_deref() {
+        Some(ns) => with_default_ns(ns, req),
+        None => req,
+    }
+}
+
+fn with_default_ns(ns: &str, mut req: axum::extract::Request) -> axum::extract::Request {
+    let Some(rest) = req.uri().path().strip_prefix("/v1/") else {
+        return req;
+    };
+    let first = rest.split('/').next().unwrap_or("");
+    if !NS_RESOURCES.contains(&first) {
+        return req;
+    }
+    let mut pq = format!("/v1/{}/{}", ns, rest);
+    if let Some(q) = req.uri().query() {
+        pq.push('?');
+        pq.push_str(q);
+    }
+    let mut parts = req.uri().clone().into_parts();
+    if let Ok(p) = pq.parse() {
+        parts.path_and_query = Some(p);
+        if let Ok(uri) = axum::http::Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+    }
+    req
+}
+
+fn reject_if_follower(app: &AppState) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if app.leader.is_some() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"error":"read_only_follower"})),
+        ));
+    }
+    Ok(())
+}
+
+// `max_bytes` claim against the declared request size
+fn reject_if_too_large(
+    claims: &serde_json::Value,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let maxb = claims.get("max_bytes").and_then(|v| v.as_u64());
+    let cl = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok());
+    match (maxb, cl) {
+        (Some(maxb), Some(cl)) if cl > maxb => Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({"error":"too_large"})),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn wants_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|a| a.contains("application/msgpack"))
+}
+
+const JSONAPI_MEDIA_TYPE: &str = "application/vnd.api+json";
+
+fn wants_jsonapi(headers: &HeaderMap) -> bool {
+    headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|a| a.contains(JSONAPI_MEDIA_TYPE))
+}
+
+// An object as a JSON:API resource: `type` and `id` lift out, `parents` (commit hashes)
+// become a `parents` relationship to `commits`, and every other field is an attribute
+fn jsonapi_resource(v: serde_json::Value) -> serde_json::Value {
+    let serde_json::Value::Object(mut attrs) = v else {
+        return v;
+    };
+    let id = attrs.remove("id").unwrap_or_default();
+    let r#type = attrs.remove("type").unwrap_or_default();
+    let parents: Vec<serde_json::Value> = match attrs.remove("parents") {
+        Some(serde_json::Value::Array(a)) => a
+            .into_iter()
+            .map(|c| json!({"type": "commits", "id": c}))
+            .collect(),
+        _ => Vec::new(),
+    };
+    json!({
+        "type": r#type,
+        "id": id,
+        "attributes": attrs,
+        "relationships": {"parents": {"data": parents}},
+    })
+}
+
+fn jsonapi_response(status: StatusCode, doc: serde_json::Value) -> axum::response::Response {
+    (
+        status,
+        [(axum::http::header::CONTENT_TYPE, JSONAPI_MEDIA_TYPE)],
+        serde_json::to_vec(&doc).unwrap_or_default(),
+    )
+        .into_response()
+}
+
+// Same shape as the JSON response (struct fields as map keys), MessagePack-encoded
+fn msgpack_response<T: serde::Serialize>(v: &T) -> axum::response::Response {
+    match rmp_serde::to_vec_named(v) {
+        Ok(buf) => (
+            [(axum::http::header::CONTENT_TYPE, "application/msgpack")],
+            buf,
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+// Token-driven field masking: the `allowed_fields` claim keeps only those body paths,
+// then `redact_fields` removes its paths, from every returned object.
+fn redact(claims: &serde_json::Value, objs: &mut [agentstate_core::Object]) {
+    for o in objs.iter_mut() {
+        redact_body(claims, &mut o.body);
+    }
+}
+
+// `redact` for one body, e.g. inside an already serialized response
+fn redact_body(claims: &serde_json::Value, body: &mut serde_json::Value) {
+    if let Some(allowed) = claims.get("allowed_fields").and_then(|v| v.as_array()) {
+        let paths: Vec<Vec<&str>> = allowed
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(body_path)
+            .collect();
+        project_body(body, &paths);
+    }
+    let Some(paths) = claims.get("redact_fields").and_then(|v| v.as_array()) else {
+        return;
+    };
+    for p in paths.iter().filter_map(|v| v.as_str()) {
+        let parts = body_path(p);
+        let Some((last, parents)) = parts.split_last() else {
+            continue;
+        };
+        let mut cur = Some(&mut *body);
+        for k in parents {
+            cur = cur.and_then(|v| v.get_mut(*k));
+        }
+        if let Some(serde_json::Value::Object(map)) = cur {
+            map.remove(*last);
+        }
+    }
+}
+
+// Server-side ceiling on a token's `max_qps` claim (MAX_QPS_CEILING, default 10000)
+static MAX_QPS_CEILING: Lazy<u64> = Lazy::new(|| {
+    std::env::var("MAX_QPS_CEILING")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(10_000)
+});
+
+// Refill rate varies by up to ±RATE_LIMIT_JITTER (fraction, default 0.1) per request,
+// so buckets of a synchronized fleet drift apart instead of refilling in lockstep
+static RATE_LIMIT_JITTER: Lazy<f64> = Lazy::new(|| {
+    std::env::var("RATE_LIMIT_JITTER")
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .unwrap_or(0.1)
+        .clamp(0.0, 1.0)
+});
+
+fn rate_limit(
+    state: &AppState,
+    claims: &serde_json::Value,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let Some(max_qps) = claims.get("max_qps").and_then(|v| v.as_u64()) else {
+        return Ok(());
+    };
+    let max_qps = max_qps.min(*MAX_QPS_CEILING);
+    let burst = claims
+        .get("max_burst")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(max_qps.saturating_mul(2))
+        .max(1);
+    take_token(state, bucket_key("", claims), max_qps, burst)
+}
+
+// Opening a watch draws from its own bucket (`max_watch_qps`, `max_watch_burst`
+// defaulting to `max_watch_qps`), so connection churn is capped apart from ops
+fn watch_rate_limit(
+    state: &AppState,
+    claims: &serde_json::Value,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let Some(max_qps) = claims.get("max_watch_qps").and_then(|v| v.as_u64()) else {
+        return Ok(());
+    };
+    let max_qps = max_qps.min(*MAX_QPS_CEILING);
+    let burst = claims
+        .get("max_watch_burst")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(max_qps)
+        .max(1);
+    take_token(state, bucket_key("watch:", claims), max_qps, burst)
+}
+
+fn bucket_key(prefix: &str, claims: &serde_json::Value) -> String {
+    let kid = claims
+        .get("kid")
+        .and_then(|v| v.as_str())
+        .unwrap_or("active");
+    let jti = claims.get("jti").and_then(|v| v.as_str()).unwrap_or("");
+    format!("{}{}:{}", prefix, kid, jti)
+}
+
+fn take_token(
+    state: &AppState,
+    key: String,
+    max_qps: u64,
+    burst: u64,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let mut map = state.qps.write();
+    let now = std::time::Instant::now();
+    let refill_per_s = max_qps as f64 * jitter_factor();
+    let entry = map.entry(key).or_insert((burst as f64, now, burst));
+    let elapsed = now.duration_since(entry.1).as_secs_f64();
+    entry.0 = (entry.0 + elapsed * refill_per_s).min(burst as f64);
+    entry.1 = now;
+    entry.2 = burst;
+    if entry.0 >= 1.0 {
+        entry.0 -= 1.0;
+        Ok(())
+    } else {
+        Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({"error":"rate_limited"})),
+        ))
+    }
+}
+
+// Rejection for a write arriving while all MAX_INFLIGHT_WRITES slots are taken
+struct Overloaded;
+
+impl IntoResponse for Overloaded {
+    fn into_response(self) -> axum::response::Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, "1")],
+            Json(json!({"error":"too_many_inflight_writes"})),
+        )
+            .into_response()
+    }
+}
+
+impl From<Overloaded> for Status {
+    fn from(_: Overloaded) -> Self {
+        Status::unavailable("too_many_inflight_writes")
+    }
+}
+
+// Holds one MAX_INFLIGHT_WRITES slot for the rest of the handler; sheds load with a 503
+// instead of queueing once every slot is taken
+fn admit_write(app: &AppState) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, Overloaded> {
+    match &app.writes {
+        None => Ok(None),
+        Some(sem) => sem.clone().try_acquire_owned().map(Some).map_err(|_| Overloaded),
+    }
+}
+
+// Uniform in [1 - RATE_LIMIT_JITTER, 1 + RATE_LIMIT_JITTER]
+fn jitter_factor() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let j = *RATE_LIMIT_JITTER;
+    if j == 0.0 {
+        return 1.0;
+    }
+    // RandomState is randomly keyed per instance: a cheap source without an rng dependency
+    let r = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let unit = (r >> 11) as f64 / (1u64 << 53) as f64;
+    1.0 - j + 2.0 * j * unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agentstate_storage::traits::GetOptions;
+    use agentstate_v1::agent_state_server::AgentState;
+    use axum::http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+    use tokio_stream::StreamExt as _;
+
+    // Signed with the one key every test state trusts
+    pub(crate) fn token(claims: serde_json::Value) -> String {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine};
+        let payload = serde_json::to_vec(&claims).unwrap();
+        let mut mac = <Hmac<Sha256>>::new_from_slice(b"sekret").unwrap();
+        mac.update(&payload);
+        let sig = mac.finalize().into_bytes();
+        format!("Bearer active.{}.{}", b64.encode(&payload), b64.encode(sig))
+    }
+
+    // Config goes in through the state rather than env, so parallel tests can't race on it
+    pub(crate) fn app_state(store: Arc<dyn Storage>) -> AppState {
+        AppState {
+            store,
+            qps: Arc::new(parking_lot::RwLock::new(Buckets::default())),
+            leader: None,
+            writes: None,
+            regions: Default::default(),
+            keys: Arc::new(CapKeys {
+                active: Some(("active".into(), "sekret".into())),
+                next: None,
+            }),
+            data_dir: None,
+            warmed: Default::default(),
+        }
+    }
+
+    fn grpc() -> AgentStateGrpc {
+        AgentStateGrpc {
+            state: app_state(Arc::new(InMemoryStore::new())),
+        }
+    }
+
+    fn authed<T>(msg: T, token: &str) -> Request<T> {
+        let mut req = Request::new(msg);
+        req.metadata_mut()
+            .insert("authorization", token.parse().unwrap());
+        req
+    }
+
+    fn headers(claims: serde_json::Value) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let bearer = token(claims).parse().unwrap();
+        headers.insert(axum::http::header::AUTHORIZATION, bearer);
+        headers
+    }
+
+    async fn json_body(resp: impl IntoResponse) -> serde_json::Value {
+        let body = resp.into_response().into_body();
+        serde_json::from_slice(&axum::body::to_bytes(body, usize::MAX).await.unwrap()).unwrap()
+    }
+
+    async fn post_query(
+        app: AppState,
+        headers: HeaderMap,
+        q: serde_json::Value,
+    ) -> axum::response::Response {
+        let uri: axum::http::Uri = "/v1/n/query".parse().unwrap();
+        let req = serde_json::from_value(q).unwrap();
+        let uri = axum::extract::OriginalUri(uri);
+        query(State(app), Path("n".into()), uri, headers, Json(req))
             .await
-            .map_err(|e| Status::not_found(e.to_string()))?;
-        Ok(TonicResponse::new(to_proto_object(o)))
+            .into_response()
+    }
+
+    fn doc(id: &str, ttl_seconds: Option<u64>) -> PutRequest {
+        PutRequest {
+            r#type: "doc".into(),
+            body: json!({}),
+            id: Some(id.into()),
+            ttl_seconds,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_responses_are_redacted_with_the_callers_claims() {
+        let svc = grpc();
+        let writer = token(json!({"ns": ["n"]}));
+        let reader = token(json!({"ns": ["n"], "redact_fields": ["secret"]}));
+        let put = agentstate_v1::PutRequest {
+            ns: "n".into(),
+            r#type: "note".into(),
+            body_json: r#"{"text":"hi","secret":"s3"}"#.into(),
+            id: "a".into(),
+            ..Default::default()
+        };
+        let stored = svc.put(authed(put, &writer)).await.unwrap().into_inner();
+        assert!(stored.body_json.contains("s3"));
+
+        let get = agentstate_v1::GetRequest {
+            ns: "n".into(),
+            id: "a".into(),
+            ..Default::default()
+        };
+        let got = svc.get(authed(get, &reader)).await.unwrap().into_inner();
+        let body: serde_json::Value = serde_json::from_str(&got.body_json).unwrap();
+        assert_eq!(body, json!({"text": "hi"}));
+
+        let watch = agentstate_v1::WatchRequest {
+            ns: "n".into(),
+            ..Default::default()
+        };
+        let mut stream = svc
+            .watch(authed(watch, &reader))
+            .await
+            .unwrap()
+            .into_inner();
+        let ev = stream.next().await.unwrap().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&ev.obj.unwrap().body_json).unwrap();
+        assert_eq!(body, json!({"text": "hi"}));
+    }
+
+    #[tokio::test]
+    async fn allowed_fields_limit_grpc_query_and_sse_watch_bodies() {
+        let svc = grpc();
+        let writer = token(json!({"ns": ["n"]}));
+        let reader = token(json!({"ns": ["n"], "allowed_fields": ["text"]}));
+        let put = agentstate_v1::PutRequest {
+            ns: "n".into(),
+            r#type: "note".into(),
+            body_json: r#"{"text":"hi","owner":"zed"}"#.into(),
+            id: "a".into(),
+            ..Default::default()
+        };
+        svc.put(authed(put, &writer)).await.unwrap();
+
+        let query = agentstate_v1::QueryRequest {
+            ns: "n".into(),
+            ..Default::default()
+        };
+        let res = svc
+            .query(authed(query, &reader))
+            .await
+            .unwrap()
+            .into_inner();
+        let body: serde_json::Value = serde_json::from_str(&res.objects[0].body_json).unwrap();
+        assert_eq!(body, json!({"text": "hi"}));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(axum::http::header::AUTHORIZATION, reader.parse().unwrap());
+        headers.insert("last-event-id", "0".parse().unwrap());
+        let resp = watch_sse(State(svc.state.clone()), Path("n".into()), None, headers)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut frames = resp.into_body().into_data_stream();
+        let chunk = frames.next().await.unwrap().unwrap();
+        let text = String::from_utf8_lossy(&chunk).to_string();
+        assert!(text.contains(r#""text":"hi""#), "{text}");
+        assert!(!text.contains("zed"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn ready_waits_for_the_startup_warm_up() {
+        let app = grpc().state;
+        let resp = ready(State(app.clone())).await.into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        app.warmed.store(true, std::sync::atomic::Ordering::SeqCst);
+        let resp = ready(State(app)).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    async fn serve_grpc(svc: AgentStateGrpc) -> tonic::transport::Channel {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = agentstate_v1::agent_state_server::AgentStateServer::new(svc);
+        tokio::spawn(GrpcServer::builder().add_service(server).serve(addr));
+        let endpoint = tonic::transport::Endpoint::from_shared(format!("http://{addr}")).unwrap();
+        for _ in 0..50 {
+            if let Ok(channel) = endpoint.connect().await {
+                return channel;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("grpc server didn't come up on {addr}");
+    }
+
+    // What the generated client would send, without generating one for the binary
+    async fn grpc_query(
+        channel: tonic::transport::Channel,
+        req: Request<agentstate_v1::QueryRequest>,
+    ) -> Result<agentstate_v1::QueryResponse, Status> {
+        let mut grpc = tonic::client::Grpc::new(channel);
+        grpc.ready().await.unwrap();
+        let path =
+            tonic::codegen::http::uri::PathAndQuery::from_static("/agentstate.v1.AgentState/Query");
+        let codec = tonic::codec::ProstCodec::default();
+        Ok(grpc.unary(req, path, codec).await?.into_inner())
+    }
+
+    #[tokio::test]
+    async fn grpc_vector_query_orders_like_http() {
+        let svc = grpc();
+        let app = svc.state.clone();
+        let bearer = token(json!({}));
+        for (id, emb) in [("a", [0.0, 1.0]), ("b", [1.0, 0.1]), ("c", [0.7, 0.7])] {
+            let req = PutRequest {
+                r#type: "doc".into(),
+                body: json!({"emb": emb}),
+                id: Some(id.into()),
+                ..Default::default()
+            };
+            app.store.put("n", req).await.unwrap();
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(axum::http::header::AUTHORIZATION, bearer.parse().unwrap());
+        let req: QueryRequest = serde_json::from_value(json!({
+            "vector": {"field": "emb", "top_k": 3, "embedding": [1.0, 0.0]}
+        }))
+        .unwrap();
+        let uri: axum::http::Uri = "/v1/n/query".parse().unwrap();
+        let resp = query(
+            State(app),
+            Path("n".into()),
+            axum::extract::OriginalUri(uri),
+            headers,
+            Json(req),
+        )
+        .await
+        .into_response();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let list: Vec<agentstate_core::Object> = serde_json::from_slice(&body).unwrap();
+        let http_ids: Vec<String> = list.into_iter().map(|o| o.id).collect();
+        assert_eq!(http_ids, ["b", "c", "a"]);
+
+        let channel = serve_grpc(svc).await;
+        let req = agentstate_v1::QueryRequest {
+            ns: "n".into(),
+            vector: Some(agentstate_v1::VectorQuery {
+                field: "emb".into(),
+                top_k: 3,
+                embedding: vec![1.0, 0.0],
+            }),
+            ..Default::default()
+        };
+        let res = grpc_query(channel.clone(), authed(req, &bearer))
+            .await
+            .unwrap();
+        let grpc_ids: Vec<String> = res.objects.into_iter().map(|o| o.id).collect();
+        assert_eq!(grpc_ids, http_ids);
+
+        let legacy = agentstate_v1::QueryRequest {
+            ns: "n".into(),
+            jsonpath: "b".into(),
+            ..Default::default()
+        };
+        let err = grpc_query(channel.clone(), authed(legacy, &bearer))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        // A tag filter that doesn't parse is refused, not dropped into an unfiltered scan
+        let malformed = agentstate_v1::QueryRequest {
+            ns: "n".into(),
+            tag_json: "{\"status\":".into(),
+            ..Default::default()
+        };
+        let err = grpc_query(channel, authed(malformed, &bearer))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(err.message().starts_with("tag_json"), "{}", err.message());
+    }
+
+    #[tokio::test]
+    async fn watch_pull_sends_only_what_was_credited() {
+        let svc = grpc();
+        let store = svc.state.store.clone();
+        let bearer = token(json!({}));
+        store.put("pull", doc("a", None)).await.unwrap();
+        store.delete("pull", "a").await.unwrap();
+        let deletes = WATCH_EVENTS_TOTAL.with_label_values(&["delete"]).get();
+
+        let channel = serve_grpc(svc).await;
+        let mut grpc = tonic::client::Grpc::new(channel);
+        grpc.ready().await.unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(agentstate_v1::WatchPullRequest {
+            ns: "pull".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let req = authed(tokio_stream::wrappers::UnboundedReceiverStream::new(rx), &bearer);
+        let path = tonic::codegen::http::uri::PathAndQuery::from_static(
+            "/agentstate.v1.AgentState/WatchPull",
+        );
+        let codec = tonic::codec::ProstCodec::<_, agentstate_v1::WatchEvent>::default();
+        let mut events = grpc.streaming(req, path, codec).await.unwrap().into_inner();
+
+        // Without credit the backlog stays on the server
+        let wait = std::time::Duration::from_millis(300);
+        assert!(tokio::time::timeout(wait, events.message()).await.is_err());
+        tx.send(agentstate_v1::WatchPullRequest {
+            credits: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut got = Vec::new();
+        for _ in 0..2 {
+            let ev = events.message().await.unwrap().unwrap();
+            got.push((ev.r#type, ev.id, ev.commit));
+        }
+        assert_eq!(got, [("put".into(), "a".into(), 1), ("delete".into(), "a".into(), 2)]);
+        assert!(WATCH_EVENTS_TOTAL.with_label_values(&["delete"]).get() > deletes);
+
+        // Spent again: a later put waits for the next grant
+        store.put("pull", doc("b", None)).await.unwrap();
+        assert!(tokio::time::timeout(wait, events.message()).await.is_err());
+        tx.send(agentstate_v1::WatchPullRequest {
+            credits: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let ev = events.message().await.unwrap().unwrap();
+        assert_eq!((ev.id.as_str(), ev.commit), ("b", 3));
+    }
+
+    #[tokio::test]
+    async fn openmetrics_output_is_typed_and_terminated() {
+        metrics::init();
+        WATCH_EVENTS_TOTAL.with_label_values(&["delete"]).inc();
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, "application/openmetrics-text".parse().unwrap());
+        let resp = metrics(headers).await.into_response();
+        let ct = resp.headers()[CONTENT_TYPE].to_str().unwrap().to_string();
+        assert!(ct.starts_with("application/openmetrics-text"), "{ct}");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.ends_with("# EOF\n"));
+        assert_eq!(text.matches("# EOF").count(), 1);
+        let typed: std::collections::HashSet<&str> = text
+            .lines()
+            .filter_map(|l| l.strip_prefix("# TYPE "))
+            .filter_map(|l| l.split(' ').next())
+            .collect();
+        assert!(typed.iter().any(|f| f.ends_with("watch_events")), "{typed:?}");
+        // Every sample belongs to a family announced by a `# TYPE` line
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = ["_total", "_bucket", "_count", "_sum"]
+                .iter()
+                .find_map(|s| name.strip_suffix(s).filter(|f| typed.contains(f)))
+                .unwrap_or(name);
+            assert!(typed.contains(family), "untyped sample: {line}");
+        }
+        assert!(text
+            .lines()
+            .any(|l| l.contains("watch_events_total{type=\"delete\"}")));
+    }
+
+    #[tokio::test]
+    async fn grpc_calls_without_a_token_are_rejected() {
+        token(json!({}));
+        let get = agentstate_v1::GetRequest {
+            ns: "n".into(),
+            id: "a".into(),
+            ..Default::default()
+        };
+        let err = grpc().get(Request::new(get)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_ops_log_a_structured_warning() {
+        let out = Captured::default();
+        let writer = out.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let slow = std::time::Instant::now() - std::time::Duration::from_millis(30);
+            warn_if_slow(10, "query", "n", slow, Some(2), Some(40));
+            warn_if_slow(10, "get", "n", std::time::Instant::now(), None, None);
+        });
+        let logged = String::from_utf8(out.0.lock().clone()).unwrap();
+        assert_eq!(logged.lines().count(), 1, "{logged}");
+        assert!(logged.contains("slow operation"), "{logged}");
+        for field in [
+            "ns=\"n\"",
+            "op=\"query\"",
+            "results=Some(2)",
+            "candidates=Some(40)",
+        ] {
+            assert!(logged.contains(field), "{field} missing from {logged}");
+        }
+    }
+
+    #[tokio::test]
+    async fn expiring_lists_the_window_soonest_first() {
+        let app = grpc().state;
+        for (id, ttl) in [
+            ("b", Some(50)),
+            ("none", None),
+            ("late", Some(500)),
+            ("a", Some(10)),
+        ] {
+            app.store.put("n", doc(id, ttl)).await.unwrap();
+        }
+        app.store.put("other", doc("x", Some(5))).await.unwrap();
+        let opts = ExpiringOpts { within_secs: 100 };
+        let resp = expiring(
+            State(app.clone()),
+            Path("n".into()),
+            Query(opts),
+            headers(json!({})),
+        )
+        .await;
+        let list = json_body(resp).await;
+        let ids: Vec<_> = list
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| &e["object"]["id"])
+            .collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert!(list[0]["expires_at"].as_str().unwrap() < list[1]["expires_at"].as_str().unwrap());
+        // Listing isn't reading: nothing counts as an access
+        assert!(app.store.access_stats("n", "a").is_none());
+    }
+
+    #[tokio::test]
+    async fn diff_names_the_changed_field() {
+        let app = grpc().state;
+        for status in ["open", "done"] {
+            let mut req = doc("a", None);
+            req.body = json!({"status": status, "owner": "kim"});
+            app.store.put("n", req).await.unwrap();
+        }
+        let path = Path(("n".to_string(), "a".to_string()));
+        let opts = Query(DiffOpts { from: 1, to: None });
+        let diff = json_body(diff_versions(State(app), path, opts, headers(json!({}))).await).await;
+        assert_eq!((&diff["from"], &diff["to"]), (&json!(1), &json!(2)));
+        let patch = json!([{"op": "replace", "path": "/status", "value": "done"}]);
+        assert_eq!(diff["patch"], patch);
+    }
+
+    #[tokio::test]
+    async fn ndjson_queries_stream_one_object_per_line() {
+        let app = grpc().state;
+        for id in ["a", "b", "c"] {
+            app.store.put("n", doc(id, None)).await.unwrap();
+        }
+        let mut h = headers(json!({}));
+        h.insert(ACCEPT, "application/x-ndjson".parse().unwrap());
+        let resp = post_query(app, h, json!({})).await;
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/x-ndjson");
+        // Each frame is one whole line, not a slice of one buffered body
+        let mut frames = resp.into_body().into_data_stream();
+        let mut ids = Vec::new();
+        while let Some(frame) = frames.next().await {
+            let frame = frame.unwrap();
+            assert_eq!(frame.iter().filter(|b| **b == b'\n').count(), 1);
+            assert!(frame.ends_with(b"\n"));
+            ids.push(serde_json::from_slice::<agentstate_core::Object>(&frame).unwrap().id);
+        }
+        assert_eq!(ids, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn ns_less_routes_use_the_default_namespace() {
+        use tower::ServiceExt;
+        let app = grpc().state;
+        let rewrite = |req| async move { with_default_ns("tenant", req) };
+        let routes = Router::new()
+            .fallback_service(router(app.clone()))
+            .layer(axum::middleware::map_request(rewrite));
+        let bearer = token(json!({}));
+        let call = |method: &str, uri: &str, body: serde_json::Value| {
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, &bearer)
+                .header(CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let put = call(
+            "POST",
+            "/v1/objects",
+            json!({"type": "doc", "id": "a", "body": {}}),
+        );
+        let resp = routes.clone().oneshot(put).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(app
+            .store
+            .get("tenant", "a", GetOptions { at_ts: None })
+            .await
+            .is_ok());
+
+        let resp = routes
+            .clone()
+            .oneshot(call("GET", "/v1/objects/a", json!(null)))
+            .await;
+        let got = json_body(resp.unwrap()).await;
+        assert_eq!((&got["ns"], &got["id"]), (&json!("tenant"), &json!("a")));
+        let resp = routes
+            .clone()
+            .oneshot(call("POST", "/v1/query", json!({})))
+            .await;
+        assert_eq!(json_body(resp.unwrap()).await.as_array().unwrap().len(), 1);
+        // An explicit namespace is left alone
+        let resp = routes
+            .oneshot(call("GET", "/v1/other/objects/a", json!(null)))
+            .await;
+        assert_eq!(resp.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn partial_on_timeout_returns_what_the_scan_had() {
+        let app = grpc().state;
+        for i in 0..3 {
+            app.store.put("n", doc(&format!("d{i}"), None)).await.unwrap();
+        }
+        // Spent before the scan starts: a timeout, unless partial results were asked for
+        let mut spent = headers(json!({}));
+        spent.insert("x-deadline", "0".parse().unwrap());
+        let resp = post_query(app.clone(), spent.clone(), json!({})).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        let resp = post_query(app.clone(), spent, json!({"partial_on_timeout": true})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-partial"], "true");
+        assert!(json_body(resp).await.as_array().unwrap().len() < 3);
+
+        // With time to finish, nothing is marked partial
+        let resp = post_query(app, headers(json!({})), json!({"partial_on_timeout": true})).await;
+        assert!(resp.headers().get("x-partial").is_none());
+        assert_eq!(json_body(resp).await.as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn rate_limit_bursts_follow_the_claim_under_the_server_ceiling() {
+        let app = grpc().state;
+        let passed = |claims: serde_json::Value, tries: usize| {
+            (0..tries).filter(|_| rate_limit(&app, &claims).is_ok()).count()
+        };
+        assert_eq!(passed(json!({"jti": "burst", "max_qps": 1, "max_burst": 5}), 8), 5);
+        // Without the claim the burst is twice max_qps
+        assert_eq!(passed(json!({"jti": "default", "max_qps": 3}), 8), 6);
+        assert_eq!(passed(json!({"jti": "unlimited"}), 8), 8);
+
+        let greedy = json!({"jti": "greedy", "max_qps": u64::MAX});
+        rate_limit(&app, &greedy).unwrap();
+        let buckets = app.qps.read();
+        let (_, _, burst) = buckets[&bucket_key("", &greedy)];
+        assert_eq!(burst, *MAX_QPS_CEILING * 2);
+
+        // Refill rates spread around max_qps rather than sharing one value
+        let j = *RATE_LIMIT_JITTER;
+        let factors: Vec<f64> = (0..64).map(|_| jitter_factor()).collect();
+        assert!(factors.iter().all(|f| (1.0 - j..=1.0 + j).contains(f)));
+        assert!(factors.iter().any(|f| *f != factors[0]));
+    }
+
+    #[tokio::test]
+    async fn writes_pinned_to_another_region_are_forwarded_there() {
+        type Seen = Arc<parking_lot::Mutex<Vec<(String, bool, serde_json::Value)>>>;
+        let seen = Seen::default();
+        let record = seen.clone();
+        let home = axum::Router::new().fallback(
+            move |uri: axum::http::Uri, headers: HeaderMap, Json(body): Json<serde_json::Value>| {
+                let record = record.clone();
+                async move {
+                    let forwarded = headers.contains_key(FORWARDED_HEADER);
+                    record.lock().push((uri.to_string(), forwarded, body));
+                    (StatusCode::CREATED, Json(json!({"served_by": "eu"})))
+                }
+            },
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, home).await });
+
+        let mut app = grpc().state;
+        app.regions = Arc::new(Regions {
+            local: Some("us".into()),
+            endpoints: [("eu".to_string(), url)].into(),
+        });
+        let put = |headers: HeaderMap, id: &str| {
+            put_objects(State(app.clone()), Path("n".into()), headers, Json(doc(id, None)))
+        };
+        let delete_a = || {
+            let ops = json!({"ops": [{"op": "delete", "id": "a"}]});
+            Json(serde_json::from_value::<TxnReq>(ops).unwrap())
+        };
+        let eu = headers(json!({"region": "eu"}));
+        let resp = put(eu.clone(), "a").await.into_response();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(json_body(resp).await, json!({"served_by": "eu"}));
+        let at = Path(("n".into(), "b".into()));
+        let q = Some(Query(PutAtOpts { if_absent: true }));
+        let resp = put_object_at(State(app.clone()), at, q, eu.clone(), Json(doc("b", None))).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = txn(State(app.clone()), Path("n".into()), eu.clone(), delete_a()).await;
+        assert_eq!(resp.into_response().status(), StatusCode::CREATED);
+
+        let seen = seen.lock().clone();
+        let paths: Vec<_> = seen.iter().map(|(p, f, _)| (p.as_str(), *f)).collect();
+        assert_eq!(
+            paths,
+            [
+                ("/v1/n/objects", true),
+                ("/v1/n/objects/b?if_absent=true", true),
+                ("/v1/n/txn", true)
+            ]
+        );
+        assert_eq!(seen[0].2["id"], "a");
+        assert_eq!(seen[2].2["ops"][0], json!({"op": "delete", "id": "a"}));
+        // Nothing was written here
+        assert!(app.store.query("n", QueryRequest::default()).await.unwrap().is_empty());
+
+        // No endpoint for the region, or a request already forwarded once: 451
+        let resp = put(headers(json!({"region": "ap"})), "c").await.into_response();
+        assert_eq!(resp.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        let mut again = eu.clone();
+        again.insert(FORWARDED_HEADER, "1".parse().unwrap());
+        let resp = txn(State(app.clone()), Path("n".into()), again, delete_a()).await;
+        assert_eq!(resp.into_response().status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        // Tokens for this region write locally
+        let resp = put(headers(json!({"region": "us"})), "d").await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_are_counted_per_namespace() {
+        let app = grpc().state;
+        for i in 0..5 {
+            app.store.put("loud", doc(&format!("d{i}"), None)).await.unwrap();
+        }
+        app.store.put("quiet", doc("a", None)).await.unwrap();
+        let drops = |ns: &str| {
+            metrics::WATCH_DROPS_TOTAL
+                .with_label_values(&["overflow", ns])
+                .get()
+        };
+        let (loud, quiet) = (drops("loud"), drops("quiet"));
+        let watch = |ns: &'static str| {
+            let mut headers = headers(json!({}));
+            headers.insert("last-event-id", "0".parse().unwrap());
+            let opts: WatchOpts = serde_json::from_value(json!({"max_events": "2"})).unwrap();
+            watch_sse(State(app.clone()), Path(ns.into()), Some(Query(opts)), headers)
+        };
+        // Five events of backlog into a two-event buffer: the stream ends on an overflow
+        let resp = watch("loud").await.into_response();
+        let mut frames = resp.into_body().into_data_stream();
+        let chunk = frames.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&chunk).contains("overflow"));
+        assert!(frames.next().await.is_none());
+        let resp = watch("quiet").await.into_response();
+        let mut frames = resp.into_body().into_data_stream();
+        let chunk = frames.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&chunk).contains(r#""type":"put""#));
+        assert_eq!((drops("loud"), drops("quiet")), (loud + 1.0, quiet));
+    }
+
+    #[tokio::test]
+    async fn history_pages_through_every_version_once() {
+        let app = grpc().state;
+        for n in 0..7 {
+            let mut req = doc("a", None);
+            req.body = json!({"n": n});
+            app.store.put("n", req).await.unwrap();
+        }
+        for (order, want) in [("asc", (0..7).collect::<Vec<_>>()), ("desc", (0..7).rev().collect())] {
+            let (mut seen, mut after) = (Vec::new(), None::<String>);
+            loop {
+                let mut q = format!("limit=3&order={order}");
+                if let Some(a) = &after {
+                    q.push_str(&format!("&after={a}"));
+                }
+                let opts = Query::try_from_uri(&format!("/h?{q}").parse().unwrap()).unwrap();
+                let resp = object_history(
+                    State(app.clone()),
+                    Path(("n".into(), "a".into())),
+                    opts,
+                    headers(json!({})),
+                )
+                .await
+                .into_response();
+                after = resp
+                    .headers()
+                    .get("x-next-cursor")
+                    .map(|v| v.to_str().unwrap().to_string());
+                let page = json_body(resp).await;
+                let page = page.as_array().unwrap();
+                assert!(page.len() <= 3);
+                seen.extend(page.iter().map(|o| o["body"]["n"].as_i64().unwrap()));
+                if after.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(seen, want, "{order}");
+        }
+    }
+
+    #[tokio::test]
+    async fn type_filters_are_served_from_the_type_index() {
+        let app = grpc().state;
+        for (id, ty) in [("a", "doc"), ("b", "note"), ("c", "doc")] {
+            let mut req = doc(id, None);
+            req.r#type = ty.into();
+            app.store.put("n", req).await.unwrap();
+        }
+        let explain = |query: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let req = json!({"ns": "n", "analyze": true, "query": query});
+                let req = serde_json::from_value(req).unwrap();
+                let resp = admin_explain_query(State(app), headers(json!({})), Json(req)).await;
+                json_body(resp).await
+            }
+        };
+        let got = explain(json!({"type": "doc"})).await;
+        assert_eq!(got["rows"], 2);
+        let stages: Vec<_> = got["stages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["stage"].as_str().unwrap().to_string())
+            .collect();
+        assert!(stages.contains(&"type_index".to_string()), "{stages:?}");
+        assert!(stages.contains(&"candidate_scan".to_string()), "{stages:?}");
+        assert!(!stages.contains(&"full_scan".to_string()), "{stages:?}");
+        // A type nothing has is answered by the index alone
+        let got = explain(json!({"type": "missing"})).await;
+        assert_eq!(got["rows"], 0);
+        let stages = got["stages"].as_array().unwrap();
+        assert_eq!(stages.len(), 1);
+        assert_eq!((&stages[0]["stage"], &stages[0]["rows"]), (&json!("type_index"), &json!(0)));
+    }
+
+    #[tokio::test]
+    async fn msgpack_responses_decode_to_the_json_ones() {
+        use tower::ServiceExt;
+        let app = grpc().state;
+        let mut req = doc("a", None);
+        req.body = json!({"text": "héllo", "list": [1.5, null, true]});
+        app.store.put("n", req).await.unwrap();
+        let routes = router(app);
+        let bearer = token(json!({}));
+        let call = |method: &str, uri: &str, accept: &str| {
+            let body = if method == "POST" { "{}" } else { "" };
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, &bearer)
+                .header(CONTENT_TYPE, "application/json")
+                .header(ACCEPT, accept)
+                .body(axum::body::Body::from(body))
+                .unwrap()
+        };
+        for (method, uri) in [("GET", "/v1/n/objects/a"), ("POST", "/v1/n/query")] {
+            let resp = routes.clone().oneshot(call(method, uri, "application/json")).await;
+            let want = json_body(resp.unwrap()).await;
+            let resp = routes
+                .clone()
+                .oneshot(call(method, uri, "application/msgpack"))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()[CONTENT_TYPE], "application/msgpack");
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            let got: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+            assert_eq!(got, want, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_json_summarises_after_some_operations() {
+        use tower::ServiceExt;
+        let app = grpc().state;
+        app.store.put("n", doc("a", None)).await.unwrap();
+        let get = axum::http::Request::builder()
+            .uri("/v1/n/objects/a")
+            .header(AUTHORIZATION, token(json!({})))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = router(app.clone()).oneshot(get).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let got = json_body(admin_metrics_json(State(app), headers(json!({}))).await).await;
+        for key in [
+            "ops_total",
+            "watch_clients",
+            "watch_backlog_events",
+            "watch_drops_total",
+            "wal",
+            "snapshots",
+            "storage_bytes",
+        ] {
+            assert!(got.get(key).is_some(), "{key} missing from {got}");
+        }
+        // The registry is process-wide, so other tests may have added to the count
+        assert!(got["ops_total"]["get"].as_u64().unwrap() >= 1, "{got}");
+        for key in ["active_segments", "records_total", "bytes_total", "fsync_total"] {
+            assert!(got["wal"][key].is_number(), "{key}");
+        }
     }
 
-    async fn query(
-        &self,
-        request: Request<agentstate_v1::QueryRequest>,
-    ) -> Result<TonicResponse<agentstate_v1::QueryResponse>, Status> {
-        let req = request.into_inner();
-        let tag_filter = if req.tag_json.is_empty() {
-            None
-        } else {
-            Some(agentstate_core::TagFilter(
-                serde_json::from_str(&req.tag_json).unwrap_or_default(),
-            ))
+    #[tokio::test]
+    async fn strong_reads_on_a_follower_are_served_by_the_leader() {
+        use tower::ServiceExt;
+        let put_where = |app: AppState, place: &'static str| async move {
+            let mut req = doc("a", None);
+            req.body = json!({"where": place});
+            app.store.put("n", req).await.unwrap();
         };
-        let qr = QueryRequest {
-            tag_filter,
-            jsonpath: if req.jsonpath.is_empty() {
-                None
-            } else {
-                Some(agentstate_core::JsonPathFilter {
-                    equals: std::collections::BTreeMap::from([("$".to_string(), serde_json::Value::String(req.jsonpath))]),
-                })
-            },
-            vector: None,
-            limit: None,
-            fields: None,
+        let leader = grpc().state;
+        put_where(leader.clone(), "leader").await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router(leader)).await });
+
+        let mut follower = grpc().state;
+        follower.leader = Some(url);
+        put_where(follower.clone(), "follower").await;
+        let routes = router(follower);
+        let bearer = token(json!({}));
+        let read = |method: &str, uri: &str, consistency: Option<&str>| {
+            let mut req = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, &bearer)
+                .header(CONTENT_TYPE, "application/json");
+            if let Some(c) = consistency {
+                req = req.header("consistency", c);
+            }
+            let body = if method == "POST" { "{}" } else { "" };
+            let req = req.body(axum::body::Body::from(body)).unwrap();
+            let routes = routes.clone();
+            async move {
+                let got = json_body(routes.oneshot(req).await.unwrap()).await;
+                let obj = if got.is_array() { got[0].clone() } else { got };
+                obj["body"]["where"].as_str().unwrap().to_string()
+            }
         };
-        let list = self
-            .state
-            .store
-            .query(&req.ns, qr)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
-        Ok(TonicResponse::new(agentstate_v1::QueryResponse {
-            objects: list.into_iter().map(to_proto_object).collect(),
-        }))
+        for (method, uri) in [("GET", "/v1/n/objects/a"), ("POST", "/v1/n/query")] {
+            assert_eq!(read(method, uri, None).await, "follower", "{uri}");
+            assert_eq!(read(method, uri, Some("eventual")).await, "follower", "{uri}");
+            assert_eq!(read(method, uri, Some("strong")).await, "leader", "{uri}");
+        }
+        let param = read("GET", "/v1/n/objects/a?consistency=strong", None).await;
+        assert_eq!(param, "leader");
+        // Without a leader there is nowhere stronger to go
+        let standalone = grpc().state;
+        put_where(standalone.clone(), "standalone").await;
+        let get = axum::http::Request::builder()
+            .uri("/v1/n/objects/a")
+            .header(AUTHORIZATION, &bearer)
+            .header("consistency", "strong")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let got = json_body(router(standalone).oneshot(get).await.unwrap()).await;
+        assert_eq!(got["body"]["where"], "standalone");
     }
 
-    async fn delete(
-        &self,
-        request: Request<agentstate_v1::DeleteRequest>,
-    ) -> Result<TonicResponse<agentstate_v1::Empty>, Status> {
-        let req = request.into_inner();
-        self.state
-            .store
-            .delete(&req.ns, &req.id)
-            .await
-            .map_err(|e| Status::not_found(e.to_string()))?;
-        Ok(TonicResponse::new(agentstate_v1::Empty {}))
+    #[tokio::test]
+    async fn admin_query_merges_namespaces_and_tags_each_result() {
+        let app = grpc().state;
+        for (ns, id, ty) in [("t1", "a", "error"), ("t1", "b", "info"), ("t2", "c", "error")] {
+            let mut req = doc(id, None);
+            req.r#type = ty.into();
+            app.store.put(ns, req).await.unwrap();
+        }
+        let run = |claims: serde_json::Value, req: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let req = serde_json::from_value(req).unwrap();
+                admin_query(State(app), headers(claims), Json(req))
+                    .await
+                    .into_response()
+            }
+        };
+        let errors = json!({"namespaces": ["t1", "t2"], "query": {"type": "error"}});
+        let got = json_body(run(json!({}), errors).await).await;
+        let tagged: Vec<_> = got["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| (o["ns"].as_str().unwrap(), o["id"].as_str().unwrap()))
+            .collect();
+        assert_eq!(tagged, [("t1", "a"), ("t2", "c")]);
+        assert_eq!(got["truncated"], false);
+
+        // The limit bounds the merged results, not each namespace's
+        let capped = json!({"namespaces": "*", "query": {"limit": 2}});
+        let got = json_body(run(json!({}), capped).await).await;
+        assert_eq!(got["results"].as_array().unwrap().len(), 2);
+        assert_eq!(got["truncated"], true);
+
+        let denied = run(json!({"verbs": ["get"]}), json!({"namespaces": "*"})).await;
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
     }
 
-    type WatchStream = WatchStream;
-    async fn watch(
-        &self,
-        request: Request<agentstate_v1::WatchRequest>,
-    ) -> Result<TonicResponse<Self::WatchStream>, Status> {
-        let req = request.into_inner();
-        let mut handle = self.state.store.subscribe(
-            agentstate_storage::traits::WatchFilter { ns: req.ns.clone() },
-            Some(req.from_commit),
-        );
-        if req.from_commit > 0 {
-            WATCH_RESUMES_TOTAL.with_label_values(&["grpc"]).inc();
+    #[tokio::test]
+    async fn delete_returns_the_seq_its_watch_event_carries() {
+        use agentstate_storage::traits::{WatchEvent, WatchFilter};
+        let app = grpc().state;
+        app.store.put("n", doc("a", None)).await.unwrap();
+        app.store.put("n", doc("b", None)).await.unwrap();
+        let filter = WatchFilter {
+            ns: "n".into(),
+            ..Default::default()
+        };
+        let mut watch = app.store.subscribe(filter, None, None);
+        let at = Path(("n".into(), "a".into()));
+        let resp = delete_object(State(app.clone()), at, None, headers(json!({}))).await;
+        let resp = resp.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let seq = json_body(resp).await["commit_seq"].as_u64().unwrap();
+        assert_eq!(seq, 3);
+        match watch.try_next() {
+            Some(WatchEvent::Delete { id, commit_seq, .. }) => {
+                assert_eq!((id.as_str(), commit_seq), ("a", seq))
+            }
+            other => panic!("expected the delete, got {other:?}"),
         }
-        WATCH_CLIENTS.with_label_values(&["grpc"]).inc();
-        let output = async_stream::try_stream! {
+    }
+
+    #[tokio::test]
+    async fn a_background_snapshot_can_be_polled_to_completion() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = grpc().state;
+        app.store = Arc::new(PersistentStore::open(dir.path().to_path_buf()).unwrap());
+        for id in ["a", "b", "c"] {
+            app.store.put("n", doc(id, None)).await.unwrap();
+        }
+        let background = Some(Query([("background".to_string(), "true".to_string())].into()));
+        let resp = admin_snapshot(State(app.clone()), background, headers(json!({}))).await;
+        let resp = resp.into_response();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let job_id = json_body(resp).await["job_id"].as_str().unwrap().to_string();
+
+        let poll = || admin_snapshot_job(State(app.clone()), Path(job_id.clone()), headers(json!({})));
+        let job = tokio::time::timeout(std::time::Duration::from_secs(10), async {
             loop {
-                if let Some((last, retry)) = handle.overflow_meta() {
-                    // terminate with RESOURCE_EXHAUSTED; include info in message (trailers API is limited here)
-                    metrics::WATCH_DROPS_TOTAL.with_label_values(&["overflow"]).inc();
-                    Err(Status::resource_exhausted(format!("overflow last_commit={} retry_after_ms={}", last, retry)))?;
-                } else if let Some(ev) = handle.try_next() {
-                    match ev {
-                        agentstate_storage::traits::WatchEvent::Put(o) => {
-                            WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
-                            let lag = (chrono::Utc::now() - o.ts).num_milliseconds() as f64 / 1000.0;
-                            metrics::WATCH_EMIT_LAG_SEC.observe(lag.max(0.0));
-                            yield agentstate_v1::WatchEvent { r#type: "put".into(), obj: Some(to_proto_object(o.clone())), id: o.id.clone(), commit: o.commit_seq };
-                        }
-                        agentstate_storage::traits::WatchEvent::Delete{ns:_, id, commit_seq} => {
-                            WATCH_EVENTS_TOTAL.with_label_values(&["put"]).inc();
-                            yield agentstate_v1::WatchEvent { r#type: "delete".into(), obj: None, id, commit: commit_seq };
-                        }
-                    }
-                } else {
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                let job = json_body(poll().await).await;
+                if job["status"] != "running" {
+                    return job;
                 }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
             }
-        };
-        Ok(TonicResponse::new(Box::pin(output) as WatchStream))
+        })
+        .await
+        .unwrap();
+        assert_eq!(job["status"], "done", "{job}");
+        assert_eq!(job["objects"], 3);
+        assert!(job["bytes"].as_i64().unwrap() > 0);
+        let snap = job["snapshot_id"].as_str().unwrap();
+        assert!(dir.path().join("snapshots").join(snap).exists());
+
+        let missing = admin_snapshot_job(State(app), Path("job-0".into()), headers(json!({})));
+        let missing = missing.await;
+        assert_eq!(missing.into_response().status(), StatusCode::NOT_FOUND);
     }
-}
 
-fn to_proto_object(o: agentstate_core::Object) -> agentstate_v1::Object {
-    agentstate_v1::Object {
-        id: o.id,
-        ns: o.ns,
-        r#type: o.r#type,
-        body_json: serde_json::to_string(&o.body).unwrap_or("null".into()),
-        tags: o.tags.0.into_iter().collect(),
-        ttl_seconds: o.ttl_seconds.unwrap_or_default() as u64,
-        parents: o.parents,
-        commit: o.commit,
-        ts_rfc3339: o.ts.to_rfc3339(),
+    #[tokio::test]
+    async fn history_is_in_commit_order_after_an_out_of_order_replay() {
+        use agentstate_storage::ObjectStore;
+        let source = InMemoryStore::new();
+        let mut versions = Vec::new();
+        for n in 0..3 {
+            let mut req = doc("a", None);
+            req.body = json!({"n": n});
+            versions.push(source.put("n", req).await.unwrap());
+        }
+        let store = Arc::new(InMemoryStore::new());
+        for i in [2, 0, 1] {
+            store.replay_put(versions[i].clone());
+        }
+        let mut app = grpc().state;
+        app.store = store;
+        for (order, want) in [("asc", [1, 2, 3]), ("desc", [3, 2, 1])] {
+            let opts = Query::try_from_uri(&format!("/h?order={order}").parse().unwrap()).unwrap();
+            let at = Path(("n".into(), "a".into()));
+            let resp = object_history(State(app.clone()), at, opts, headers(json!({}))).await;
+            let got = json_body(resp).await;
+            let seqs: Vec<_> = got
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|o| o["commit_seq"].as_u64().unwrap())
+                .collect();
+            assert_eq!(seqs, want, "{order}");
+        }
     }
-}
 
-// Capability token enforcement (simple HMAC signed JSON)
-fn enforce_caps(
-    headers: &HeaderMap,
-    ns: &str,
-    verb: &str,
-) -> Result<serde_json::Value, (StatusCode, Json<serde_json::Value>)> {
-    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine};
-    // Dual keys: CAP_KEY_ACTIVE, CAP_KEY_NEXT; token format: kid.payload.sig
-    let active = std::env::var("CAP_KEY_ACTIVE").ok();
-    let next = std::env::var("CAP_KEY_NEXT").ok();
-    if active.is_none() && next.is_none() {
-        return Ok(serde_json::json!({}));
+    #[tokio::test]
+    async fn introspection_returns_claims_or_the_reason_a_token_fails() {
+        let app = grpc().state;
+        let introspect = |claims| token_introspect(State(app.clone()), headers(claims));
+        let now = chrono::Utc::now().timestamp();
+        let valid = json!({"ns": ["n"], "verbs": ["get"], "exp": now + 600});
+        let got = json_body(introspect(valid).await).await;
+        assert_eq!(got["valid"], true);
+        assert_eq!(got["claims"]["ns"], json!(["n"]));
+        assert_eq!(got["claims"]["verbs"], json!(["get"]));
+        assert!(!got.to_string().contains("sekret"));
+
+        let expired = introspect(json!({"exp": now - 1})).await;
+        let expired = expired.into_response();
+        assert_eq!(expired.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(json_body(expired).await, json!({"valid": false, "error": "expired"}));
     }
-    let auth = match headers
-        .get(axum::http::header::AUTHORIZATION)
-        .and_then(|v| v.to_str().ok())
-    {
-        Some(s) if s.starts_with("Bearer ") => &s[7..],
-        _ => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({"error":"missing token"})),
-            ))
+
+    #[tokio::test]
+    async fn cascade_delete_removes_the_root_and_everything_below_it() {
+        let app = grpc().state;
+        let put = |id: &'static str, parents: Vec<String>| {
+            let app = app.clone();
+            async move {
+                let mut req = doc(id, None);
+                req.parents = parents;
+                app.store.put("n", req).await.unwrap().commit
+            }
+        };
+        let root = put("root", vec![]).await;
+        let c1 = put("c1", vec![root.clone()]).await;
+        put("c2", vec![root.clone()]).await;
+        let g = put("g", vec![c1]).await;
+        // The root's new version points back at its grandchild: a cycle
+        put("root", vec![g]).await;
+        put("other", vec![]).await;
+
+        // Over the limit nothing is deleted
+        let err = app.store.delete_cascade("n", "root", 3).await.unwrap_err();
+        assert!(matches!(&err, StateError::Invalid(m) if m.starts_with("cascade_too_large")));
+        assert!(app.store.get("n", "root", GetOptions { at_ts: None }).await.is_ok());
+
+        let at = Path(("n".into(), "root".into()));
+        let cascade = Some(Query(DeleteOpts { cascade: true }));
+        let resp = delete_object(State(app.clone()), at, cascade, headers(json!({}))).await;
+        let resp = resp.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let got = json_body(resp).await;
+        let mut deleted: Vec<_> = got["deleted"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        assert_eq!(deleted.remove(0), "root");
+        deleted.sort();
+        assert_eq!(deleted, ["c1", "c2", "g"]);
+        for id in ["root", "c1", "c2", "g"] {
+            let get = app.store.get("n", id, GetOptions { at_ts: None }).await;
+            assert!(get.is_err(), "{id}");
         }
-    };
-    let parts: Vec<&str> = auth.split('.').collect();
-    if parts.len() != 3 {
-        return Err((StatusCode::UNAUTHORIZED, Json(json!({"error":"bad token"}))));
-    }
-    let kid = parts[0];
-    let payload = b64
-        .decode(parts[1])
-        .map_err(|_| (StatusCode::UNAUTHORIZED, Json(json!({"error":"bad b64"}))))?;
-    let sig_bytes = b64
-        .decode(parts[2])
-        .map_err(|_| (StatusCode::UNAUTHORIZED, Json(json!({"error":"bad b64"}))))?;
-    let secret = match kid {
-        k if k == std::env::var("CAP_KEY_ACTIVE_ID").unwrap_or("active".into()) => active,
-        k if k == std::env::var("CAP_KEY_NEXT_ID").unwrap_or("next".into()) => next,
-        _ => None,
+        assert!(app.store.get("n", "other", GetOptions { at_ts: None }).await.is_ok());
     }
-    .ok_or((
-        StatusCode::UNAUTHORIZED,
-        Json(json!({"error":"unknown kid"})),
-    ))?;
-    let mut mac = <Hmac<Sha256>>::new_from_slice(secret.as_bytes()).unwrap();
-    mac.update(&payload);
-    let sig = mac.finalize().into_bytes();
-    if sig.as_slice() != sig_bytes.as_slice() {
-        return Err((StatusCode::UNAUTHORIZED, Json(json!({"error":"bad sig"}))));
+
+    #[tokio::test]
+    async fn descendants_follow_a_chain_down_to_the_requested_depth() {
+        let app = grpc().state;
+        let mut parent = Vec::new();
+        for id in ["a", "b", "c", "d"] {
+            let mut req = doc(id, None);
+            req.parents = parent;
+            parent = vec![app.store.put("n", req).await.unwrap().commit];
+        }
+        let below = |depth: usize| {
+            let app = app.clone();
+            async move {
+                let q = DescendantsOpts {
+                    depth: Some(depth),
+                    limit: None,
+                };
+                let at = Path(("n".into(), "a".into()));
+                let resp = object_descendants(State(app), at, Query(q), headers(json!({}))).await;
+                let got = json_body(resp).await;
+                assert_eq!(got["truncated"], false);
+                got["descendants"].clone()
+            }
+        };
+        assert_eq!(below(1).await, json!([{"id": "b", "depth": 1}]));
+        let two = json!([{"id": "b", "depth": 1}, {"id": "c", "depth": 2}]);
+        assert_eq!(below(2).await, two);
+        assert_eq!(below(10).await.as_array().unwrap().len(), 3);
+
+        // A deleted link ends the chain there
+        app.store.delete("n", "c").await.unwrap();
+        assert_eq!(below(10).await, json!([{"id": "b", "depth": 1}]));
+        // As does a child whose new version drops its parent
+        app.store.put("n", doc("b", None)).await.unwrap();
+        assert_eq!(below(10).await, json!([]));
     }
-    let mut claims: serde_json::Value = serde_json::from_slice(&payload).map_err(|_| {
-        (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({"error":"bad claims"})),
-        )
-    })?;
-    // inject kid for tracing/audit
-    if let serde_json::Value::Object(ref mut map) = claims {
-        map.insert("kid".into(), serde_json::Value::String(kid.to_string()));
+
+    #[tokio::test]
+    async fn an_unchanged_reassert_makes_no_version_and_no_event() {
+        use agentstate_storage::traits::{WatchEvent, WatchFilter};
+        let app = grpc().state;
+        let put = |tags: serde_json::Value| {
+            let mut req = doc("a", None);
+            req.body = json!({"state": "idle"});
+            req.tags = serde_json::from_value(tags).unwrap();
+            req.only_if_changed = true;
+            let app = app.clone();
+            async move {
+                let resp = put_objects(State(app), Path("n".into()), headers(json!({})), Json(req));
+                json_body(resp.await).await
+            }
+        };
+        let first = put(json!({"k": "v"})).await;
+        assert_eq!(first["changed"], true);
+        let filter = WatchFilter {
+            ns: "n".into(),
+            ..Default::default()
+        };
+        let mut watch = app.store.subscribe(filter, None, None);
+
+        let again = put(json!({"k": "v"})).await;
+        assert_eq!(again["changed"], false);
+        assert_eq!(again["commit_seq"], first["commit_seq"]);
+        assert_eq!(app.store.versions("n", "a").await.unwrap().len(), 1);
+        assert!(watch.try_next().is_none());
+
+        // Tags count as content too
+        let retagged = put(json!({"k": "w"})).await;
+        assert_eq!(retagged["changed"], true);
+        assert!(matches!(watch.try_next(), Some(WatchEvent::Put(o)) if o.tags.0["k"] == "w"));
     }
-    // ns check
-    if let Some(arr) = claims.get("ns").and_then(|v| v.as_array()) {
-        if !arr.iter().any(|v| v.as_str() == Some(ns)) {
-            return Err((StatusCode::FORBIDDEN, Json(json!({"error":"ns denied"}))));
+
+    #[tokio::test]
+    async fn a_consistent_dump_ignores_writes_made_while_it_streams() {
+        let app = grpc().state;
+        for id in ["a", "b", "c"] {
+            let mut req = doc(id, None);
+            req.body = json!({"v": 1});
+            app.store.put("n", req).await.unwrap();
         }
+        let admin = headers(json!({}));
+        let dump = |q: &str| {
+            let q = Query::try_from_uri(&format!("/admin/dump?{q}").parse().unwrap()).unwrap();
+            admin_dump(State(app.clone()), Some(q), admin.clone())
+        };
+        let lines = |resp: axum::response::Response| async move {
+            let raw = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            raw.split(|b| *b == b'\n')
+                .filter(|l| !l.is_empty())
+                .map(|l| serde_json::from_slice::<agentstate_core::Object>(l).unwrap())
+                .map(|o| (o.id, o.body["v"].as_i64().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        let resp = dump("consistent=true").await.into_response();
+        let watermark = resp.headers()["x-export-watermark"].to_str().unwrap().to_string();
+        // Rewrite every object and add one more before the body has been read
+        let writer = {
+            let store = app.store.clone();
+            tokio::spawn(async move {
+                for id in ["a", "b", "c", "d"] {
+                    let mut req = doc(id, None);
+                    req.body = json!({"v": 2});
+                    store.put("n", req).await.unwrap();
+                }
+            })
+        };
+        writer.await.unwrap();
+        let v1 = |ids: &[&str]| ids.iter().map(|id| (id.to_string(), 1)).collect::<Vec<_>>();
+        assert_eq!(lines(resp).await, v1(&["a", "b", "c"]));
+
+        // Resuming after "a" keeps the same point-in-time view
+        let resumed = dump(&format!("watermark={watermark}&after_ns=n&after_id=a")).await;
+        assert_eq!(lines(resumed.into_response()).await, v1(&["b", "c"]));
+        // A fresh export sees the new state
+        let fresh = dump("consistent=true").await;
+        let all: Vec<_> = lines(fresh.into_response()).await;
+        assert_eq!(all.len(), 4);
+        assert!(all.iter().all(|(_, v)| *v == 2));
     }
-    // verbs check
-    if let Some(arr) = claims.get("verbs").and_then(|v| v.as_array()) {
-        if !arr.iter().any(|v| v.as_str() == Some(verb)) {
-            return Err((StatusCode::FORBIDDEN, Json(json!({"error":"verb denied"}))));
+
+    #[test]
+    fn compressed_watch_events_round_trip_to_the_original_payload() {
+        use base64::{engine::general_purpose::STANDARD as b64, Engine};
+        let data = |event: &Bytes| {
+            let text = std::str::from_utf8(event).unwrap();
+            let line = text.lines().find_map(|l| l.strip_prefix("data: ")).unwrap();
+            serde_json::from_str::<serde_json::Value>(line).unwrap()
+        };
+        let payload = json!({"id": "a", "body": {"notes": "lorem ipsum ".repeat(100)}});
+
+        let event = sse_event(7, &payload, true);
+        assert!(std::str::from_utf8(&event).unwrap().starts_with("id: 7\n"));
+        let envelope = data(&event);
+        assert_eq!(envelope["encoding"], "zstd+base64");
+        let z = b64.decode(envelope["payload"].as_str().unwrap()).unwrap();
+        assert!(z.len() < payload.to_string().len());
+        let raw = zstd::stream::decode_all(&z[..]).unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&raw).unwrap(), payload);
+
+        // Small payloads and clients that didn't opt in get plain JSON
+        let small = json!({"id": "a"});
+        assert_eq!(data(&sse_event(8, &small, true)), small);
+        assert_eq!(data(&sse_event(9, &payload, false)), payload);
+    }
+
+    #[tokio::test]
+    async fn explain_analyze_counts_match_the_query_it_ran() {
+        let app = grpc().state;
+        for i in 0..12 {
+            let mut req = doc(&format!("d{i}"), None);
+            let team = if i % 3 == 0 { "red" } else { "blue" };
+            req.tags = serde_json::from_value(json!({"team": team})).unwrap();
+            req.body = json!({"n": i});
+            app.store.put("n", req).await.unwrap();
         }
+        let q = json!({"tag_filter": {"team": "red"}, "limit": 3});
+        let req = json!({"ns": "n", "analyze": true, "query": q});
+        let resp = admin_explain_query(
+            State(app.clone()),
+            headers(json!({})),
+            Json(serde_json::from_value(req).unwrap()),
+        );
+        let got = json_body(resp.await).await;
+        let rows = |stage: &str| {
+            let found = got["stages"].as_array().unwrap().iter().find(|s| s["stage"] == stage);
+            found.unwrap_or_else(|| panic!("no {stage} stage in {got}"))["rows"].clone()
+        };
+
+        let direct = app.store.query("n", serde_json::from_value(q).unwrap()).await.unwrap();
+        assert_eq!(got["rows"], direct.len());
+        assert_eq!(rows("limit"), direct.len());
+        // Before the limit, every red object was a candidate
+        let unlimited = json!({"tag_filter": {"team": "red"}});
+        let red = app.store.query("n", serde_json::from_value(unlimited).unwrap()).await.unwrap();
+        assert_eq!(red.len(), 4);
+        assert_eq!(rows("tag_index"), red.len());
+        // Plan-only stays the default and runs nothing
+        let req = json!({"ns": "n", "filter": {"tags": {"team": "red"}}});
+        let resp = admin_explain_query(
+            State(app),
+            headers(json!({})),
+            Json(serde_json::from_value(req).unwrap()),
+        );
+        let plan = json_body(resp.await).await;
+        assert!(plan.get("rows").is_none());
+        assert_eq!(plan["plan"][0]["index"], "tags");
     }
-    // ttl check
-    if let Some(exp) = claims.get("exp").and_then(|v| v.as_i64()) {
-        if exp
-            < (std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
+
+    #[tokio::test]
+    async fn opening_watches_too_fast_is_rate_limited_apart_from_ops() {
+        let svc = grpc();
+        let app = svc.state.clone();
+        let claims = json!({"jti": "churn", "max_watch_qps": 1, "max_watch_burst": 2, "max_qps": 1000});
+        let open = || {
+            let (app, headers) = (app.clone(), headers(claims.clone()));
+            async move {
+                watch_sse(State(app), Path("n".into()), None, headers)
+                    .await
+                    .into_response()
+            }
+        };
+        let first = open().await;
+        let second = open().await;
+        assert_eq!((first.status(), second.status()), (StatusCode::OK, StatusCode::OK));
+        let third = open().await;
+        assert_eq!(third.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(json_body(third).await["error"], "rate_limited");
+        // gRPC watches draw from the same bucket
+        let watch = agentstate_v1::WatchRequest {
+            ns: "n".into(),
+            ..Default::default()
+        };
+        let err = svc.watch(authed(watch, &token(claims.clone()))).await.err().unwrap();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        // Ordinary ops and other tokens are untouched
+        assert!(rate_limit(&app, &claims).is_ok());
+        let other = headers(json!({"jti": "calm", "max_watch_qps": 1}));
+        let resp = watch_sse(State(app.clone()), Path("n".into()), None, other).await;
+        assert_eq!(resp.into_response().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn jsonapi_documents_wrap_objects_and_map_parents() {
+        use tower::ServiceExt;
+        let app = grpc().state;
+        let root = app.store.put("n", doc("root", None)).await.unwrap();
+        let mut req = doc("a", None);
+        req.body = json!({"text": "hi"});
+        req.parents = vec![root.commit.clone()];
+        let a = app.store.put("n", req).await.unwrap();
+        let routes = router(app);
+        let bearer = token(json!({}));
+        let call = |method: &str, uri: &str, accept: &str| {
+            let body = if method == "POST" { r#"{"limit": 1}"# } else { "" };
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, &bearer)
+                .header(CONTENT_TYPE, "application/json")
+                .header(ACCEPT, accept)
+                .body(axum::body::Body::from(body))
                 .unwrap()
-                .as_secs() as i64)
-        {
-            return Err((StatusCode::UNAUTHORIZED, Json(json!({"error":"expired"}))));
+        };
+        let jsonapi = "application/vnd.api+json";
+
+        let resp = routes.clone().oneshot(call("GET", "/v1/n/objects/a", jsonapi)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], jsonapi);
+        let got = json_body(resp).await;
+        let data = &got["data"];
+        assert_eq!((&data["type"], &data["id"]), (&json!("doc"), &json!("a")));
+        assert_eq!(data["attributes"]["body"], json!({"text": "hi"}));
+        assert_eq!(data["attributes"]["commit_seq"], a.commit_seq);
+        assert!(data["attributes"].get("id").is_none());
+        assert_eq!(
+            data["relationships"]["parents"]["data"],
+            json!([{"type": "commits", "id": root.commit}])
+        );
+
+        // Queries come back as a collection of the same resources
+        let resp = routes.clone().oneshot(call("POST", "/v1/n/query", jsonapi)).await.unwrap();
+        let got = json_body(resp).await;
+        assert_eq!(got["data"].as_array().unwrap().len(), 1);
+        assert_eq!(got["data"][0]["id"], "root");
+        assert_eq!(got["data"][0]["relationships"]["parents"]["data"], json!([]));
+        // Errors use the JSON:API error shape, and the native shape stays the default
+        let resp = routes.clone().oneshot(call("GET", "/v1/n/objects/zzz", jsonapi)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(resp).await["errors"][0]["status"], "404");
+        let resp = routes.oneshot(call("GET", "/v1/n/objects/a", "application/json")).await;
+        let native = json_body(resp.unwrap()).await;
+        assert_eq!((&native["id"], &native["body"]), (&json!("a"), &json!({"text": "hi"})));
+    }
+
+    #[tokio::test]
+    async fn an_overflowed_watch_ends_with_a_resume_point_and_retry() {
+        let app = grpc().state;
+        for i in 0..5 {
+            app.store.put("n", doc(&format!("d{i}"), None)).await.unwrap();
         }
+        let watch = |from: u64, max_events: &str| {
+            let mut headers = headers(json!({}));
+            headers.insert("last-event-id", from.to_string().parse().unwrap());
+            let opts: WatchOpts = serde_json::from_value(json!({"max_events": max_events})).unwrap();
+            watch_sse(State(app.clone()), Path("n".into()), Some(Query(opts)), headers)
+        };
+        // A one-event buffer can't hold the backlog
+        let resp = watch(0, "1").await.into_response();
+        let mut frames = resp.into_body().into_data_stream();
+        let chunk = frames.next().await.unwrap().unwrap();
+        assert!(frames.next().await.is_none());
+        let text = String::from_utf8(chunk.to_vec()).unwrap();
+        let mut lines = text.lines();
+        let retry: u64 = lines.next().unwrap().strip_prefix("retry: ").unwrap().parse().unwrap();
+        let id: u64 = lines.next().unwrap().strip_prefix("id: ").unwrap().parse().unwrap();
+        let data = lines.next().unwrap().strip_prefix("data: ").unwrap();
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(data["error"], "overflow");
+        assert_eq!(data["retry_after_ms"], retry);
+        assert_eq!(data["last_commit"], id);
+        assert!(retry > 0);
+
+        // Reconnecting from last_commit, as a browser would, picks up the rest
+        let resp = watch(id, "100").await.into_response();
+        let mut frames = resp.into_body().into_data_stream();
+        let chunk = frames.next().await.unwrap().unwrap();
+        let text = String::from_utf8_lossy(&chunk);
+        assert!(text.starts_with(&format!("id: {}\n", id + 1)), "{text}");
     }
-    // size limit for PUT
-    if verb == "put" {
-        if let Some(max) = claims.get("max_bytes").and_then(|v| v.as_u64()) {
-            /* enforced in handler if needed */
-            let _ = max;
+
+    #[tokio::test]
+    async fn writes_past_the_inflight_limit_get_503_with_retry_after() {
+        use tower::ServiceExt;
+        let mut app = grpc().state;
+        app.store.put("n", doc("a", None)).await.unwrap();
+        let slots = Arc::new(tokio::sync::Semaphore::new(2));
+        app.writes = Some(slots.clone());
+        let _held = slots.acquire_many_owned(2).await.unwrap();
+        let bearer = token(json!({}));
+        let call = |method: &str, uri: &str, body: serde_json::Value| {
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, &bearer)
+                .header(CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+        let writes = [
+            call("POST", "/v1/n/objects", json!({"type": "doc", "body": {}})),
+            call("PUT", "/v1/n/objects/b", json!({"type": "doc", "body": {}})),
+            call("DELETE", "/v1/n/objects/a", json!(null)),
+            call("POST", "/v1/n/objects/a:rename", json!({"new_id": "c"})),
+            call("POST", "/v1/n/txn", json!({"ops": [{"op": "delete", "id": "a"}]})),
+        ];
+        for req in writes {
+            let uri = req.uri().clone();
+            let resp = router(app.clone()).oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{uri}");
+            assert_eq!(resp.headers()[axum::http::header::RETRY_AFTER], "1");
+            assert_eq!(json_body(resp).await["error"], "too_many_inflight_writes");
         }
+        // gRPC writes share the same slots
+        let svc = AgentStateGrpc { state: app.clone() };
+        let bearer = token(json!({}));
+        let put = agentstate_v1::PutRequest {
+            ns: "n".into(),
+            r#type: "doc".into(),
+            body_json: "{}".into(),
+            ..Default::default()
+        };
+        let err = svc.put(authed(put, &bearer)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        let del = agentstate_v1::DeleteRequest { ns: "n".into(), id: "a".into() };
+        let err = svc.delete(authed(del, &bearer)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        // Nothing got through, and reads are never shed
+        let resp = router(app).oneshot(call("GET", "/v1/n/objects/a", json!(null))).await;
+        assert_eq!(resp.unwrap().status(), StatusCode::OK);
     }
-    Ok(claims)
-}
 
-fn rate_limit(
-    state: &AppState,
-    claims: &serde_json::Value,
-) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
-    let max_qps = claims
-        .get("max_qps")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(u64::MAX);
-    if max_qps == u64::MAX {
-        return Ok(());
+    #[tokio::test]
+    async fn a_repeated_if_absent_put_hands_back_the_first_object() {
+        let app = grpc().state;
+        let put = |status: &str| {
+            let mut req = doc("a", None);
+            req.body = json!({"status": status});
+            let at = Path(("n".into(), "a".into()));
+            let q = Some(Query(PutAtOpts { if_absent: true }));
+            put_object_at(State(app.clone()), at, q, headers(json!({})), Json(req))
+        };
+        let resp = put("open").await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let first = json_body(resp).await;
+        let resp = put("done").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(json_body(resp).await, first);
+        let stored = app.store.get("n", "a", GetOptions { at_ts: None }).await.unwrap();
+        assert_eq!(stored.body, json!({"status": "open"}));
+        assert_eq!(json!(stored.commit), first["commit"]);
     }
-    let kid = claims
-        .get("kid")
-        .and_then(|v| v.as_str())
-        .unwrap_or("active");
-    let jti = claims.get("jti").and_then(|v| v.as_str()).unwrap_or("");
-    let key = format!("{}:{}", kid, jti);
-    let mut map = state.qps.write();
-    let now = std::time::Instant::now();
-    let (refill_per_s, burst) = (max_qps as f64, (max_qps * 2) as u64);
-    let entry = map.entry(key).or_insert((burst as f64, now, burst));
-    let elapsed = now.duration_since(entry.1).as_secs_f64();
-    entry.0 = (entry.0 + elapsed * refill_per_s).min(burst as f64);
-    entry.1 = now;
-    if entry.0 >= 1.0 {
-        entry.0 -= 1.0;
-        Ok(())
-    } else {
-        Err((
-            StatusCode::TOO_MANY_REQUESTS,
-            Json(json!({"error":"rate_limited"})),
-        ))
+
+    #[tokio::test]
+    async fn text_objects_are_served_as_their_own_bytes() {
+        use tower::ServiceExt;
+        let app = grpc().state;
+        let text = "héllo\n  world\n";
+        let ct = "text/plain; charset=utf-8";
+        let put = json!({"type": "note", "id": "a", "content_type": ct, "body": text});
+        let bearer = token(json!({}));
+        let call = |method: &str, uri: &str, body: String| {
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, &bearer)
+                .header(CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap()
+        };
+        let resp = router(app.clone()).oneshot(call("POST", "/v1/n/objects", put.to_string())).await;
+        assert_eq!(resp.unwrap().status(), StatusCode::OK);
+        let get = call("GET", "/v1/n/objects/a", String::new());
+        let resp = router(app.clone()).oneshot(get).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], ct);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], text.as_bytes());
+        // Asking for JSON still gets the object
+        let mut get = call("GET", "/v1/n/objects/a", String::new());
+        get.headers_mut().insert(ACCEPT, "application/json".parse().unwrap());
+        let got = json_body(router(app).oneshot(get).await.unwrap()).await;
+        assert_eq!(got["body"], text);
+    }
+
+    #[tokio::test]
+    async fn http_get_and_query_redact_body_fields_by_claim() {
+        use tower::ServiceExt;
+        let app = grpc().state;
+        let mut req = doc("a", None);
+        req.body = json!({"name": "kim", "ssn": "123-45-6789"});
+        app.store.put("n", req).await.unwrap();
+        let call = |claims: serde_json::Value, method: &str, uri: &str, body: &str| {
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, token(claims))
+                .header(CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+        let bodies = |claims: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let get = call(claims.clone(), "GET", "/v1/n/objects/a", "");
+                let got = json_body(router(app.clone()).oneshot(get).await.unwrap()).await;
+                let query = call(claims, "POST", "/v1/n/query", "{}");
+                let found = json_body(router(app).oneshot(query).await.unwrap()).await;
+                (got["body"].clone(), found[0]["body"].clone())
+            }
+        };
+        let redacted = json!({"name": "kim"});
+        let (got, found) = bodies(json!({"redact_fields": ["body.ssn"]})).await;
+        assert_eq!((&got, &found), (&redacted, &redacted));
+        let full = json!({"name": "kim", "ssn": "123-45-6789"});
+        let (got, found) = bodies(json!({})).await;
+        assert_eq!((&got, &found), (&full, &full));
     }
 }
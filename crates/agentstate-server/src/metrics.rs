@@ -1,69 +1,439 @@
 use once_cell::sync::Lazy;
+use opentelemetry::trace::TraceContextExt;
+use prometheus::proto::{MetricFamily, MetricType};
+use agentstate_storage::metrics::{unprefixed, REGISTRY};
 use prometheus::{
-    register_counter_vec, register_gauge, register_gauge_vec, register_histogram,
-    register_histogram_vec, CounterVec, Gauge, GaugeVec, Histogram, HistogramVec,
+    register_counter_vec_with_registry, register_gauge_vec_with_registry,
+    register_gauge_with_registry, register_histogram_vec_with_registry,
+    register_histogram_with_registry, register_int_counter_vec_with_registry, CounterVec, Gauge,
+    GaugeVec, Histogram, HistogramVec, IntCounterVec,
 };
+use std::collections::HashMap;
+use std::fmt::Write;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-pub static WAL_ACTIVE_SEGMENTS: Lazy<Gauge> =
-    Lazy::new(|| register_gauge!("wal_active_segments", "Current WAL segments").unwrap());
+pub static WAL_ACTIVE_SEGMENTS: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge_with_registry!("wal_active_segments", "Current WAL segments", REGISTRY).unwrap()
+});
 
 pub static STORAGE_BYTES_TOTAL: Lazy<GaugeVec> = Lazy::new(|| {
-    register_gauge_vec!("storage_bytes_total", "Bytes by storage kind", &["kind"]).unwrap()
+    register_gauge_vec_with_registry!(
+        "storage_bytes_total",
+        "Bytes by storage kind",
+        &["kind"],
+        REGISTRY
+    )
+    .unwrap()
 });
 
 pub static WATCH_DROPS_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
-    register_counter_vec!("watch_drops_total", "Watch drops by reason", &["reason"]).unwrap()
+    register_counter_vec_with_registry!(
+        "watch_drops_total",
+        "Watch drops by reason and namespace",
+        &["reason", "ns"],
+        REGISTRY
+    )
+    .unwrap()
 });
 
 pub static WATCH_BACKLOG_EVENTS: Lazy<GaugeVec> = Lazy::new(|| {
-    register_gauge_vec!(
+    register_gauge_vec_with_registry!(
         "watch_backlog_events",
         "Max backlog (events) per namespace",
-        &["ns"]
+        &["ns"],
+        REGISTRY
     )
     .unwrap()
 });
 
 pub static WATCH_EMIT_LAG_SEC: Lazy<Histogram> = Lazy::new(|| {
-    register_histogram!(
+    register_histogram_with_registry!(
         "watch_emit_lag_seconds",
         "now - commit_ts for emitted events",
-        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0],
+        REGISTRY
     )
     .unwrap()
 });
 
 pub static SNAPSHOT_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
-    register_counter_vec!("snapshot_total", "Snapshots by result", &["result"]).unwrap()
+    register_counter_vec_with_registry!(
+        "snapshot_total",
+        "Snapshots by result",
+        &["result"],
+        REGISTRY
+    )
+    .unwrap()
 });
 
-pub static SNAPSHOT_DURATION_SEC: Lazy<Histogram> =
-    Lazy::new(|| register_histogram!("snapshot_duration_seconds", "Snapshot duration").unwrap());
-
-pub static RESTORE_RUNS_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
-    register_counter_vec!("restore_runs_total", "Restore runs by status", &["status"]).unwrap()
+pub static SNAPSHOT_DURATION_SEC: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram_with_registry!("snapshot_duration_seconds", "Snapshot duration", REGISTRY)
+        .unwrap()
 });
 
-pub static VECTOR_QUERY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
-    register_histogram_vec!("vector_query_seconds", "ANN latency", &["field"]).unwrap()
+pub static RESTORE_RUNS_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec_with_registry!(
+        "restore_runs_total",
+        "Restore runs by status",
+        &["status"],
+        REGISTRY
+    )
+    .unwrap()
 });
 
 pub static QUERY_PLANNER_MICROS: Lazy<Histogram> = Lazy::new(|| {
-    register_histogram!(
+    register_histogram_with_registry!(
         "query_planner_micros",
         "Explain query plan time (µs)",
-        vec![50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0]
+        vec![50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0],
+        REGISTRY
     )
     .unwrap()
 });
 
-pub static WATCH_CLIENTS: Lazy<GaugeVec> =
-    Lazy::new(|| register_gauge_vec!("watch_clients", "Active watch clients", &["proto"]).unwrap());
+pub static WEBHOOK_FAILURES_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec_with_registry!(
+        "webhook_failures_total",
+        "Expiry webhook notifications lost, by reason",
+        &["reason"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+pub static WATCH_CLIENTS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec_with_registry!(
+        "watch_clients",
+        "Active watch clients",
+        &["proto"],
+        REGISTRY
+    )
+    .unwrap()
+});
 
 pub static WATCH_EVENTS_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
-    register_counter_vec!("watch_events_total", "Watch events emitted", &["type"]).unwrap()
+    register_counter_vec_with_registry!(
+        "watch_events_total",
+        "Watch events emitted",
+        &["type"],
+        REGISTRY
+    )
+    .unwrap()
 });
 
 pub static WATCH_RESUMES_TOTAL: Lazy<CounterVec> = Lazy::new(|| {
-    register_counter_vec!("watch_resumes_total", "Watch resumes", &["proto"]).unwrap()
+    register_counter_vec_with_registry!(
+        "watch_resumes_total",
+        "Watch resumes",
+        &["proto"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+pub static OPS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "ops_total",
+        "Successful puts, gets and queries",
+        &["op"],
+        REGISTRY
+    )
+    .unwrap()
 });
+
+pub static INDEX_INCONSISTENCY_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "index_inconsistency_total",
+        "Index entries found pointing at objects without the indexed tag or value",
+        &["index"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+pub static OP_DURATION_SEC: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "op_duration_seconds",
+        "Request latency by op",
+        &["op"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+/// Registers the server's metrics up front, so every family is scraped from the first
+/// request instead of appearing on first use.
+pub fn init() {
+    Lazy::force(&WAL_ACTIVE_SEGMENTS);
+    Lazy::force(&STORAGE_BYTES_TOTAL);
+    Lazy::force(&WATCH_DROPS_TOTAL);
+    Lazy::force(&WATCH_BACKLOG_EVENTS);
+    Lazy::force(&WATCH_EMIT_LAG_SEC);
+    Lazy::force(&SNAPSHOT_TOTAL);
+    Lazy::force(&SNAPSHOT_DURATION_SEC);
+    Lazy::force(&RESTORE_RUNS_TOTAL);
+    Lazy::force(&QUERY_PLANNER_MICROS);
+    Lazy::force(&WEBHOOK_FAILURES_TOTAL);
+    Lazy::force(&WATCH_CLIENTS);
+    Lazy::force(&WATCH_EVENTS_TOTAL);
+    Lazy::force(&WATCH_RESUMES_TOTAL);
+    Lazy::force(&OPS_TOTAL);
+    Lazy::force(&INDEX_INCONSISTENCY_TOTAL);
+    Lazy::force(&OP_DURATION_SEC);
+}
+
+// Latest exemplar per (op, bucket index) of op_duration_seconds, exposed via OpenMetrics
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    ts: f64,
+}
+
+static OP_EXEMPLARS: Lazy<parking_lot::RwLock<HashMap<(String, usize), Exemplar>>> =
+    Lazy::new(Default::default);
+
+/// Observes `op_duration_seconds` on drop, tagging the bucket with the current trace id.
+pub struct OpTimer {
+    op: &'static str,
+    start: std::time::Instant,
+}
+
+impl OpTimer {
+    pub fn new(op: &'static str) -> Self {
+        Self {
+            op,
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let secs = self.start.elapsed().as_secs_f64();
+        OP_DURATION_SEC.with_label_values(&[self.op]).observe(secs);
+        let cx = tracing::Span::current().context();
+        let sc = cx.span().span_context().clone();
+        if !sc.is_valid() {
+            return;
+        }
+        let bucket = prometheus::DEFAULT_BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(prometheus::DEFAULT_BUCKETS.len());
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        OP_EXEMPLARS.write().insert(
+            (self.op.to_string(), bucket),
+            Exemplar {
+                trace_id: sc.trace_id().to_string(),
+                value: secs,
+                ts,
+            },
+        );
+    }
+}
+
+/// Renders gathered families in the OpenMetrics 1.0 text format.
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let exemplars = OP_EXEMPLARS.read();
+    let mut out = String::new();
+    for mf in families {
+        let name = mf.get_name();
+        let (family, kind) = match mf.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family, kind);
+        let _ = writeln!(out, "# HELP {} {}", family, escape(mf.get_help(), false));
+        for m in mf.get_metric() {
+            let labels: Vec<(String, String)> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect();
+            match mf.get_field_type() {
+                MetricType::COUNTER => sample(
+                    &mut out,
+                    &format!("{}_total", family),
+                    &labels,
+                    m.get_counter().get_value(),
+                ),
+                MetricType::GAUGE => sample(&mut out, family, &labels, m.get_gauge().get_value()),
+                MetricType::UNTYPED => {
+                    sample(&mut out, family, &labels, m.get_untyped().get_value())
+                }
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    let op = labels
+                        .iter()
+                        .find(|(k, _)| k == "op")
+                        .map(|(_, v)| v.as_str());
+                    let bucket_name = format!("{}_bucket", family);
+                    let bounds = h
+                        .get_bucket()
+                        .iter()
+                        .map(|b| (b.get_upper_bound(), b.get_cumulative_count()))
+                        .chain(std::iter::once((f64::INFINITY, h.get_sample_count())));
+                    for (i, (le, count)) in bounds.enumerate() {
+                        let mut l = labels.clone();
+                        l.push(("le".into(), fmt_f64(le)));
+                        sample(&mut out, &bucket_name, &l, count as f64);
+                        let ex = op
+                            .filter(|_| unprefixed(family) == "op_duration_seconds")
+                            .and_then(|op| exemplars.get(&(op.to_string(), i)));
+                        if let Some(ex) = ex {
+                            // Attach to the sample line just written
+                            out.pop();
+                            let _ = writeln!(
+                                out,
+                                " # {{trace_id=\"{}\"}} {} {}",
+                                ex.trace_id,
+                                fmt_f64(ex.value),
+                                ex.ts
+                            );
+                        }
+                    }
+                    sample(
+                        &mut out,
+                        &format!("{}_count", family),
+                        &labels,
+                        h.get_sample_count() as f64,
+                    );
+                    sample(
+                        &mut out,
+                        &format!("{}_sum", family),
+                        &labels,
+                        h.get_sample_sum(),
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        let mut l = labels.clone();
+                        l.push(("quantile".into(), fmt_f64(q.get_quantile())));
+                        sample(&mut out, family, &l, q.get_value());
+                    }
+                    sample(
+                        &mut out,
+                        &format!("{}_count", family),
+                        &labels,
+                        s.get_sample_count() as f64,
+                    );
+                    sample(
+                        &mut out,
+                        &format!("{}_sum", family),
+                        &labels,
+                        s.get_sample_sum(),
+                    );
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// A curated JSON view of the gathered registry, for consumers that don't parse
+/// exposition format. Missing families (never touched yet) come back as 0 or {}.
+pub fn summary_json(families: &[MetricFamily]) -> serde_json::Value {
+    serde_json::json!({
+        // Every handled request is timed, so the histogram count is the op total
+        "ops_total": by_label(families, "op_duration_seconds", "op"),
+        "watch_clients": by_label(families, "watch_clients", "proto"),
+        "watch_backlog_events": by_label(families, "watch_backlog_events", "ns"),
+        "watch_drops_total": by_label(families, "watch_drops_total", "ns"),
+        "wal": {
+            "active_segments": total(families, "wal_active_segments"),
+            "records_total": total(families, "wal_records_total"),
+            "bytes_total": total(families, "wal_bytes_total"),
+            "fsync_total": total(families, "wal_fsync_total"),
+            "write_errors_total": total(families, "wal_write_errors_total"),
+        },
+        "snapshots": by_label(families, "snapshot_total", "result"),
+        "storage_bytes": by_label(families, "storage_bytes_total", "kind"),
+    })
+}
+
+// Counter/gauge value, or the sample count of a histogram/summary
+fn value_of(mf: &MetricFamily, m: &prometheus::proto::Metric) -> f64 {
+    match mf.get_field_type() {
+        MetricType::COUNTER => m.get_counter().get_value(),
+        MetricType::GAUGE => m.get_gauge().get_value(),
+        MetricType::UNTYPED => m.get_untyped().get_value(),
+        MetricType::HISTOGRAM => m.get_histogram().get_sample_count() as f64,
+        MetricType::SUMMARY => m.get_summary().get_sample_count() as f64,
+    }
+}
+
+// Sums a family's series grouped by one label's value
+fn by_label(families: &[MetricFamily], name: &str, label: &str) -> serde_json::Value {
+    let mut sums: std::collections::BTreeMap<String, f64> = Default::default();
+    for mf in families.iter().filter(|mf| unprefixed(mf.get_name()) == name) {
+        for m in mf.get_metric() {
+            let key = m
+                .get_label()
+                .iter()
+                .find(|l| l.get_name() == label)
+                .map(|l| l.get_value().to_string())
+                .unwrap_or_default();
+            *sums.entry(key).or_default() += value_of(mf, m);
+        }
+    }
+    sums.into_iter().map(|(k, v)| (k, json_num(v))).collect()
+}
+
+fn total(families: &[MetricFamily], name: &str) -> serde_json::Value {
+    let sum = families
+        .iter()
+        .filter(|mf| unprefixed(mf.get_name()) == name)
+        .flat_map(|mf| mf.get_metric().iter().map(move |m| value_of(mf, m)))
+        .sum();
+    json_num(sum)
+}
+
+// Whole values as integers, so counts don't read as 3.0
+fn json_num(v: f64) -> serde_json::Value {
+    if v.fract() == 0.0 && (0.0..9_007_199_254_740_992.0).contains(&v) {
+        serde_json::Value::from(v as u64)
+    } else {
+        serde_json::Value::from(v)
+    }
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(String, String)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let parts: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v, true)))
+            .collect();
+        let _ = write!(out, "{{{}}}", parts.join(","));
+    }
+    let _ = writeln!(out, " {}", fmt_f64(value));
+}
+
+fn fmt_f64(v: f64) -> String {
+    if v == f64::INFINITY {
+        "+Inf".into()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".into()
+    } else if v.is_nan() {
+        "NaN".into()
+    } else {
+        v.to_string()
+    }
+}
+
+fn escape(s: &str, quotes: bool) -> String {
+    let s = s.replace('\\', "\\\\").replace('\n', "\\n");
+    if quotes {
+        s.replace('"', "\\\"")
+    } else {
+        s
+    }
+}
@@ -0,0 +1,222 @@
+// TLS_MIN_VERSION: oldest TLS version either listener accepts, 1.2 (default) or 1.3.
+// With 1.3 the HTTP and gRPC listeners get their own rustls configs offering only TLS 1.3,
+// so TLS 1.2 clients fail the handshake. Any other value stops startup.
+use axum_server::tls_rustls::RustlsConfig;
+use futures::Stream;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinVersion {
+    Tls12,
+    Tls13,
+}
+
+pub fn min_version() -> Result<MinVersion, String> {
+    match std::env::var("TLS_MIN_VERSION").ok().as_deref() {
+        None | Some("") | Some("1.2") => Ok(MinVersion::Tls12),
+        Some("1.3") => Ok(MinVersion::Tls13),
+        Some(other) => Err(format!("TLS_MIN_VERSION must be 1.2 or 1.3, got {:?}", other)),
+    }
+}
+
+fn io_other(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+fn pem_certs(pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut &pem[..]).collect()
+}
+
+fn pem_key(pem: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut &pem[..])?.ok_or_else(|| io_other("no private key found"))
+}
+
+/// HTTP listener config; axum-server's defaults unless TLS 1.3 is required.
+pub async fn http_config(
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    min: MinVersion,
+) -> io::Result<RustlsConfig> {
+    if min == MinVersion::Tls12 {
+        return RustlsConfig::from_pem(cert, key).await;
+    }
+    // axum-server 0.6 is built on rustls 0.21, which has its own certificate types
+    let certs = pem_certs(&cert)?
+        .into_iter()
+        .map(|c| rustls::Certificate(c.to_vec()))
+        .collect();
+    let key = rustls::PrivateKey(pem_key(&key)?.secret_der().to_vec());
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io_other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io_other)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// TLS 1.3-only acceptor for the gRPC listener, requiring client certificates signed by
+/// `client_ca` when given, as tonic's own config does with TLS_CLIENT_CA_PATH.
+pub fn grpc_acceptor(
+    cert: &[u8],
+    key: &[u8],
+    client_ca: Option<&[u8]>,
+) -> io::Result<TlsAcceptor> {
+    // tonic 0.11 and tokio-rustls 0.25 are on rustls 0.22
+    use tokio_rustls::rustls;
+    let builder =
+        rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13]);
+    let builder = match client_ca {
+        Some(ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for c in pem_certs(ca)? {
+                roots.add(c).map_err(io_other)?;
+            }
+            let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(io_other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(pem_certs(cert)?, pem_key(key)?)
+        .map_err(io_other)?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepted gRPC connections with their handshakes done. Each handshake runs on its own
+/// task, so a slow or refused client never holds up the accept loop.
+pub async fn grpc_incoming(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+) -> io::Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let Ok((tcp, peer)) = listener.accept().await else {
+                continue;
+            };
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                match acceptor.accept(tcp).await {
+                    Ok(tls) => {
+                        let _ = tx.send(tls).await;
+                    }
+                    Err(e) => tracing::debug!("grpc tls handshake from {} failed: {}", peer, e),
+                }
+            });
+        }
+    });
+    Ok(async_stream::stream! {
+        while let Some(tls) = rx.recv().await {
+            yield Ok(tls);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CA and a "localhost" leaf it signed, valid until 2126
+    const CA: &[u8] = include_bytes!("../testdata/ca.pem");
+    const CERT: &[u8] = include_bytes!("../testdata/server.pem");
+    const KEY: &[u8] = include_bytes!("../testdata/server.key");
+
+    // Runs a handshake between in-memory rustls 0.21 connections, as axum-server would
+    fn http_handshake(
+        server: Arc<rustls::ServerConfig>,
+        version: &'static rustls::SupportedProtocolVersion,
+    ) -> Result<rustls::ProtocolVersion, rustls::Error> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(pem_certs(CA).unwrap()[0].to_vec())).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = "localhost".try_into().unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(client), name).unwrap();
+        let mut server = rustls::ServerConnection::new(server).unwrap();
+        for _ in 0..10 {
+            let mut buf = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut buf).unwrap();
+            }
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+            let mut buf = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut buf).unwrap();
+            }
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(server.protocol_version().unwrap());
+            }
+        }
+        panic!("handshake didn't finish");
+    }
+
+    #[tokio::test]
+    async fn a_tls13_http_config_refuses_tls12_clients() {
+        use rustls::version::{TLS12, TLS13};
+        let strict = http_config(CERT.to_vec(), KEY.to_vec(), MinVersion::Tls13)
+            .await
+            .unwrap()
+            .get_inner();
+        assert!(http_handshake(strict.clone(), &TLS12).is_err());
+        assert_eq!(
+            http_handshake(strict, &TLS13).unwrap(),
+            rustls::ProtocolVersion::TLSv1_3
+        );
+        // The default still lets TLS 1.2 through
+        let default = http_config(CERT.to_vec(), KEY.to_vec(), MinVersion::Tls12)
+            .await
+            .unwrap()
+            .get_inner();
+        assert_eq!(
+            http_handshake(default, &TLS12).unwrap(),
+            rustls::ProtocolVersion::TLSv1_2
+        );
+    }
+
+    #[tokio::test]
+    async fn the_grpc_acceptor_refuses_tls12_clients() {
+        use tokio_rustls::rustls::{self, pki_types::ServerName, version};
+        let acceptor = grpc_acceptor(CERT, KEY, None).unwrap();
+        let handshake = |v: &'static rustls::SupportedProtocolVersion| {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(pem_certs(CA).unwrap().remove(0)).unwrap();
+            let client = rustls::ClientConfig::builder_with_protocol_versions(&[v])
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+            let acceptor = acceptor.clone();
+            async move {
+                let (c, s) = tokio::io::duplex(1 << 16);
+                let name = ServerName::try_from("localhost").unwrap();
+                let (client, server) =
+                    tokio::join!(connector.connect(name, c), acceptor.accept(s));
+                client.and(server.map(|tls| tls.get_ref().1.protocol_version()))
+            }
+        };
+        assert!(handshake(&version::TLS12).await.is_err());
+        let negotiated = handshake(&version::TLS13).await.unwrap();
+        assert_eq!(negotiated, Some(rustls::ProtocolVersion::TLSv1_3));
+    }
+}
@@ -0,0 +1,192 @@
+// Expiry webhooks: the TTL sweeper queues one notification per removed object, and a
+// single worker POSTs them to the namespace's admin-configured URL with retries.
+// EXPIRY_WEBHOOK_QUEUE bounds the queue (default 1024); when full, notifications are
+// dropped rather than stalling the sweeper. EXPIRY_WEBHOOK_RETRIES (default 3) caps
+// redelivery attempts. URLs live in memory and must be re-registered after a restart.
+use crate::metrics::WEBHOOK_FAILURES_TOTAL;
+use agentstate_core::Object;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const BACKOFF_MIN: Duration = Duration::from_millis(200);
+
+static URLS: Lazy<parking_lot::RwLock<HashMap<String, String>>> = Lazy::new(Default::default);
+static QUEUE: OnceCell<mpsc::Sender<(String, serde_json::Value)>> = OnceCell::new();
+
+pub fn spawn() {
+    let cap = std::env::var("EXPIRY_WEBHOOK_QUEUE")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(1024);
+    let retries = std::env::var("EXPIRY_WEBHOOK_RETRIES")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(3);
+    let (tx, mut rx) = mpsc::channel::<(String, serde_json::Value)>(cap);
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        while let Some((url, payload)) = rx.recv().await {
+            if let Err(e) = deliver(&client, &url, &payload, retries).await {
+                WEBHOOK_FAILURES_TOTAL
+                    .with_label_values(&["delivery"])
+                    .inc();
+                tracing::warn!("expiry webhook {} failed: {}", url, e);
+            }
+        }
+    });
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    payload: &serde_json::Value,
+    retries: u32,
+) -> Result<(), reqwest::Error> {
+    let mut backoff = BACKOFF_MIN;
+    let mut attempt = 0;
+    loop {
+        let res = client
+            .post(url)
+            .json(payload)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= retries => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+pub fn url(ns: &str) -> Option<String> {
+    URLS.read().get(ns).cloned()
+}
+
+pub fn set_url(ns: &str, url: Option<String>) {
+    let mut urls = URLS.write();
+    match url {
+        Some(u) => urls.insert(ns.to_string(), u),
+        None => urls.remove(ns),
+    };
+}
+
+/// Queues a notification for each swept object whose namespace has a webhook.
+pub fn notify_expired(objects: &[Object]) {
+    let Some(tx) = QUEUE.get() else {
+        return;
+    };
+    for o in objects {
+        let Some(url) = url(&o.ns) else {
+            continue;
+        };
+        let payload = serde_json::json!({
+            "event": "expired",
+            "ns": o.ns,
+            "id": o.id,
+            "commit": o.commit,
+            "commit_seq": o.commit_seq,
+        });
+        if tx.try_send((url, payload)).is_err() {
+            WEBHOOK_FAILURES_TOTAL
+                .with_label_values(&["queue_full"])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use std::sync::Arc;
+
+    // Payloads each path was sent, in arrival order
+    type Received = Arc<parking_lot::Mutex<Vec<(String, serde_json::Value)>>>;
+
+    fn expired(ns: &str, id: &str) -> Object {
+        Object {
+            id: id.into(),
+            ns: ns.into(),
+            r#type: "doc".into(),
+            body: serde_json::json!({}),
+            tags: Default::default(),
+            ttl_seconds: Some(1),
+            parents: vec![],
+            commit: format!("c-{}", id),
+            ts: chrono::Utc::now(),
+            commit_seq: 7,
+            content_type: None,
+        }
+    }
+
+    // `/flaky` fails its first request and accepts the rest; `/down` never accepts
+    async fn endpoint(received: Received) -> String {
+        let post = |path: &'static str| {
+            let received = received.clone();
+            axum::routing::post(move |axum::Json(v): axum::Json<serde_json::Value>| async move {
+                let mut got = received.lock();
+                let first = !got.iter().any(|(p, _)| p == path);
+                got.push((path.to_string(), v));
+                match path {
+                    "/flaky" if !first => StatusCode::OK,
+                    _ => StatusCode::SERVICE_UNAVAILABLE,
+                }
+            })
+        };
+        let app = axum::Router::new()
+            .route("/flaky", post("/flaky"))
+            .route("/down", post("/down"));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn expiry_notices_are_retried_and_failures_counted() {
+        let received = Received::default();
+        let url = endpoint(received.clone()).await;
+        let failures = || WEBHOOK_FAILURES_TOTAL.with_label_values(&["delivery"]).get();
+        let before = failures();
+        spawn();
+        set_url("hook-flaky", Some(format!("{}/flaky", url)));
+        set_url("hook-down", Some(format!("{}/down", url)));
+        notify_expired(&[
+            expired("hook-flaky", "a"),
+            expired("hook-none", "b"),
+            expired("hook-down", "c"),
+        ]);
+
+        // One worker: the flaky notice, then every attempt at the down one
+        let attempts = 2 + 1 + 3;
+        for _ in 0..100 {
+            if received.lock().len() >= attempts && failures() > before {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let got = received.lock().clone();
+        let paths: Vec<&str> = got.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, ["/flaky", "/flaky", "/down", "/down", "/down", "/down"]);
+        let sent = &got[1].1;
+        assert_eq!(sent["event"], "expired");
+        assert_eq!((sent["ns"].as_str(), sent["id"].as_str()), (Some("hook-flaky"), Some("a")));
+        assert_eq!((sent["commit"].as_str(), sent["commit_seq"].as_u64()), (Some("c-a"), Some(7)));
+        assert_eq!(failures(), before + 1.0);
+    }
+}
@@ -0,0 +1,562 @@
+// Assembles a full `Storage` from an engine that only implements `ObjectStore`,
+// reusing the in-memory watch, lease and idempotency machinery.
+use crate::traits::{
+    AdminOps, GetOptions, IdempotencyRecord, IdempotencyStore, Lease, LeaseStore, ObjectStore,
+    WatchEvent, WatchFilter, WatchHandle, WatchLimits, WatchSource,
+};
+use crate::InMemoryStore;
+use agentstate_core::{
+    Object, PutRequest, QueryRequest, Result, StateError, TransformRule, TxnOp, VecField,
+};
+use chrono::{DateTime, Utc};
+
+/// Wraps an object engine; writes through it are published to watchers kept in
+/// an internal `InMemoryStore`. Puts keep the engine's `commit_seq`; deletes take
+/// the next seq the watch layer has seen for the namespace.
+pub struct Composed<O> {
+    objects: O,
+    shared: InMemoryStore,
+}
+
+impl<O: ObjectStore> Composed<O> {
+    pub fn new(objects: O) -> Self {
+        Self {
+            objects,
+            shared: InMemoryStore::new(),
+        }
+    }
+
+    pub fn objects(&self) -> &O {
+        &self.objects
+    }
+}
+
+#[async_trait::async_trait]
+impl<O: ObjectStore> ObjectStore for Composed<O> {
+    async fn put(&self, ns: &str, req: PutRequest) -> Result<Object> {
+        let o = self.objects.put(ns, req).await?;
+        self.shared.publish_put(&o);
+        Ok(o)
+    }
+
+    async fn put_if_absent(&self, ns: &str, req: PutRequest) -> Result<(Object, bool)> {
+        let (o, created) = self.objects.put_if_absent(ns, req).await?;
+        if created {
+            self.shared.publish_put(&o);
+        }
+        Ok((o, created))
+    }
+
+    async fn put_if_changed(&self, ns: &str, req: PutRequest) -> Result<(Object, bool)> {
+        let (o, changed) = self.objects.put_if_changed(ns, req).await?;
+        if changed {
+            self.shared.publish_put(&o);
+        }
+        Ok((o, changed))
+    }
+
+    async fn get(&self, ns: &str, id: &str, opts: GetOptions) -> Result<Object> {
+        self.objects.get(ns, id, opts).await
+    }
+
+    async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>> {
+        self.objects.query(ns, req).await
+    }
+
+    async fn delete(&self, ns: &str, id: &str) -> Result<u64> {
+        let seq = self.objects.delete(ns, id).await?;
+        self.shared.publish_delete(ns, id, seq);
+        Ok(seq)
+    }
+
+    async fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>> {
+        self.objects.versions(ns, id).await
+    }
+
+    async fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize> {
+        self.objects.trim_versions(ns, id, keep).await
+    }
+
+    async fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object> {
+        let o = self.objects.rename(ns, id, new_id).await?;
+        // Engines tombstone the old id at the seq just before the new object's
+        self.shared.publish_delete(ns, id, o.commit_seq - 1);
+        self.shared.publish_put(&o);
+        Ok(o)
+    }
+
+    async fn txn(&self, ns: &str, ops: Vec<TxnOp>) -> Result<Vec<WatchEvent>> {
+        let events = self.objects.txn(ns, ops).await?;
+        for ev in events.iter() {
+            match ev {
+                WatchEvent::Put(o) => self.shared.publish_put(o),
+                WatchEvent::Delete { ns, id, commit_seq } => {
+                    self.shared.publish_delete(ns, id, *commit_seq)
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    async fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<Object>> {
+        let swept = self.objects.sweep_expired_seqs(retention_secs).await?;
+        Ok(swept
+            .into_iter()
+            .map(|(o, seq)| {
+                self.shared.publish_delete(&o.ns, &o.id, seq);
+                o
+            })
+            .collect())
+    }
+
+    fn register_vec_field(&self, ns: &str, field: VecField) -> Result<()> {
+        self.objects.register_vec_field(ns, field)
+    }
+
+    fn register_composite_index(&self, ns: &str, keys: Vec<String>) -> Result<()> {
+        self.objects.register_composite_index(ns, keys)
+    }
+
+    fn register_index_paths(&self, ns: &str, paths: Vec<String>) -> Result<Vec<String>> {
+        self.objects.register_index_paths(ns, paths)
+    }
+
+    fn index_paths(&self, ns: &str) -> Vec<String> {
+        self.objects.index_paths(ns)
+    }
+
+    fn export_watermark(&self) -> Result<std::collections::BTreeMap<String, u64>> {
+        self.objects.export_watermark()
+    }
+
+    fn export_keys(&self) -> Result<Vec<(String, String)>> {
+        self.objects.export_keys()
+    }
+
+    fn export_batch(
+        &self,
+        keys: &[(String, String)],
+        watermark: &std::collections::BTreeMap<String, u64>,
+    ) -> Vec<Object> {
+        self.objects.export_batch(keys, watermark)
+    }
+
+    fn set_quota(&self, ns: &str, quota: crate::traits::NsQuota) -> Result<()> {
+        self.objects.set_quota(ns, quota)
+    }
+
+    fn quota(&self, ns: &str) -> Option<crate::traits::NsQuota> {
+        self.objects.quota(ns)
+    }
+
+    fn usage(&self, ns: &str) -> Option<crate::traits::NsUsage> {
+        self.objects.usage(ns)
+    }
+
+    fn expiring(&self, ns: &str, until: DateTime<Utc>) -> Result<Vec<Object>> {
+        self.objects.expiring(ns, until)
+    }
+
+    fn check_indexes(&self, sample: usize) -> Vec<crate::traits::IndexMismatch> {
+        self.objects.check_indexes(sample)
+    }
+
+    fn register_transform(&self, ns: &str, rule: TransformRule) -> Result<()> {
+        self.objects.register_transform(ns, rule)
+    }
+
+    fn all_objects(&self) -> Vec<Object> {
+        self.objects.all_objects()
+    }
+
+    fn namespaces(&self) -> Vec<String> {
+        self.objects.namespaces()
+    }
+
+    fn children(&self, ns: &str, id: &str) -> Result<Vec<String>> {
+        self.objects.children(ns, id)
+    }
+
+    async fn query_traced(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+        trace: &mut crate::traits::QueryTrace,
+    ) -> Result<Vec<Object>> {
+        self.objects.query_traced(ns, req, trace).await
+    }
+
+    async fn query_analyze(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+    ) -> Result<(Vec<Object>, crate::traits::QueryTrace)> {
+        self.objects.query_analyze(ns, req).await
+    }
+
+    fn access_stats(&self, ns: &str, id: &str) -> Option<crate::traits::AccessStats> {
+        self.objects.access_stats(ns, id)
+    }
+
+    fn advisory_lock(
+        &self,
+        ns: &str,
+        id: &str,
+        owner: &str,
+        ttl_secs: u64,
+    ) -> Result<crate::traits::AdvisoryLock> {
+        self.objects.advisory_lock(ns, id, owner, ttl_secs)
+    }
+
+    fn advisory_unlock(&self, ns: &str, id: &str, owner: &str) -> Result<()> {
+        self.objects.advisory_unlock(ns, id, owner)
+    }
+
+    fn advisory_lock_state(&self, ns: &str, id: &str) -> Option<crate::traits::AdvisoryLock> {
+        self.objects.advisory_lock_state(ns, id)
+    }
+}
+
+impl<O: ObjectStore> WatchSource for Composed<O> {
+    fn subscribe(
+        &self,
+        filter: WatchFilter,
+        from_commit: Option<u64>,
+        limits: Option<WatchLimits>,
+    ) -> Box<dyn WatchHandle> {
+        self.shared.subscribe(filter, from_commit, limits)
+    }
+
+    fn backlog_map(&self) -> std::collections::HashMap<String, u64> {
+        WatchSource::backlog_map(&self.shared)
+    }
+}
+
+#[async_trait::async_trait]
+impl<O: ObjectStore> LeaseStore for Composed<O> {
+    async fn lease_acquire(
+        &self,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        ttl_secs: u64,
+    ) -> Result<Lease> {
+        self.shared.lease_acquire(ns, key, owner, ttl_secs).await
+    }
+
+    async fn lease_renew(
+        &self,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        token: u64,
+        ttl_secs: u64,
+    ) -> Result<Lease> {
+        self.shared
+            .lease_renew(ns, key, owner, token, ttl_secs)
+            .await
+    }
+
+    async fn lease_release(&self, ns: &str, key: &str, owner: &str, token: u64) -> Result<()> {
+        self.shared.lease_release(ns, key, owner, token).await
+    }
+
+    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()> {
+        self.shared.validate_fence(ns, resource, fence).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<O: ObjectStore> IdempotencyStore for Composed<O> {
+    async fn idempotency_lookup(
+        &self,
+        ns: &str,
+        key: &str,
+        body_hash: &str,
+    ) -> Result<Option<IdempotencyRecord>> {
+        self.shared.idempotency_lookup(ns, key, body_hash).await
+    }
+
+    async fn idempotency_commit(
+        &self,
+        ns: &str,
+        key: &str,
+        body_hash: &str,
+        response: serde_json::Value,
+        commit_seq: u64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        self.shared
+            .idempotency_commit(ns, key, body_hash, response, commit_seq, expires_at)
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl<O: ObjectStore> AdminOps for Composed<O> {
+    async fn admin_snapshot(&self) -> Result<(String, u64)> {
+        Err(StateError::Invalid(
+            "snapshots not supported by this engine".into(),
+        ))
+    }
+
+    async fn admin_manifest(&self) -> Result<serde_json::Value> {
+        Err(StateError::Invalid(
+            "manifest not supported by this engine".into(),
+        ))
+    }
+
+    async fn admin_trim_wal(&self, _snapshot_id: &str) -> Result<Vec<String>> {
+        Err(StateError::Invalid(
+            "wal not supported by this engine".into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::past_grace;
+    use parking_lot::Mutex;
+    use std::collections::{BTreeMap, HashMap};
+
+    // An engine with nothing but `ObjectStore`, everything else left to `Composed`
+    #[derive(Default)]
+    struct MockObjects {
+        inner: Mutex<MockInner>,
+    }
+
+    #[derive(Default)]
+    struct MockInner {
+        versions: BTreeMap<(String, String), Vec<Object>>,
+        seqs: HashMap<String, u64>,
+    }
+
+    impl MockInner {
+        fn next_seq(&mut self, ns: &str, by: u64) -> u64 {
+            let seq = self.seqs.entry(ns.to_string()).or_default();
+            *seq += by;
+            *seq
+        }
+
+        fn latest(&self, ns: &str, id: &str) -> Option<&Object> {
+            self.versions.get(&(ns.to_string(), id.to_string()))?.last()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ObjectStore for MockObjects {
+        async fn put(&self, ns: &str, req: PutRequest) -> Result<Object> {
+            let mut inner = self.inner.lock();
+            let seq = inner.next_seq(ns, 1);
+            let obj = Object::new_with_seq(ns.to_string(), req, seq);
+            let key = (ns.to_string(), obj.id.clone());
+            inner.versions.entry(key).or_default().push(obj.clone());
+            Ok(obj)
+        }
+
+        async fn get(&self, ns: &str, id: &str, opts: GetOptions) -> Result<Object> {
+            let inner = self.inner.lock();
+            let versions = inner.versions.get(&(ns.to_string(), id.to_string()));
+            versions
+                .and_then(|v| {
+                    v.iter()
+                        .rev()
+                        .find(|o| opts.at_ts.is_none_or(|at| o.ts <= at))
+                })
+                .filter(|o| !past_grace(o, Utc::now()))
+                .cloned()
+                .ok_or(StateError::NotFound)
+        }
+
+        async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>> {
+            let inner = self.inner.lock();
+            let mut out: Vec<Object> = inner
+                .versions
+                .iter()
+                .filter(|((n, _), _)| n == ns)
+                .filter_map(|(_, v)| v.last())
+                .filter(|o| req.r#type.as_ref().is_none_or(|t| o.r#type == *t))
+                .filter(|o| {
+                    req.tag_filter.as_ref().is_none_or(|tf| {
+                        tf.0.iter().all(|(k, v)| o.tags.0.get(k) == Some(v))
+                    })
+                })
+                .filter(|o| o.expires_at().is_none_or(|at| at >= Utc::now()))
+                .cloned()
+                .collect();
+            out.sort_by_key(|o| o.commit_seq);
+            out.truncate(req.limit.unwrap_or(usize::MAX));
+            Ok(out)
+        }
+
+        async fn delete(&self, ns: &str, id: &str) -> Result<u64> {
+            let mut inner = self.inner.lock();
+            inner
+                .versions
+                .remove(&(ns.to_string(), id.to_string()))
+                .ok_or(StateError::NotFound)?;
+            Ok(inner.next_seq(ns, 1))
+        }
+
+        async fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>> {
+            let inner = self.inner.lock();
+            inner
+                .versions
+                .get(&(ns.to_string(), id.to_string()))
+                .cloned()
+                .ok_or(StateError::NotFound)
+        }
+
+        async fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize> {
+            if keep == 0 {
+                return Err(StateError::Invalid("keep must be at least 1".into()));
+            }
+            let mut inner = self.inner.lock();
+            let versions = inner
+                .versions
+                .get_mut(&(ns.to_string(), id.to_string()))
+                .ok_or(StateError::NotFound)?;
+            let n = versions.len().saturating_sub(keep);
+            versions.drain(..n);
+            Ok(n)
+        }
+
+        async fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object> {
+            let mut inner = self.inner.lock();
+            if inner.latest(ns, new_id).is_some() {
+                return Err(StateError::Conflict(format!("object {} already exists", new_id)));
+            }
+            let cur = inner.latest(ns, id).cloned().ok_or(StateError::NotFound)?;
+            inner.versions.remove(&(ns.to_string(), id.to_string()));
+            let req = PutRequest {
+                r#type: cur.r#type,
+                body: cur.body,
+                tags: cur.tags,
+                ttl_seconds: cur.ttl_seconds,
+                id: Some(new_id.to_string()),
+                parents: vec![cur.commit],
+                ..Default::default()
+            };
+            let seq = inner.next_seq(ns, 2);
+            let obj = Object::new_with_seq(ns.to_string(), req, seq);
+            let key = (ns.to_string(), new_id.to_string());
+            inner.versions.insert(key, vec![obj.clone()]);
+            Ok(obj)
+        }
+
+        async fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<Object>> {
+            let swept = self.sweep_expired_seqs(retention_secs).await?;
+            Ok(swept.into_iter().map(|(o, _)| o).collect())
+        }
+
+        async fn sweep_expired_seqs(&self, retention_secs: u64) -> Result<Vec<(Object, u64)>> {
+            let mut inner = self.inner.lock();
+            let cutoff = Utc::now() - chrono::Duration::seconds(retention_secs as i64);
+            let dead: Vec<(String, String)> = inner
+                .versions
+                .iter()
+                .filter(|(_, v)| v.last().is_some_and(|o| past_grace(o, cutoff)))
+                .map(|(k, _)| k.clone())
+                .collect();
+            let mut swept = Vec::new();
+            for key in dead {
+                if let Some(o) = inner.versions.remove(&key).and_then(|mut v| v.pop()) {
+                    let seq = inner.next_seq(&key.0, 1);
+                    swept.push((o, seq));
+                }
+            }
+            Ok(swept)
+        }
+
+        fn register_vec_field(&self, _ns: &str, _field: VecField) -> Result<()> {
+            Ok(())
+        }
+
+        fn register_composite_index(&self, _ns: &str, _keys: Vec<String>) -> Result<()> {
+            Err(StateError::Invalid("no composite indexes".into()))
+        }
+
+        fn all_objects(&self) -> Vec<Object> {
+            let inner = self.inner.lock();
+            inner.versions.values().filter_map(|v| v.last().cloned()).collect()
+        }
+    }
+
+    fn composed() -> Composed<MockObjects> {
+        Composed::new(MockObjects::default())
+    }
+
+    #[tokio::test]
+    async fn passes_the_shared_object_store_checks() {
+        crate::conformance::run(&composed()).await;
+    }
+
+    #[tokio::test]
+    async fn watchers_see_the_engine_writes() {
+        let store = composed();
+        let filter = WatchFilter {
+            ns: "ns".into(),
+            ..Default::default()
+        };
+        let mut watch = store.subscribe(filter, None, None);
+        let req = PutRequest {
+            r#type: "doc".into(),
+            id: Some("a".into()),
+            ..Default::default()
+        };
+        store.put("ns", req).await.unwrap();
+        store.rename("ns", "a", "b").await.unwrap();
+        store.delete("ns", "b").await.unwrap();
+        let mut seen = Vec::new();
+        while let Some(ev) = watch.try_next() {
+            seen.push(match ev {
+                WatchEvent::Put(o) => format!("put {} {}", o.id, o.commit_seq),
+                WatchEvent::Delete { id, commit_seq, .. } => format!("delete {} {}", id, commit_seq),
+            });
+        }
+        assert_eq!(seen, ["put a 1", "delete a 2", "put b 3", "delete b 4"]);
+    }
+
+    #[tokio::test]
+    async fn swept_objects_reach_watchers_as_deletes() {
+        let store = composed();
+        let filter = WatchFilter {
+            ns: "ns".into(),
+            ..Default::default()
+        };
+        let mut watch = store.subscribe(filter, None, None);
+        let expired = PutRequest {
+            r#type: "doc".into(),
+            id: Some("gone".into()),
+            ts: Some(Utc::now() - chrono::Duration::seconds(60)),
+            ttl_seconds: Some(10),
+            ..Default::default()
+        };
+        store.put("ns", expired).await.unwrap();
+        let swept = store.sweep_expired(0).await.unwrap();
+        assert_eq!(swept.len(), 1);
+        let next = PutRequest {
+            r#type: "doc".into(),
+            id: Some("next".into()),
+            ..Default::default()
+        };
+        assert_eq!(store.put("ns", next).await.unwrap().commit_seq, 3);
+        assert!(matches!(watch.try_next(), Some(WatchEvent::Put(o)) if o.id == "gone"));
+        let ev = watch.try_next();
+        assert!(
+            matches!(&ev, Some(WatchEvent::Delete { id, commit_seq: 2, .. }) if id == "gone"),
+            "{ev:?}"
+        );
+        assert!(matches!(watch.try_next(), Some(WatchEvent::Put(o)) if o.commit_seq == 3));
+    }
+
+    #[tokio::test]
+    async fn leases_come_from_the_shared_machinery() {
+        let store = composed();
+        let lease = store.lease_acquire("ns", "job", "me", 30).await.unwrap();
+        store.validate_fence("ns", "job", lease.token).await.unwrap();
+        let err = store.lease_acquire("ns", "job", "you", 30).await.unwrap_err();
+        assert!(matches!(err, StateError::Conflict(_)), "{err:?}");
+    }
+}
@@ -0,0 +1,160 @@
+// What every `ObjectStore` engine does alike, run from each engine's own tests. Each
+// check writes to a namespace of its own, so they share one store.
+use crate::traits::{GetOptions, ObjectStore};
+use agentstate_core::{PutRequest, QueryRequest, StateError};
+use chrono::{Duration, Utc};
+use serde_json::json;
+
+pub(crate) fn doc(id: &str, body: serde_json::Value) -> PutRequest {
+    PutRequest {
+        r#type: "doc".into(),
+        body,
+        id: Some(id.into()),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn query(q: serde_json::Value) -> QueryRequest {
+    serde_json::from_value(q).unwrap()
+}
+
+fn latest() -> GetOptions {
+    GetOptions { at_ts: None }
+}
+
+pub(crate) async fn run(store: &impl ObjectStore) {
+    puts_keep_every_version(store).await;
+    queries_filter_on_type_and_tags(store).await;
+    deletes_take_the_next_seq(store).await;
+    trims_keep_the_newest(store).await;
+    renames_move_the_current_version(store).await;
+    sweeps_remove_expired_objects(store).await;
+}
+
+async fn puts_keep_every_version(store: &impl ObjectStore) {
+    let first = store.put("puts", doc("a", json!({"v": 1}))).await.unwrap();
+    let second = store.put("puts", doc("a", json!({"v": 2}))).await.unwrap();
+    store.put("puts", doc("b", json!({"v": 1}))).await.unwrap();
+    assert_eq!((first.commit_seq, second.commit_seq), (1, 2));
+    assert_eq!(store.get("puts", "a", latest()).await.unwrap().body, json!({"v": 2}));
+    let at = GetOptions {
+        at_ts: Some(first.ts),
+    };
+    assert_eq!(store.get("puts", "a", at).await.unwrap().body, json!({"v": 1}));
+    let seqs: Vec<u64> = store
+        .versions("puts", "a")
+        .await
+        .unwrap()
+        .iter()
+        .map(|o| o.commit_seq)
+        .collect();
+    assert_eq!(seqs, [1, 2]);
+    let err = store.get("puts", "missing", latest()).await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+    let mut all: Vec<_> = store
+        .all_objects()
+        .into_iter()
+        .filter(|o| o.ns == "puts")
+        .map(|o| (o.id, o.body))
+        .collect();
+    all.sort_by(|x, y| x.0.cmp(&y.0));
+    assert_eq!(all, [("a".into(), json!({"v": 2})), ("b".into(), json!({"v": 1}))]);
+}
+
+async fn queries_filter_on_type_and_tags(store: &impl ObjectStore) {
+    for (id, ty, status) in [("a", "task", "open"), ("b", "task", "done"), ("c", "note", "open")] {
+        let mut req = doc(id, json!({}));
+        req.r#type = ty.into();
+        req.tags = serde_json::from_value(json!({ "status": status })).unwrap();
+        store.put("queries", req).await.unwrap();
+    }
+    let ids = |list: Vec<agentstate_core::Object>| -> Vec<String> {
+        list.into_iter().map(|o| o.id).collect()
+    };
+    let open = query(json!({"tag_filter": {"status": "open"}}));
+    assert_eq!(ids(store.query("queries", open).await.unwrap()), ["a", "c"]);
+    let tasks = query(json!({"type": "task", "tag_filter": {"status": "open"}}));
+    assert_eq!(ids(store.query("queries", tasks).await.unwrap()), ["a"]);
+    // The index follows the current version, not the first
+    let mut closed = doc("a", json!({}));
+    closed.r#type = "task".into();
+    closed.tags = serde_json::from_value(json!({"status": "done"})).unwrap();
+    store.put("queries", closed).await.unwrap();
+    let open = query(json!({"tag_filter": {"status": "open"}}));
+    assert_eq!(ids(store.query("queries", open).await.unwrap()), ["c"]);
+    let limited = query(json!({"limit": 2}));
+    assert_eq!(store.query("queries", limited).await.unwrap().len(), 2);
+}
+
+async fn deletes_take_the_next_seq(store: &impl ObjectStore) {
+    let put = store.put("deletes", doc("a", json!({}))).await.unwrap();
+    let seq = store.delete("deletes", "a").await.unwrap();
+    assert_eq!(seq, put.commit_seq + 1);
+    let err = store.get("deletes", "a", latest()).await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+    let err = store.delete("deletes", "a").await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+    assert!(store.query("deletes", query(json!({}))).await.unwrap().is_empty());
+    let again = store.put("deletes", doc("a", json!({}))).await.unwrap();
+    assert_eq!(again.commit_seq, seq + 1);
+}
+
+async fn trims_keep_the_newest(store: &impl ObjectStore) {
+    for v in 0..5 {
+        store.put("trims", doc("a", json!({ "v": v }))).await.unwrap();
+    }
+    assert_eq!(store.trim_versions("trims", "a", 2).await.unwrap(), 3);
+    let kept: Vec<_> = store
+        .versions("trims", "a")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|o| o.body["v"].clone())
+        .collect();
+    assert_eq!(kept, [3, 4]);
+    let err = store.trim_versions("trims", "a", 0).await.unwrap_err();
+    assert!(matches!(err, StateError::Invalid(_)), "{err:?}");
+    let err = store.trim_versions("trims", "missing", 1).await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+}
+
+async fn renames_move_the_current_version(store: &impl ObjectStore) {
+    let before = store.put("renames", doc("old", json!({"v": 1}))).await.unwrap();
+    store.put("renames", doc("taken", json!({}))).await.unwrap();
+    let moved = store.rename("renames", "old", "new").await.unwrap();
+    // The old id's tombstone takes the seq in between
+    assert_eq!(moved.commit_seq, before.commit_seq + 3);
+    assert_eq!((moved.body.clone(), moved.parents), (json!({"v": 1}), vec![before.commit]));
+    let err = store.get("renames", "old", latest()).await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+    let err = store.rename("renames", "new", "taken").await.unwrap_err();
+    assert!(matches!(err, StateError::Conflict(_)), "{err:?}");
+    let err = store.rename("renames", "old", "other").await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+}
+
+async fn sweeps_remove_expired_objects(store: &impl ObjectStore) {
+    let mut expired = doc("gone", json!({}));
+    expired.ts = Some(Utc::now() - Duration::seconds(60));
+    expired.ttl_seconds = Some(10);
+    store.put("sweeps", expired).await.unwrap();
+    let mut live = doc("kept", json!({}));
+    live.ttl_seconds = Some(3600);
+    store.put("sweeps", live).await.unwrap();
+    // Due 50s ago, so an hour's retention keeps it
+    let kept = store.sweep_expired(3600).await.unwrap();
+    assert!(kept.iter().all(|o| o.ns != "sweeps"));
+    assert!(store.versions("sweeps", "gone").await.is_ok());
+    let swept: Vec<String> = store
+        .sweep_expired(0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|o| o.ns == "sweeps")
+        .map(|o| o.id)
+        .collect();
+    assert_eq!(swept, ["gone"]);
+    let err = store.get("sweeps", "gone", latest()).await.unwrap_err();
+    assert!(matches!(err, StateError::NotFound), "{err:?}");
+    assert!(store.get("sweeps", "kept", latest()).await.is_ok());
+}
@@ -0,0 +1,242 @@
+// Field-level encryption: configured body paths are sealed with AES-256-GCM while the
+// store stages a write, whichever API it came in on, so memory, WAL, snapshots and watch
+// events only hold ciphertext. Reads open them again for tokens allowed to see plaintext.
+//
+// FIELD_ENCRYPTION_KEY: base64 32-byte key
+// ENCRYPTED_FIELDS: JSON map of ns -> body paths, e.g. {"acme":["ssn","$.card.number"]}
+use agentstate_core::{Object, QueryRequest, Result, StateError};
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use once_cell::sync::Lazy;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Sealed values are stored as "enc:v1:<base64(nonce || ciphertext || tag)>"
+const SEALED_PREFIX: &str = "enc:v1:";
+
+static KEY: Lazy<Option<LessSafeKey>> = Lazy::new(|| {
+    let raw = std::env::var("FIELD_ENCRYPTION_KEY").ok()?;
+    let bytes = b64.decode(raw.trim()).ok()?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes).ok()?;
+    Some(LessSafeKey::new(key))
+});
+
+static FIELDS: Lazy<HashMap<String, Vec<String>>> = Lazy::new(|| {
+    let Ok(raw) = std::env::var("ENCRYPTED_FIELDS") else {
+        return HashMap::new();
+    };
+    let parsed: HashMap<String, Vec<String>> = serde_json::from_str(&raw).unwrap_or_else(|e| {
+        tracing::warn!("ENCRYPTED_FIELDS is not a JSON map of ns -> paths: {}", e);
+        HashMap::new()
+    });
+    parsed
+        .into_iter()
+        .map(|(ns, paths)| (ns, paths.iter().map(|p| normalize(p)).collect()))
+        .collect()
+});
+
+static RNG: Lazy<SystemRandom> = Lazy::new(SystemRandom::new);
+
+/// Startup check: encrypted fields without a usable key would reject every put.
+pub fn validate_config() {
+    if !FIELDS.is_empty() && KEY.is_none() {
+        tracing::warn!("ENCRYPTED_FIELDS set but FIELD_ENCRYPTION_KEY is missing or not 32 bytes");
+    }
+}
+
+/// Encrypts the namespace's configured fields in a body about to be stored. Values are
+/// sealed whatever they hold: one that already looks like ciphertext is client input
+/// like any other, and storing it as-is would keep it out of encryption.
+pub fn seal(ns: &str, body: &mut Value) -> Result<()> {
+    let Some(paths) = FIELDS.get(ns) else {
+        return Ok(());
+    };
+    let key = KEY
+        .as_ref()
+        .ok_or_else(|| StateError::Invalid("field encryption key not configured".into()))?;
+    seal_paths(key, ns, paths, body)
+}
+
+fn seal_paths(key: &LessSafeKey, ns: &str, paths: &[String], body: &mut Value) -> Result<()> {
+    let failed = |what: &str| StateError::Internal(format!("field encryption: {}", what));
+    for path in paths {
+        let Some(v) = field_mut(body, path) else {
+            continue;
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        RNG.fill(&mut nonce).map_err(|_| failed("rng failure"))?;
+        let mut buf = serde_json::to_vec(v).map_err(|e| failed(&e.to_string()))?;
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad(ns, path).as_bytes()),
+            &mut buf,
+        )
+        .map_err(|_| failed("seal failed"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&buf);
+        *v = Value::String(format!("{}{}", SEALED_PREFIX, b64.encode(sealed)));
+    }
+    Ok(())
+}
+
+/// Decrypts sealed fields in place when the token may read plaintext; other
+/// tokens keep seeing ciphertext.
+pub fn open(claims: &Value, objs: &mut [Object]) {
+    if !may_decrypt(claims) {
+        return;
+    }
+    for o in objs.iter_mut() {
+        open_body(&o.ns, &mut o.body);
+    }
+}
+
+/// Same as `open`, for an object already serialized (idempotent replays).
+pub fn open_value(claims: &Value, obj: &mut Value) {
+    if !may_decrypt(claims) {
+        return;
+    }
+    let Some(ns) = obj.get("ns").and_then(|v| v.as_str()).map(str::to_string) else {
+        return;
+    };
+    if let Some(body) = obj.get_mut("body") {
+        open_body(&ns, body);
+    }
+}
+
+/// Whether a stored body holds the same plaintext as `plain`. Every seal draws a fresh
+/// nonce, so sealed bodies are opened before they're compared.
+pub(crate) fn same_plaintext(ns: &str, stored: &Value, plain: &Value) -> bool {
+    if !encrypts(ns) {
+        return stored == plain;
+    }
+    let mut opened = stored.clone();
+    open_body(ns, &mut opened);
+    opened == *plain
+}
+
+/// Whether the namespace has encrypted fields.
+pub fn encrypts(ns: &str) -> bool {
+    FIELDS.contains_key(ns)
+}
+
+/// Equality and range filters and `distinct_by` can't work on ciphertext (every seal uses a
+/// fresh nonce), so queries on encrypted paths are rejected.
+pub fn check_filter(ns: &str, req: &QueryRequest) -> std::result::Result<(), String> {
+    let Some(paths) = FIELDS.get(ns) else {
+        return Ok(());
+    };
+    let filtered = req
+        .jsonpath
+        .iter()
+        .flat_map(|jf| jf.equals.keys().chain(jf.ranges.keys()));
+    for p in filtered.chain(req.distinct_by.iter()) {
+        let p = normalize(p);
+        let hit = paths
+            .iter()
+            .any(|enc| p == *enc || p.starts_with(&format!("{}.", enc)));
+        if hit {
+            return Err(format!("cannot filter or dedup on encrypted field {}", p));
+        }
+    }
+    req.any_of.iter().try_for_each(|sub| check_filter(ns, sub))
+}
+
+// Caps disabled means every caller is trusted; otherwise the token needs `decrypt: true`
+fn may_decrypt(claims: &Value) -> bool {
+    let caps_off =
+        std::env::var("CAP_KEY_ACTIVE").is_err() && std::env::var("CAP_KEY_NEXT").is_err();
+    caps_off || claims.get("decrypt").and_then(|v| v.as_bool()) == Some(true)
+}
+
+// Opens a stored body's sealed fields in place, whoever is asking
+pub(crate) fn open_body(ns: &str, body: &mut Value) {
+    if let (Some(paths), Some(key)) = (FIELDS.get(ns), KEY.as_ref()) {
+        open_paths(key, ns, paths, body);
+    }
+}
+
+fn open_paths(key: &LessSafeKey, ns: &str, paths: &[String], body: &mut Value) {
+    for path in paths {
+        let Some(v) = field_mut(body, path) else {
+            continue;
+        };
+        let Some(sealed) = v
+            .as_str()
+            .and_then(|s| s.strip_prefix(SEALED_PREFIX))
+            .and_then(|s| b64.decode(s).ok())
+        else {
+            continue;
+        };
+        if sealed.len() < NONCE_LEN {
+            continue;
+        }
+        let (nonce, ct) = sealed.split_at(NONCE_LEN);
+        let Ok(nonce) = Nonce::try_assume_unique_for_key(nonce) else {
+            continue;
+        };
+        let mut buf = ct.to_vec();
+        let Ok(plain) = key.open_in_place(nonce, Aad::from(aad(ns, path).as_bytes()), &mut buf)
+        else {
+            tracing::warn!("failed to decrypt {} in ns {}", path, ns);
+            continue;
+        };
+        if let Ok(val) = serde_json::from_slice(plain) {
+            *v = val;
+        }
+    }
+}
+
+// Binds ciphertext to its namespace and path so it can't be replayed elsewhere
+fn aad(ns: &str, path: &str) -> String {
+    format!("{}:{}", ns, path)
+}
+
+// `$.a.b`, `body.a.b` and `a.b` all name the same body field
+fn normalize(path: &str) -> String {
+    let p = path.trim().trim_start_matches('$').trim_start_matches('.');
+    p.strip_prefix("body.").unwrap_or(p).to_string()
+}
+
+fn field_mut<'a>(body: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.')
+        .filter(|s| !s.is_empty())
+        .try_fold(body, |cur, k| cur.get_mut(k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &[7u8; 32]).unwrap())
+    }
+
+    #[test]
+    fn values_that_look_sealed_are_still_encrypted() {
+        let paths = vec!["ssn".to_string()];
+        let forged = format!("{}AAAA", SEALED_PREFIX);
+        let mut body = serde_json::json!({"ssn": forged, "name": "ann"});
+        seal_paths(&key(), "acme", &paths, &mut body).unwrap();
+        let stored = body["ssn"].as_str().unwrap().to_string();
+        assert!(stored.starts_with(SEALED_PREFIX));
+        assert_ne!(stored, forged);
+        open_paths(&key(), "acme", &paths, &mut body);
+        assert_eq!(body, serde_json::json!({"ssn": forged, "name": "ann"}));
+    }
+
+    #[test]
+    fn stored_form_is_ciphertext_and_reads_return_plaintext() {
+        let paths = vec![normalize("$.card.number")];
+        let mut body = serde_json::json!({"card": {"number": "4111"}});
+        seal_paths(&key(), "acme", &paths, &mut body).unwrap();
+        let stored = serde_json::to_string(&body).unwrap();
+        assert!(!stored.contains("4111"), "{stored}");
+        // Bound to its namespace: another ns can't open it
+        let mut elsewhere = body.clone();
+        open_paths(&key(), "other", &paths, &mut elsewhere);
+        assert_eq!(elsewhere, body);
+        open_paths(&key(), "acme", &paths, &mut body);
+        assert_eq!(body, serde_json::json!({"card": {"number": "4111"}}));
+    }
+}
@@ -0,0 +1,423 @@
+//! Approximate nearest-neighbour index (HNSW) over one registered embedding field.
+//!
+//! Vectors are stored normalized so cosine similarity is a dot product. Deletes and
+//! updates tombstone the old node, which stays in the graph for navigation until
+//! tombstones outnumber live nodes and the graph is rebuilt.
+
+use once_cell::sync::Lazy;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+fn env_usize(key: &str, default: usize) -> usize {
+    std::env::var(key)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(default)
+}
+
+// Graph degree per layer; layer 0 keeps twice as many links (HNSW_M, default 16)
+static HNSW_M: Lazy<usize> = Lazy::new(|| env_usize("HNSW_M", 16).max(2));
+// Candidate list size while inserting (HNSW_EF_CONSTRUCTION, default 200)
+static HNSW_EF_CONSTRUCTION: Lazy<usize> = Lazy::new(|| env_usize("HNSW_EF_CONSTRUCTION", 200));
+// Candidate list size while searching, raised to top_k when smaller (HNSW_EF_SEARCH, default 64)
+pub(crate) static HNSW_EF_SEARCH: Lazy<usize> = Lazy::new(|| env_usize("HNSW_EF_SEARCH", 64));
+// Fields with fewer live vectors are scored brute force (HNSW_MIN_VECTORS, default 10000)
+pub(crate) static HNSW_MIN_VECTORS: Lazy<usize> =
+    Lazy::new(|| env_usize("HNSW_MIN_VECTORS", 10_000));
+// Warm finished graphs with synthetic searches before they serve (ANN_WARMUP=1, default off)
+pub(crate) static ANN_WARMUP: Lazy<bool> =
+    Lazy::new(|| std::env::var("ANN_WARMUP").is_ok_and(|v| v == "1"));
+// Synthetic searches per graph when warming (ANN_WARMUP_QUERIES, default 16)
+pub(crate) static ANN_WARMUP_QUERIES: Lazy<usize> =
+    Lazy::new(|| env_usize("ANN_WARMUP_QUERIES", 16));
+
+// Rebuild once tombstones outnumber live nodes, but not for tiny graphs
+const COMPACT_MIN_TOMBSTONES: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, usize);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+struct Node {
+    id: String,
+    vec: Vec<f32>,
+    // links[l]: neighbour slots on layer l, for l in 0..=level
+    links: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+pub(crate) struct Hnsw {
+    dims: usize,
+    m: usize,
+    ef_construction: usize,
+    level_mult: f64,
+    nodes: Vec<Node>,
+    // live id -> slot
+    ids: HashMap<String, usize>,
+    entry: Option<usize>,
+    max_level: usize,
+    tombstones: usize,
+    rng: u64,
+    // While a registration backfills: ids written since it started, which the
+    // backfill must not overwrite. None once the index is complete.
+    building: Option<HashSet<String>>,
+}
+
+impl Hnsw {
+    pub(crate) fn new(dims: usize) -> Self {
+        let m = *HNSW_M;
+        Self {
+            dims,
+            m,
+            ef_construction: (*HNSW_EF_CONSTRUCTION).max(m),
+            level_mult: 1.0 / (m as f64).ln(),
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            max_level: 0,
+            tombstones: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+            building: Some(HashSet::new()),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub(crate) fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.building.is_none()
+    }
+
+    pub(crate) fn finish_build(&mut self) {
+        self.building = None;
+    }
+
+    /// Adds or replaces the vector for `id`; a value that isn't a numeric array of the
+    /// index's dims removes it instead.
+    pub(crate) fn upsert(&mut self, id: &str, value: Option<&serde_json::Value>) {
+        if let Some(touched) = self.building.as_mut() {
+            touched.insert(id.to_string());
+        }
+        self.tombstone(id);
+        if let Some(v) = value.and_then(|v| normalized(v, self.dims)) {
+            self.insert(id.to_string(), v);
+        }
+        self.maybe_compact();
+    }
+
+    pub(crate) fn remove(&mut self, id: &str) {
+        if let Some(touched) = self.building.as_mut() {
+            touched.insert(id.to_string());
+        }
+        self.tombstone(id);
+        self.maybe_compact();
+    }
+
+    /// Backfill insert: skipped when a write already touched `id` during the build.
+    pub(crate) fn backfill(&mut self, id: &str, value: &serde_json::Value) {
+        if self.ids.contains_key(id) || self.building.as_ref().is_some_and(|t| t.contains(id)) {
+            return;
+        }
+        if let Some(v) = normalized(value, self.dims) {
+            self.insert(id.to_string(), v);
+        }
+    }
+
+    /// Ids of the (approximately) `k` most similar live vectors, most similar first.
+    pub(crate) fn search(&self, query: &[f32], k: usize, ef: usize) -> Vec<String> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        if query.len() != self.dims || k == 0 {
+            return Vec::new();
+        }
+        let q = normalize(query.to_vec());
+        let mut ep = entry;
+        for layer in (1..=self.max_level).rev() {
+            ep = self.greedy(&q, ep, layer);
+        }
+        let mut found = self.search_layer(&q, &[ep], ef.max(k), 0, true);
+        found.truncate(k);
+        found
+            .into_iter()
+            .map(|s| self.nodes[s.1].id.clone())
+            .collect()
+    }
+
+    /// Runs `queries` searches toward pseudo-random directions so the graph's nodes are
+    /// paged in before real traffic; returns how many ran.
+    pub(crate) fn warm(&self, queries: usize) -> usize {
+        if self.entry.is_none() {
+            return 0;
+        }
+        let mut seed = self.rng;
+        for _ in 0..queries {
+            let q: Vec<f32> = (0..self.dims)
+                .map(|_| {
+                    seed ^= seed >> 12;
+                    seed ^= seed << 25;
+                    seed ^= seed >> 27;
+                    let r = seed.wrapping_mul(0x2545_f491_4f6c_dd1d);
+                    ((r >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0) as f32
+                })
+                .collect();
+            self.search(&q, 10, *HNSW_EF_SEARCH);
+        }
+        queries
+    }
+
+    fn tombstone(&mut self, id: &str) {
+        if let Some(slot) = self.ids.remove(id) {
+            self.nodes[slot].deleted = true;
+            self.tombstones += 1;
+        }
+    }
+
+    fn maybe_compact(&mut self) {
+        if self.tombstones < COMPACT_MIN_TOMBSTONES || self.tombstones <= self.ids.len() {
+            return;
+        }
+        let live: Vec<(String, Vec<f32>)> = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .filter(|n| !n.deleted)
+            .map(|n| (n.id, n.vec))
+            .collect();
+        self.ids.clear();
+        self.entry = None;
+        self.max_level = 0;
+        self.tombstones = 0;
+        for (id, vec) in live {
+            self.insert(id, vec);
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64*; levels only need to be geometric, not unpredictable
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let u = ((r >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        (-u.ln() * self.level_mult) as usize
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.m * 2
+        } else {
+            self.m
+        }
+    }
+
+    fn insert(&mut self, id: String, vec: Vec<f32>) {
+        let level = self.random_level();
+        let slot = self.nodes.len();
+        self.nodes.push(Node {
+            id: id.clone(),
+            vec,
+            links: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id, slot);
+        let Some(mut ep) = self.entry else {
+            self.entry = Some(slot);
+            self.max_level = level;
+            return;
+        };
+        let q = self.nodes[slot].vec.clone();
+        for layer in (level + 1..=self.max_level).rev() {
+            ep = self.greedy(&q, ep, layer);
+        }
+        let mut eps = vec![ep];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = self.search_layer(&q, &eps, self.ef_construction, layer, false);
+            let neighbours: Vec<usize> = found
+                .iter()
+                .take(self.max_links(layer))
+                .map(|s| s.1)
+                .collect();
+            for &n in neighbours.iter() {
+                self.link(n, slot, layer);
+            }
+            self.nodes[slot].links[layer] = neighbours;
+            eps = found.into_iter().map(|s| s.1).collect();
+        }
+        if level > self.max_level {
+            self.entry = Some(slot);
+            self.max_level = level;
+        }
+    }
+
+    // Adds `to` to `from`'s links on `layer`, keeping only the closest when over capacity
+    fn link(&mut self, from: usize, to: usize, layer: usize) {
+        let cap = self.max_links(layer);
+        self.nodes[from].links[layer].push(to);
+        if self.nodes[from].links[layer].len() <= cap {
+            return;
+        }
+        let base = &self.nodes[from].vec;
+        let mut scored: Vec<Scored> = self.nodes[from].links[layer]
+            .iter()
+            .map(|&n| Scored(dot(base, &self.nodes[n].vec), n))
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        scored.truncate(cap);
+        self.nodes[from].links[layer] = scored.into_iter().map(|s| s.1).collect();
+    }
+
+    fn greedy(&self, q: &[f32], mut cur: usize, layer: usize) -> usize {
+        let mut best = dot(q, &self.nodes[cur].vec);
+        loop {
+            let mut moved = false;
+            for &n in self.nodes[cur].links[layer].iter() {
+                let s = dot(q, &self.nodes[n].vec);
+                if s > best {
+                    best = s;
+                    cur = n;
+                    moved = true;
+                }
+            }
+            if !moved {
+                return cur;
+            }
+        }
+    }
+
+    // Best-first search on one layer; returns up to `ef` slots, most similar first. With
+    // `live_only`, tombstoned nodes are still walked through but never returned, so a
+    // search near many deletes still fills `ef`.
+    fn search_layer(
+        &self,
+        q: &[f32],
+        eps: &[usize],
+        ef: usize,
+        layer: usize,
+        live_only: bool,
+    ) -> Vec<Scored> {
+        let mut visited: HashSet<usize> = eps.iter().copied().collect();
+        // max-heap of candidates to expand, min-heap (via Reverse) of the current best
+        let mut frontier: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut best: BinaryHeap<std::cmp::Reverse<Scored>> = BinaryHeap::new();
+        let keep = |s: Scored, best: &mut BinaryHeap<std::cmp::Reverse<Scored>>| {
+            if live_only && self.nodes[s.1].deleted {
+                return;
+            }
+            best.push(std::cmp::Reverse(s));
+            if best.len() > ef {
+                best.pop();
+            }
+        };
+        for &e in eps {
+            let s = Scored(dot(q, &self.nodes[e].vec), e);
+            frontier.push(s);
+            keep(s, &mut best);
+        }
+        while let Some(c) = frontier.pop() {
+            let worst = best.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+            if c.0 < worst && best.len() >= ef {
+                break;
+            }
+            for &n in self.nodes[c.1].links[layer].iter() {
+                if !visited.insert(n) {
+                    continue;
+                }
+                let s = Scored(dot(q, &self.nodes[n].vec), n);
+                let worst = best.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+                if best.len() < ef || s.0 > worst {
+                    frontier.push(s);
+                    keep(s, &mut best);
+                }
+            }
+        }
+        let mut out: Vec<Scored> = best.into_iter().map(|r| r.0).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+}
+
+fn normalized(value: &serde_json::Value, dims: usize) -> Option<Vec<f32>> {
+    // Same reading as brute-force scoring: non-numbers are skipped, then dims must match
+    let v: Vec<f32> = value
+        .as_array()?
+        .iter()
+        .filter_map(|x| x.as_f64().map(|f| f as f32))
+        .collect();
+    (v.len() == dims).then(|| normalize(v))
+}
+
+// Zero vectors stay zero, scoring 0 against everything like the brute-force cosine
+fn normalize(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points spread around the unit circle, so the neighbours of any angle are known
+    fn on_circle(i: usize, n: usize) -> serde_json::Value {
+        let a = i as f64 / n as f64 * std::f64::consts::TAU;
+        serde_json::json!([a.cos(), a.sin()])
+    }
+
+    #[test]
+    fn searches_fill_k_past_tombstones_and_after_a_rebuild() {
+        let n = 2100;
+        let mut index = Hnsw::new(2);
+        index.finish_build();
+        for i in 0..n {
+            index.upsert(&format!("p{i}"), Some(&on_circle(i, n)));
+        }
+        // Delete the 200 points closest to the query: more than ef, too few to rebuild
+        for i in (0..100).chain(n - 100..n) {
+            index.remove(&format!("p{i}"));
+        }
+        assert_eq!(index.tombstones, 200);
+        let found = index.search(&[1.0, 0.0], 10, 16);
+        assert_eq!(found.len(), 10);
+        let mut want: Vec<String> = (100..105)
+            .chain(n - 105..n - 100)
+            .map(|i| format!("p{i}"))
+            .collect();
+        let mut got = found;
+        want.sort();
+        got.sort();
+        assert_eq!(got, want);
+
+        // Once tombstones outnumber live nodes the graph is rebuilt without them
+        for i in 100..1000 {
+            index.remove(&format!("p{i}"));
+        }
+        assert_eq!(index.len(), n - 1100);
+        assert!(index.tombstones < 200);
+        assert_eq!(index.nodes.len(), index.len() + index.tombstones);
+        assert_eq!(index.search(&[1.0, 0.0], 10, 16).len(), 10);
+    }
+}
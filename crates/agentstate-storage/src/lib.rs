@@ -1,10 +1,22 @@
+pub mod compose;
+#[cfg(test)]
+mod conformance;
+pub mod crypt;
+mod hnsw;
 pub mod mem;
+pub mod metrics;
 pub mod persistent;
+#[cfg(feature = "rocksdb")]
+pub mod rocks;
 pub mod snapshot;
 pub mod traits;
+pub mod validator;
 pub mod wal;
 pub mod walbin;
 
+pub use compose::Composed;
 pub use mem::InMemoryStore;
 pub use persistent::PersistentStore;
+#[cfg(feature = "rocksdb")]
+pub use rocks::RocksStore;
 pub use traits::*;
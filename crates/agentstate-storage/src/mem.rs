@@ -1,19 +1,158 @@
-use crate::traits::{Storage, WatchEvent, WatchFilter, WatchHandle};
-use agentstate_core::{Object, PutRequest, QueryRequest, Result, StateError};
+use crate::traits::{
+    AccessStats, AdminOps, AdvisoryLock, IdempotencyStore, LeaseStore, NsQuota, NsUsage,
+    ObjectStore, QueryTrace, WatchEvent, WatchFilter, WatchHandle, WatchLimits, WatchSource,
+};
+use crate::hnsw::{Hnsw, ANN_WARMUP, ANN_WARMUP_QUERIES, HNSW_EF_SEARCH, HNSW_MIN_VECTORS};
+use crate::walbin::RecBody;
+use agentstate_core::{
+    Deadline, IdStrategy, Object, PutRequest, QueryRequest, Range, Result, StateError, Tags,
+    TransformOp, TransformRule, TxnOp, VecDimsMode, VecField, VectorQuery,
+};
 use chrono::{DateTime, Duration, Utc};
 use once_cell::sync::Lazy;
-use parking_lot::RwLock;
-use prometheus::{register_histogram_vec, HistogramVec};
-use std::collections::HashMap;
+use parking_lot::{Mutex, RwLock};
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry, HistogramVec,
+    IntCounterVec,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 static VECTOR_QUERY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
-    register_histogram_vec!("vector_query_seconds", "ANN latency", &["field"]).unwrap()
+    register_histogram_vec_with_registry!(
+        "vector_query_seconds",
+        "ANN latency",
+        &["field"],
+        crate::metrics::REGISTRY
+    )
+    .unwrap()
+});
+
+static VECTOR_QUERY_ALGO: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "vector_query_algo_total",
+        "Vector queries by scoring path (hnsw or exact)",
+        &["algo"],
+        crate::metrics::REGISTRY
+    )
+    .unwrap()
+});
+
+// Expired objects stay readable by get, and are kept by the sweeper, for this long
+// past their TTL (TTL_GRACE_SECS, default 0)
+static TTL_GRACE: Lazy<Duration> = Lazy::new(|| {
+    Duration::seconds(
+        std::env::var("TTL_GRACE_SECS")
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|n| *n >= 0)
+            .unwrap_or(0),
+    )
+});
+
+// Version trims keep whatever a time-travel read this far back still needs
+// (TIME_TRAVEL_RETENTION_SECS, default 0: only the newest `keep` are safe)
+static TIME_TRAVEL_RETENTION: Lazy<Duration> = Lazy::new(|| {
+    Duration::seconds(
+        std::env::var("TIME_TRAVEL_RETENTION_SECS")
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|n| *n >= 0)
+            .unwrap_or(0),
+    )
+});
+
+// MAX_VERSIONS_PER_OBJECT: most versions kept per object; a put past it drops the
+// oldest (unset or 0: no limit)
+pub(crate) static MAX_VERSIONS_PER_OBJECT: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_VERSIONS_PER_OBJECT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+});
+
+// Retry hint on an overflowed watch: the midpoint of WATCH_RETRY_MIN_MS (default 250)
+// and WATCH_RETRY_MAX_MS (default 4000)
+static WATCH_RETRY_MS: Lazy<u32> = Lazy::new(|| {
+    let ms = |var: &str, default: u32| {
+        std::env::var(var)
+            .ok()
+            .and_then(|s| s.parse::<u32>().ok())
+            .unwrap_or(default)
+    };
+    let (min, max) = (ms("WATCH_RETRY_MIN_MS", 250), ms("WATCH_RETRY_MAX_MS", 4000));
+    ((min as u64 + max as u64) / 2) as u32
+});
+
+// Most ops one transaction may carry (MAX_TXN_OPS, default 256)
+static MAX_TXN_OPS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_TXN_OPS")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(256)
+});
+
+pub(crate) fn past_grace(o: &Object, now: DateTime<Utc>) -> bool {
+    o.expires_at().is_some_and(|at| at + *TTL_GRACE < now)
+}
+
+/// Which current version, if any, turns a put into a no-op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PutMode {
+    Always,
+    IfAbsent,
+    IfChanged,
+}
+
+// Scan loops poll the request deadline every this many items
+pub(crate) const SCAN_CHECK_EVERY: usize = 256;
+
+// QUERY_MAX_CANDIDATES: most objects a query may match on its indexed filters before
+// they are loaded (unset or 0: no limit)
+static QUERY_MAX_CANDIDATES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("QUERY_MAX_CANDIDATES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+});
+
+pub(crate) fn check_candidates(n: usize) -> Result<()> {
+    let max = *QUERY_MAX_CANDIDATES;
+    if max > 0 && n > max {
+        return Err(StateError::Invalid(format!(
+            "query_too_broad: {} candidates exceed QUERY_MAX_CANDIDATES={}, add filters",
+            n, max
+        )));
+    }
+    Ok(())
+}
+
+// Max concurrent vector scoring jobs on the blocking pool (VECTOR_QUERY_WORKERS, default: cores)
+static VECTOR_WORKERS: Lazy<tokio::sync::Semaphore> = Lazy::new(|| {
+    let n = std::env::var("VECTOR_QUERY_WORKERS")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4)
+        });
+    tokio::sync::Semaphore::new(n)
 });
 
 #[derive(Clone)]
 pub struct InMemoryStore {
     inner: Arc<RwLock<Inner>>,
+    // Read counters, under their own lock so reads never need the data write lock
+    access: Arc<Mutex<HashMap<(String, String), AccessStats>>>,
+    // Advisory locks, like the read counters kept apart from the data and never persisted
+    locks: Arc<Mutex<HashMap<(String, String), AdvisoryLock>>>,
+    // Where the next index consistency check resumes
+    check_cursor: Arc<AtomicUsize>,
+    // ANN warm-ups in progress
+    warming: Arc<AtomicUsize>,
 }
 
 #[derive(Default)]
@@ -27,6 +166,10 @@ struct Inner {
     commit_log: HashMap<String, Vec<WatchEvent>>, // events with commit_seq embedded
     // Secondary indexes on tags: (ns, tag_k, tag_v) -> set(ids as map for O(1))
     tag_index: HashMap<(String, String, String), HashMap<String, ()>>,
+    // Tag key existence: (ns, tag_k) -> ids carrying that key with any value
+    tag_key_index: HashMap<(String, String), HashMap<String, ()>>,
+    // Object type: (ns, type) -> ids; may lag a type change, so hits are re-checked
+    type_index: HashMap<(String, String), HashMap<String, ()>>,
     // JSONPath (equality on materialized paths): (ns, path, value_json) -> ids
     json_index: HashMap<(String, String, String), HashMap<String, ()>>,
     // Registered json paths to index per ns
@@ -34,6 +177,24 @@ struct Inner {
     // Leases: (ns,key) -> (owner, token, expires)
     leases: HashMap<(String, String), (String, u64, DateTime<Utc>)>,
     idem: HashMap<(String, String), super::traits::IdempotencyRecord>,
+    // Registered embedding fields: (ns, field) -> dims + coercion mode
+    vec_fields: HashMap<(String, String), VecField>,
+    // Composite tag indexes: registered sorted key sets per ns, and
+    // (ns, composite_value) -> ids where composite_value joins k=v over those keys
+    composite_keys: HashMap<String, Vec<Vec<String>>>,
+    composite_index: HashMap<(String, String), HashMap<String, ()>>,
+    // Write-time body transforms per ns, in registration order
+    transforms: HashMap<String, Vec<TransformRule>>,
+    // References: (ns, parent commit) -> ids with a version listing it in `parents`;
+    // may lag a later version that drops the reference, so hits are re-checked
+    child_index: HashMap<(String, String), HashMap<String, ()>>,
+    // HNSW graphs over registered embedding fields: (ns, field) -> latest vector per id
+    ann: HashMap<(String, String), Arc<RwLock<Hnsw>>>,
+    // Admin-set write quotas per ns, and each ns's usage counting latest versions only
+    quotas: HashMap<String, NsQuota>,
+    usage: HashMap<String, NsUsage>,
+    // Latest versions carrying a TTL, ordered by expiry: (expires_at, ns, id)
+    expiry_index: BTreeSet<(DateTime<Utc>, String, String)>,
 }
 
 #[derive(Clone, Default)]
@@ -42,25 +203,29 @@ struct WatchBuffer {
     cursor: Arc<RwLock<usize>>,  // consumer cursor
     bytes: Arc<RwLock<usize>>,   // approximate queued bytes
     overflow: Arc<RwLock<bool>>, // overflow flag
+    // Events the subscriber didn't ask for are never queued
+    filter: Arc<WatchFilter>,
+    // Fixed at subscribe time
+    limits: WatchLimits,
 }
 
 impl WatchBuffer {
     fn push(&self, ev: WatchEvent) {
-        let max_events = std::env::var("WATCH_BUFFER_EVENTS")
-            .ok()
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(10_000);
-        let max_bytes = std::env::var("WATCH_BUFFER_BYTES")
-            .ok()
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(64 * 1024 * 1024);
+        if !self.filter.matches(&ev) {
+            return;
+        }
         let approx = match &ev {
             WatchEvent::Put(o) => serde_json::to_vec(&o).map(|v| v.len()).unwrap_or(256),
             WatchEvent::Delete { .. } => 64,
         };
         let mut w = self.events.write();
         let mut b = self.bytes.write();
-        if *b + approx > max_bytes || w.len() + 1 > max_events {
+        // Once an event is dropped nothing more is queued, so the buffer stays a gap-free
+        // prefix that watch_pull can drain before resubscribing
+        if *self.overflow.read() {
+            return;
+        }
+        if *b + approx > self.limits.max_bytes || w.len() + 1 > self.limits.max_events {
             *self.overflow.write() = true;
             return;
         }
@@ -69,126 +234,180 @@ impl WatchBuffer {
     }
 }
 
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InMemoryStore {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(RwLock::new(Inner::default())),
+            access: Arc::new(Mutex::new(HashMap::new())),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            check_cursor: Arc::new(AtomicUsize::new(0)),
+            warming: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    fn is_expired(o: &Object, now: DateTime<Utc>) -> bool {
-        if let Some(ttl) = o.ttl_seconds {
-            o.ts + Duration::seconds(ttl as i64) < now
-        } else {
-            false
+    /// With ANN_WARMUP=1, runs synthetic searches over every HNSW graph so it's paged in
+    /// before serving; returns the number of searches. `ann_warming` is true meanwhile.
+    pub fn warm_ann(&self) -> usize {
+        let indexes: Vec<Arc<RwLock<Hnsw>>> = self.inner.read().ann.values().cloned().collect();
+        self.warm_indexes(&indexes)
+    }
+
+    pub fn ann_warming(&self) -> bool {
+        self.warming.load(Ordering::SeqCst) > 0
+    }
+
+    fn warm_indexes(&self, indexes: &[Arc<RwLock<Hnsw>>]) -> usize {
+        if !*ANN_WARMUP {
+            return 0;
         }
+        self.run_warm_up(indexes, *ANN_WARMUP_QUERIES)
     }
 
-    pub fn replay_put(&self, obj: Object) {
-        let mut inner = self.inner.write();
-        let key = (obj.ns.clone(), obj.id.clone());
-        inner.data.entry(key).or_default().push(obj.clone());
-        for (k, v) in obj.tags.0.iter() {
+    fn run_warm_up(&self, indexes: &[Arc<RwLock<Hnsw>>], queries: usize) -> usize {
+        self.warming.fetch_add(1, Ordering::SeqCst);
+        let ran = indexes.iter().map(|i| i.read().warm(queries)).sum();
+        self.warming.fetch_sub(1, Ordering::SeqCst);
+        ran
+    }
+
+    pub fn register_vec_field(&self, ns: &str, field: VecField) -> Result<()> {
+        if field.name.is_empty() || field.dims == 0 {
+            return Err(StateError::Invalid("vec field needs a name and dims > 0".into()));
+        }
+        let key = (ns.to_string(), field.name.clone());
+        let index = Arc::new(RwLock::new(Hnsw::new(field.dims)));
+        // Writes from here on go to the new index; the backfill below runs without the
+        // store lock and skips ids they touched
+        let existing: Vec<(String, serde_json::Value)> = {
+            let mut inner = self.inner.write();
+            inner.vec_fields.insert(key.clone(), field.clone());
+            inner.ann.insert(key, index.clone());
             inner
-                .tag_index
-                .entry((obj.ns.clone(), k.clone(), v.clone()))
-                .or_default()
-                .insert(obj.id.clone(), ());
+                .data
+                .iter()
+                .filter(|((n, _), _)| n == ns)
+                .filter_map(|((_, id), versions)| {
+                    let v = versions.last()?.body.get(&field.name)?;
+                    Some((id.clone(), v.clone()))
+                })
+                .collect()
+        };
+        for (id, v) in existing.iter() {
+            index.write().backfill(id, v);
         }
-        let paths = inner
-            .json_index_paths
-            .get(&obj.ns)
-            .cloned()
-            .unwrap_or_default();
-        drop(inner);
-        let mut inner = self.inner.write();
+        index.write().finish_build();
+        self.warm_indexes(std::slice::from_ref(&index));
+        Ok(())
+    }
+
+    pub fn register_index_paths(&self, ns: &str, paths: Vec<String>) -> Result<Vec<String>> {
+        let mut canonical = Vec::new();
         for p in paths.iter() {
-            if let Some(val) = obj.body.pointer(&json_pointer_from_path(p)) {
-                let key = (obj.ns.clone(), p.clone(), val.to_string());
-                inner
-                    .json_index
-                    .entry(key)
-                    .or_default()
-                    .insert(obj.id.clone(), ());
+            if !is_valid_json_path(p) {
+                return Err(StateError::Invalid(format!(
+                    "array steps must be [n] or [-]: {}",
+                    p
+                )));
+            }
+            let c = canonical_json_path(p);
+            if c == "$" {
+                return Err(StateError::Invalid("index path needs a body field".into()));
             }
+            canonical.push(c);
         }
-        inner
-            .commit_log
-            .entry(obj.ns.clone())
-            .or_default()
-            .push(WatchEvent::Put(obj));
-    }
-
-    pub fn replay_delete(&self, ns: &str, id: &str, commit_seq: u64) {
         let mut inner = self.inner.write();
-        let key = (ns.to_string(), id.to_string());
-        inner.data.remove(&key);
-        inner
-            .commit_log
-            .entry(ns.to_string())
-            .or_default()
-            .push(WatchEvent::Delete {
-                ns: ns.to_string(),
-                id: id.to_string(),
-                commit_seq,
-            });
-    }
-
-    pub fn all_objects(&self) -> Vec<Object> {
-        let inner = self.inner.read();
-        let mut out = Vec::new();
-        for ((_ns, _id), versions) in inner.data.iter() {
-            if let Some(v) = versions.last() {
-                out.push(v.clone());
+        let registered = inner.json_index_paths.entry(ns.to_string()).or_default();
+        let mut added = Vec::new();
+        for p in canonical {
+            if !registered.contains(&p) {
+                registered.push(p.clone());
+                added.push(p);
             }
         }
-        out
-    }
-
-    pub fn backlog_map(&self) -> std::collections::HashMap<String, usize> {
-        let inner = self.inner.read();
-        let mut map: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
-        for (ns, bufs) in inner.buffers.iter() {
-            let mut maxv = 0usize;
-            for b in bufs.iter() {
-                let c = *b.cursor.read();
-                let len = b.events.read().len();
-                let backlog = len.saturating_sub(c);
-                if backlog > maxv {
-                    maxv = backlog;
+        let all = registered.clone();
+        // Backfill from the latest version of every object in the ns
+        let mut entries = Vec::new();
+        for ((n, id), versions) in inner.data.iter() {
+            let Some(latest) = versions.last().filter(|_| n == ns) else {
+                continue;
+            };
+            for p in added.iter() {
+                if let Some(val) = value_at_path(&latest.body, p) {
+                    entries.push(((ns.to_string(), p.clone(), val.to_string()), id.clone()));
                 }
             }
-            map.insert(ns.clone(), maxv);
         }
-        map
+        for (key, id) in entries {
+            inner.json_index.entry(key).or_default().insert(id, ());
+        }
+        Ok(all)
     }
-}
 
-#[async_trait::async_trait]
-impl Storage for InMemoryStore {
-    async fn put(&self, ns: &str, req: PutRequest) -> Result<Object> {
-        let mut inner = self.inner.write();
-        let next = inner
-            .commit_seq
+    pub fn register_transform(&self, ns: &str, rule: TransformRule) -> Result<()> {
+        if transform_pointer(&rule.path).is_empty() {
+            return Err(StateError::Invalid("transform needs a body path".into()));
+        }
+        self.inner
+            .write()
+            .transforms
             .entry(ns.to_string())
-            .and_modify(|c| *c += 1)
-            .or_insert(1);
-        let commit_seq = *next;
-        let obj = Object::new_with_seq(ns.to_string(), req, commit_seq);
-        let key = (obj.ns.clone(), obj.id.clone());
-        inner.data.entry(key.clone()).or_default().push(obj.clone());
-        // maintain indexes
-        for (k, v) in obj.tags.0.iter() {
+            .or_default()
+            .push(rule);
+        Ok(())
+    }
+
+    pub fn register_composite_index(&self, ns: &str, mut keys: Vec<String>) -> Result<()> {
+        keys.sort();
+        keys.dedup();
+        if keys.len() < 2 || keys.iter().any(|k| k.is_empty()) {
+            return Err(StateError::Invalid(
+                "composite index needs at least two non-empty tag keys".into(),
+            ));
+        }
+        let mut inner = self.inner.write();
+        let registered = inner.composite_keys.entry(ns.to_string()).or_default();
+        if registered.contains(&keys) {
+            return Ok(());
+        }
+        registered.push(keys.clone());
+        // Backfill from the latest version of every object in the ns
+        let mut entries = Vec::new();
+        for ((n, id), versions) in inner.data.iter() {
+            if n != ns {
+                continue;
+            }
+            if let Some(cv) = versions.last().and_then(|v| composite_value(&keys, &v.tags.0)) {
+                entries.push((cv, id.clone()));
+            }
+        }
+        for (cv, id) in entries {
             inner
-                .tag_index
-                .entry((obj.ns.clone(), k.clone(), v.clone()))
+                .composite_index
+                .entry((ns.to_string(), cv))
                 .or_default()
-                .insert(obj.id.clone(), ());
+                .insert(id, ());
         }
+        Ok(())
+    }
+
+    // Maintain tag, composite and JSONPath indexes for a newly written version
+    fn index_object(inner: &mut Inner, obj: &Object) {
+        Self::unindex_previous(inner, obj);
+        Self::index_tags(inner, obj);
+        Self::index_composites(inner, obj);
+        Self::index_children(inner, obj);
+        Self::index_vectors(inner, obj);
+        Self::index_expiry(inner, obj);
         let paths_to_index = inner.json_index_paths.get(&obj.ns).cloned();
         if let Some(paths) = paths_to_index {
             for p in paths {
-                if let Some(val) = obj.body.pointer(&json_pointer_from_path(&p)) {
+                if let Some(val) = value_at_path(&obj.body, &p) {
                     let key = (obj.ns.clone(), p.clone(), val.to_string());
                     inner
                         .json_index
@@ -198,480 +417,2256 @@ impl Storage for InMemoryStore {
                 }
             }
         }
-        // fanout/log
-        let ev = WatchEvent::Put(obj.clone());
-        inner
-            .commit_log
-            .entry(obj.ns.clone())
-            .or_default()
-            .push(ev.clone());
-        // fanout to watchers for ns
-        if let Some(bufs) = inner.buffers.get_mut(&obj.ns) {
-            for b in bufs.iter() {
-                b.push(WatchEvent::Put(obj.clone()));
-            }
+    }
+
+    fn index_expiry(inner: &mut Inner, obj: &Object) {
+        if let Some(at) = obj.expires_at() {
+            inner
+                .expiry_index
+                .insert((at, obj.ns.clone(), obj.id.clone()));
         }
-        Ok(obj)
     }
 
-    async fn get(&self, ns: &str, id: &str, opts: crate::traits::GetOptions) -> Result<Object> {
-        let now = Utc::now();
-        let inner = self.inner.read();
-        let key = (ns.to_string(), id.to_string());
-        let versions = inner.data.get(&key).ok_or(StateError::NotFound)?;
-        let mut cand = None;
-        for v in versions.iter().rev() {
-            if let Some(at) = opts.at_ts {
-                if v.ts > at {
-                    continue;
-                }
-            }
-            if !Self::is_expired(v, now) {
-                cand = Some(v.clone());
-                break;
-            }
+    // Moves index entries and ns usage off the version `obj` just superseded, if any
+    fn unindex_previous(inner: &mut Inner, obj: &Object) {
+        let prev = inner
+            .data
+            .get(&(obj.ns.clone(), obj.id.clone()))
+            .and_then(|v| v.len().checked_sub(2).map(|i| v[i].clone()));
+        if let Some(prev) = prev.as_ref() {
+            Self::unindex_stale(inner, prev, Some(obj));
         }
-        cand.ok_or(StateError::NotFound)
+        let usage = inner.usage.entry(obj.ns.clone()).or_default();
+        *usage = usage_after(*usage, &[(prev.as_ref(), Some(obj))]);
     }
 
-    async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>> {
-        let now = Utc::now();
-        let inner = self.inner.read();
-        let mut candidate_ids: Option<HashMap<String, ()>> = None;
-        // tag index intersect
-        if let Some(tf) = &req.tag_filter {
-            for (k, v) in tf.0.iter() {
-                let key = (ns.to_string(), k.clone(), v.clone());
-                if let Some(ids) = inner.tag_index.get(&key) {
-                    candidate_ids = Some(match candidate_ids.take() {
-                        None => ids.clone(),
-                        Some(prev) => prev
-                            .into_iter()
-                            .filter(|(id, _)| ids.contains_key(id))
-                            .collect(),
-                    });
-                } else {
-                    return Ok(vec![]);
-                }
-            }
+    // Fails with `quota_exceeded` when replacing each `old` latest version with its `new`
+    // one (None: absent) would take the ns past a limit. A ns already over a lowered limit
+    // still takes writes that don't grow it.
+    fn check_quota(
+        inner: &Inner,
+        ns: &str,
+        changes: &[(Option<&Object>, Option<&Object>)],
+    ) -> Result<()> {
+        let Some(quota) = inner.quotas.get(ns) else {
+            return Ok(());
+        };
+        let before = inner.usage.get(ns).copied().unwrap_or_default();
+        let after = usage_after(before, changes);
+        let over = |max: Option<u64>, before: u64, after: u64| {
+            max.is_some_and(|m| after > m && after > before)
+        };
+        if over(quota.max_objects, before.objects, after.objects)
+            || over(quota.max_bytes, before.bytes, after.bytes)
+        {
+            let limit = |m: Option<u64>| m.map_or("none".to_string(), |m| m.to_string());
+            return Err(StateError::Invalid(format!(
+                "quota_exceeded: {} objects, {} bytes in use (max_objects {}, max_bytes {})",
+                before.objects,
+                before.bytes,
+                limit(quota.max_objects),
+                limit(quota.max_bytes)
+            )));
         }
-        // json index intersect
-        if let Some(jf) = &req.jsonpath {
-            for (p, val) in jf.equals.iter() {
-                let key = (ns.to_string(), p.clone(), val.to_string());
-                if let Some(ids) = inner.json_index.get(&key) {
-                    candidate_ids = Some(match candidate_ids.take() {
-                        None => ids.clone(),
-                        Some(prev) => prev
-                            .into_iter()
-                            .filter(|(id, _)| ids.contains_key(id))
-                            .collect(),
-                    });
-                } else {
-                    return Ok(vec![]);
+        Ok(())
+    }
+
+    // Drops the type, tag, composite and JSONPath entries of `old` that its successor
+    // `new` doesn't have; with no successor (the object is gone) all of them
+    fn unindex_stale(inner: &mut Inner, old: &Object, new: Option<&Object>) {
+        fn drop_id<K: std::hash::Hash + Eq>(
+            index: &mut HashMap<K, HashMap<String, ()>>,
+            key: K,
+            id: &str,
+        ) {
+            if let Some(ids) = index.get_mut(&key) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    index.remove(&key);
                 }
             }
         }
-        // Scan candidates or full ns
-        let mut out = Vec::new();
-        match candidate_ids {
-            Some(ids) => {
-                for (id, _) in ids.into_iter() {
-                    if let Some(versions) = inner.data.get(&(ns.to_string(), id.clone())) {
-                        if let Some(v) = versions.last() {
-                            if !Self::is_expired(v, now) {
-                                out.push(v.clone());
-                            }
-                        }
-                    }
-                }
+        let (ns, id) = (&old.ns, old.id.as_str());
+        // Only the latest version is indexed by expiry, and `new` adds its own entry
+        if let Some(at) = old.expires_at() {
+            inner.expiry_index.remove(&(at, ns.clone(), id.to_string()));
+        }
+        if new.is_none_or(|n| n.r#type != old.r#type) {
+            drop_id(&mut inner.type_index, (ns.clone(), old.r#type.clone()), id);
+        }
+        for (k, v) in old.tags.0.iter() {
+            let new_v = new.and_then(|n| n.tags.0.get(k));
+            if new_v != Some(v) {
+                drop_id(&mut inner.tag_index, (ns.clone(), k.clone(), v.clone()), id);
             }
-            None => {
-                for ((n, _id), versions) in inner.data.iter() {
-                    if n != ns {
-                        continue;
-                    }
-                    if let Some(v) = versions.last() {
-                        if !Self::is_expired(v, now) {
-                            out.push(v.clone());
-                        }
-                    }
-                }
+            if new_v.is_none() {
+                drop_id(&mut inner.tag_key_index, (ns.clone(), k.clone()), id);
             }
         }
-        // Vector ANN naive filter over out
-        if let Some(vq) = &req.vector {
-            let _timer = VECTOR_QUERY_SECONDS
-                .with_label_values(&[&vq.field])
-                .start_timer();
-            let mut scored: Vec<(f32, Object)> = Vec::new();
-            for o in out.into_iter() {
-                if let Some(vec_val) = o.body.get(&vq.field).and_then(|v| v.as_array()) {
-                    let v: Vec<f32> = vec_val
-                        .iter()
-                        .filter_map(|x| x.as_f64().map(|f| f as f32))
-                        .collect();
-                    if v.len() == vq.embedding.len() {
-                        let s = cosine_sim(&v, &vq.embedding);
-                        scored.push((s, o));
-                    }
-                }
-            }
-            scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
-            let top_k = vq.top_k.min(scored.len());
-            let mut res = Vec::with_capacity(top_k);
-            for i in 0..top_k {
-                res.push(scored[i].1.clone());
+        let composites: Vec<String> = inner
+            .composite_keys
+            .get(ns)
+            .into_iter()
+            .flatten()
+            .filter_map(|keys| {
+                let cv = composite_value(keys, &old.tags.0)?;
+                let kept = new.and_then(|n| composite_value(keys, &n.tags.0));
+                (kept.as_ref() != Some(&cv)).then_some(cv)
+            })
+            .collect();
+        for cv in composites {
+            drop_id(&mut inner.composite_index, (ns.clone(), cv), id);
+        }
+        let paths = inner.json_index_paths.get(ns).cloned().unwrap_or_default();
+        for p in paths {
+            let ptr = json_pointer_from_path(&p);
+            let Some(val) = value_at_pointer(&old.body, &ptr) else {
+                continue;
+            };
+            if new.and_then(|n| value_at_pointer(&n.body, &ptr)) != Some(val) {
+                drop_id(&mut inner.json_index, (ns.clone(), p, val.to_string()), id);
             }
-            return Ok(res);
         }
-        if let Some(l) = req.limit {
-            out.truncate(l);
+    }
+
+    fn index_tags(inner: &mut Inner, obj: &Object) {
+        inner
+            .type_index
+            .entry((obj.ns.clone(), obj.r#type.clone()))
+            .or_default()
+            .insert(obj.id.clone(), ());
+        for (k, v) in obj.tags.0.iter() {
+            inner
+                .tag_index
+                .entry((obj.ns.clone(), k.clone(), v.clone()))
+                .or_default()
+                .insert(obj.id.clone(), ());
+            inner
+                .tag_key_index
+                .entry((obj.ns.clone(), k.clone()))
+                .or_default()
+                .insert(obj.id.clone(), ());
         }
-        Ok(out)
     }
 
-    async fn delete(&self, ns: &str, id: &str) -> Result<()> {
-        let mut inner = self.inner.write();
-        let key = (ns.to_string(), id.to_string());
-        let existed = inner.data.remove(&key).is_some();
-        if existed {
-            let seq = inner
-                .commit_seq
-                .entry(ns.to_string())
-                .and_modify(|c| *c += 1)
-                .or_insert(1);
-            let commit_seq = *seq;
-            let ev = WatchEvent::Delete {
-                ns: ns.to_string(),
-                id: id.to_string(),
-                commit_seq,
-            };
+    fn index_children(inner: &mut Inner, obj: &Object) {
+        for p in obj.parents.iter() {
             inner
-                .commit_log
-                .entry(ns.to_string())
+                .child_index
+                .entry((obj.ns.clone(), p.clone()))
                 .or_default()
-                .push(ev.clone());
-            if let Some(bufs) = inner.buffers.get_mut(ns) {
-                for b in bufs.iter() {
-                    b.push(ev.clone());
-                }
+                .insert(obj.id.clone(), ());
+        }
+    }
+
+    fn index_vectors(inner: &Inner, obj: &Object) {
+        for ((n, field), index) in inner.ann.iter() {
+            if *n == obj.ns {
+                index.write().upsert(&obj.id, obj.body.get(field));
             }
-            Ok(())
-        } else {
-            Err(StateError::NotFound)
         }
     }
 
-    fn subscribe(
-        &self,
-        filter: WatchFilter,
-        from_commit: Option<u64>,
-    ) -> Box<dyn crate::traits::WatchHandle> {
-        let mut inner = self.inner.write();
-        let buf = WatchBuffer::default();
-        // Prime the buffer with backlog since from_commit
-        if let Some(from) = from_commit {
-            if let Some(log) = inner.commit_log.get(&filter.ns) {
-                for ev in log.iter() {
-                    let c = match ev {
-                        WatchEvent::Put(o) => o.commit_seq,
-                        WatchEvent::Delete { commit_seq, .. } => *commit_seq,
-                    };
-                    if c > from {
-                        buf.push(ev.clone());
+    fn unindex_vectors(inner: &Inner, ns: &str, id: &str) {
+        for ((n, _), index) in inner.ann.iter() {
+            if n == ns {
+                index.write().remove(id);
+            }
+        }
+    }
+
+    fn index_composites(inner: &mut Inner, obj: &Object) {
+        let Some(sets) = inner.composite_keys.get(&obj.ns) else {
+            return;
+        };
+        let values: Vec<String> = sets
+            .iter()
+            .filter_map(|keys| composite_value(keys, &obj.tags.0))
+            .collect();
+        for cv in values {
+            inner
+                .composite_index
+                .entry((obj.ns.clone(), cv))
+                .or_default()
+                .insert(obj.id.clone(), ());
+        }
+    }
+
+    pub(crate) fn is_expired(o: &Object, now: DateTime<Utc>) -> bool {
+        o.expires_at().is_some_and(|at| at < now)
+    }
+
+    // Counts a get/query hit on each object
+    fn record_access(&self, objs: &[Object]) {
+        if objs.is_empty() {
+            return;
+        }
+        let now = Utc::now();
+        let mut access = self.access.lock();
+        for o in objs {
+            access
+                .entry((o.ns.clone(), o.id.clone()))
+                .and_modify(|a| {
+                    a.access_count += 1;
+                    a.last_access = now;
+                })
+                .or_insert(AccessStats {
+                    access_count: 1,
+                    last_access: now,
+                });
+        }
+    }
+
+    // Removes an object's versions and drops its id from every index, the read counters
+    // and the advisory locks
+    fn remove_object(&self, inner: &mut Inner, ns: &str, id: &str) -> Option<Vec<Object>> {
+        let key = (ns.to_string(), id.to_string());
+        self.access.lock().remove(&key);
+        self.locks.lock().remove(&key);
+        let versions = inner.data.remove(&key)?;
+        Self::unindex_vectors(inner, ns, id);
+        if let Some(latest) = versions.last() {
+            Self::unindex_stale(inner, latest, None);
+            let usage = inner.usage.entry(ns.to_string()).or_default();
+            *usage = usage_after(*usage, &[(Some(latest), None)]);
+        }
+        for o in versions.iter() {
+            if let Some(ids) = inner.type_index.get_mut(&(ns.to_string(), o.r#type.clone())) {
+                ids.remove(id);
+            }
+            for p in o.parents.iter() {
+                let k = (ns.to_string(), p.clone());
+                if let Some(ids) = inner.child_index.get_mut(&k) {
+                    ids.remove(id);
+                    if ids.is_empty() {
+                        inner.child_index.remove(&k);
                     }
                 }
             }
         }
-        inner
-            .buffers
-            .entry(filter.ns.clone())
-            .or_default()
-            .push(buf.clone());
-        Box::new(MemWatch {
-            buf,
-            ns: filter.ns,
-            last_commit: from_commit.unwrap_or(0),
+        Some(versions)
+    }
+
+    // The checks a put's request must pass on its own, before it is staged
+    fn check_put(req: &PutRequest, mode: PutMode) -> Result<()> {
+        match mode {
+            PutMode::IfAbsent if req.id.is_none() => {
+                return Err(StateError::Invalid("put_if_absent needs an id".into()))
+            }
+            PutMode::IfChanged if req.id.is_none() => {
+                return Err(StateError::Invalid("only_if_changed needs an id".into()))
+            }
+            _ => {}
+        }
+        check_tag_limits(&req.tags)?;
+        check_classification(req)?;
+        check_body_depth(&req.body)?;
+        req.check_content()
+    }
+
+    // The last steps of staging any write, on the body as it will be stored but still in
+    // plaintext: the id, the validation hook, and only then sealing
+    fn finish_staged(ns: &str, req: &mut PutRequest, now: DateTime<Utc>) -> Result<()> {
+        assign_id(req, now);
+        crate::validator::check(ns, req)?;
+        crate::crypt::seal(ns, &mut req.body)
+    }
+
+    // Works out the version a put would write without changing anything. With IfAbsent
+    // or IfChanged the current version may make it a no-op; it comes back with false.
+    fn stage_put_in(
+        inner: &Inner,
+        ns: &str,
+        mut req: PutRequest,
+        mode: PutMode,
+    ) -> Result<(Object, bool)> {
+        let now = Utc::now();
+        let current = req
+            .id
+            .as_ref()
+            .and_then(|id| inner.data.get(&(ns.to_string(), id.clone())))
+            .and_then(|v| v.last())
+            .filter(|o| !Self::is_expired(o, now));
+        if let (PutMode::IfAbsent, Some(o)) = (mode, current) {
+            return Ok((o.clone(), false));
+        }
+        apply_transforms(&inner.transforms, ns, &mut req.body);
+        conform_vectors(&inner.vec_fields, ns, &mut req.body)?;
+        // Compared as it would be stored after transforms, but before sealing
+        if let (PutMode::IfChanged, Some(o)) = (mode, current) {
+            if crate::crypt::same_plaintext(ns, &o.body, &req.body)
+                && o.tags.0 == req.tags.0
+                && o.content_type == req.content_type
+            {
+                return Ok((o.clone(), false));
+            }
+        }
+        if let (Some(ts), Some(id)) = (req.ts, req.id.as_ref()) {
+            if let Some(prev) = inner
+                .data
+                .get(&(ns.to_string(), id.clone()))
+                .and_then(|v| v.last())
+            {
+                check_clock_skew(ts, prev)?;
+            }
+        }
+        Self::finish_staged(ns, &mut req, now)?;
+        let commit_seq = inner.commit_seq.get(ns).copied().unwrap_or(0) + 1;
+        let obj = Object::new_with_seq(ns.to_string(), req, commit_seq);
+        let prev = inner
+            .data
+            .get(&(obj.ns.clone(), obj.id.clone()))
+            .and_then(|v| v.last());
+        Self::check_quota(inner, ns, &[(prev, Some(&obj))])?;
+        Ok((obj, true))
+    }
+
+    // A delete of an object that exists, as the event it would commit
+    fn stage_delete_in(inner: &Inner, ns: &str, id: &str) -> Result<WatchEvent> {
+        if !inner.data.contains_key(&(ns.to_string(), id.to_string())) {
+            return Err(StateError::NotFound);
+        }
+        Ok(WatchEvent::Delete {
+            ns: ns.to_string(),
+            id: id.to_string(),
+            commit_seq: inner.commit_seq.get(ns).copied().unwrap_or(0) + 1,
         })
     }
 
-    async fn sweep_expired(&self, _retention_secs: u64) -> Result<u64> {
+    // Applies staged events in order and fans each one out; the ns counter moves up to
+    // the events' seqs. Callers hold the write lock from staging on.
+    fn commit_in(&self, inner: &mut Inner, events: &[WatchEvent]) {
+        for ev in events {
+            let (ns, commit_seq) = match ev {
+                WatchEvent::Put(obj) => {
+                    let key = (obj.ns.clone(), obj.id.clone());
+                    inner.data.entry(key).or_default().push(obj.clone());
+                    Self::index_object(inner, obj);
+                    Self::cap_versions_in(inner, &obj.ns, &obj.id);
+                    (&obj.ns, obj.commit_seq)
+                }
+                WatchEvent::Delete { ns, id, commit_seq } => {
+                    self.remove_object(inner, ns, id);
+                    (ns, *commit_seq)
+                }
+            };
+            let seq = inner.commit_seq.entry(ns.clone()).or_insert(0);
+            *seq = (*seq).max(commit_seq);
+            Self::fanout(inner, ns, ev.clone());
+        }
+    }
+
+    // A rename as the old id's tombstone followed by the new id's first version
+    fn stage_rename_in(
+        inner: &Inner,
+        ns: &str,
+        id: &str,
+        new_id: &str,
+    ) -> Result<Vec<WatchEvent>> {
+        if new_id.is_empty() || new_id == id {
+            return Err(StateError::Invalid("new_id must be non-empty and differ from id".into()));
+        }
         let now = Utc::now();
-        let mut objects_to_cleanup = Vec::new();
-        
-        // First pass: identify and remove expired objects
-        {
-            let mut inner = self.inner.write();
-            let keys: Vec<(String, String)> = inner.data.keys().cloned().collect();
-            for k in keys {
-                if let Some(vec) = inner.data.get_mut(&k) {
-                    if let Some(last) = vec.last() {
-                        if last
-                            .ttl_seconds
-                            .map(|t| last.ts + Duration::seconds(t as i64) < now)
-                            .unwrap_or(false)
-                        {
-                            if let Some(dead) = vec.last().cloned() {
-                                inner.data.remove(&k);
-                                objects_to_cleanup.push(dead);
-                            }
+        if inner.data.contains_key(&(ns.to_string(), new_id.to_string())) {
+            return Err(StateError::Conflict(format!("object {} already exists", new_id)));
+        }
+        let cur = inner
+            .data
+            .get(&(ns.to_string(), id.to_string()))
+            .and_then(|v| v.last())
+            .filter(|o| !Self::is_expired(o, now))
+            .cloned()
+            .ok_or(StateError::NotFound)?;
+        let put_seq = inner.commit_seq.get(ns).copied().unwrap_or(0) + 2;
+        let del = WatchEvent::Delete {
+            ns: ns.to_string(),
+            id: id.to_string(),
+            commit_seq: put_seq - 1,
+        };
+        let obj = Object::new_with_seq(
+            ns.to_string(),
+            PutRequest {
+                r#type: cur.r#type,
+                body: cur.body,
+                tags: cur.tags,
+                ttl_seconds: cur.ttl_seconds,
+                id: Some(new_id.to_string()),
+                parents: vec![cur.commit],
+                ts: None,
+                only_if_changed: false,
+                content_type: cur.content_type,
+            },
+            put_seq,
+        );
+        Ok(vec![del, WatchEvent::Put(obj)])
+    }
+
+    // Plans every op of a transaction against the store plus the earlier ops, so a
+    // failing op leaves no trace; the events are committed together
+    fn stage_txn_in(inner: &Inner, ns: &str, ops: Vec<TxnOp>) -> Result<Vec<WatchEvent>> {
+        let max_ops = *MAX_TXN_OPS;
+        if ops.is_empty() || ops.len() > max_ops {
+            return Err(StateError::Invalid(format!(
+                "a transaction takes 1 to {} ops",
+                max_ops
+            )));
+        }
+        let now = Utc::now();
+        let base = inner.commit_seq.get(ns).copied().unwrap_or(0);
+        let mut staged: HashMap<String, Option<Object>> = HashMap::new();
+        let mut events = Vec::with_capacity(ops.len());
+        for (i, op) in ops.into_iter().enumerate() {
+            let commit_seq = base + i as u64 + 1;
+            let current = |staged: &HashMap<String, Option<Object>>, id: &str| {
+                staged.get(id).cloned().unwrap_or_else(|| {
+                    inner
+                        .data
+                        .get(&(ns.to_string(), id.to_string()))
+                        .and_then(|v| v.last())
+                        .filter(|o| !Self::is_expired(o, now))
+                        .cloned()
+                })
+            };
+            let op_err = |e: StateError| match e {
+                StateError::Invalid(m) => StateError::Invalid(format!("op {}: {}", i, m)),
+                e => e,
+            };
+            let ev = match op {
+                TxnOp::Put(mut req) => {
+                    // The same checks as put, on the request as sent
+                    Self::check_put(&req, PutMode::Always).map_err(op_err)?;
+                    apply_transforms(&inner.transforms, ns, &mut req.body);
+                    conform_vectors(&inner.vec_fields, ns, &mut req.body).map_err(op_err)?;
+                    if let (Some(ts), Some(id)) = (req.ts, req.id.as_ref()) {
+                        if let Some(prev) = current(&staged, id) {
+                            check_clock_skew(ts, &prev).map_err(op_err)?;
                         }
                     }
+                    Self::finish_staged(ns, &mut req, now).map_err(op_err)?;
+                    let obj = Object::new_with_seq(ns.to_string(), req, commit_seq);
+                    staged.insert(obj.id.clone(), Some(obj.clone()));
+                    WatchEvent::Put(obj)
                 }
+                TxnOp::Delete { id } => {
+                    if current(&staged, &id).is_none() {
+                        return Err(StateError::Conflict(format!("op {}: {} not found", i, id)));
+                    }
+                    staged.insert(id.clone(), None);
+                    WatchEvent::Delete {
+                        ns: ns.to_string(),
+                        id,
+                        commit_seq,
+                    }
+                }
+                TxnOp::Patch { id, mut patch } => {
+                    let Some(cur) = current(&staged, &id) else {
+                        return Err(StateError::Conflict(format!("op {}: {} not found", i, id)));
+                    };
+                    // Only fields the patch sets are transformed; the rest already were
+                    apply_transforms(&inner.transforms, ns, &mut patch);
+                    // Merged in plaintext, so the validator sees what a put would show it;
+                    // the whole body is sealed again below
+                    let mut body = cur.body;
+                    crate::crypt::open_body(ns, &mut body);
+                    agentstate_core::util::merge_patch(&mut body, &patch);
+                    check_body_depth(&body).map_err(op_err)?;
+                    conform_vectors(&inner.vec_fields, ns, &mut body).map_err(op_err)?;
+                    let mut req = PutRequest {
+                        r#type: cur.r#type,
+                        body,
+                        tags: cur.tags,
+                        ttl_seconds: cur.ttl_seconds,
+                        id: Some(id.clone()),
+                        parents: vec![cur.commit],
+                        ts: None,
+                        only_if_changed: false,
+                        content_type: cur.content_type,
+                    };
+                    req.check_content().map_err(op_err)?;
+                    Self::finish_staged(ns, &mut req, now).map_err(op_err)?;
+                    let obj = Object::new_with_seq(ns.to_string(), req, commit_seq);
+                    staged.insert(id, Some(obj.clone()));
+                    WatchEvent::Put(obj)
+                }
+            };
+            events.push(ev);
+        }
+        let changes: Vec<_> = staged
+            .iter()
+            .map(|(id, new)| {
+                let old = inner.data.get(&(ns.to_string(), id.clone())).and_then(|v| v.last());
+                (old, new.as_ref())
+            })
+            .collect();
+        Self::check_quota(inner, ns, &changes)?;
+        Ok(events)
+    }
+
+    /// Stages a put without applying it: the version it would write, or the current one
+    /// when `mode` makes it a no-op (false). Pass the version to [`Self::commit_staged`].
+    pub(crate) fn stage_put(
+        &self,
+        ns: &str,
+        req: PutRequest,
+        mode: PutMode,
+    ) -> Result<(Object, bool)> {
+        Self::check_put(&req, mode)?;
+        Self::stage_put_in(&self.inner.read(), ns, req, mode)
+    }
+
+    pub(crate) fn stage_delete(&self, ns: &str, id: &str) -> Result<WatchEvent> {
+        Self::stage_delete_in(&self.inner.read(), ns, id)
+    }
+
+    pub(crate) fn stage_rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Vec<WatchEvent>> {
+        Self::stage_rename_in(&self.inner.read(), ns, id, new_id)
+    }
+
+    /// How many versions a trim asking for `keep` actually keeps, and how many it drops.
+    pub(crate) fn stage_trim(&self, ns: &str, id: &str, keep: usize) -> Result<(usize, usize)> {
+        let cutoff = Utc::now() - *TIME_TRAVEL_RETENTION;
+        Self::stage_trim_in(&self.inner.read(), ns, id, keep, cutoff)
+    }
+
+    // At least `keep`, and never a version written after `cutoff`, nor the one a read at
+    // `cutoff` resolves to
+    fn stage_trim_in(
+        inner: &Inner,
+        ns: &str,
+        id: &str,
+        keep: usize,
+        cutoff: DateTime<Utc>,
+    ) -> Result<(usize, usize)> {
+        if keep == 0 {
+            return Err(StateError::Invalid("keep must be at least 1".into()));
+        }
+        let versions = inner
+            .data
+            .get(&(ns.to_string(), id.to_string()))
+            .ok_or(StateError::NotFound)?;
+        let retained = versions.iter().rev().take_while(|o| o.ts > cutoff).count();
+        let retained = if retained > 0 { retained + 1 } else { 0 };
+        let keep = keep.max(retained).min(versions.len());
+        Ok((keep, versions.len() - keep))
+    }
+
+    pub(crate) fn stage_txn(&self, ns: &str, ops: Vec<TxnOp>) -> Result<Vec<WatchEvent>> {
+        Self::stage_txn_in(&self.inner.read(), ns, ops)
+    }
+
+    /// Applies events staged earlier. Staging and committing aren't atomic: the caller
+    /// keeps other writes out in between.
+    pub(crate) fn commit_staged(&self, events: &[WatchEvent]) {
+        self.commit_in(&mut self.inner.write(), events);
+    }
+
+    // The whole put under one write lock
+    fn put_mode(&self, ns: &str, req: PutRequest, mode: PutMode) -> Result<(Object, bool)> {
+        Self::check_put(&req, mode)?;
+        let mut inner = self.inner.write();
+        let (obj, new) = Self::stage_put_in(&inner, ns, req, mode)?;
+        if new {
+            self.commit_in(&mut inner, &[WatchEvent::Put(obj.clone())]);
+        }
+        Ok((obj, new))
+    }
+
+    pub fn replay_put(&self, obj: Object) {
+        let mut inner = self.inner.write();
+        let seq = inner.commit_seq.entry(obj.ns.clone()).or_insert(0);
+        *seq = (*seq).max(obj.commit_seq);
+        let key = (obj.ns.clone(), obj.id.clone());
+        inner.data.entry(key).or_default().push(obj.clone());
+        Self::index_object(&mut inner, &obj);
+        Self::cap_versions_in(&mut inner, &obj.ns, &obj.id);
+        let ns = obj.ns.clone();
+        Self::fanout(&mut inner, &ns, WatchEvent::Put(obj));
+    }
+
+    /// Records a write committed by another engine and fans it out to watchers.
+    pub fn publish_put(&self, obj: &Object) {
+        let mut inner = self.inner.write();
+        let seq = inner.commit_seq.entry(obj.ns.clone()).or_insert(0);
+        *seq = (*seq).max(obj.commit_seq);
+        Self::fanout(&mut inner, &obj.ns, WatchEvent::Put(obj.clone()));
+    }
+
+    /// Records a delete committed by another engine at `commit_seq` and fans it out.
+    pub fn publish_delete(&self, ns: &str, id: &str, commit_seq: u64) {
+        let mut inner = self.inner.write();
+        let seq = inner.commit_seq.entry(ns.to_string()).or_insert(0);
+        *seq = (*seq).max(commit_seq);
+        let ev = WatchEvent::Delete {
+            ns: ns.to_string(),
+            id: id.to_string(),
+            commit_seq,
+        };
+        Self::fanout(&mut inner, ns, ev);
+    }
+
+    fn fanout(inner: &mut Inner, ns: &str, ev: WatchEvent) {
+        inner
+            .commit_log
+            .entry(ns.to_string())
+            .or_default()
+            .push(ev.clone());
+        if let Some(bufs) = inner.buffers.get(ns) {
+            for b in bufs.iter() {
+                b.push(ev.clone());
             }
-        } // inner lock is dropped here
-        
-        // Second pass: cleanup indexes (can be async)
-        let removed = objects_to_cleanup.len() as u64;
-        for obj in objects_to_cleanup {
-            self.cleanup_indexes_for(&obj).await;
         }
-        
-        Ok(removed)
     }
 
-    async fn lease_acquire(
+    /// Drops all but the newest `keep` versions of an object; returns how many were dropped.
+    pub fn drop_old_versions(&self, ns: &str, id: &str, keep: usize) -> usize {
+        Self::drop_old_versions_in(&mut self.inner.write(), ns, id, keep)
+    }
+
+    fn drop_old_versions_in(inner: &mut Inner, ns: &str, id: &str, keep: usize) -> usize {
+        match inner.data.get_mut(&(ns.to_string(), id.to_string())) {
+            Some(versions) if versions.len() > keep => {
+                let n = versions.len() - keep;
+                versions.drain(..n);
+                n
+            }
+            _ => 0,
+        }
+    }
+
+    // Once the newest version is indexed, so dropping the oldest can't strand an entry
+    fn cap_versions_in(inner: &mut Inner, ns: &str, id: &str) {
+        let max = *MAX_VERSIONS_PER_OBJECT;
+        if max > 0 {
+            Self::drop_old_versions_in(inner, ns, id, max);
+        }
+    }
+
+    pub fn replay_delete(&self, ns: &str, id: &str, commit_seq: u64) {
+        let mut inner = self.inner.write();
+        let seq = inner.commit_seq.entry(ns.to_string()).or_insert(0);
+        *seq = (*seq).max(commit_seq);
+        self.remove_object(&mut inner, ns, id);
+        let ev = WatchEvent::Delete {
+            ns: ns.to_string(),
+            id: id.to_string(),
+            commit_seq,
+        };
+        Self::fanout(&mut inner, ns, ev);
+    }
+
+    /// Restores a lease from an acquire or renew record. Records older than the lease
+    /// already held are ignored, and one that has since expired just clears the key.
+    pub fn replay_lease(
         &self,
         ns: &str,
         key: &str,
         owner: &str,
+        token: u64,
+        expires_at: DateTime<Utc>,
+    ) {
+        Self::replay_lease_in(&mut self.inner.write(), ns, key, owner, token, expires_at);
+    }
+
+    fn replay_lease_in(
+        inner: &mut Inner,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        token: u64,
+        expires_at: DateTime<Utc>,
+    ) {
+        // Tokens come from the ns commit counter; never hand one out twice
+        let seq = inner.commit_seq.entry(ns.to_string()).or_insert(0);
+        *seq = (*seq).max(token);
+        let k = (ns.to_string(), key.to_string());
+        if inner.leases.get(&k).is_some_and(|(_, cur, _)| *cur > token) {
+            return;
+        }
+        if expires_at <= Utc::now() {
+            inner.leases.remove(&k);
+        } else {
+            inner.leases.insert(k, (owner.to_string(), token, expires_at));
+        }
+    }
+
+    pub fn replay_lease_release(&self, ns: &str, key: &str, token: u64) {
+        Self::replay_lease_release_in(&mut self.inner.write(), ns, key, token);
+    }
+
+    fn replay_lease_release_in(inner: &mut Inner, ns: &str, key: &str, token: u64) {
+        let k = (ns.to_string(), key.to_string());
+        if inner.leases.get(&k).is_some_and(|(_, cur, _)| *cur == token) {
+            inner.leases.remove(&k);
+        }
+    }
+
+    // The lease an acquire would grant, with the next token from the ns commit counter;
+    // Conflict while another owner holds a live one
+    fn stage_lease_acquire_in(
+        inner: &Inner,
+        ns: &str,
+        key: &str,
+        owner: &str,
         ttl_secs: u64,
     ) -> Result<crate::traits::Lease> {
-        let mut inner = self.inner.write();
         let now = Utc::now();
-        let expires = now + Duration::seconds(ttl_secs as i64);
-        let entry = inner
-            .leases
-            .get(&(ns.to_string(), key.to_string()))
-            .cloned();
-        let token = {
-            let token_ref = inner
-                .commit_seq
-                .entry(ns.to_string())
-                .and_modify(|c| *c += 1)
-                .or_insert(1);
-            *token_ref
-        };
-        if let Some((cur_owner, _tok, cur_exp)) = entry {
-            if cur_exp > now && cur_owner != owner {
+        if let Some((cur_owner, _, cur_exp)) = inner.leases.get(&(ns.to_string(), key.to_string()))
+        {
+            if *cur_exp > now && cur_owner != owner {
                 return Err(StateError::Conflict("lease held".into()));
             }
         }
-        inner.leases.insert(
-            (ns.to_string(), key.to_string()),
-            (owner.to_string(), token, expires),
-        );
         Ok(crate::traits::Lease {
             ns: ns.to_string(),
             key: key.to_string(),
             owner: owner.to_string(),
-            token: token,
-            expires_at: expires,
+            token: inner.commit_seq.get(ns).copied().unwrap_or(0) + 1,
+            expires_at: now + Duration::seconds(ttl_secs as i64),
         })
     }
 
-    async fn lease_renew(
-        &self,
+    // The held lease extended by `ttl_secs` from now; Conflict unless owner and token match
+    fn stage_lease_renew_in(
+        inner: &Inner,
         ns: &str,
         key: &str,
         owner: &str,
         token: u64,
         ttl_secs: u64,
     ) -> Result<crate::traits::Lease> {
-        let mut inner = self.inner.write();
-        let now = Utc::now();
-        let expires = now + Duration::seconds(ttl_secs as i64);
-        match inner.leases.get_mut(&(ns.to_string(), key.to_string())) {
-            Some((cur_owner, cur_tok, cur_exp)) => {
-                if *cur_owner != owner || *cur_tok != token {
-                    return Err(StateError::Conflict("fencing".into()));
-                }
-                *cur_exp = expires;
+        match inner.leases.get(&(ns.to_string(), key.to_string())) {
+            Some((cur_owner, cur_tok, _)) if cur_owner == owner && *cur_tok == token => {
                 Ok(crate::traits::Lease {
                     ns: ns.to_string(),
                     key: key.to_string(),
                     owner: owner.to_string(),
                     token,
-                    expires_at: expires,
+                    expires_at: Utc::now() + Duration::seconds(ttl_secs as i64),
                 })
             }
+            Some(_) => Err(StateError::Conflict("fencing".into())),
             None => Err(StateError::NotFound),
         }
     }
 
-    async fn lease_release(&self, ns: &str, key: &str, owner: &str, token: u64) -> Result<()> {
-        let mut inner = self.inner.write();
-        match inner.leases.remove(&(ns.to_string(), key.to_string())) {
-            Some((cur_owner, cur_tok, _)) if cur_owner == owner && cur_tok == token => Ok(()),
-            Some((cur_owner, cur_tok, exp)) => {
-                inner
-                    .leases
-                    .insert((ns.to_string(), key.to_string()), (cur_owner, cur_tok, exp));
-                Err(StateError::Conflict("fencing".into()))
-            }
+    fn stage_lease_release_in(
+        inner: &Inner,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        token: u64,
+    ) -> Result<()> {
+        match inner.leases.get(&(ns.to_string(), key.to_string())) {
+            Some((cur_owner, cur_tok, _)) if cur_owner == owner && *cur_tok == token => Ok(()),
+            Some(_) => Err(StateError::Conflict("fencing".into())),
             None => Err(StateError::NotFound),
         }
     }
 
-    async fn idempotency_lookup(
+    // Installs a staged acquire or renew
+    fn commit_lease_in(inner: &mut Inner, lease: &crate::traits::Lease) {
+        let k = (lease.ns.clone(), lease.key.clone());
+        let seq = inner.commit_seq.entry(lease.ns.clone()).or_insert(0);
+        *seq = (*seq).max(lease.token);
+        inner
+            .leases
+            .insert(k, (lease.owner.clone(), lease.token, lease.expires_at));
+    }
+
+    /// Stages a lease acquire without applying it; pass the lease to [`Self::commit_lease`].
+    pub(crate) fn stage_lease_acquire(
         &self,
         ns: &str,
         key: &str,
-        body_hash: &str,
-    ) -> Result<Option<super::traits::IdempotencyRecord>> {
-        let inner = self.inner.read();
-        if let Some(r) = inner.idem.get(&(ns.to_string(), key.to_string())) {
-            if r.body_hash == body_hash {
-                return Ok(Some(r.clone()));
-            } else {
-                return Err(StateError::Conflict("idempotency body mismatch".into()));
-            }
-        }
-        Ok(None)
+        owner: &str,
+        ttl_secs: u64,
+    ) -> Result<crate::traits::Lease> {
+        Self::stage_lease_acquire_in(&self.inner.read(), ns, key, owner, ttl_secs)
     }
-    async fn idempotency_commit(
+
+    pub(crate) fn stage_lease_renew(
         &self,
         ns: &str,
         key: &str,
-        body_hash: &str,
-        response: serde_json::Value,
-        commit_seq: u64,
-        expires_at: DateTime<Utc>,
+        owner: &str,
+        token: u64,
+        ttl_secs: u64,
+    ) -> Result<crate::traits::Lease> {
+        Self::stage_lease_renew_in(&self.inner.read(), ns, key, owner, token, ttl_secs)
+    }
+
+    pub(crate) fn stage_lease_release(
+        &self,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        token: u64,
     ) -> Result<()> {
-        let resp_hash =
-            agentstate_core::util::blake3_hex(serde_json::to_string(&response).unwrap().as_bytes());
-        let rec = super::traits::IdempotencyRecord {
-            ns: ns.to_string(),
-            key: key.to_string(),
-            body_hash: body_hash.to_string(),
-            response_hash: resp_hash,
-            commit_seq,
-            expires_at,
-            response,
-        };
-        self.inner
-            .write()
-            .idem
-            .insert((ns.to_string(), key.to_string()), rec);
-        Ok(())
+        Self::stage_lease_release_in(&self.inner.read(), ns, key, owner, token)
     }
-    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()> {
-        let inner = self.inner.read();
-        if let Some((_, tok, exp)) = inner.leases.get(&(ns.to_string(), resource.to_string())) {
-            if *tok == fence && *exp > Utc::now() {
-                return Ok(());
-            }
+
+    /// Applies a lease staged earlier. As with [`Self::commit_staged`], the caller keeps
+    /// other writes out in between.
+    pub(crate) fn commit_lease(&self, lease: &crate::traits::Lease) {
+        Self::commit_lease_in(&mut self.inner.write(), lease);
+    }
+
+    // Metadata stage of a query: index intersection, scan and non-vector filters
+    async fn run_query(
+        &self,
+        ns: &str,
+        req: &QueryRequest,
+        trace: &mut QueryTrace,
+    ) -> Result<Vec<Object>> {
+        req.check_any_of_depth()?;
+        let mut out = self.select(ns, req, trace)?;
+        if let Some(want) = req.locked {
+            let now = Utc::now();
+            let locks = self.locks.lock();
+            out.retain(|o| {
+                let key = (o.ns.clone(), o.id.clone());
+                locks.get(&key).is_some_and(|l| l.expires_at > now) == want
+            });
+            drop(locks);
+            trace.record("locked", want, out.len());
+        }
+        // Vector ANN naive filter over out
+        if let Some(vq) = &req.vector {
+            let key = (ns.to_string(), vq.field.clone());
+            let (vf, ann) = {
+                let inner = self.inner.read();
+                (inner.vec_fields.get(&key).cloned(), inner.ann.get(&key).cloned())
+            };
+            let mut ranked = rank_by_vector(out, vq, vf, ann, &req.deadline).await?;
+            trace.record("vector_rank", &vq.field, ranked.len());
+            if let Some(path) = &req.distinct_by {
+                keep_first_distinct(&mut ranked, path);
+                trace.record("distinct", path, ranked.len());
+            }
+            self.record_access(&ranked);
+            project_fields(&mut ranked, req.fields.as_ref());
+            return Ok(ranked);
+        }
+        if let Some(path) = &req.distinct_by {
+            keep_first_distinct(&mut out, path);
+            trace.record("distinct", path, out.len());
+        }
+        if let Some(l) = req.limit {
+            out.truncate(l);
+            trace.record("limit", l, out.len());
+        }
+        self.record_access(&out);
+        project_fields(&mut out, req.fields.as_ref());
+        Ok(out)
+    }
+
+    fn select(&self, ns: &str, req: &QueryRequest, trace: &mut QueryTrace) -> Result<Vec<Object>> {
+        if !req.any_of.is_empty() {
+            return self.select_any_of(ns, req, trace);
+        }
+        let now = Utc::now();
+        let inner = self.inner.read();
+        let mut candidate_ids: Option<HashMap<String, ()>> = None;
+        // A composite index over exactly the filtered keys answers in one lookup
+        let composite = req.tag_filter.as_ref().and_then(|tf| {
+            let keys: Vec<String> = tf.0.keys().cloned().collect();
+            inner
+                .composite_keys
+                .get(ns)
+                .filter(|sets| sets.contains(&keys))
+                .and_then(|_| composite_value(&keys, &tf.0))
+        });
+        if let Some(cv) = composite {
+            let ids = inner.composite_index.get(&(ns.to_string(), cv.clone()));
+            trace.record("composite_index", &cv, ids.map_or(0, |ids| ids.len()));
+            match ids {
+                Some(ids) => candidate_ids = Some(ids.clone()),
+                None => return Ok(vec![]),
+            }
+        } else if let Some(tf) = &req.tag_filter {
+            // tag index intersect
+            for (k, v) in tf.0.iter() {
+                let key = (ns.to_string(), k.clone(), v.clone());
+                if let Some(ids) = inner.tag_index.get(&key) {
+                    candidate_ids = Some(match candidate_ids.take() {
+                        None => ids.clone(),
+                        Some(prev) => prev
+                            .into_iter()
+                            .filter(|(id, _)| ids.contains_key(id))
+                            .collect(),
+                    });
+                }
+                let rows = candidate_ids.as_ref().map_or(0, |c| c.len());
+                trace.record("tag_index", format_args!("{}={}", k, v), rows);
+                if !inner.tag_index.contains_key(&key) {
+                    return Ok(vec![]);
+                }
+            }
+        }
+        // type intersect
+        if let Some(t) = &req.r#type {
+            let Some(ids) = inner.type_index.get(&(ns.to_string(), t.clone())) else {
+                trace.record("type_index", t, 0);
+                return Ok(vec![]);
+            };
+            candidate_ids = Some(match candidate_ids.take() {
+                None => ids.clone(),
+                Some(prev) => prev
+                    .into_iter()
+                    .filter(|(id, _)| ids.contains_key(id))
+                    .collect(),
+            });
+            trace.record("type_index", t, candidate_ids.as_ref().map_or(0, |c| c.len()));
+        }
+        // tag key existence intersect
+        for k in req.has_tag_keys.iter() {
+            let Some(ids) = inner.tag_key_index.get(&(ns.to_string(), k.clone())) else {
+                trace.record("tag_key_index", k, 0);
+                return Ok(vec![]);
+            };
+            candidate_ids = Some(match candidate_ids.take() {
+                None => ids.clone(),
+                Some(prev) => prev
+                    .into_iter()
+                    .filter(|(id, _)| ids.contains_key(id))
+                    .collect(),
+            });
+            trace.record("tag_key_index", k, candidate_ids.as_ref().map_or(0, |c| c.len()));
+        }
+        // json index intersect, for registered paths; others are checked on the scan below
+        if let Some(jf) = &req.jsonpath {
+            let indexed = inner.json_index_paths.get(ns);
+            for (p, val) in jf.equals.iter() {
+                let p = canonical_json_path(p);
+                if !indexed.is_some_and(|paths| paths.contains(&p)) {
+                    continue;
+                }
+                let key = (ns.to_string(), p, val.to_string());
+                if let Some(ids) = inner.json_index.get(&key) {
+                    candidate_ids = Some(match candidate_ids.take() {
+                        None => ids.clone(),
+                        Some(prev) => prev
+                            .into_iter()
+                            .filter(|(id, _)| ids.contains_key(id))
+                            .collect(),
+                    });
+                    let rows = candidate_ids.as_ref().map_or(0, |c| c.len());
+                    trace.record("json_index", format_args!("{}={}", key.1, key.2), rows);
+                } else {
+                    trace.record("json_index", format_args!("{}={}", key.1, key.2), 0);
+                    return Ok(vec![]);
+                }
+            }
+        }
+        // Count before cloning anything; a full scan counts the namespace's keys
+        check_candidates(match &candidate_ids {
+            Some(ids) => ids.len(),
+            None if *QUERY_MAX_CANDIDATES > 0 => {
+                inner.data.keys().filter(|(n, _)| n == ns).count()
+            }
+            None => 0,
+        })?;
+        // Scan candidates or full ns
+        let mut out = Vec::new();
+        let scan = if candidate_ids.is_some() {
+            "candidate_scan"
+        } else {
+            "full_scan"
+        };
+        match candidate_ids {
+            Some(ids) => {
+                for (i, (id, _)) in ids.into_iter().enumerate() {
+                    if i % SCAN_CHECK_EVERY == 0 && !req.deadline.keep_going()? {
+                        break;
+                    }
+                    trace.scanned += 1;
+                    if let Some(versions) = inner.data.get(&(ns.to_string(), id.clone())) {
+                        if let Some(v) = versions.last() {
+                            if !Self::is_expired(v, now) {
+                                out.push(v.clone());
+                            }
+                        }
+                    }
+                }
+            }
+            None => {
+                for (i, ((n, _id), versions)) in inner.data.iter().enumerate() {
+                    if i % SCAN_CHECK_EVERY == 0 && !req.deadline.keep_going()? {
+                        break;
+                    }
+                    trace.scanned += 1;
+                    if n != ns {
+                        continue;
+                    }
+                    if let Some(v) = versions.last() {
+                        if !Self::is_expired(v, now) {
+                            out.push(v.clone());
+                        }
+                    }
+                }
+            }
+        }
+        trace.candidates += out.len();
+        trace.record(scan, "", out.len());
+        // Every metadata filter is applied to the latest version here, whichever way the
+        // candidates were found: index entries can outlive a tag, type or body change, and
+        // a full scan has no index to narrow it. The vector stage only ever sees this set.
+        out.retain(|o| matches_filters(o, req));
+        trace.record("filter", "", out.len());
+        // Both branches walk hash maps; commit_seq is unique per ns, so this is a total order
+        out.sort_by_key(|o| o.commit_seq);
+        Ok(out)
+    }
+
+    // Union of the sub-queries' selections, one entry per id, narrowed by the outer filters
+    fn select_any_of(
+        &self,
+        ns: &str,
+        req: &QueryRequest,
+        trace: &mut QueryTrace,
+    ) -> Result<Vec<Object>> {
+        let mut union: HashMap<String, Object> = HashMap::new();
+        for (i, sub) in req.any_of.iter().enumerate() {
+            let mut sub = sub.clone();
+            sub.deadline = req.deadline.clone();
+            let mut sub_trace = trace.child();
+            let found = self.select(ns, &sub, &mut sub_trace)?;
+            trace.absorb(i, sub_trace);
+            for o in found {
+                match union.get(&o.id) {
+                    Some(prev) if prev.commit_seq >= o.commit_seq => {}
+                    _ => {
+                        union.insert(o.id.clone(), o);
+                    }
+                }
+            }
+        }
+        trace.record("any_of_union", "", union.len());
+        let mut out: Vec<Object> = union
+            .into_values()
+            .filter(|o| matches_filters(o, req))
+            .collect();
+        trace.record("filter", "", out.len());
+        out.sort_by_key(|o| o.commit_seq);
+        Ok(out)
+    }
+
+    /// Applies records shipped from a leader's WAL, fanning them out to local watchers.
+    /// `recs` is one record or a whole transaction, applied under one write lock so
+    /// readers never see part of it; `ts` is when the leader logged them.
+    pub fn apply_records(&self, ts: i64, recs: Vec<RecBody>) -> Result<()> {
+        let mut objs = Vec::new();
+        for rec in recs.iter() {
+            if let RecBody::Put { obj, .. } = rec {
+                let o = serde_json::from_value::<Object>(obj.clone())
+                    .map_err(|e| StateError::Invalid(e.to_string()))?;
+                objs.push(o);
+            }
+        }
+        let mut objs = objs.into_iter();
+        let logged = DateTime::from_timestamp(ts, 0).unwrap_or_else(Utc::now);
+        let mut inner = self.inner.write();
+        for rec in recs {
+            match rec {
+                RecBody::Put { .. } => {
+                    if let Some(o) = objs.next() {
+                        self.commit_in(&mut inner, &[WatchEvent::Put(o)]);
+                    }
+                }
+                RecBody::Delete { ns, id, commit_seq } => {
+                    if !inner.data.contains_key(&(ns.clone(), id.clone())) {
+                        continue;
+                    }
+                    // Records from older leaders don't carry their seq; take the next one
+                    let last = inner.commit_seq.get(&ns).copied().unwrap_or(0);
+                    let commit_seq = if commit_seq > 0 { commit_seq } else { last + 1 };
+                    self.commit_in(&mut inner, &[WatchEvent::Delete { ns, id, commit_seq }]);
+                }
+                RecBody::LeaseAcquire {
+                    ns,
+                    key,
+                    owner,
+                    token,
+                    ttl,
+                }
+                | RecBody::LeaseRenew {
+                    ns,
+                    key,
+                    owner,
+                    token,
+                    ttl,
+                } => {
+                    // The lease runs from when the leader granted it, not from arrival
+                    let expires_at = logged + Duration::seconds(ttl as i64);
+                    Self::replay_lease_in(&mut inner, &ns, &key, &owner, token, expires_at);
+                }
+                RecBody::LeaseRelease { ns, key, token, .. } => {
+                    Self::replay_lease_release_in(&mut inner, &ns, &key, token);
+                }
+                RecBody::Idempotency { .. } => {}
+                RecBody::TrimVersions { ns, id, keep } => {
+                    Self::drop_old_versions_in(&mut inner, &ns, &id, keep);
+                }
+                RecBody::TxnBegin { .. } | RecBody::TxnCommit { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn all_objects(&self) -> Vec<Object> {
+        let inner = self.inner.read();
+        let mut out = Vec::new();
+        for ((_ns, _id), versions) in inner.data.iter() {
+            if let Some(v) = versions.last() {
+                out.push(v.clone());
+            }
+        }
+        out
+    }
+
+    pub fn backlog_map(&self) -> std::collections::HashMap<String, usize> {
+        let inner = self.inner.read();
+        let mut map: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+        for (ns, bufs) in inner.buffers.iter() {
+            let mut maxv = 0usize;
+            for b in bufs.iter() {
+                let c = *b.cursor.read();
+                let len = b.events.read().len();
+                let backlog = len.saturating_sub(c);
+                if backlog > maxv {
+                    maxv = backlog;
+                }
+            }
+            map.insert(ns.clone(), maxv);
+        }
+        map
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for InMemoryStore {
+    async fn put(&self, ns: &str, req: PutRequest) -> Result<Object> {
+        Ok(self.put_mode(ns, req, PutMode::Always)?.0)
+    }
+
+    async fn put_if_absent(&self, ns: &str, req: PutRequest) -> Result<(Object, bool)> {
+        self.put_mode(ns, req, PutMode::IfAbsent)
+    }
+
+    async fn put_if_changed(&self, ns: &str, req: PutRequest) -> Result<(Object, bool)> {
+        self.put_mode(ns, req, PutMode::IfChanged)
+    }
+
+    async fn get(&self, ns: &str, id: &str, opts: crate::traits::GetOptions) -> Result<Object> {
+        let now = Utc::now();
+        let inner = self.inner.read();
+        let key = (ns.to_string(), id.to_string());
+        let versions = inner.data.get(&key).ok_or(StateError::NotFound)?;
+        let mut cand = None;
+        for v in versions.iter().rev() {
+            if let Some(at) = opts.at_ts {
+                if v.ts > at {
+                    continue;
+                }
+            }
+            if !past_grace(v, now) {
+                cand = Some(v.clone());
+                break;
+            }
+        }
+        drop(inner);
+        let obj = cand.ok_or(StateError::NotFound)?;
+        self.record_access(std::slice::from_ref(&obj));
+        Ok(obj)
+    }
+
+    async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>> {
+        self.run_query(ns, &req, &mut QueryTrace::default()).await
+    }
+
+    async fn query_traced(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+        trace: &mut QueryTrace,
+    ) -> Result<Vec<Object>> {
+        self.run_query(ns, &req, trace).await
+    }
+
+    async fn query_analyze(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+    ) -> Result<(Vec<Object>, QueryTrace)> {
+        let mut trace = QueryTrace::enabled();
+        let out = self.run_query(ns, &req, &mut trace).await?;
+        Ok((out, trace))
+    }
+
+    async fn delete(&self, ns: &str, id: &str) -> Result<u64> {
+        let mut inner = self.inner.write();
+        let ev = Self::stage_delete_in(&inner, ns, id)?;
+        self.commit_in(&mut inner, std::slice::from_ref(&ev));
+        Ok(ev.commit_seq())
+    }
+
+    async fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>> {
+        self.inner
+            .read()
+            .data
+            .get(&(ns.to_string(), id.to_string()))
+            .cloned()
+            .ok_or(StateError::NotFound)
+    }
+
+    async fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize> {
+        let mut inner = self.inner.write();
+        let cutoff = Utc::now() - *TIME_TRAVEL_RETENTION;
+        let (keep, _) = Self::stage_trim_in(&inner, ns, id, keep, cutoff)?;
+        Ok(Self::drop_old_versions_in(&mut inner, ns, id, keep))
+    }
+
+    async fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object> {
+        let mut inner = self.inner.write();
+        let events = Self::stage_rename_in(&inner, ns, id, new_id)?;
+        // Under the same lock, so watchers observe the delete and put back to back
+        self.commit_in(&mut inner, &events);
+        match events.into_iter().last() {
+            Some(WatchEvent::Put(obj)) => Ok(obj),
+            _ => Err(StateError::Internal("rename staged no put".into())),
+        }
+    }
+
+    async fn txn(&self, ns: &str, ops: Vec<TxnOp>) -> Result<Vec<WatchEvent>> {
+        let mut inner = self.inner.write();
+        let events = Self::stage_txn_in(&inner, ns, ops)?;
+        // Applied under the same lock; watchers get the whole txn back to back
+        self.commit_in(&mut inner, &events);
+        Ok(events)
+    }
+
+    async fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<Object>> {
+        let now = Utc::now();
+        self.locks.lock().retain(|_, l| l.expires_at > now);
+        let mut inner = self.inner.write();
+        // Walks the expiry index only up to the cutoff, so objects without a TTL or not yet
+        // due cost nothing
+        let cutoff = now - Duration::seconds(retention_secs as i64);
+        let dead: Vec<(String, String)> = inner
+            .expiry_index
+            .iter()
+            .take_while(|(at, _, _)| *at + *TTL_GRACE < cutoff)
+            .map(|(_, ns, id)| (ns.clone(), id.clone()))
+            .collect();
+        // Removed with their index entries under one lock, so no index outlives its object
+        let mut expired = Vec::new();
+        for (ns, id) in dead {
+            if let Some(mut versions) = self.remove_object(&mut inner, &ns, &id) {
+                expired.extend(versions.pop());
+            }
+        }
+        Ok(expired)
+    }
+
+    fn register_vec_field(&self, ns: &str, field: VecField) -> Result<()> {
+        InMemoryStore::register_vec_field(self, ns, field)
+    }
+
+    fn warm_ann(&self) -> usize {
+        InMemoryStore::warm_ann(self)
+    }
+
+    fn ann_warming(&self) -> bool {
+        InMemoryStore::ann_warming(self)
+    }
+
+    fn register_composite_index(&self, ns: &str, keys: Vec<String>) -> Result<()> {
+        InMemoryStore::register_composite_index(self, ns, keys)
+    }
+
+    fn register_index_paths(&self, ns: &str, paths: Vec<String>) -> Result<Vec<String>> {
+        InMemoryStore::register_index_paths(self, ns, paths)
+    }
+
+    fn index_paths(&self, ns: &str) -> Vec<String> {
+        self.inner
+            .read()
+            .json_index_paths
+            .get(ns)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn export_watermark(&self) -> Result<BTreeMap<String, u64>> {
+        let inner = self.inner.read();
+        Ok(inner.commit_seq.iter().map(|(ns, s)| (ns.clone(), *s)).collect())
+    }
+
+    fn export_keys(&self) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<(String, String)> = self.inner.read().data.keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn export_batch(
+        &self,
+        keys: &[(String, String)],
+        watermark: &BTreeMap<String, u64>,
+    ) -> Vec<Object> {
+        let inner = self.inner.read();
+        keys.iter()
+            .filter_map(|key| {
+                let max = watermark.get(&key.0)?;
+                let versions = inner.data.get(key)?;
+                versions.iter().rev().find(|o| o.commit_seq <= *max).cloned()
+            })
+            .collect()
+    }
+
+    fn set_quota(&self, ns: &str, quota: NsQuota) -> Result<()> {
+        let mut inner = self.inner.write();
+        if quota == NsQuota::default() {
+            inner.quotas.remove(ns);
+        } else {
+            inner.quotas.insert(ns.to_string(), quota);
+        }
+        Ok(())
+    }
+
+    fn quota(&self, ns: &str) -> Option<NsQuota> {
+        self.inner.read().quotas.get(ns).copied()
+    }
+
+    fn usage(&self, ns: &str) -> Option<NsUsage> {
+        Some(self.inner.read().usage.get(ns).copied().unwrap_or_default())
+    }
+
+    fn expiring(&self, ns: &str, until: DateTime<Utc>) -> Result<Vec<Object>> {
+        let now = Utc::now();
+        let inner = self.inner.read();
+        // The expiry index is ordered by deadline, so the walk stops at `until`
+        Ok(inner
+            .expiry_index
+            .iter()
+            .take_while(|(at, _, _)| *at <= until)
+            .filter(|(at, n, _)| n == ns && *at >= now)
+            .filter_map(|(_, n, id)| inner.data.get(&(n.clone(), id.clone()))?.last())
+            .cloned()
+            .collect())
+    }
+
+    fn check_indexes(&self, sample: usize) -> Vec<crate::traits::IndexMismatch> {
+        let inner = self.inner.read();
+        let latest = |ns: &str, id: &str| {
+            inner
+                .data
+                .get(&(ns.to_string(), id.to_string()))
+                .and_then(|v| v.last())
+        };
+        let tag_entries = inner.tag_index.iter().flat_map(|((ns, k, v), ids)| {
+            ids.keys().map(move |id| ("tag", ns, k, v, id))
+        });
+        let json_entries = inner.json_index.iter().flat_map(|((ns, p, v), ids)| {
+            ids.keys().map(move |id| ("json", ns, p, v, id))
+        });
+        let total: usize = inner
+            .tag_index
+            .values()
+            .chain(inner.json_index.values())
+            .map(|ids| ids.len())
+            .sum();
+        if total == 0 || sample == 0 {
+            return Vec::new();
+        }
+        // Hash map order is stable while the maps are unchanged, which is enough to rotate
+        let start = self.check_cursor.fetch_add(sample, Ordering::Relaxed) % total;
+        let mut out = Vec::new();
+        let entries = tag_entries.chain(json_entries);
+        for (index, ns, key, val, id) in entries.cycle().skip(start).take(sample.min(total)) {
+            let problem = match latest(ns, id) {
+                None => Some("object not found".to_string()),
+                Some(o) if index == "tag" => match o.tags.0.get(key) {
+                    Some(v) if v == val => None,
+                    Some(v) => Some(format!("tag {}={} but object has {}", key, val, v)),
+                    None => Some(format!("tag {}={} but object has no {}", key, val, key)),
+                },
+                Some(o) => {
+                    let found = value_at_path(&o.body, key);
+                    match found.map(|v| v.to_string()) {
+                        Some(v) if &v == val => None,
+                        Some(v) => Some(format!("{} = {} but object has {}", key, val, v)),
+                        None => Some(format!("{} = {} but object has no value there", key, val)),
+                    }
+                }
+            };
+            if let Some(detail) = problem {
+                out.push(crate::traits::IndexMismatch {
+                    index,
+                    ns: ns.clone(),
+                    id: id.clone(),
+                    detail,
+                });
+            }
+        }
+        out
+    }
+
+    fn register_transform(&self, ns: &str, rule: TransformRule) -> Result<()> {
+        InMemoryStore::register_transform(self, ns, rule)
+    }
+
+    fn children(&self, ns: &str, id: &str) -> Result<Vec<String>> {
+        let inner = self.inner.read();
+        let versions = inner
+            .data
+            .get(&(ns.to_string(), id.to_string()))
+            .ok_or(StateError::NotFound)?;
+        let commits: HashSet<&String> = versions.iter().map(|v| &v.commit).collect();
+        let mut out = BTreeSet::new();
+        for c in commits.iter() {
+            let Some(ids) = inner.child_index.get(&(ns.to_string(), c.to_string())) else {
+                continue;
+            };
+            // Its own lineage (patches, renames) isn't a reference
+            for child in ids.keys().filter(|c| *c != id) {
+                let latest = inner
+                    .data
+                    .get(&(ns.to_string(), child.clone()))
+                    .and_then(|v| v.last());
+                if latest.is_some_and(|o| o.parents.iter().any(|p| commits.contains(p))) {
+                    out.insert(child.clone());
+                }
+            }
+        }
+        Ok(out.into_iter().collect())
+    }
+
+    fn access_stats(&self, ns: &str, id: &str) -> Option<AccessStats> {
+        self.access
+            .lock()
+            .get(&(ns.to_string(), id.to_string()))
+            .cloned()
+    }
+
+    fn advisory_lock(
+        &self,
+        ns: &str,
+        id: &str,
+        owner: &str,
+        ttl_secs: u64,
+    ) -> Result<AdvisoryLock> {
+        let now = Utc::now();
+        let key = (ns.to_string(), id.to_string());
+        // Held across the existence check, so a delete can't slip in and leave a stray lock
+        let inner = self.inner.read();
+        let live = inner
+            .data
+            .get(&key)
+            .and_then(|versions| versions.last())
+            .is_some_and(|o| !past_grace(o, now));
+        if !live {
+            return Err(StateError::NotFound);
+        }
+        let mut locks = self.locks.lock();
+        if let Some(cur) = locks.get(&key) {
+            if cur.expires_at > now && cur.owner != owner {
+                return Err(StateError::Conflict(format!("locked by {}", cur.owner)));
+            }
+        }
+        let lock = AdvisoryLock {
+            owner: owner.to_string(),
+            expires_at: now + Duration::seconds(ttl_secs as i64),
+        };
+        locks.insert(key, lock.clone());
+        Ok(lock)
+    }
+
+    fn advisory_unlock(&self, ns: &str, id: &str, owner: &str) -> Result<()> {
+        let key = (ns.to_string(), id.to_string());
+        let mut locks = self.locks.lock();
+        if let Some(cur) = locks.get(&key) {
+            if cur.expires_at > Utc::now() && cur.owner != owner {
+                return Err(StateError::Conflict(format!("locked by {}", cur.owner)));
+            }
+            locks.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn advisory_lock_state(&self, ns: &str, id: &str) -> Option<AdvisoryLock> {
+        self.locks
+            .lock()
+            .get(&(ns.to_string(), id.to_string()))
+            .filter(|l| l.expires_at > Utc::now())
+            .cloned()
+    }
+
+    fn all_objects(&self) -> Vec<Object> {
+        let inner = self.inner.read();
+        let mut objects = Vec::new();
+        for vectors in inner.data.values() {
+            if let Some(latest) = vectors.last() {
+                objects.push(latest.clone());
+            }
+        }
+        objects
+    }
+
+    fn namespaces(&self) -> Vec<String> {
+        let inner = self.inner.read();
+        let set: std::collections::BTreeSet<&String> =
+            inner.data.keys().map(|(ns, _)| ns).collect();
+        set.into_iter().cloned().collect()
+    }
+}
+
+impl WatchSource for InMemoryStore {
+    fn subscribe(
+        &self,
+        filter: WatchFilter,
+        from_commit: Option<u64>,
+        limits: Option<WatchLimits>,
+    ) -> Box<dyn crate::traits::WatchHandle> {
+        let mut inner = self.inner.write();
+        let buf = WatchBuffer {
+            filter: Arc::new(filter.clone()),
+            limits: limits.unwrap_or_default(),
+            ..Default::default()
+        };
+        // Prime the buffer with backlog since from_commit, through the same filter
+        if let Some(from) = from_commit {
+            if let Some(log) = inner.commit_log.get(&filter.ns) {
+                for ev in log.iter() {
+                    let c = match ev {
+                        WatchEvent::Put(o) => o.commit_seq,
+                        WatchEvent::Delete { commit_seq, .. } => *commit_seq,
+                    };
+                    if c > from {
+                        buf.push(ev.clone());
+                    }
+                }
+            }
+        }
+        inner
+            .buffers
+            .entry(filter.ns.clone())
+            .or_default()
+            .push(buf.clone());
+        Box::new(MemWatch {
+            buf,
+            last_commit: from_commit.unwrap_or(0),
+            store: Arc::downgrade(&self.inner),
+        })
+    }
+
+    fn backlog_map(&self) -> std::collections::HashMap<String, u64> {
+        let inner = self.inner.read();
+        let mut map: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+        for (ns, bufs) in inner.buffers.iter() {
+            let mut maxv = 0usize;
+            for b in bufs.iter() {
+                let c = *b.cursor.read();
+                let len = b.events.read().len();
+                let backlog = len.saturating_sub(c);
+                if backlog > maxv {
+                    maxv = backlog;
+                }
+            }
+            map.insert(ns.clone(), maxv as u64);
+        }
+        map
+    }
+}
+
+#[async_trait::async_trait]
+impl LeaseStore for InMemoryStore {
+    async fn lease_acquire(
+        &self,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        ttl_secs: u64,
+    ) -> Result<crate::traits::Lease> {
+        let mut inner = self.inner.write();
+        let lease = Self::stage_lease_acquire_in(&inner, ns, key, owner, ttl_secs)?;
+        Self::commit_lease_in(&mut inner, &lease);
+        Ok(lease)
+    }
+
+    async fn lease_renew(
+        &self,
+        ns: &str,
+        key: &str,
+        owner: &str,
+        token: u64,
+        ttl_secs: u64,
+    ) -> Result<crate::traits::Lease> {
+        let mut inner = self.inner.write();
+        let lease = Self::stage_lease_renew_in(&inner, ns, key, owner, token, ttl_secs)?;
+        Self::commit_lease_in(&mut inner, &lease);
+        Ok(lease)
+    }
+
+    async fn lease_release(&self, ns: &str, key: &str, owner: &str, token: u64) -> Result<()> {
+        let mut inner = self.inner.write();
+        Self::stage_lease_release_in(&inner, ns, key, owner, token)?;
+        Self::replay_lease_release_in(&mut inner, ns, key, token);
+        Ok(())
+    }
+
+    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()> {
+        let inner = self.inner.read();
+        if let Some((_, tok, exp)) = inner.leases.get(&(ns.to_string(), resource.to_string())) {
+            if *tok == fence && *exp > Utc::now() {
+                return Ok(());
+            }
+        }
+        Err(StateError::Conflict("fence mismatch".into()))
+    }
+}
+
+#[async_trait::async_trait]
+impl IdempotencyStore for InMemoryStore {
+    async fn idempotency_lookup(
+        &self,
+        ns: &str,
+        key: &str,
+        body_hash: &str,
+    ) -> Result<Option<super::traits::IdempotencyRecord>> {
+        let inner = self.inner.read();
+        if let Some(r) = inner.idem.get(&(ns.to_string(), key.to_string())) {
+            if r.body_hash == body_hash {
+                return Ok(Some(r.clone()));
+            } else {
+                return Err(StateError::Conflict("idempotency body mismatch".into()));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn idempotency_commit(
+        &self,
+        ns: &str,
+        key: &str,
+        body_hash: &str,
+        response: serde_json::Value,
+        commit_seq: u64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let resp_hash =
+            agentstate_core::util::blake3_hex(serde_json::to_string(&response).unwrap().as_bytes());
+        let rec = super::traits::IdempotencyRecord {
+            ns: ns.to_string(),
+            key: key.to_string(),
+            body_hash: body_hash.to_string(),
+            response_hash: resp_hash,
+            commit_seq,
+            expires_at,
+            response,
+        };
+        self.inner
+            .write()
+            .idem
+            .insert((ns.to_string(), key.to_string()), rec);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl AdminOps for InMemoryStore {
+    async fn admin_snapshot(&self) -> Result<(String, u64)> {
+        Err(StateError::Invalid("not persistent".into()))
+    }
+
+    async fn admin_manifest(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({"mode":"memory"}))
+    }
+
+    async fn admin_trim_wal(&self, _snapshot_id: &str) -> Result<Vec<String>> {
+        Err(StateError::Invalid("not persistent".into()))
+    }
+
+    async fn admin_wal_freeze(&self, _frozen: bool) -> Result<serde_json::Value> {
+        Err(StateError::Invalid("not persistent".into()))
+    }
+
+    async fn apply_replicated(&self, ts: i64, recs: Vec<RecBody>) -> Result<()> {
+        self.apply_records(ts, recs)
+    }
+}
+
+struct MemWatch {
+    buf: WatchBuffer,
+    last_commit: u64,
+    // Where the buffer is registered, to take it out again
+    store: std::sync::Weak<RwLock<Inner>>,
+}
+
+impl Drop for MemWatch {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+impl WatchHandle for MemWatch {
+    fn try_next(&mut self) -> Option<WatchEvent> {
+        let mut c = self.buf.cursor.write();
+        let events = self.buf.events.read();
+        if *c < events.len() {
+            let ev = events[*c].clone();
+            *c += 1;
+            // reduce bytes count approximately
+            {
+                let mut b = self.buf.bytes.write();
+                *b = b.saturating_sub(match &ev {
+                    WatchEvent::Put(o) => serde_json::to_vec(o).map(|v| v.len()).unwrap_or(256),
+                    _ => 64,
+                });
+            }
+            self.last_commit = match &ev {
+                WatchEvent::Put(o) => o.commit_seq,
+                WatchEvent::Delete { commit_seq, .. } => *commit_seq,
+            };
+            Some(ev)
+        } else {
+            None
+        }
+    }
+
+    fn last_commit(&self) -> u64 {
+        self.last_commit
+    }
+
+    fn overflow_meta(&self) -> Option<(u64, u32)> {
+        if *self.buf.overflow.read() {
+            Some((self.last_commit, *WATCH_RETRY_MS))
+        } else {
+            None
+        }
+    }
+
+    fn unsubscribe(&mut self) {
+        let Some(store) = self.store.upgrade() else {
+            return;
+        };
+        let mut inner = store.write();
+        let ns = &self.buf.filter.ns;
+        if let Some(bufs) = inner.buffers.get_mut(ns) {
+            bufs.retain(|b| !Arc::ptr_eq(&b.events, &self.buf.events));
+            if bufs.is_empty() {
+                inner.buffers.remove(ns);
+            }
+        }
+    }
+}
+
+// A query's filters checked against one object, for results that didn't come from its
+// own index lookups (any_of unions)
+fn matches_filters(o: &Object, req: &QueryRequest) -> bool {
+    let tags_ok = req
+        .tag_filter
+        .as_ref()
+        .is_none_or(|tf| tf.0.iter().all(|(k, v)| o.tags.0.get(k) == Some(v)));
+    let equals_ok = req.jsonpath.as_ref().is_none_or(|jf| {
+        jf.equals
+            .iter()
+            .all(|(p, val)| value_at_path(&o.body, p) == Some(val))
+            && matches_ranges(o, &jf.ranges)
+    });
+    tags_ok
+        && equals_ok
+        && req.r#type.as_ref().is_none_or(|t| o.r#type == *t)
+        && req.has_tag_keys.iter().all(|k| o.tags.0.contains_key(k))
+        && req.since_commit_seq.is_none_or(|since| o.commit_seq > since)
+}
+
+/// Body path as written in claims and projections (`body.ssn`, `$.contact.email`, `ssn`).
+pub fn body_path(p: &str) -> Vec<&str> {
+    let p = p.trim().trim_start_matches('$').trim_start_matches('.');
+    let p = p.strip_prefix("body.").unwrap_or(p);
+    p.split('.').filter(|s| !s.is_empty()).collect()
+}
+
+/// Rebuilds a body from only the given paths; anything else is dropped.
+pub fn project_body(body: &mut serde_json::Value, paths: &[Vec<&str>]) {
+    fn insert(dst: &mut serde_json::Value, parts: &[&str], v: serde_json::Value) {
+        let Some(map) = dst.as_object_mut() else {
+            return;
+        };
+        match parts {
+            [last] => {
+                map.insert(last.to_string(), v);
+            }
+            [k, rest @ ..] => {
+                let child = map.entry(k.to_string()).or_insert(serde_json::json!({}));
+                insert(child, rest, v)
+            }
+            [] => {}
+        }
+    }
+    let mut out = serde_json::json!({});
+    for parts in paths.iter().filter(|p| !p.is_empty()) {
+        if let Some(v) = parts.iter().try_fold(&*body, |cur, k| cur.get(*k)) {
+            insert(&mut out, parts, v.clone());
+        }
+    }
+    *body = out;
+}
+
+// QueryRequest.fields: trims each body to the listed paths, leaving the envelope as is
+pub(crate) fn project_fields(list: &mut [Object], fields: Option<&Vec<String>>) {
+    let Some(fields) = fields else {
+        return;
+    };
+    let paths: Vec<Vec<&str>> = fields.iter().map(|f| body_path(f)).collect();
+    for o in list.iter_mut() {
+        project_body(&mut o.body, &paths);
+    }
+}
+
+/// Whether the object's body satisfies every range; a missing path doesn't.
+pub(crate) fn matches_ranges(o: &Object, ranges: &BTreeMap<String, Range>) -> bool {
+    ranges.iter().all(|(p, r)| {
+        value_at_path(&o.body, p).is_some_and(|v| r.matches(v))
+    })
+}
+
+// `status`, `.status` and `$.status` all name the same field; indexes key on `$.status`,
+// with array steps written `$.steps[0].tool`
+fn canonical_json_path(path: &str) -> String {
+    let ptr = json_pointer_from_path(path);
+    let mut out = String::from("$");
+    for seg in ptr.split('/').skip(1) {
+        if seg == "-" || (!seg.is_empty() && seg.bytes().all(|b| b.is_ascii_digit())) {
+            out.push('[');
+            out.push_str(seg);
+            out.push(']');
+        } else {
+            out.push('.');
+            out.push_str(seg);
+        }
+    }
+    out
+}
+
+// Splits `a.b[2].c` into ["a", "b", "2", "c"]; None when a bracket holds anything
+// but an index or `-`, or isn't closed
+fn json_path_segments(path: &str) -> Option<Vec<&str>> {
+    let mut out = Vec::new();
+    for part in path.split('.') {
+        let (name, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if !name.is_empty() {
+            out.push(name);
+        }
+        while !rest.is_empty() {
+            let (index, tail) = rest.strip_prefix('[')?.split_once(']')?;
+            let numeric = !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit());
+            if !numeric && index != "-" {
+                return None;
+            }
+            out.push(index);
+            rest = tail;
+        }
+    }
+    Some(out)
+}
+
+/// Converts `$.a.b[2].c` to the JSON Pointer `/a/b/2/c`; `[-]` becomes `-`, which
+/// [`value_at_pointer`] reads as the last element. Malformed brackets are kept verbatim.
+pub(crate) fn json_pointer_from_path(path: &str) -> String {
+    let p = path.trim();
+    let p = p.trim_start_matches('$').trim_start_matches('.');
+    let segments = json_path_segments(p)
+        .unwrap_or_else(|| p.split('.').filter(|s| !s.is_empty()).collect());
+    let mut out = String::new();
+    for seg in segments {
+        out.push('/');
+        out.push_str(seg);
+    }
+    out
+}
+
+/// Whether `path` parses as dotted fields with `[n]` / `[-]` array steps.
+pub(crate) fn is_valid_json_path(path: &str) -> bool {
+    let p = path.trim();
+    json_path_segments(p.trim_start_matches('$').trim_start_matches('.')).is_some()
+}
+
+/// Like `Value::pointer`, except `-` on an array is its last element (None when empty).
+/// Indices out of range and steps into scalars are None.
+pub(crate) fn value_at_pointer<'a>(
+    body: &'a serde_json::Value,
+    ptr: &str,
+) -> Option<&'a serde_json::Value> {
+    use serde_json::Value;
+    ptr.split('/').skip(1).try_fold(body, |v, seg| match v {
+        Value::Object(map) => map.get(seg),
+        Value::Array(items) if seg == "-" => items.last(),
+        Value::Array(items) => seg.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+/// The value at a JSONPath-style body path such as `$.steps[0].tool`.
+pub(crate) fn value_at_path<'a>(
+    body: &'a serde_json::Value,
+    path: &str,
+) -> Option<&'a serde_json::Value> {
+    value_at_pointer(body, &json_pointer_from_path(path))
+}
+
+// Collapses results sharing a value at `path`, keeping the earliest in result order.
+// Objects without the field are never considered duplicates.
+pub(crate) fn keep_first_distinct(out: &mut Vec<Object>, path: &str) {
+    let ptr = json_pointer_from_path(path);
+    let mut seen = HashSet::new();
+    out.retain(|o| match value_at_pointer(&o.body, &ptr) {
+        Some(v) => seen.insert(v.to_string()),
+        None => true,
+    });
+}
+
+// Gives a put without an id its generated one while the body is still plaintext, so
+// content_hash ids don't depend on the nonces of encrypted fields
+pub(crate) fn assign_id(req: &mut PutRequest, now: DateTime<Utc>) {
+    if req.id.is_none() {
+        req.id = Some(IdStrategy::configured().generate(now, &req.body));
+        // Keeps the default ts on the instant a ULID or UUIDv7 id carries
+        req.ts.get_or_insert(now);
+    }
+}
+
+static MAX_CLOCK_SKEW_SECS: Lazy<i64> = Lazy::new(|| {
+    std::env::var("MAX_CLOCK_SKEW_SECS")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or(5)
+});
+
+// Client timestamps may not rewind an object's history past the allowed skew
+pub(crate) fn check_clock_skew(ts: DateTime<Utc>, prev: &Object) -> Result<()> {
+    let skew = *MAX_CLOCK_SKEW_SECS;
+    if ts < prev.ts - Duration::seconds(skew) {
+        return Err(StateError::Invalid(format!(
+            "ts {} is older than previous version ts {} beyond {}s skew",
+            ts.to_rfc3339(),
+            prev.ts.to_rfc3339(),
+            skew
+        )));
+    }
+    Ok(())
+}
+
+static MAX_BODY_DEPTH: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_BODY_DEPTH")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(64)
+});
+
+// Rejects bodies nested deeper than MAX_BODY_DEPTH (default 64) containers, so
+// recursive serde (WAL, snapshots, diff) can't overflow the stack. Iterative on purpose.
+pub(crate) fn check_body_depth(body: &serde_json::Value) -> Result<()> {
+    let max_depth = *MAX_BODY_DEPTH;
+    let mut stack = vec![(body, 1usize)];
+    while let Some((v, depth)) = stack.pop() {
+        let children: Box<dyn Iterator<Item = &serde_json::Value>> = match v {
+            serde_json::Value::Object(m) => Box::new(m.values()),
+            serde_json::Value::Array(a) => Box::new(a.iter()),
+            _ => continue,
+        };
+        if depth > max_depth {
+            return Err(StateError::Invalid(format!(
+                "body nested deeper than {} levels",
+                max_depth
+            )));
         }
-        Err(StateError::Conflict("fence mismatch".into()))
+        stack.extend(children.map(|c| (c, depth + 1)));
     }
-    async fn admin_snapshot(&self) -> Result<(String, u64)> {
-        Err(StateError::Invalid("not persistent".into()))
+    Ok(())
+}
+
+// Max TTL (seconds) per `classification` tag value, from CLASSIFICATION_MAX_TTL as
+// `class=secs,...`; pii defaults to 30 days
+static CLASSIFICATION_MAX_TTL: Lazy<HashMap<String, u64>> = Lazy::new(|| {
+    std::env::var("CLASSIFICATION_MAX_TTL")
+        .unwrap_or_else(|_| "pii=2592000".into())
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(c, s)| Some((c.trim().to_string(), s.trim().parse().ok()?)))
+        .collect()
+});
+
+// A classified object must carry a TTL within its class's maximum
+pub(crate) fn check_classification(req: &PutRequest) -> Result<()> {
+    let Some(class) = req.tags.0.get("classification") else {
+        return Ok(());
+    };
+    let Some(max) = CLASSIFICATION_MAX_TTL.get(class) else {
+        return Ok(());
+    };
+    match req.ttl_seconds {
+        Some(ttl) if ttl <= *max => Ok(()),
+        Some(ttl) => Err(StateError::Invalid(format!(
+            "ttl_seconds {} exceeds the {}s maximum for classification {}",
+            ttl, max, class
+        ))),
+        None => Err(StateError::Invalid(format!(
+            "classification {} requires ttl_seconds of at most {}",
+            class, max
+        ))),
     }
-    async fn admin_manifest(&self) -> Result<serde_json::Value> {
-        Ok(serde_json::json!({"mode":"memory"}))
+}
+
+// (MAX_TAGS_PER_OBJECT, MAX_TAG_KEY_LEN, MAX_TAG_VALUE_LEN), read once
+static TAG_LIMITS: Lazy<(usize, usize, usize)> = Lazy::new(|| {
+    let limit = |name: &str, default: usize| {
+        std::env::var(name)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(default)
+    };
+    (
+        limit("MAX_TAGS_PER_OBJECT", 64),
+        limit("MAX_TAG_KEY_LEN", 128),
+        limit("MAX_TAG_VALUE_LEN", 1024),
+    )
+});
+
+// Bounds tag_index growth per object: MAX_TAGS_PER_OBJECT, MAX_TAG_KEY_LEN, MAX_TAG_VALUE_LEN (bytes)
+pub(crate) fn check_tag_limits(tags: &Tags) -> Result<()> {
+    let (max_tags, max_key, max_val) = *TAG_LIMITS;
+    if tags.0.len() > max_tags {
+        return Err(StateError::Invalid(format!(
+            "too many tags: {} > {}",
+            tags.0.len(),
+            max_tags
+        )));
     }
-    async fn admin_trim_wal(&self, _snapshot_id: &str) -> Result<Vec<String>> {
-        Err(StateError::Invalid("not persistent".into()))
+    for (k, v) in tags.0.iter() {
+        if k.len() > max_key {
+            return Err(StateError::Invalid(format!(
+                "tag key longer than {} bytes",
+                max_key
+            )));
+        }
+        if v.len() > max_val {
+            return Err(StateError::Invalid(format!(
+                "tag {} value longer than {} bytes",
+                k, max_val
+            )));
+        }
     }
-    
-    fn backlog_map(&self) -> std::collections::HashMap<String, u64> {
-        let inner = self.inner.read();
-        let mut map: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
-        for (ns, bufs) in inner.buffers.iter() {
-            let mut maxv = 0usize;
-            for b in bufs.iter() {
-                let c = *b.cursor.read();
-                let len = b.events.read().len();
-                let backlog = len.saturating_sub(c);
-                if backlog > maxv {
-                    maxv = backlog;
-                }
-            }
-            map.insert(ns.clone(), maxv as u64);
+    Ok(())
+}
+
+// Serialized size of a version, as counted against `max_bytes`
+fn stored_bytes(o: &Object) -> u64 {
+    serde_json::to_vec(o).map_or(0, |v| v.len() as u64)
+}
+
+// `usage` once each `old` latest version (None: absent) is replaced by its `new` one
+fn usage_after(mut usage: NsUsage, changes: &[(Option<&Object>, Option<&Object>)]) -> NsUsage {
+    for (old, new) in changes {
+        if let Some(o) = old {
+            usage.objects = usage.objects.saturating_sub(1);
+            usage.bytes = usage.bytes.saturating_sub(stored_bytes(o));
+        }
+        if let Some(n) = new {
+            usage.objects += 1;
+            usage.bytes += stored_bytes(n);
         }
-        map
     }
-    
-    fn all_objects(&self) -> Vec<Object> {
-        let inner = self.inner.read();
-        let mut objects = Vec::new();
-        for vectors in inner.data.values() {
-            if let Some(latest) = vectors.last() {
-                objects.push(latest.clone());
-            }
+    usage
+}
+
+// Composite index value for `keys` (sorted), or None if the object lacks any of them.
+// Unit separators keep "a=b|c" from colliding with a real second key.
+fn composite_value(keys: &[String], tags: &BTreeMap<String, String>) -> Option<String> {
+    let mut out = String::new();
+    for k in keys {
+        let v = tags.get(k)?;
+        if !out.is_empty() {
+            out.push('\u{1f}');
         }
-        objects
+        out.push_str(k);
+        out.push('\u{1e}');
+        out.push_str(v);
     }
+    Some(out)
+}
 
+// ANN stage shared by engines: coerce the query embedding to the registered field,
+// then score on the blocking pool, bounded so a burst of ANN queries can't occupy
+// every blocking thread. Uses the field's HNSW index when there is one.
+pub(crate) async fn rank_by_vector(
+    candidates: Vec<Object>,
+    vq: &VectorQuery,
+    vf: Option<VecField>,
+    ann: Option<Arc<RwLock<Hnsw>>>,
+    deadline: &Deadline,
+) -> Result<Vec<Object>> {
+    let _timer = VECTOR_QUERY_SECONDS
+        .with_label_values(&[&vq.field])
+        .start_timer();
+    let mut embedding = vq.embedding.clone();
+    if let Some(vf) = vf {
+        if vf.mode == VecDimsMode::PadOrTruncate {
+            embedding.resize(vf.dims, 0.0);
+        }
+    }
+    let _permit = VECTOR_WORKERS
+        .acquire()
+        .await
+        .map_err(|e| StateError::Internal(e.to_string()))?;
+    if !deadline.keep_going()? {
+        return Ok(Vec::new());
+    }
+    let field = vq.field.clone();
+    let top_k = vq.top_k;
+    let deadline = deadline.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Some(ranked) = ann.and_then(|a| ann_top_k(&a.read(), &candidates, &embedding, top_k))
+        {
+            VECTOR_QUERY_ALGO.with_label_values(&["hnsw"]).inc();
+            return Ok(ranked);
+        }
+        VECTOR_QUERY_ALGO.with_label_values(&["exact"]).inc();
+        score_top_k(candidates, &field, &embedding, top_k, &deadline)
+    })
+    .await
+    .map_err(|e| StateError::Internal(e.to_string()))?
 }
 
-struct MemWatch {
-    buf: WatchBuffer,
-    ns: String,
-    last_commit: u64,
+// Top k from the HNSW index, restricted to the metadata stage's candidates. The search
+// is widened by how much of the field the filters dropped; None means exact scoring is
+// as cheap or the index came up short, and the caller scores brute force.
+fn ann_top_k(
+    index: &Hnsw,
+    candidates: &[Object],
+    embedding: &[f32],
+    top_k: usize,
+) -> Option<Vec<Object>> {
+    if !index.is_ready() || index.len() < *HNSW_MIN_VECTORS || embedding.len() != index.dims() {
+        return None;
+    }
+    let wanted = top_k
+        .saturating_mul(index.len())
+        .div_ceil(candidates.len().max(1));
+    let ef = wanted.max(*HNSW_EF_SEARCH);
+    if ef >= candidates.len() {
+        return None;
+    }
+    let by_id: HashMap<&str, &Object> = candidates.iter().map(|o| (o.id.as_str(), o)).collect();
+    let ranked: Vec<Object> = index
+        .search(embedding, wanted, ef)
+        .iter()
+        .filter_map(|id| by_id.get(id.as_str()).map(|o| (*o).clone()))
+        .take(top_k)
+        .collect();
+    (ranked.len() >= top_k.min(index.len())).then_some(ranked)
 }
 
-impl WatchHandle for MemWatch {
-    fn try_next(&mut self) -> Option<WatchEvent> {
-        let mut c = self.buf.cursor.write();
-        let events = self.buf.events.read();
-        if *c < events.len() {
-            let ev = events[*c].clone();
-            *c += 1;
-            // reduce bytes count approximately
-            {
-                let mut b = self.buf.bytes.write();
-                *b = b.saturating_sub(match &ev {
-                    WatchEvent::Put(o) => serde_json::to_vec(o).map(|v| v.len()).unwrap_or(256),
-                    _ => 64,
-                });
+fn score_top_k(
+    candidates: Vec<Object>,
+    field: &str,
+    embedding: &[f32],
+    top_k: usize,
+    deadline: &Deadline,
+) -> Result<Vec<Object>> {
+    let mut scored: Vec<(f32, Object)> = Vec::new();
+    for (i, o) in candidates.into_iter().enumerate() {
+        if i % SCAN_CHECK_EVERY == 0 && !deadline.keep_going()? {
+            break;
+        }
+        if let Some(vec_val) = o.body.get(field).and_then(|v| v.as_array()) {
+            let v: Vec<f32> = vec_val
+                .iter()
+                .filter_map(|x| x.as_f64().map(|f| f as f32))
+                .collect();
+            if v.len() == embedding.len() {
+                let s = cosine_sim(&v, embedding);
+                scored.push((s, o));
             }
-            self.last_commit = match &ev {
-                WatchEvent::Put(o) => o.commit_seq,
-                WatchEvent::Delete { commit_seq, .. } => *commit_seq,
-            };
-            Some(ev)
-        } else {
-            None
         }
     }
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    scored.truncate(top_k);
+    Ok(scored.into_iter().map(|(_, o)| o).collect())
+}
 
-    fn last_commit(&self) -> u64 {
-        self.last_commit
-    }
-
-    fn overflow_meta(&self) -> Option<(u64, u32)> {
-        if *self.buf.overflow.read() {
-            let min = std::env::var("WATCH_RETRY_MIN_MS")
-                .ok()
-                .and_then(|s| s.parse::<u32>().ok())
-                .unwrap_or(250);
-            let max = std::env::var("WATCH_RETRY_MAX_MS")
-                .ok()
-                .and_then(|s| s.parse::<u32>().ok())
-                .unwrap_or(4000);
-            let retry = ((min as u64 + max as u64) / 2) as u32;
-            Some((self.last_commit, retry))
-        } else {
-            None
+// Applies registered `VecField` dims to embedding arrays in a put body.
+pub(crate) fn conform_vectors(
+    vec_fields: &HashMap<(String, String), VecField>,
+    ns: &str,
+    body: &mut serde_json::Value,
+) -> Result<()> {
+    for ((fns, name), vf) in vec_fields.iter() {
+        if fns != ns {
+            continue;
+        }
+        let Some(arr) = body.get_mut(name).and_then(|v| v.as_array_mut()) else {
+            continue;
+        };
+        if arr.len() == vf.dims {
+            continue;
+        }
+        match vf.mode {
+            VecDimsMode::Strict => {
+                return Err(StateError::Invalid(format!(
+                    "vector field {} has {} dims, expected {}",
+                    name,
+                    arr.len(),
+                    vf.dims
+                )));
+            }
+            VecDimsMode::PadOrTruncate => arr.resize(vf.dims, serde_json::json!(0.0)),
         }
     }
+    Ok(())
 }
 
-fn json_pointer_from_path(path: &str) -> String {
-    // Very naive: convert $.a.b -> /a/b ; $.items[0].id not supported yet
-    let p = path.trim();
-    let p = p.trim_start_matches('$').trim_start_matches('.');
-    let parts: Vec<&str> = p.split('.').collect();
-    let mut out = String::new();
-    for part in parts {
-        if !part.is_empty() {
-            out.push('/');
-            out.push_str(part);
+// Transform paths name body fields as `body.a.b`, `$.a.b` or `a.b`
+fn transform_pointer(path: &str) -> String {
+    let p = path.trim().trim_start_matches('$').trim_start_matches('.');
+    json_pointer_from_path(p.strip_prefix("body.").unwrap_or(p))
+}
+
+// Runs a namespace's registered `TransformRule`s over a put body, in order.
+pub(crate) fn apply_transforms(
+    transforms: &HashMap<String, Vec<TransformRule>>,
+    ns: &str,
+    body: &mut serde_json::Value,
+) {
+    use serde_json::Value;
+    for rule in transforms.get(ns).into_iter().flatten() {
+        let ptr = transform_pointer(&rule.path);
+        if rule.op == TransformOp::Redact {
+            if let Some((parent, key)) = ptr.rsplit_once('/') {
+                if let Some(map) = body.pointer_mut(parent).and_then(|v| v.as_object_mut()) {
+                    map.remove(key);
+                }
+            }
+            continue;
+        }
+        let Some(v) = body.pointer_mut(&ptr) else {
+            continue;
+        };
+        match (rule.op, &mut *v) {
+            (TransformOp::Lowercase, Value::String(s)) => *s = s.to_lowercase(),
+            (TransformOp::Trim, Value::String(s)) => *s = s.trim().to_string(),
+            (TransformOp::Hash, Value::String(s)) => {
+                *s = agentstate_core::util::blake3_hex(s.as_bytes())
+            }
+            (TransformOp::Hash, Value::Null) => {}
+            (TransformOp::Hash, other) => {
+                *other = Value::String(agentstate_core::util::blake3_hex(
+                    other.to_string().as_bytes(),
+                ))
+            }
+            _ => {}
         }
     }
-    out
 }
 
 fn cosine_sim(a: &[f32], b: &[f32]) -> f32 {
@@ -689,30 +2684,855 @@ fn cosine_sim(a: &[f32], b: &[f32]) -> f32 {
     dot / (na.sqrt() * nb.sqrt())
 }
 
-impl InMemoryStore {
-    async fn cleanup_indexes_for(&self, obj: &Object) {
-        let mut inner = self.inner.write();
-        for (k, v) in obj.tags.0.iter() {
-            if let Some(set) = inner
-                .tag_index
-                .get_mut(&(obj.ns.clone(), k.clone(), v.clone()))
-            {
-                set.remove(&obj.id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{doc, query};
+    use crate::traits::GetOptions;
+    use serde_json::json;
+
+    fn ids(objs: &[Object]) -> Vec<&str> {
+        objs.iter().map(|o| o.id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn passes_the_shared_object_store_checks() {
+        crate::conformance::run(&InMemoryStore::new()).await;
+    }
+
+    fn lease(ttl: u64) -> RecBody {
+        RecBody::LeaseAcquire {
+            ns: "ns".into(),
+            key: "job".into(),
+            owner: "leader-side".into(),
+            token: 7,
+            ttl,
+        }
+    }
+
+    #[tokio::test]
+    async fn replicated_lease_runs_from_the_leaders_ts() {
+        let store = InMemoryStore::new();
+        let granted = (Utc::now() - Duration::seconds(120)).timestamp();
+        store.apply_records(granted, vec![lease(60)]).unwrap();
+        // Expired a minute ago on the leader, so it's free here too
+        let l = store.lease_acquire("ns", "job", "local", 30).await.unwrap();
+        assert_eq!(l.owner, "local");
+
+        let store = InMemoryStore::new();
+        store.apply_records(granted, vec![lease(600)]).unwrap();
+        let err = store
+            .lease_acquire("ns", "job", "local", 30)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StateError::Conflict(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn replicated_txn_is_applied_whole() {
+        let store = InMemoryStore::new();
+        let mut watch = store.subscribe(
+            WatchFilter {
+                ns: "ns".into(),
+                ..Default::default()
+            },
+            None,
+            None,
+        );
+        let put = |id: &str, seq: u64| RecBody::Put {
+            ns: "ns".into(),
+            obj: serde_json::json!({
+                "id": id, "ns": "ns", "type": "t", "body": {}, "tags": {},
+                "commit": "c", "commit_seq": seq, "ts": Utc::now(),
+            }),
+        };
+        let txn = vec![
+            RecBody::TxnBegin { id: "t1".into() },
+            put("a", 1),
+            put("b", 2),
+            RecBody::TxnCommit { id: "t1".into() },
+        ];
+        store.apply_records(Utc::now().timestamp(), txn).unwrap();
+        for id in ["a", "b"] {
+            let got = store
+                .get("ns", id, GetOptions { at_ts: None })
+                .await
+                .unwrap();
+            assert_eq!(got.id, id);
+            assert!(matches!(watch.try_next(), Some(WatchEvent::Put(o)) if o.id == id));
+        }
+    }
+
+    #[tokio::test]
+    async fn store_is_not_ready_until_the_ann_warm_up_finishes() {
+        let store = InMemoryStore::new();
+        for i in 0..32 {
+            let req = PutRequest {
+                r#type: "doc".into(),
+                body: serde_json::json!({"emb": [i as f32, 1.0, 0.5]}),
+                id: Some(format!("d{i}")),
+                ..Default::default()
+            };
+            store.put("ns", req).await.unwrap();
+        }
+        let field = VecField {
+            name: "emb".into(),
+            dims: 3,
+            mode: Default::default(),
+        };
+        store.register_vec_field("ns", field).unwrap();
+        assert!(!store.ann_warming());
+
+        // Hold the graph so the warm-up can't finish until released
+        let index = store.inner.read().ann.values().next().unwrap().clone();
+        let held = index.write();
+        // ANN_WARMUP is read once per process, so run the warm-up it gates directly
+        let warming = {
+            let (store, index) = (store.clone(), index.clone());
+            std::thread::spawn(move || store.run_warm_up(&[index], 16))
+        };
+        while !store.ann_warming() {
+            std::thread::yield_now();
+        }
+        drop(held);
+        assert_eq!(warming.join().unwrap(), 16);
+        assert!(!store.ann_warming());
+    }
+
+    #[tokio::test]
+    async fn untraced_queries_still_count_their_candidates() {
+        let store = InMemoryStore::new();
+        for (id, ty) in [("a", "doc"), ("b", "doc"), ("c", "note")] {
+            let req = PutRequest {
+                r#type: ty.into(),
+                body: serde_json::json!({"n": id}),
+                id: Some(id.into()),
+                ..Default::default()
+            };
+            store.put("ns", req).await.unwrap();
+        }
+        let req: QueryRequest = serde_json::from_value(serde_json::json!({
+            "type": "doc", "jsonpath": {"equals": {"n": "a"}}
+        }))
+        .unwrap();
+        let mut trace = QueryTrace::default();
+        let out = store.query_traced("ns", req, &mut trace).await.unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(trace.candidates, 2);
+        assert!(trace.stages.is_empty());
+
+        let req: QueryRequest = serde_json::from_value(serde_json::json!({
+            "any_of": [{"type": "doc"}, {"type": "note"}]
+        }))
+        .unwrap();
+        let mut trace = QueryTrace::default();
+        store.query_traced("ns", req, &mut trace).await.unwrap();
+        assert_eq!(trace.candidates, 3);
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_and_dropped_watches_release_their_buffers() {
+        let store = InMemoryStore::new();
+        let filter = WatchFilter {
+            ns: "ns".into(),
+            ..Default::default()
+        };
+        let buffers = |store: &InMemoryStore| store.inner.read().buffers.get("ns").map(Vec::len);
+        let mut first = store.subscribe(filter.clone(), None, None);
+        let second = store.subscribe(filter.clone(), None, None);
+        assert_eq!(buffers(&store), Some(2));
+
+        first.unsubscribe();
+        assert_eq!(buffers(&store), Some(1));
+        let req = PutRequest {
+            r#type: "t".into(),
+            body: serde_json::json!({}),
+            ..Default::default()
+        };
+        store.put("ns", req).await.unwrap();
+        assert!(first.try_next().is_none());
+
+        drop(second);
+        drop(first);
+        assert_eq!(buffers(&store), None);
+    }
+
+    #[tokio::test]
+    async fn coerced_vectors_of_any_length_are_all_ranked() {
+        let store = InMemoryStore::new();
+        let field = VecField {
+            name: "emb".into(),
+            dims: 3,
+            mode: VecDimsMode::PadOrTruncate,
+        };
+        store.register_vec_field("ns", field).unwrap();
+        store
+            .put("ns", doc("short", json!({"emb": [1.0, 0.0]})))
+            .await
+            .unwrap();
+        store
+            .put("ns", doc("exact", json!({"emb": [0.0, 1.0, 0.0]})))
+            .await
+            .unwrap();
+        store
+            .put("ns", doc("long", json!({"emb": [0.9, 0.1, 0.0, 5.0]})))
+            .await
+            .unwrap();
+        let long = store
+            .get("ns", "long", GetOptions { at_ts: None })
+            .await
+            .unwrap();
+        assert_eq!(long.body["emb"], json!([0.9, 0.1, 0.0]));
+
+        let vector = json!({"field": "emb", "top_k": 3, "embedding": [1.0, 0.0, 0.0]});
+        let out = store
+            .query("ns", query(json!({ "vector": vector })))
+            .await
+            .unwrap();
+        assert_eq!(ids(&out), ["short", "long", "exact"]);
+
+        let strict = VecField {
+            name: "emb".into(),
+            dims: 3,
+            mode: VecDimsMode::Strict,
+        };
+        store.register_vec_field("strict", strict).unwrap();
+        let err = store
+            .put("strict", doc("a", json!({"emb": [1.0]})))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StateError::Invalid(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn since_commit_seq_pulls_only_later_writes_in_order() {
+        let store = InMemoryStore::new();
+        for id in ["a", "b", "c", "d", "e"] {
+            store.put("ns", doc(id, json!({}))).await.unwrap();
+        }
+        // Rewriting b moves it past the cursor
+        store.put("ns", doc("b", json!({"v": 2}))).await.unwrap();
+        let out = store
+            .query("ns", query(json!({"since_commit_seq": 3})))
+            .await
+            .unwrap();
+        assert_eq!(ids(&out), ["d", "e", "b"]);
+        assert!(out.iter().all(|o| o.commit_seq > 3));
+
+        let first = query(json!({"since_commit_seq": 3, "limit": 2}));
+        let chunk = store.query("ns", first).await.unwrap();
+        assert_eq!(ids(&chunk), ["d", "e"]);
+        let next = query(json!({"since_commit_seq": chunk[1].commit_seq, "limit": 2}));
+        assert_eq!(ids(&store.query("ns", next).await.unwrap()), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn vector_scoring_leaves_the_runtime_free() {
+        // One runtime thread: scoring inline would hold every other request until it ended
+        let store = InMemoryStore::new();
+        for i in 0..20 {
+            let emb: Vec<f32> = (0..4).map(|d| ((i * 31 + d * 7) % 97) as f32).collect();
+            store
+                .put("vec", doc(&format!("v{i}"), json!({ "emb": emb })))
+                .await
+                .unwrap();
+        }
+        let field = VecField {
+            name: "emb".into(),
+            dims: 4,
+            mode: Default::default(),
+        };
+        store.register_vec_field("vec", field).unwrap();
+        store.put("kv", doc("k", json!({}))).await.unwrap();
+
+        // Scoring reads the graph first, so holding it keeps the query in the scoring stage
+        let index = store.inner.read().ann.values().next().unwrap().clone();
+        let (locked, release) = (std::sync::mpsc::channel(), std::sync::mpsc::channel::<()>());
+        let holder = std::thread::spawn(move || {
+            let _held = index.write();
+            locked.0.send(()).unwrap();
+            release.1.recv().unwrap();
+        });
+        locked.1.recv().unwrap();
+        let vector = json!({"field": "emb", "top_k": 5, "embedding": vec![1.0; 4]});
+        let q = query(json!({ "vector": vector }));
+        let scoring = {
+            let store = store.clone();
+            tokio::spawn(async move { store.query("vec", q).await })
+        };
+        tokio::task::yield_now().await;
+        assert!(store.get("kv", "k", GetOptions { at_ts: None }).await.is_ok());
+        assert!(!scoring.is_finished());
+        release.0.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(scoring.await.unwrap().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn composite_index_answers_a_two_key_filter_in_one_lookup() {
+        let store = InMemoryStore::new();
+        let tagged = [
+            ("a", "x", "open"),
+            ("b", "x", "done"),
+            ("c", "y", "open"),
+            ("d", "x", "open"),
+        ];
+        for (id, project, status) in tagged {
+            let mut req = doc(id, json!({}));
+            let tags = json!({"project": project, "status": status});
+            req.tags = serde_json::from_value(tags).unwrap();
+            store.put("ns", req).await.unwrap();
+        }
+        store
+            .register_composite_index("ns", vec!["status".into(), "project".into()])
+            .unwrap();
+        let q = query(json!({"tag_filter": {"project": "x", "status": "open"}}));
+        let (out, trace) = store.query_analyze("ns", q).await.unwrap();
+        assert_eq!(ids(&out), ["a", "d"]);
+        assert_eq!(trace.stages[0].stage, "composite_index");
+        assert_eq!(trace.stages[0].rows, 2);
+        assert!(trace.stages.iter().all(|s| s.stage != "tag_index"));
+    }
+
+    #[tokio::test]
+    async fn rename_moves_the_content_to_the_new_id() {
+        let store = InMemoryStore::new();
+        store.put("ns", doc("old", json!({"v": 1}))).await.unwrap();
+        let before = store
+            .get("ns", "old", GetOptions { at_ts: None })
+            .await
+            .unwrap();
+        let renamed = store.rename("ns", "old", "new").await.unwrap();
+        assert_eq!(renamed.id, "new");
+        let got = store.get("ns", "old", GetOptions { at_ts: None }).await;
+        assert!(matches!(got, Err(StateError::NotFound)));
+        let got = store
+            .get("ns", "new", GetOptions { at_ts: None })
+            .await
+            .unwrap();
+        assert_eq!(got.body, before.body);
+        assert_eq!(got.parents, [before.commit]);
+        let err = store.rename("ns", "old", "other").await.unwrap_err();
+        assert!(matches!(err, StateError::NotFound));
+    }
+
+    #[tokio::test]
+    async fn trim_keeps_only_the_newest_versions() {
+        let store = InMemoryStore::new();
+        for v in 0..10 {
+            store.put("ns", doc("a", json!({ "v": v }))).await.unwrap();
+        }
+        assert_eq!(store.trim_versions("ns", "a", 3).await.unwrap(), 7);
+        let versions = store.versions("ns", "a").await.unwrap();
+        let kept: Vec<_> = versions.iter().map(|o| o.body["v"].clone()).collect();
+        assert_eq!(kept, [7, 8, 9]);
+        assert_eq!(store.trim_versions("ns", "a", 3).await.unwrap(), 0);
+        let err = store.trim_versions("ns", "a", 0).await.unwrap_err();
+        assert!(matches!(err, StateError::Invalid(_)));
+    }
+
+    #[tokio::test]
+    async fn trim_spares_the_time_travel_window() {
+        let store = InMemoryStore::new();
+        let now = Utc::now();
+        for v in 0..10 {
+            let mut req = doc("a", json!({ "v": v }));
+            req.ts = Some(now - Duration::seconds(100 - v * 10));
+            store.put("ns", req).await.unwrap();
+        }
+        // v7..v9 fall after the cutoff; v6 is what a read at the cutoff sees
+        let cutoff = now - Duration::seconds(35);
+        let inner = store.inner.read();
+        let staged = InMemoryStore::stage_trim_in(&inner, "ns", "a", 2, cutoff).unwrap();
+        assert_eq!(staged, (4, 6));
+        let staged = InMemoryStore::stage_trim_in(&inner, "ns", "a", 5, cutoff).unwrap();
+        assert_eq!(staged, (5, 5));
+        let staged = InMemoryStore::stage_trim_in(&inner, "ns", "a", 1, now).unwrap();
+        assert_eq!(staged, (1, 9));
+    }
+
+    #[tokio::test]
+    async fn puts_over_a_tag_limit_are_rejected() {
+        let store = InMemoryStore::new();
+        let tagged = |tags: serde_json::Value| {
+            let mut req = doc("a", json!({}));
+            req.tags = serde_json::from_value(tags).unwrap();
+            req
+        };
+        let many: serde_json::Map<_, _> = (0..65).map(|i| (format!("k{i}"), json!("v"))).collect();
+        let over = [
+            (json!(many), "too many tags"),
+            (json!({ "k".repeat(129): "v" }), "tag key longer than 128"),
+            (json!({ "k": "v".repeat(1025) }), "value longer than 1024"),
+        ];
+        for (tags, msg) in over {
+            match store.put("ns", tagged(tags)).await {
+                Err(StateError::Invalid(m)) => assert!(m.contains(msg), "{m}"),
+                other => panic!("expected {msg}, got {other:?}"),
             }
         }
-        let paths_to_cleanup = inner.json_index_paths.get(&obj.ns).cloned();
-        if let Some(paths) = paths_to_cleanup {
-            for p in paths {
-                if let Some(val) = obj.body.pointer(&json_pointer_from_path(&p)) {
-                    if let Some(set) =
-                        inner
-                            .json_index
-                            .get_mut(&(obj.ns.clone(), p.clone(), val.to_string()))
-                    {
-                        set.remove(&obj.id);
-                    }
-                }
+        let at_limits = json!({ "k".repeat(128): "v".repeat(1024) });
+        store.put("ns", tagged(at_limits)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_query_stops_its_scan() {
+        let store = InMemoryStore::new();
+        for i in 0..2000 {
+            store.put("ns", doc(&format!("d{i}"), json!({ "n": i }))).await.unwrap();
+        }
+        let scan = || query(json!({"jsonpath": {"ranges": {"$.n": {"gte": 0}}}}));
+        let mut trace = QueryTrace::default();
+        let full = store.query_traced("ns", scan(), &mut trace).await.unwrap();
+        assert_eq!((full.len(), trace.scanned), (2000, 2000));
+
+        // Cancelled before it runs: the scan's first deadline poll ends it
+        let req = scan();
+        req.deadline.cancel();
+        let mut trace = QueryTrace::default();
+        let err = store.query_traced("ns", req, &mut trace).await.unwrap_err();
+        assert!(matches!(err, StateError::DeadlineExceeded), "{err:?}");
+        assert_eq!(trace.scanned, 0);
+    }
+
+    #[tokio::test]
+    async fn has_tag_keys_matches_any_value_of_the_key() {
+        let store = InMemoryStore::new();
+        let tagged = |id: &str, tags: serde_json::Value| {
+            let mut req = doc(id, json!({}));
+            req.tags = serde_json::from_value(tags).unwrap();
+            req
+        };
+        store.put("ns", tagged("a", json!({"trace_id": "t1"}))).await.unwrap();
+        store.put("ns", tagged("b", json!({"trace_id": "t2", "kind": "x"}))).await.unwrap();
+        store.put("ns", tagged("c", json!({"kind": "x"}))).await.unwrap();
+        store.put("other", tagged("d", json!({"trace_id": "t3"}))).await.unwrap();
+        let has = |q: serde_json::Value| {
+            let store = &store;
+            async move {
+                let mut trace = QueryTrace::default();
+                let got = store.query_traced("ns", query(q), &mut trace).await.unwrap();
+                (got.iter().map(|o| o.id.clone()).collect::<Vec<_>>(), trace.candidates)
+            }
+        };
+        // Served from the key index: only the objects carrying the key are visited
+        let (ids, visited) = has(json!({"has_tag_keys": ["trace_id"]})).await;
+        assert_eq!((ids, visited), (vec!["a".to_string(), "b".to_string()], 2));
+        let both = json!({"has_tag_keys": ["trace_id"], "tag_filter": {"kind": "x"}});
+        assert_eq!(has(both).await.0, ["b"]);
+        assert!(has(json!({"has_tag_keys": ["missing"]})).await.0.is_empty());
+
+        // The index follows the current version, and deletes
+        store.put("ns", tagged("a", json!({"other": "y"}))).await.unwrap();
+        store.delete("ns", "b").await.unwrap();
+        assert!(has(json!({"has_tag_keys": ["trace_id"]})).await.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn distinct_by_keeps_the_first_result_in_query_order() {
+        let store = InMemoryStore::new();
+        for (id, key, emb) in [
+            ("e3", Some("x"), [1.0, 0.0]),
+            ("e1", Some("x"), [0.0, 1.0]),
+            ("e2", Some("y"), [0.5, 0.5]),
+            ("e4", None, [0.9, 0.1]),
+        ] {
+            let body = match key {
+                Some(k) => json!({"dedup_id": k, "emb": emb}),
+                None => json!({"emb": emb}),
+            };
+            store.put("ns", doc(id, body)).await.unwrap();
+        }
+        let run = |q: serde_json::Value| {
+            let store = &store;
+            async move {
+                let got = store.query("ns", query(q)).await.unwrap();
+                got.into_iter().map(|o| o.id).collect::<Vec<_>>()
             }
+        };
+        // Scans come back in commit order, so the earlier write wins; objects without
+        // the key are never collapsed
+        assert_eq!(run(json!({"distinct_by": "$.dedup_id"})).await, ["e3", "e2", "e4"]);
+        // Under a vector query the better match wins instead
+        let ranked = json!({
+            "distinct_by": "$.dedup_id",
+            "vector": {"field": "emb", "top_k": 4, "embedding": [0.0, 1.0]}
+        });
+        assert_eq!(run(ranked).await, ["e1", "e2", "e4"]);
+        // The limit counts what's left after collapsing
+        assert_eq!(run(json!({"distinct_by": "$.dedup_id", "limit": 2})).await, ["e3", "e2"]);
+    }
+
+    #[tokio::test]
+    async fn txn_with_one_invalid_op_applies_nothing() {
+        let store = InMemoryStore::new();
+        store.put("ns", doc("keep", json!({"n": 1}))).await.unwrap();
+        let mut watch = store.subscribe(
+            WatchFilter {
+                ns: "ns".into(),
+                ..Default::default()
+            },
+            None,
+            None,
+        );
+        let mut deep = json!(1);
+        for _ in 0..100 {
+            deep = json!([deep]);
+        }
+        let ops = vec![
+            TxnOp::Put(doc("a", json!({}))),
+            TxnOp::Patch {
+                id: "keep".into(),
+                patch: json!({"n": 2}),
+            },
+            TxnOp::Delete { id: "keep".into() },
+            TxnOp::Put(doc("b", deep)),
+        ];
+        let err = store.txn("ns", ops).await.unwrap_err();
+        assert!(matches!(&err, StateError::Invalid(m) if m.starts_with("op 3:")), "{err:?}");
+        let kept = store
+            .get("ns", "keep", GetOptions { at_ts: None })
+            .await
+            .unwrap();
+        assert_eq!(kept.body["n"], 1);
+        assert_eq!(kept.commit_seq, 1);
+        let get = store.get("ns", "a", GetOptions { at_ts: None }).await;
+        assert!(matches!(get, Err(StateError::NotFound)));
+        assert!(watch.try_next().is_none());
+        // The seqs it would have used are still free
+        let next = store.put("ns", doc("c", json!({}))).await.unwrap();
+        assert_eq!(next.commit_seq, 2);
+    }
+
+    #[tokio::test]
+    async fn reads_are_counted_without_new_versions() {
+        let store = InMemoryStore::new();
+        store.put("ns", doc("a", json!({"k": 1}))).await.unwrap();
+        store.put("ns", doc("b", json!({"k": 2}))).await.unwrap();
+        assert!(store.access_stats("ns", "a").is_none());
+        let mut last = None;
+        for n in 1..=3 {
+            store.get("ns", "a", GetOptions { at_ts: None }).await.unwrap();
+            let stats = store.access_stats("ns", "a").unwrap();
+            assert_eq!(stats.access_count, n);
+            assert!(last.is_none_or(|l| stats.last_access >= l));
+            last = Some(stats.last_access);
+        }
+        // A query counts its hits only
+        let q = query(json!({"jsonpath": {"equals": {"k": 1}}}));
+        assert_eq!(ids(&store.query("ns", q).await.unwrap()), ["a"]);
+        assert_eq!(store.access_stats("ns", "a").unwrap().access_count, 4);
+        assert!(store.access_stats("ns", "b").is_none());
+        assert_eq!(store.versions("ns", "a").await.unwrap().len(), 1);
+
+        store.delete("ns", "a").await.unwrap();
+        assert!(store.access_stats("ns", "a").is_none());
+    }
+
+    #[tokio::test]
+    async fn unfiltered_queries_come_back_in_the_same_order() {
+        let store = InMemoryStore::new();
+        // Enough ids that hash order would differ from insertion order
+        let written: Vec<String> = (0..50).rev().map(|i| format!("o{i:02}")).collect();
+        for id in &written {
+            store.put("ns", doc(id, json!({}))).await.unwrap();
+        }
+        let first = store.query("ns", query(json!({}))).await.unwrap();
+        let second = store.query("ns", query(json!({}))).await.unwrap();
+        assert_eq!(ids(&first), ids(&second));
+        // Commit order, which here is the order they were written
+        assert_eq!(ids(&first), written);
+        let page = store.query("ns", query(json!({"limit": 5}))).await.unwrap();
+        assert_eq!(ids(&page), &written[..5]);
+    }
+
+    #[tokio::test]
+    async fn transforms_normalise_bodies_before_they_are_stored() {
+        let store = InMemoryStore::new();
+        let rule = |path: &str, op| TransformRule {
+            path: path.into(),
+            op,
+        };
+        store.register_transform("ns", rule("body.email", TransformOp::Trim)).unwrap();
+        store.register_transform("ns", rule("body.email", TransformOp::Lowercase)).unwrap();
+        store.register_transform("ns", rule("body.ssn", TransformOp::Redact)).unwrap();
+        assert!(store.register_transform("ns", rule("body.", TransformOp::Trim)).is_err());
+
+        let body = json!({"email": "  Ann@Example.COM ", "ssn": "123"});
+        let put = store.put("ns", doc("a", body.clone())).await.unwrap();
+        assert_eq!(put.body, json!({"email": "ann@example.com"}));
+        let got = store.get("ns", "a", GetOptions { at_ts: None }).await.unwrap();
+        assert_eq!(got.body, put.body);
+        let q = query(json!({"jsonpath": {"equals": {"email": "ann@example.com"}}}));
+        assert_eq!(ids(&store.query("ns", q).await.unwrap()), ["a"]);
+        // Rules belong to their namespace
+        let other = store.put("other", doc("a", body.clone())).await.unwrap();
+        assert_eq!(other.body, body);
+    }
+
+    #[tokio::test]
+    async fn pii_objects_need_a_ttl_within_the_class_maximum() {
+        let store = InMemoryStore::new();
+        let classified = |id: &str, class: &str, ttl: Option<u64>| {
+            let mut req = doc(id, json!({}));
+            req.tags = serde_json::from_value(json!({"classification": class})).unwrap();
+            req.ttl_seconds = ttl;
+            req
+        };
+        let thirty_days = 30 * 24 * 3600;
+        for ttl in [None, Some(thirty_days + 1)] {
+            let err = store.put("ns", classified("p", "pii", ttl)).await.unwrap_err();
+            assert!(matches!(&err, StateError::Invalid(m) if m.contains("pii")), "{err:?}");
+        }
+        let put = store
+            .put("ns", classified("p", "pii", Some(thirty_days)))
+            .await
+            .unwrap();
+        assert_eq!(put.ttl_seconds, Some(thirty_days));
+        // Classes without a maximum are unrestricted
+        store.put("ns", classified("q", "public", None)).await.unwrap();
+        let q = query(json!({"tag_filter": {"classification": "pii"}}));
+        let found = store.query("ns", q).await.unwrap();
+        assert_eq!(ids(&found), ["p"]);
+        assert_eq!(found[0].tags.0["classification"], "pii");
+    }
+
+    #[tokio::test]
+    async fn ulid_ids_carry_the_objects_creation_time() {
+        use agentstate_core::util::ulid_timestamp;
+        // The example from the ULID spec
+        let known = ulid_timestamp("01ARZ3NDEKTSV4RRFFQ69G5FAV").unwrap();
+        assert_eq!(known.to_rfc3339(), "2016-07-30T23:54:10.259+00:00");
+        assert!(ulid_timestamp("not-a-ulid").is_none());
+
+        let store = InMemoryStore::new();
+        let mut req = doc("x", json!({}));
+        req.id = None;
+        let put = store.put("ns", req).await.unwrap();
+        let created = ulid_timestamp(&put.id).unwrap();
+        assert_eq!(created.timestamp_millis(), put.ts.timestamp_millis());
+        let v = serde_json::to_value(&put).unwrap();
+        assert_eq!(v["created_at"], json!(created));
+        // Ids that aren't ULIDs get no derived field
+        let named = store.put("ns", doc("x", json!({}))).await.unwrap();
+        assert!(serde_json::to_value(&named).unwrap().get("created_at").is_none());
+    }
+
+    #[tokio::test]
+    async fn vector_results_only_come_from_the_filtered_set() {
+        let store = InMemoryStore::new();
+        // Project y holds the closest vectors, so a stage run before filtering would pick them
+        for i in 0..20 {
+            let (project, emb) = if i % 2 == 0 {
+                ("x", [1.0, i as f32])
+            } else {
+                ("y", [1.0, 0.0])
+            };
+            let mut req = doc(&format!("d{i:02}"), json!({"project": project, "emb": emb}));
+            req.tags = serde_json::from_value(json!({"project": project})).unwrap();
+            store.put("ns", req).await.unwrap();
+        }
+        let vector = json!({"field": "emb", "top_k": 3, "embedding": [1.0, 0.0]});
+        // Served from the tag index, and from a full scan on an unindexed body path
+        let filters = [
+            json!({"tag_filter": {"project": "x"}, "vector": vector}),
+            json!({"jsonpath": {"equals": {"project": "x"}}, "vector": vector}),
+        ];
+        for q in filters {
+            let got = store.query("ns", query(q)).await.unwrap();
+            assert_eq!(ids(&got), ["d00", "d02", "d04"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn index_check_reports_entries_the_objects_disagree_with() {
+        let store = InMemoryStore::new();
+        store.register_index_paths("ns", vec!["$.n".into()]).unwrap();
+        let mut req = doc("a", json!({"n": 1}));
+        req.tags = serde_json::from_value(json!({"k": "v"})).unwrap();
+        store.put("ns", req).await.unwrap();
+        assert!(store.check_indexes(1000).is_empty());
+
+        // Entries no write would leave behind
+        {
+            let mut inner = store.inner.write();
+            let key = ("ns".to_string(), "k".to_string(), "stale".to_string());
+            inner.tag_index.entry(key).or_default().insert("a".into(), ());
+            let key = ("ns".to_string(), "k".to_string(), "v".to_string());
+            inner.tag_index.entry(key).or_default().insert("gone".into(), ());
+            let path = inner.json_index.keys().next().unwrap().1.clone();
+            let key = ("ns".to_string(), path, "2".to_string());
+            inner.json_index.entry(key).or_default().insert("a".into(), ());
+        }
+        let mut found: Vec<_> = store
+            .check_indexes(1000)
+            .into_iter()
+            .map(|m| (m.index, m.id, m.detail))
+            .collect();
+        found.sort();
+        assert_eq!(found.len(), 3, "{found:?}");
+        assert_eq!((found[0].0, found[0].1.as_str()), ("json", "a"));
+        assert_eq!(found[1], ("tag", "a".into(), "tag k=stale but object has v".into()));
+        assert_eq!(found[2], ("tag", "gone".into(), "object not found".into()));
+    }
+
+    #[tokio::test]
+    async fn advisory_locks_show_until_they_expire() {
+        let store = InMemoryStore::new();
+        for id in ["a", "b"] {
+            store.put("ns", doc(id, json!({}))).await.unwrap();
+        }
+        let locked = |want: bool| query(json!({"locked": want}));
+        store.advisory_lock("ns", "a", "agent-1", 60).unwrap();
+        assert_eq!(store.advisory_lock_state("ns", "a").unwrap().owner, "agent-1");
+        assert!(store.advisory_lock_state("ns", "b").is_none());
+        assert_eq!(ids(&store.query("ns", locked(true)).await.unwrap()), ["a"]);
+        assert_eq!(ids(&store.query("ns", locked(false)).await.unwrap()), ["b"]);
+        let err = store.advisory_lock("ns", "a", "agent-2", 60).unwrap_err();
+        assert!(matches!(err, StateError::Conflict(m) if m.contains("agent-1")));
+        // Cooperative only: the lock doesn't stand in the way of writes
+        store.put("ns", doc("a", json!({"by": "agent-2"}))).await.unwrap();
+
+        // Let the lock lapse
+        let past = Utc::now() - Duration::seconds(1);
+        store.locks.lock().get_mut(&("ns".into(), "a".into())).unwrap().expires_at = past;
+        assert!(store.advisory_lock_state("ns", "a").is_none());
+        assert!(store.query("ns", locked(true)).await.unwrap().is_empty());
+        assert_eq!(ids(&store.query("ns", locked(false)).await.unwrap()), ["b", "a"]);
+        store.advisory_lock("ns", "a", "agent-2", 60).unwrap();
+        assert!(matches!(store.advisory_lock("ns", "gone", "agent-2", 60), Err(StateError::NotFound)));
+    }
+
+    #[test]
+    fn each_id_strategy_makes_ids_of_its_own_format() {
+        let now = Utc::now();
+        let body = json!({"b": 2, "a": [1, "x"]});
+        let id = |s: IdStrategy| s.generate(now, &body);
+
+        let ulid = ulid::Ulid::from_string(&id(IdStrategy::Ulid)).unwrap();
+        assert_eq!(ulid.timestamp_ms(), now.timestamp_millis() as u64);
+        let v4 = uuid::Uuid::parse_str(&id(IdStrategy::UuidV4)).unwrap();
+        assert_eq!(v4.get_version_num(), 4);
+        assert_ne!(id(IdStrategy::UuidV4), id(IdStrategy::UuidV4));
+        let v7 = uuid::Uuid::parse_str(&id(IdStrategy::UuidV7)).unwrap();
+        assert_eq!(v7.get_version_num(), 7);
+        let (secs, _) = v7.get_timestamp().unwrap().to_unix();
+        assert_eq!(secs, now.timestamp() as u64);
+
+        let hash = id(IdStrategy::ContentHash);
+        assert_eq!(hash.len(), 32);
+        assert!(hash.bytes().all(|b| b.is_ascii_hexdigit()));
+        // Same body, whatever the key order or the time, same id
+        let reordered = json!({"a": [1, "x"], "b": 2});
+        let later = now + Duration::hours(1);
+        assert_eq!(IdStrategy::ContentHash.generate(later, &reordered), hash);
+        assert_ne!(IdStrategy::ContentHash.generate(now, &json!({"b": 3})), hash);
+        assert_eq!(IdStrategy::default(), IdStrategy::Ulid);
+    }
+
+    #[tokio::test]
+    async fn the_sweeper_only_visits_objects_with_a_ttl() {
+        let store = InMemoryStore::new();
+        for i in 0..500 {
+            store.put("ns", doc(&format!("keep{i}"), json!({}))).await.unwrap();
+        }
+        let ttl = |id: &str, secs: u64, age: i64| {
+            let mut req = doc(id, json!({}));
+            req.ttl_seconds = Some(secs);
+            req.ts = Some(Utc::now() - Duration::seconds(age));
+            req
+        };
+        for id in ["old1", "old2", "old3"] {
+            store.put("ns", ttl(id, 1, 60)).await.unwrap();
+        }
+        store.put("ns", ttl("fresh", 3600, 0)).await.unwrap();
+        store.put("ns", ttl("untimed", 3600, 0)).await.unwrap();
+        store.put("ns", ttl("gone", 3600, 0)).await.unwrap();
+        let indexed = |store: &InMemoryStore| {
+            let inner = store.inner.read();
+            let mut ids: Vec<_> = inner.expiry_index.iter().map(|(_, _, id)| id.clone()).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(indexed(&store), ["fresh", "gone", "old1", "old2", "old3", "untimed"]);
+        // Dropping the TTL or the object takes it out of the index
+        store.put("ns", doc("untimed", json!({}))).await.unwrap();
+        store.delete("ns", "gone").await.unwrap();
+        assert_eq!(indexed(&store), ["fresh", "old1", "old2", "old3"]);
+
+        let swept = store.sweep_expired(0).await.unwrap();
+        let mut swept = ids(&swept);
+        swept.sort();
+        assert_eq!(swept, ["old1", "old2", "old3"]);
+        assert_eq!(indexed(&store), ["fresh"]);
+        assert_eq!(store.inner.read().data.len(), 502);
+        assert!(store.sweep_expired(0).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_paths_mix_fields_and_array_steps() {
+        assert_eq!(json_path_segments("a.b[2].c"), Some(vec!["a", "b", "2", "c"]));
+        assert_eq!(json_path_segments("a[0][-]"), Some(vec!["a", "0", "-"]));
+        assert_eq!(json_pointer_from_path("$.a.b[2].c"), "/a/b/2/c");
+        assert_eq!(json_pointer_from_path(" $.steps[-].tool "), "/steps/-/tool");
+        let body = json!({"a": {"b": [0, 1, {"c": "x"}]}, "steps": [{"tool": "a"}, {"tool": "b"}]});
+        assert_eq!(value_at_path(&body, "$.a.b[2].c"), Some(&json!("x")));
+        assert_eq!(value_at_path(&body, "steps[-].tool"), Some(&json!("b")));
+        assert_eq!(value_at_path(&json!({"a": []}), "a[-]"), None);
+        // Out of range, or stepping into a scalar
+        assert_eq!(value_at_path(&body, "a.b[3]"), None);
+        assert_eq!(value_at_path(&body, "a.b[0].c"), None);
+        assert_eq!(value_at_pointer(&body, "/a/b/99999999999999999999"), None);
+    }
+
+    #[test]
+    fn malformed_json_path_brackets_match_nothing() {
+        for path in ["a[", "a[1", "a[]", "a[x]", "a[1]b", "a[-1]"] {
+            assert_eq!(json_path_segments(path), None, "{path}");
+            assert!(!is_valid_json_path(path), "{path}");
+        }
+        // Kept verbatim, so the pointer can't hit a real field
+        assert_eq!(json_pointer_from_path("$.a[1"), "/a[1");
+        assert_eq!(value_at_path(&json!({"a": [1, 2]}), "a[1"), None);
+    }
+
+    #[tokio::test]
+    async fn client_ts_may_rewind_only_within_the_clock_skew() {
+        let store = InMemoryStore::new();
+        let first = store.put("n", doc("a", json!({}))).await.unwrap();
+        let skew = Duration::seconds(*MAX_CLOCK_SKEW_SECS);
+        let mut req = doc("a", json!({}));
+        req.ts = Some(first.ts - skew - Duration::seconds(1));
+        assert!(matches!(store.put("n", req).await, Err(StateError::Invalid(_))));
+        let mut req = doc("a", json!({}));
+        req.ts = Some(first.ts - skew + Duration::seconds(1));
+        let second = store.put("n", req).await.unwrap();
+        assert_eq!(second.ts, first.ts - skew + Duration::seconds(1));
+    }
+
+    #[tokio::test]
+    async fn bodies_nested_past_the_depth_limit_are_rejected() {
+        let nested = |levels: usize| (0..levels).fold(json!(1), |v, _| json!([v]));
+        let store = InMemoryStore::new();
+        let deep = doc("a", json!({"x": nested(*MAX_BODY_DEPTH)}));
+        assert!(matches!(store.put("n", deep).await, Err(StateError::Invalid(_))));
+        let edge = doc("a", json!({"x": nested(*MAX_BODY_DEPTH - 1)}));
+        store.put("n", edge).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_full_byte_quota_refuses_puts_until_a_delete_frees_room() {
+        let store = InMemoryStore::new();
+        for id in ["a", "b"] {
+            store.put("n", doc(id, json!({"pad": "xxxxxxxx"}))).await.unwrap();
         }
+        let full = store.usage("n").unwrap().bytes;
+        store.set_quota("n", NsQuota { max_objects: None, max_bytes: Some(full) }).unwrap();
+        let err = store.put("n", doc("c", json!({"pad": "xxxxxxxx"}))).await.unwrap_err();
+        assert!(matches!(err, StateError::Invalid(m) if m.starts_with("quota_exceeded")));
+        assert_eq!(store.usage("n").unwrap().objects, 2);
+        store.delete("n", "a").await.unwrap();
+        store.put("n", doc("c", json!({"pad": "xxxxxxxx"}))).await.unwrap();
+        assert_eq!(store.usage("n").unwrap().bytes, full);
     }
 }
@@ -0,0 +1,36 @@
+//! The one registry every agentstate metric registers into, so `METRICS_PREFIX` reaches
+//! all of them.
+
+use once_cell::sync::Lazy;
+use prometheus::Registry;
+
+// METRICS_PREFIX: prepended to every metric name, joined with `_` (default: none).
+// `agentstate_` is recommended when sharing a Prometheus with other services.
+static PREFIX: Lazy<Option<String>> = Lazy::new(|| {
+    let p = std::env::var("METRICS_PREFIX").ok()?;
+    let p = p.trim_end_matches('_');
+    if p.is_empty() {
+        return None;
+    }
+    let valid = !p.starts_with(|c: char| c.is_ascii_digit())
+        && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if !valid {
+        tracing::warn!("METRICS_PREFIX {:?} is not a valid metric name prefix; ignoring", p);
+        return None;
+    }
+    Some(p.to_string())
+});
+
+pub static REGISTRY: Lazy<Registry> =
+    Lazy::new(|| Registry::new_custom(PREFIX.clone(), None).unwrap());
+
+/// A gathered family name without `METRICS_PREFIX`, for lookups by base name.
+pub fn unprefixed(name: &str) -> &str {
+    match PREFIX.as_deref() {
+        Some(p) => name
+            .strip_prefix(p)
+            .and_then(|n| n.strip_prefix('_'))
+            .unwrap_or(name),
+        None => name,
+    }
+}
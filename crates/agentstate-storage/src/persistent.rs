@@ -1,63 +1,307 @@
 use crate::walbin::{Manifest, RecBody, WalWriter};
-use crate::{InMemoryStore, Storage};
-use agentstate_core::{Object, PutRequest, QueryRequest, Result, StateError};
-use chrono::Utc;
+use crate::traits::{AdminOps, IdempotencyStore, LeaseStore, ObjectStore, WatchEvent, WatchSource};
+use crate::mem::PutMode;
+use crate::InMemoryStore;
+use agentstate_core::{Object, PutRequest, QueryRequest, Result, StateError, TxnOp};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_gauge_with_registry, IntGauge};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 use std::{io::Write, path::PathBuf};
 use tokio::sync::Mutex;
 use ulid;
 use zstd;
 
+/// Objects and uncompressed bytes written by the current (or last) snapshot.
+pub static SNAPSHOT_PROGRESS_OBJECTS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "snapshot_progress_objects",
+        "Objects written by the current snapshot",
+        crate::metrics::REGISTRY
+    )
+    .unwrap()
+});
+
+pub static SNAPSHOT_PROGRESS_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "snapshot_progress_bytes",
+        "Uncompressed bytes written by the current snapshot",
+        crate::metrics::REGISTRY
+    )
+    .unwrap()
+});
+
+// WAL_FAILURE_MODE: what writes do once WAL appends start failing (disk full, remount)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WalFailureMode {
+    // Refuse writes while the WAL is failing, letting one through per second as a probe
+    Reject,
+    // Stop taking writes after the first failure and serve reads until restart
+    Readonly,
+}
+
+static WAL_FAILURE_MODE: Lazy<WalFailureMode> =
+    Lazy::new(|| match std::env::var("WAL_FAILURE_MODE").as_deref() {
+        Ok("readonly") => WalFailureMode::Readonly,
+        Ok("reject") | Err(_) => WalFailureMode::Reject,
+        Ok(other) => {
+            tracing::warn!("unknown WAL_FAILURE_MODE {:?}, using reject", other);
+            WalFailureMode::Reject
+        }
+    });
+
+const WAL_PROBE_INTERVAL_MS: i64 = 1000;
+
+/// WAL_DISABLED=1: writes aren't logged and only snapshots make them durable.
+pub fn wal_disabled() -> bool {
+    std::env::var("WAL_DISABLED")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+static WRITES_COMMITTED: AtomicU64 = AtomicU64::new(0);
+
+/// Object writes this process has committed so far, logged or not.
+pub fn writes_committed() -> u64 {
+    WRITES_COMMITTED.load(Ordering::Relaxed)
+}
+
 pub struct PersistentStore {
     mem: InMemoryStore,
     wal: Mutex<WalWriter>,
     manifest: parking_lot::RwLock<Manifest>,
     data_dir: PathBuf,
+    // WAL_DISABLED=1: skip per-write logging; durability comes from snapshots only
+    wal_disabled: bool,
+    // What open couldn't recover (unreadable segments, a bad snapshot); empty after a clean start
+    recovery_issues: Vec<String>,
     idem: parking_lot::RwLock<
         std::collections::HashMap<(String, String), crate::traits::IdempotencyRecord>,
     >,
+    // Set by a failed WAL append, cleared by the next successful one
+    wal_failing: AtomicBool,
+    // WAL_FAILURE_MODE=readonly: latched by the first failure
+    read_only: AtomicBool,
+    last_probe_ms: AtomicI64,
 }
 
 impl PersistentStore {
     pub fn open(data_dir: PathBuf) -> std::io::Result<Self> {
+        Self::open_with(data_dir, wal_disabled())
+    }
+
+    pub(crate) fn open_with(data_dir: PathBuf, wal_disabled: bool) -> std::io::Result<Self> {
         let wal_writer = WalWriter::open(&data_dir, 256 * 1024 * 1024, 0)?;
         let manifest = wal_writer.manifest();
-        // Replay existing WAL
-        let recs = crate::walbin::replay(&data_dir).unwrap_or_default();
         let mem = InMemoryStore::new();
-        let mut max_seq_per_ns: std::collections::HashMap<String, u64> = Default::default();
-        for r in recs {
-            match r {
-                RecBody::Put { ns: _, obj } => {
-                    if let Ok(o) = serde_json::from_value::<Object>(obj) {
-                        // Track per-ns commit seq
-                        max_seq_per_ns
-                            .entry(o.ns.clone())
-                            .and_modify(|m| *m = (*m).max(o.commit_seq))
-                            .or_insert(o.commit_seq);
-                        mem.replay_put(o);
-                    }
+        let mut recovery_issues = Vec::new();
+        if wal_disabled {
+            // Nothing was logged since the last snapshot, so it is the whole recoverable state
+            if let Some(snap) = &manifest.current_snapshot {
+                match read_snapshot_objects(&data_dir.join("snapshots").join(snap)) {
+                    Ok(objs) => objs.into_iter().for_each(|o| mem.replay_put(o)),
+                    Err(e) => recovery_issues.push(format!("snapshot {}: {}", snap, e)),
                 }
-                RecBody::Delete { ns, id } => {
-                    let seq = *max_seq_per_ns.get(&ns).unwrap_or(&0);
-                    mem.replay_delete(&ns, &id, seq);
+            }
+        }
+        // Replay existing WAL (with the WAL disabled the snapshot already holds the latest state)
+        let mut max_seq_per_ns: std::collections::HashMap<String, u64> = Default::default();
+        let apply = |ts: i64, r: RecBody| match r {
+            RecBody::Put { ns: _, obj } => {
+                if let Ok(o) = serde_json::from_value::<Object>(obj) {
+                    // Track per-ns commit seq
+                    max_seq_per_ns
+                        .entry(o.ns.clone())
+                        .and_modify(|m| *m = (*m).max(o.commit_seq))
+                        .or_insert(o.commit_seq);
+                    mem.replay_put(o);
                 }
-                RecBody::LeaseAcquire { .. }
-                | RecBody::LeaseRenew { .. }
-                | RecBody::LeaseRelease { .. }
-                | RecBody::Idempotency { .. } => {
-                    // TODO: apply to lease and idempotency stores; left as an exercise for now
+            }
+            RecBody::Delete { ns, id, commit_seq } => {
+                // Older records don't carry their seq: take the next after the last known
+                let last = max_seq_per_ns.entry(ns.clone()).or_insert(0);
+                let seq = if commit_seq > 0 { commit_seq } else { *last + 1 };
+                *last = (*last).max(seq);
+                mem.replay_delete(&ns, &id, seq);
+            }
+            RecBody::TrimVersions { ns, id, keep } => {
+                mem.drop_old_versions(&ns, &id, keep);
+            }
+            RecBody::LeaseAcquire {
+                ns,
+                key,
+                owner,
+                token,
+                ttl,
+            }
+            | RecBody::LeaseRenew {
+                ns,
+                key,
+                owner,
+                token,
+                ttl,
+            } => {
+                // The lease ran for `ttl` from when it was granted, not from the restart
+                let granted = DateTime::from_timestamp(ts, 0).unwrap_or_else(Utc::now);
+                let expires_at = granted + chrono::Duration::seconds(ttl as i64);
+                mem.replay_lease(&ns, &key, &owner, token, expires_at);
+            }
+            RecBody::LeaseRelease { ns, key, token, .. } => {
+                mem.replay_lease_release(&ns, &key, token);
+            }
+            RecBody::Idempotency { .. } => {
+                // TODO: apply to the idempotency store; left as an exercise for now
+            }
+            // replay_with only hands over the records of committed transactions
+            RecBody::TxnBegin { .. } | RecBody::TxnCommit { .. } => {}
+        };
+        if !wal_disabled {
+            match crate::walbin::replay_timed(&data_dir, apply) {
+                Ok(report) => {
+                    tracing::info!(
+                        records = report.records_applied,
+                        crc_failures = report.crc_failures,
+                        "wal replayed"
+                    );
+                    recovery_issues.extend(report.issues);
                 }
+                Err(e) => recovery_issues.push(format!("wal replay: {}", e)),
             }
         }
+        for issue in &recovery_issues {
+            tracing::error!("degraded recovery: {}", issue);
+        }
         Ok(Self {
             mem,
             wal: Mutex::new(wal_writer),
             manifest: parking_lot::RwLock::new(manifest),
             data_dir,
+            wal_disabled,
+            recovery_issues,
             idem: parking_lot::RwLock::new(std::collections::HashMap::new()),
+            wal_failing: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+            last_probe_ms: AtomicI64::new(0),
         })
     }
 
+    // Checked before a write is staged; see log_and_commit for why a refused write is
+    // never applied
+    fn admit_write(&self) -> Result<()> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(StateError::Unavailable(
+                "read-only after a WAL failure; restart once the data dir is writable".into(),
+            ));
+        }
+        if !self.wal_failing.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp_millis();
+        let last = self.last_probe_ms.load(Ordering::Relaxed);
+        let probe = now - last >= WAL_PROBE_INTERVAL_MS
+            && self
+                .last_probe_ms
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok();
+        if probe {
+            return Ok(());
+        }
+        Err(StateError::Unavailable("wal is failing; retry later".into()))
+    }
+
+    fn wal_result(&self, res: std::io::Result<()>) -> Result<()> {
+        match res {
+            Ok(()) => {
+                self.wal_failing.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+            // A record too large to log: the write is refused, the WAL itself is fine
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                Err(StateError::Invalid(e.to_string()))
+            }
+            Err(e) => {
+                self.wal_failing.store(true, Ordering::Relaxed);
+                // The next probe waits a full interval from this failure
+                self.last_probe_ms
+                    .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+                if *WAL_FAILURE_MODE == WalFailureMode::Readonly
+                    && !self.read_only.swap(true, Ordering::Relaxed)
+                {
+                    tracing::error!("wal failed, store is now read-only: {}", e);
+                }
+                Err(StateError::Unavailable(format!("wal write failed: {}", e)))
+            }
+        }
+    }
+
+    async fn log(&self, seq: u64, body: &RecBody) -> Result<()> {
+        if self.wal_disabled {
+            return Ok(());
+        }
+        let wal = self.wal.lock().await;
+        let res = wal.append(seq, Utc::now().timestamp(), body).await;
+        self.wal_result(res)
+    }
+
+    // Appends records back to back, for callers already holding the WAL lock
+    async fn append_locked(&self, wal: &WalWriter, recs: &[(u64, RecBody)]) -> Result<()> {
+        if self.wal_disabled {
+            return Ok(());
+        }
+        let res = wal.append_all(Utc::now().timestamp(), recs).await;
+        self.wal_result(res)
+    }
+
+    // Logs staged events and only then applies them, so a write the WAL refused is never
+    // seen by readers or watchers. The caller holds the WAL lock from staging on, which
+    // keeps other writes from staging against the same state.
+    async fn log_and_commit(
+        &self,
+        wal: &WalWriter,
+        events: Vec<WatchEvent>,
+        txn: bool,
+    ) -> Result<()> {
+        let mut records: Vec<(u64, RecBody)> = events
+            .iter()
+            .map(|ev| {
+                let body = match ev {
+                    WatchEvent::Put(o) => RecBody::Put {
+                        ns: o.ns.clone(),
+                        obj: serde_json::to_value(o).unwrap(),
+                    },
+                    WatchEvent::Delete { ns, id, commit_seq } => RecBody::Delete {
+                        ns: ns.clone(),
+                        id: id.clone(),
+                        commit_seq: *commit_seq,
+                    },
+                };
+                (ev.commit_seq(), body)
+            })
+            .collect();
+        let last_seq = records.iter().map(|(seq, _)| *seq).max().unwrap_or(0);
+        if txn {
+            let id = ulid::Ulid::new().to_string();
+            records.insert(0, (0, RecBody::TxnBegin { id: id.clone() }));
+            records.push((0, RecBody::TxnCommit { id }));
+        }
+        self.append_locked(wal, &records).await?;
+        self.mem.commit_staged(&events);
+        WRITES_COMMITTED.fetch_add(events.len() as u64, Ordering::Relaxed);
+        let mut m = self.manifest.write();
+        m.last_seq = m.last_seq.max(last_seq);
+        Ok(())
+    }
+
+    async fn put_mode(&self, ns: &str, req: PutRequest, mode: PutMode) -> Result<(Object, bool)> {
+        self.admit_write()?;
+        let wal = self.wal.lock().await;
+        let (o, new) = self.mem.stage_put(ns, req, mode)?;
+        if new {
+            self.log_and_commit(&wal, vec![WatchEvent::Put(o.clone())], false).await?;
+        }
+        Ok((o, new))
+    }
+
     pub fn snapshot(&self) -> std::io::Result<String> {
         let ulid = ulid::Ulid::new().to_string();
         let path = self
@@ -66,10 +310,21 @@ impl PersistentStore {
             .join(format!("snap-{}.zst", ulid));
         let file = std::fs::File::create(&path)?;
         let mut z = zstd::Encoder::new(file, 3)?;
-        for o in self.mem.all_objects().into_iter() {
+        SNAPSHOT_PROGRESS_OBJECTS.set(0);
+        SNAPSHOT_PROGRESS_BYTES.set(0);
+        // Expired-but-unswept objects are left out so a restore doesn't bring them back
+        let now = Utc::now();
+        for o in self
+            .mem
+            .all_objects()
+            .into_iter()
+            .filter(|o| !InMemoryStore::is_expired(o, now))
+        {
             let line = serde_json::to_string(&o).unwrap();
             z.write_all(line.as_bytes())?;
             z.write_all(b"\n")?;
+            SNAPSHOT_PROGRESS_OBJECTS.inc();
+            SNAPSHOT_PROGRESS_BYTES.add(line.len() as i64 + 1);
         }
         z.finish()?;
         let mut m = self.manifest.write();
@@ -85,55 +340,212 @@ impl PersistentStore {
 }
 
 #[async_trait::async_trait]
-impl Storage for PersistentStore {
+impl ObjectStore for PersistentStore {
     async fn put(&self, ns: &str, req: PutRequest) -> Result<Object> {
-        let o = self.mem.put(ns, req).await?;
-        {
-            let wal = self.wal.lock().await;
-            let body = RecBody::Put {
-                ns: o.ns.clone(),
-                obj: serde_json::to_value(&o).unwrap(),
-            };
-            wal.append(o.commit_seq, Utc::now().timestamp(), &body)
-                .await
-                .map_err(|e| StateError::Internal(e.to_string()))?;
-        }
-        self.manifest.write().last_seq = self.manifest.read().last_seq.max(o.commit_seq);
-        Ok(o)
+        Ok(self.put_mode(ns, req, PutMode::Always).await?.0)
+    }
+
+    async fn put_if_absent(&self, ns: &str, req: PutRequest) -> Result<(Object, bool)> {
+        self.put_mode(ns, req, PutMode::IfAbsent).await
+    }
+
+    async fn put_if_changed(&self, ns: &str, req: PutRequest) -> Result<(Object, bool)> {
+        self.put_mode(ns, req, PutMode::IfChanged).await
     }
 
     async fn get(&self, ns: &str, id: &str, opts: crate::traits::GetOptions) -> Result<Object> {
         self.mem.get(ns, id, opts).await
     }
+
     async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>> {
         self.mem.query(ns, req).await
     }
-    async fn delete(&self, ns: &str, id: &str) -> Result<()> {
-        // Note: for durability correctness, capture commit seq before applying delete
-        let now = Utc::now().timestamp();
-        self.mem.delete(ns, id).await?;
-        let body = RecBody::Delete {
-            ns: ns.to_string(),
-            id: id.to_string(),
+
+    async fn delete(&self, ns: &str, id: &str) -> Result<u64> {
+        self.admit_write()?;
+        let wal = self.wal.lock().await;
+        let ev = self.mem.stage_delete(ns, id)?;
+        let seq = ev.commit_seq();
+        self.log_and_commit(&wal, vec![ev], false).await?;
+        Ok(seq)
+    }
+
+    async fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>> {
+        self.mem.versions(ns, id).await
+    }
+
+    async fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize> {
+        self.admit_write()?;
+        let wal = self.wal.lock().await;
+        // Logged with the count actually kept, so replay drops the same versions later on
+        let (keep, dropped) = self.mem.stage_trim(ns, id, keep)?;
+        if dropped > 0 {
+            let rec = RecBody::TrimVersions {
+                ns: ns.to_string(),
+                id: id.to_string(),
+                keep,
+            };
+            self.append_locked(&wal, &[(0, rec)]).await?;
+            self.mem.drop_old_versions(ns, id, keep);
+            WRITES_COMMITTED.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(dropped)
+    }
+
+    async fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object> {
+        self.admit_write()?;
+        let wal = self.wal.lock().await;
+        let events = self.mem.stage_rename(ns, id, new_id)?;
+        let obj = match events.last() {
+            Some(WatchEvent::Put(o)) => o.clone(),
+            _ => return Err(StateError::Internal("rename staged no put".into())),
         };
+        // Logged as a transaction, so a crash can't keep the tombstone without the put
+        self.log_and_commit(&wal, events, true).await?;
+        Ok(obj)
+    }
+
+    async fn txn(&self, ns: &str, ops: Vec<TxnOp>) -> Result<Vec<WatchEvent>> {
+        self.admit_write()?;
         let wal = self.wal.lock().await;
-        wal.append(0, now, &body)
-            .await
-            .map_err(|e| StateError::Internal(e.to_string()))
+        let events = self.mem.stage_txn(ns, ops)?;
+        self.log_and_commit(&wal, events.clone(), true).await?;
+        Ok(events)
+    }
+
+    async fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<Object>> {
+        self.mem.sweep_expired(retention_secs).await
+    }
+
+    fn register_vec_field(&self, ns: &str, field: agentstate_core::VecField) -> Result<()> {
+        self.mem.register_vec_field(ns, field)
+    }
+
+    fn warm_ann(&self) -> usize {
+        self.mem.warm_ann()
+    }
+
+    fn ann_warming(&self) -> bool {
+        self.mem.ann_warming()
+    }
+
+    fn register_composite_index(&self, ns: &str, keys: Vec<String>) -> Result<()> {
+        self.mem.register_composite_index(ns, keys)
+    }
+
+    fn register_index_paths(&self, ns: &str, paths: Vec<String>) -> Result<Vec<String>> {
+        self.mem.register_index_paths(ns, paths)
+    }
+
+    fn index_paths(&self, ns: &str) -> Vec<String> {
+        self.mem.index_paths(ns)
+    }
+
+    fn export_watermark(&self) -> Result<std::collections::BTreeMap<String, u64>> {
+        self.mem.export_watermark()
+    }
+
+    fn export_keys(&self) -> Result<Vec<(String, String)>> {
+        self.mem.export_keys()
+    }
+
+    fn export_batch(
+        &self,
+        keys: &[(String, String)],
+        watermark: &std::collections::BTreeMap<String, u64>,
+    ) -> Vec<Object> {
+        self.mem.export_batch(keys, watermark)
+    }
+
+    fn set_quota(&self, ns: &str, quota: crate::traits::NsQuota) -> Result<()> {
+        self.mem.set_quota(ns, quota)
+    }
+
+    fn quota(&self, ns: &str) -> Option<crate::traits::NsQuota> {
+        self.mem.quota(ns)
+    }
+
+    fn usage(&self, ns: &str) -> Option<crate::traits::NsUsage> {
+        self.mem.usage(ns)
+    }
+
+    fn expiring(&self, ns: &str, until: DateTime<Utc>) -> Result<Vec<Object>> {
+        self.mem.expiring(ns, until)
+    }
+
+    fn check_indexes(&self, sample: usize) -> Vec<crate::traits::IndexMismatch> {
+        self.mem.check_indexes(sample)
+    }
+
+    fn register_transform(&self, ns: &str, rule: agentstate_core::TransformRule) -> Result<()> {
+        self.mem.register_transform(ns, rule)
+    }
+
+    fn all_objects(&self) -> Vec<Object> {
+        self.mem.all_objects()
+    }
+
+    fn namespaces(&self) -> Vec<String> {
+        self.mem.namespaces()
+    }
+
+    fn children(&self, ns: &str, id: &str) -> Result<Vec<String>> {
+        self.mem.children(ns, id)
+    }
+
+    async fn query_traced(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+        trace: &mut crate::traits::QueryTrace,
+    ) -> Result<Vec<Object>> {
+        self.mem.query_traced(ns, req, trace).await
     }
 
+    async fn query_analyze(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+    ) -> Result<(Vec<Object>, crate::traits::QueryTrace)> {
+        self.mem.query_analyze(ns, req).await
+    }
+
+    fn access_stats(&self, ns: &str, id: &str) -> Option<crate::traits::AccessStats> {
+        self.mem.access_stats(ns, id)
+    }
+
+    fn advisory_lock(
+        &self,
+        ns: &str,
+        id: &str,
+        owner: &str,
+        ttl_secs: u64,
+    ) -> Result<crate::traits::AdvisoryLock> {
+        self.mem.advisory_lock(ns, id, owner, ttl_secs)
+    }
+
+    fn advisory_unlock(&self, ns: &str, id: &str, owner: &str) -> Result<()> {
+        self.mem.advisory_unlock(ns, id, owner)
+    }
+
+    fn advisory_lock_state(&self, ns: &str, id: &str) -> Option<crate::traits::AdvisoryLock> {
+        self.mem.advisory_lock_state(ns, id)
+    }
+}
+
+impl WatchSource for PersistentStore {
     fn subscribe(
         &self,
         filter: crate::traits::WatchFilter,
         from_commit: Option<u64>,
+        limits: Option<crate::traits::WatchLimits>,
     ) -> Box<dyn crate::traits::WatchHandle> {
-        self.mem.subscribe(filter, from_commit)
-    }
-
-    async fn sweep_expired(&self, retention_secs: u64) -> Result<u64> {
-        self.mem.sweep_expired(retention_secs).await
+        self.mem.subscribe(filter, from_commit, limits)
     }
+}
 
+#[async_trait::async_trait]
+impl LeaseStore for PersistentStore {
     async fn lease_acquire(
         &self,
         ns: &str,
@@ -141,25 +553,23 @@ impl Storage for PersistentStore {
         owner: &str,
         ttl_secs: u64,
     ) -> Result<crate::traits::Lease> {
-        let l = self.mem.lease_acquire(ns, key, owner, ttl_secs).await?;
-        {
-            let wal = self.wal.lock().await;
-            wal.append(
-                0,
-                Utc::now().timestamp(),
-                &RecBody::LeaseAcquire {
-                    ns: ns.to_string(),
-                    key: key.to_string(),
-                    owner: owner.to_string(),
-                    token: l.token,
-                    ttl: ttl_secs,
-                },
-            )
-            .await
-            .map_err(|e| StateError::Internal(e.to_string()))?;
-        }
+        self.admit_write()?;
+        // Logged before it's applied, under the WAL lock like every object write, so
+        // leases are granted in log order and a refused record leaves memory untouched
+        let wal = self.wal.lock().await;
+        let l = self.mem.stage_lease_acquire(ns, key, owner, ttl_secs)?;
+        let rec = RecBody::LeaseAcquire {
+            ns: ns.to_string(),
+            key: key.to_string(),
+            owner: owner.to_string(),
+            token: l.token,
+            ttl: ttl_secs,
+        };
+        self.append_locked(&wal, &[(0, rec)]).await?;
+        self.mem.commit_lease(&l);
         Ok(l)
     }
+
     async fn lease_renew(
         &self,
         ns: &str,
@@ -168,45 +578,43 @@ impl Storage for PersistentStore {
         token: u64,
         ttl_secs: u64,
     ) -> Result<crate::traits::Lease> {
-        let l = self
-            .mem
-            .lease_renew(ns, key, owner, token, ttl_secs)
-            .await?;
-        {
-            let wal = self.wal.lock().await;
-            wal.append(
-                0,
-                Utc::now().timestamp(),
-                &RecBody::LeaseRenew {
-                    ns: ns.to_string(),
-                    key: key.to_string(),
-                    owner: owner.to_string(),
-                    token,
-                    ttl: ttl_secs,
-                },
-            )
-            .await
-            .map_err(|e| StateError::Internal(e.to_string()))?;
-        }
+        self.admit_write()?;
+        let wal = self.wal.lock().await;
+        let l = self.mem.stage_lease_renew(ns, key, owner, token, ttl_secs)?;
+        let rec = RecBody::LeaseRenew {
+            ns: ns.to_string(),
+            key: key.to_string(),
+            owner: owner.to_string(),
+            token,
+            ttl: ttl_secs,
+        };
+        self.append_locked(&wal, &[(0, rec)]).await?;
+        self.mem.commit_lease(&l);
         Ok(l)
     }
+
     async fn lease_release(&self, ns: &str, key: &str, owner: &str, token: u64) -> Result<()> {
-        self.mem.lease_release(ns, key, owner, token).await?;
+        self.admit_write()?;
         let wal = self.wal.lock().await;
-        wal.append(
-            0,
-            Utc::now().timestamp(),
-            &RecBody::LeaseRelease {
-                ns: ns.to_string(),
-                key: key.to_string(),
-                owner: owner.to_string(),
-                token,
-            },
-        )
-        .await
-        .map_err(|e| StateError::Internal(e.to_string()))
+        self.mem.stage_lease_release(ns, key, owner, token)?;
+        let rec = RecBody::LeaseRelease {
+            ns: ns.to_string(),
+            key: key.to_string(),
+            owner: owner.to_string(),
+            token,
+        };
+        self.append_locked(&wal, &[(0, rec)]).await?;
+        self.mem.replay_lease_release(ns, key, token);
+        Ok(())
+    }
+
+    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()> {
+        self.mem.validate_fence(ns, resource, fence).await
     }
+}
 
+#[async_trait::async_trait]
+impl IdempotencyStore for PersistentStore {
     async fn idempotency_lookup(
         &self,
         ns: &str,
@@ -222,6 +630,7 @@ impl Storage for PersistentStore {
         }
         Ok(None)
     }
+
     async fn idempotency_commit(
         &self,
         ns: &str,
@@ -245,10 +654,8 @@ impl Storage for PersistentStore {
         self.idem
             .write()
             .insert((ns.to_string(), key.to_string()), rec.clone());
-        let wal = self.wal.lock().await;
-        wal.append(
+        self.log(
             0,
-            Utc::now().timestamp(),
             &RecBody::Idempotency {
                 ns: ns.to_string(),
                 key: key.to_string(),
@@ -257,12 +664,11 @@ impl Storage for PersistentStore {
             },
         )
         .await
-        .map_err(|e| StateError::Internal(e.to_string()))
-    }
-    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()> {
-        self.mem.validate_fence(ns, resource, fence).await
     }
+}
 
+#[async_trait::async_trait]
+impl AdminOps for PersistentStore {
     async fn admin_snapshot(&self) -> Result<(String, u64)> {
         let id = self
             .snapshot()
@@ -270,11 +676,16 @@ impl Storage for PersistentStore {
         let last = self.manifest.read().snapshot_bookmark.unwrap_or(0);
         Ok((id, last))
     }
+
     async fn admin_manifest(&self) -> Result<serde_json::Value> {
         let m = self.manifest.read().clone();
         Ok(serde_json::to_value(m).unwrap())
     }
+
     async fn admin_trim_wal(&self, snapshot_id: &str) -> Result<Vec<String>> {
+        if self.wal.lock().await.is_frozen() {
+            return Err(StateError::Invalid("wal is frozen for backup".into()));
+        }
         let mut m = self.manifest.write();
         if m.current_snapshot.as_deref() != Some(snapshot_id) {
             return Err(StateError::Invalid("snapshot id mismatch".into()));
@@ -293,7 +704,7 @@ impl Storage for PersistentStore {
             if seg.max_seq < cutoff {
                 // delete unless it's the last_before_idx
                 if Some(i) != last_before_idx {
-                    let p = self.data_dir.join("wal").join(&seg.name);
+                    let p = seg.path(&self.data_dir);
                     let _ = std::fs::remove_file(&p);
                     deleted.push(seg.name.clone());
                 } else {
@@ -312,4 +723,305 @@ impl Storage for PersistentStore {
             .map_err(|e| StateError::Internal(e.to_string()))?;
         Ok(deleted)
     }
+
+    async fn admin_wal_freeze(&self, frozen: bool) -> Result<serde_json::Value> {
+        if self.wal_disabled {
+            return Err(StateError::Invalid("wal disabled".into()));
+        }
+        let m = self.wal.lock().await.set_frozen(frozen);
+        let segments: Vec<_> = m
+            .segments
+            .iter()
+            .map(|s| {
+                serde_json::json!({
+                    "name": s.name,
+                    "path": s.path(&self.data_dir),
+                    "max_seq": s.max_seq,
+                })
+            })
+            .collect();
+        Ok(serde_json::json!({
+            "frozen": frozen,
+            "current_segment": m.current_segment,
+            "last_seq": m.last_seq,
+            "segments": segments,
+        }))
+    }
+
+    async fn wal_health(&self) -> Result<()> {
+        if self.wal_disabled {
+            return Ok(());
+        }
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(StateError::Unavailable("read-only after a WAL failure".into()));
+        }
+        match self.wal.lock().await.last_error() {
+            Some(e) => Err(StateError::Internal(e)),
+            None => Ok(()),
+        }
+    }
+
+    fn recovery_issues(&self) -> Vec<String> {
+        self.recovery_issues.clone()
+    }
+
+    async fn apply_replicated(&self, ts: i64, recs: Vec<RecBody>) -> Result<()> {
+        self.admit_write()?;
+        // Re-log locally, txn markers and the leader's ts included, so a restarted
+        // follower recovers without re-streaming everything
+        let recs: Vec<(u64, RecBody)> = recs
+            .into_iter()
+            .map(|r| {
+                let seq = match &r {
+                    RecBody::Put { obj, .. } => {
+                        obj.get("commit_seq").and_then(|v| v.as_u64()).unwrap_or(0)
+                    }
+                    _ => 0,
+                };
+                (seq, r)
+            })
+            .collect();
+        let wal = self.wal.lock().await;
+        if !self.wal_disabled {
+            let res = wal.append_all(ts, &recs).await;
+            self.wal_result(res)?;
+        }
+        self.mem
+            .apply_records(ts, recs.into_iter().map(|(_, r)| r).collect())
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl PersistentStore {
+    /// Makes the next WAL append fail, as a full or remounted disk would.
+    #[doc(hidden)]
+    pub async fn fail_next_wal_write(&self) {
+        self.wal.lock().await.fail_next_write();
+    }
+
+    /// Makes the WAL writable again after `fail_next_wal_write`.
+    #[doc(hidden)]
+    pub async fn heal_wal_writes(&self) {
+        self.wal.lock().await.heal_writes();
+    }
+}
+
+fn read_snapshot_objects(path: &std::path::Path) -> std::io::Result<Vec<Object>> {
+    use std::io::{BufRead, BufReader};
+    let z = zstd::Decoder::new(std::fs::File::open(path)?)?;
+    let mut out = Vec::new();
+    for line in BufReader::new(z).lines() {
+        let line = line?;
+        if let Ok(o) = serde_json::from_str::<Object>(&line) {
+            out.push(o);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::{GetOptions, WatchFilter};
+
+    fn req(id: &str) -> PutRequest {
+        PutRequest {
+            r#type: "note".into(),
+            body: serde_json::json!({"id": id}),
+            id: Some(id.into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_the_shared_object_store_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        crate::conformance::run(&store).await;
+    }
+
+    #[tokio::test]
+    async fn write_the_wal_refuses_is_never_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        store.put("ns", req("a")).await.unwrap();
+        let mut watch = store.subscribe(
+            WatchFilter {
+                ns: "ns".into(),
+                ..Default::default()
+            },
+            None,
+            None,
+        );
+        store.wal.lock().await.fail_next_write();
+        let err = store.put("ns", req("b")).await.unwrap_err();
+        assert!(matches!(err, StateError::Unavailable(_)));
+        let get = store.get("ns", "b", GetOptions { at_ts: None }).await;
+        assert!(matches!(get, Err(StateError::NotFound)));
+        assert!(watch.try_next().is_none());
+        drop(store);
+        let reopened = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        assert!(reopened
+            .get("ns", "a", GetOptions { at_ts: None })
+            .await
+            .is_ok());
+        let get = reopened.get("ns", "b", GetOptions { at_ts: None }).await;
+        assert!(matches!(get, Err(StateError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn a_failed_wal_write_shows_in_wal_health_until_one_succeeds() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        store.put("ns", req("a")).await.unwrap();
+        store.wal_health().await.unwrap();
+        store.wal.lock().await.fail_next_write();
+        assert!(store.put("ns", req("b")).await.is_err());
+        let err = store.wal_health().await.unwrap_err();
+        assert!(matches!(err, StateError::Internal(_)), "{err:?}");
+        // Inside the probe interval writes are turned away without touching the WAL
+        let err = store.put("ns", req("c")).await.unwrap_err();
+        assert!(matches!(err, StateError::Unavailable(m) if m.contains("retry")));
+        // The next probe finds the segment writable again, clearing the failure
+        store.wal.lock().await.heal_writes();
+        store.last_probe_ms.store(0, Ordering::Relaxed);
+        store.put("ns", req("c")).await.unwrap();
+        store.wal_health().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn with_the_wal_disabled_only_snapshots_persist_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open_with(dir.path().to_path_buf(), true).unwrap();
+        store.put("ns", req("a")).await.unwrap();
+        store.put("ns", req("b")).await.unwrap();
+        let got = store
+            .get("ns", "a", GetOptions { at_ts: None })
+            .await
+            .unwrap();
+        assert_eq!(got.body["id"], "a");
+        assert!(crate::walbin::dump_wal(dir.path()).unwrap().is_empty());
+        store.snapshot().unwrap();
+        // Not in any snapshot, so a restart loses it
+        store.put("ns", req("c")).await.unwrap();
+        drop(store);
+
+        let reopened = PersistentStore::open_with(dir.path().to_path_buf(), true).unwrap();
+        for id in ["a", "b"] {
+            assert!(reopened
+                .get("ns", id, GetOptions { at_ts: None })
+                .await
+                .is_ok());
+        }
+        let get = reopened.get("ns", "c", GetOptions { at_ts: None }).await;
+        assert!(matches!(get, Err(StateError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn leases_survive_a_reopen_and_fence_as_before() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        let held = store.lease_acquire("ns", "job", "w1", 60).await.unwrap();
+        let released = store.lease_acquire("ns", "other", "w1", 60).await.unwrap();
+        store
+            .lease_release("ns", "other", "w1", released.token)
+            .await
+            .unwrap();
+        // A refused record changes nothing, even when the owner already holds the lease
+        store.wal.lock().await.fail_next_write();
+        let err = store.lease_acquire("ns", "job", "w1", 60).await.unwrap_err();
+        assert!(matches!(err, StateError::Unavailable(_)));
+        store.validate_fence("ns", "job", held.token).await.unwrap();
+        drop(store);
+
+        let reopened = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        reopened.validate_fence("ns", "job", held.token).await.unwrap();
+        for (key, fence) in [("job", held.token + 1), ("other", released.token)] {
+            let err = reopened.validate_fence("ns", key, fence).await.unwrap_err();
+            assert!(matches!(err, StateError::Conflict(_)), "{key}");
+        }
+        // Tokens are never handed out twice across the restart
+        let next = reopened.lease_acquire("ns", "other", "w2", 60).await.unwrap();
+        assert!(next.token > released.token);
+    }
+
+    #[tokio::test]
+    async fn one_unreadable_segment_leaves_the_rest_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        // One record per segment
+        let wal = WalWriter::open(dir.path(), 1, 0).unwrap();
+        let source = InMemoryStore::new();
+        for id in ["a", "b", "c"] {
+            let obj = source.put("ns", req(id)).await.unwrap();
+            let rec = RecBody::Put {
+                ns: "ns".into(),
+                obj: serde_json::to_value(&obj).unwrap(),
+            };
+            wal.append(obj.commit_seq, 0, &rec).await.unwrap();
+        }
+        let manifest = wal.manifest();
+        drop(wal);
+        assert!(manifest.segments.len() >= 3);
+        let bad = &manifest.segments[1];
+        std::fs::write(bad.path(dir.path()), [0xff; 64]).unwrap();
+
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        for id in ["a", "c"] {
+            assert!(store.get("ns", id, GetOptions { at_ts: None }).await.is_ok(), "{id}");
+        }
+        let get = store.get("ns", "b", GetOptions { at_ts: None }).await;
+        assert!(matches!(get, Err(StateError::NotFound)));
+        let issues = store.recovery_issues();
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert!(issues[0].starts_with(&bad.name), "{issues:?}");
+    }
+
+    #[tokio::test]
+    async fn snapshots_leave_out_expired_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        store.put("ns", req("live")).await.unwrap();
+        // Written a minute ago with a one second TTL, so already expired and not yet swept
+        let mut short = req("short");
+        short.ttl_seconds = Some(1);
+        short.ts = Some(Utc::now() - chrono::Duration::seconds(60));
+        store.put("ns", short).await.unwrap();
+
+        let name = store.snapshot().unwrap();
+        let objs = read_snapshot_objects(&dir.path().join("snapshots").join(name)).unwrap();
+        let ids: Vec<_> = objs.iter().map(|o| o.id.as_str()).collect();
+        assert_eq!(ids, ["live"]);
+    }
+
+    #[tokio::test]
+    async fn a_replayed_delete_resumes_watches_at_its_own_seq() {
+        use crate::traits::WatchEvent;
+        let dir = tempfile::tempdir().unwrap();
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        store.put("ns", req("a")).await.unwrap();
+        store.put("ns", req("b")).await.unwrap();
+        store.delete("ns", "a").await.unwrap();
+        store.put("ns", req("c")).await.unwrap();
+        drop(store);
+
+        let reopened = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        let filter = WatchFilter {
+            ns: "ns".into(),
+            ..Default::default()
+        };
+        let mut watch = reopened.subscribe(filter.clone(), Some(2), None);
+        match watch.try_next() {
+            Some(WatchEvent::Delete { id, commit_seq, .. }) => {
+                assert_eq!((id.as_str(), commit_seq), ("a", 3));
+            }
+            other => panic!("expected the delete of a, got {other:?}"),
+        }
+        assert!(matches!(watch.try_next(), Some(WatchEvent::Put(o)) if o.id == "c" && o.commit_seq == 4));
+        assert!(watch.try_next().is_none());
+        // Resuming past the delete skips it, and new writes carry on from the logged seq
+        let mut watch = reopened.subscribe(filter, Some(3), None);
+        assert!(matches!(watch.try_next(), Some(WatchEvent::Put(o)) if o.id == "c"));
+        let next = reopened.put("ns", req("d")).await.unwrap();
+        assert_eq!(next.commit_seq, 5);
+    }
 }
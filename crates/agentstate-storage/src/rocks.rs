@@ -0,0 +1,688 @@
+// RocksDB object engine: versions live on disk, so datasets aren't bounded by RAM.
+// Only implements `ObjectStore`; wrap it in `Composed` for watch, leases and idempotency.
+//
+// Column families (keys are `\0`-separated, seqs big-endian so they sort):
+//   objects: ns\0id\0seq -> Object JSON, one entry per retained version
+//   latest:  ns\0id      -> seq of the current version
+//   tags:    ns\0k\0v\0id -> (), secondary index over current versions
+//   meta:    ns          -> last commit_seq assigned in the namespace
+use crate::mem::{
+    assign_id, check_body_depth, check_candidates, check_classification, check_clock_skew,
+    check_tag_limits, conform_vectors, keep_first_distinct, matches_ranges, past_grace,
+    project_fields, rank_by_vector, value_at_path, MAX_VERSIONS_PER_OBJECT, SCAN_CHECK_EVERY,
+};
+use crate::traits::{GetOptions, ObjectStore};
+use agentstate_core::{Object, PutRequest, QueryRequest, Result, StateError, VecField};
+use chrono::{DateTime, Duration, Utc};
+use parking_lot::{Mutex, RwLock};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch, DB,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+const CF_OBJECTS: &str = "objects";
+const CF_LATEST: &str = "latest";
+const CF_TAGS: &str = "tags";
+const CF_META: &str = "meta";
+
+/// Every RocksDB call blocks, so each operation runs on the blocking pool against the
+/// shared `Engine` rather than on the async runtime.
+pub struct RocksStore {
+    engine: Arc<Engine>,
+}
+
+struct Engine {
+    db: DB,
+    // Serializes writers so seq assignment and index updates stay consistent
+    write: Mutex<()>,
+    vec_fields: RwLock<HashMap<(String, String), VecField>>,
+}
+
+impl RocksStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            engine: Arc::new(Engine::open(path)?),
+        })
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Engine) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || f(&engine))
+            .await
+            .map_err(|e| StateError::Internal(e.to_string()))?
+    }
+}
+
+impl Engine {
+    fn open(path: &Path) -> Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let cfs = [CF_OBJECTS, CF_LATEST, CF_TAGS, CF_META]
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        let db = DB::open_cf_descriptors(&opts, path, cfs).map_err(internal)?;
+        Ok(Self {
+            db,
+            write: Mutex::new(()),
+            vec_fields: RwLock::new(HashMap::new()),
+        })
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        self.db
+            .cf_handle(name)
+            .expect("column family created at open")
+    }
+
+    // All entries of `cf` whose key starts with `prefix`, in key order
+    fn scan(&self, cf: &str, prefix: &[u8]) -> Result<Vec<(Box<[u8]>, Box<[u8]>)>> {
+        let mut out = Vec::new();
+        let iter = self
+            .db
+            .iterator_cf(self.cf(cf), IteratorMode::From(prefix, Direction::Forward));
+        for item in iter {
+            let (k, v) = item.map_err(internal)?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            out.push((k, v));
+        }
+        Ok(out)
+    }
+
+    fn last_seq(&self, ns: &str) -> Result<u64> {
+        let raw = self
+            .db
+            .get_cf(self.cf(CF_META), ns.as_bytes())
+            .map_err(internal)?;
+        Ok(raw.map(|b| decode_seq(&b)).unwrap_or(0))
+    }
+
+    fn latest(&self, ns: &str, id: &str) -> Result<Option<Object>> {
+        let Some(seq) = self
+            .db
+            .get_cf(self.cf(CF_LATEST), key(&[ns, id]))
+            .map_err(internal)?
+        else {
+            return Ok(None);
+        };
+        let raw = self
+            .db
+            .get_cf(self.cf(CF_OBJECTS), version_key(ns, id, decode_seq(&seq)))
+            .map_err(internal)?;
+        raw.map(|b| decode_obj(&b)).transpose()
+    }
+
+    // Oldest first, with their keys in the objects CF
+    fn versions_of(&self, ns: &str, id: &str) -> Result<Vec<(Box<[u8]>, Object)>> {
+        let mut prefix = key(&[ns, id]);
+        prefix.push(0);
+        self.scan(CF_OBJECTS, &prefix)?
+            .into_iter()
+            .map(|(k, v)| Ok((k, decode_obj(&v)?)))
+            .collect()
+    }
+
+    // Newest version at or before `at` (or the newest at all) that is still readable,
+    // walking back from the end so only the versions newer than it are loaded
+    fn newest_readable(
+        &self,
+        ns: &str,
+        id: &str,
+        at: Option<DateTime<Utc>>,
+    ) -> Result<Option<Object>> {
+        let mut prefix = key(&[ns, id]);
+        prefix.push(0);
+        let end = version_key(ns, id, u64::MAX);
+        let iter = self.db.iterator_cf(
+            self.cf(CF_OBJECTS),
+            IteratorMode::From(end.as_slice(), Direction::Reverse),
+        );
+        let now = Utc::now();
+        for item in iter {
+            let (k, v) = item.map_err(internal)?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            let o = decode_obj(&v)?;
+            if at.is_some_and(|at| o.ts > at) || past_grace(&o, now) {
+                continue;
+            }
+            return Ok(Some(o));
+        }
+        Ok(None)
+    }
+
+    // Ids of current versions carrying tag k=v, or key k with any value
+    fn tagged(&self, ns: &str, k: &str, v: Option<&str>) -> Result<BTreeSet<String>> {
+        let mut prefix = match v {
+            Some(v) => key(&[ns, k, v]),
+            None => key(&[ns, k]),
+        };
+        prefix.push(0);
+        Ok(self
+            .scan(CF_TAGS, &prefix)?
+            .into_iter()
+            .filter_map(|(k, _)| {
+                let rest = String::from_utf8_lossy(&k[prefix.len()..]).into_owned();
+                match v {
+                    Some(_) => Some(rest),
+                    None => rest.split_once('\0').map(|(_, id)| id.to_string()),
+                }
+            })
+            .collect())
+    }
+
+    // (ns, id) of every current object, optionally limited to one namespace
+    fn live_ids(&self, ns: Option<&str>) -> Result<Vec<(String, String)>> {
+        let prefix = ns
+            .map(|ns| {
+                let mut p = ns.as_bytes().to_vec();
+                p.push(0);
+                p
+            })
+            .unwrap_or_default();
+        Ok(self
+            .scan(CF_LATEST, &prefix)?
+            .into_iter()
+            .filter_map(|(k, _)| {
+                let s = String::from_utf8_lossy(&k).into_owned();
+                let (ns, id) = s.split_once('\0')?;
+                Some((ns.to_string(), id.to_string()))
+            })
+            .collect())
+    }
+
+    fn stage_put(&self, batch: &mut WriteBatch, obj: &Object, prev: Option<&Object>) -> Result<()> {
+        if let Some(prev) = prev {
+            self.stage_untag(batch, prev);
+        }
+        let body = serde_json::to_vec(obj).map_err(|e| StateError::Internal(e.to_string()))?;
+        batch.put_cf(
+            self.cf(CF_OBJECTS),
+            version_key(&obj.ns, &obj.id, obj.commit_seq),
+            body,
+        );
+        batch.put_cf(
+            self.cf(CF_LATEST),
+            key(&[&obj.ns, &obj.id]),
+            obj.commit_seq.to_be_bytes(),
+        );
+        for (k, v) in obj.tags.0.iter() {
+            batch.put_cf(self.cf(CF_TAGS), key(&[&obj.ns, k, v, &obj.id]), b"");
+        }
+        self.stage_seq(batch, &obj.ns, obj.commit_seq);
+        Ok(())
+    }
+
+    // Removes an object with its full history and tag entries
+    fn stage_remove(&self, batch: &mut WriteBatch, cur: &Object) -> Result<()> {
+        for (k, _) in self.versions_of(&cur.ns, &cur.id)? {
+            batch.delete_cf(self.cf(CF_OBJECTS), k);
+        }
+        batch.delete_cf(self.cf(CF_LATEST), key(&[&cur.ns, &cur.id]));
+        self.stage_untag(batch, cur);
+        Ok(())
+    }
+
+    fn stage_untag(&self, batch: &mut WriteBatch, obj: &Object) {
+        for (k, v) in obj.tags.0.iter() {
+            batch.delete_cf(self.cf(CF_TAGS), key(&[&obj.ns, k, v, &obj.id]));
+        }
+    }
+
+    fn stage_seq(&self, batch: &mut WriteBatch, ns: &str, seq: u64) {
+        batch.put_cf(self.cf(CF_META), ns.as_bytes(), seq.to_be_bytes());
+    }
+
+    fn commit(&self, batch: WriteBatch) -> Result<()> {
+        self.db.write(batch).map_err(internal)
+    }
+
+    fn matches_jsonpath(o: &Object, req: &QueryRequest) -> bool {
+        req.jsonpath.as_ref().map_or(true, |jf| {
+            jf.equals
+                .iter()
+                .all(|(p, val)| value_at_path(&o.body, p) == Some(val))
+                && matches_ranges(o, &jf.ranges)
+        })
+    }
+
+    fn is_expired(o: &Object) -> bool {
+        o.expires_at().is_some_and(|at| at < Utc::now())
+    }
+
+    fn put(&self, ns: &str, mut req: PutRequest) -> Result<Object> {
+        check_tag_limits(&req.tags)?;
+        check_classification(&req)?;
+        check_body_depth(&req.body)?;
+        req.check_content()?;
+        conform_vectors(&self.vec_fields.read(), ns, &mut req.body)?;
+        // Same order as the in-memory engine: id and validation on the plaintext, then seal
+        assign_id(&mut req, Utc::now());
+        crate::validator::check(ns, &req)?;
+        crate::crypt::seal(ns, &mut req.body)?;
+        let _w = self.write.lock();
+        let prev = match &req.id {
+            Some(id) => self.latest(ns, id)?,
+            None => None,
+        };
+        if let (Some(ts), Some(prev)) = (req.ts, prev.as_ref()) {
+            check_clock_skew(ts, prev)?;
+        }
+        let obj = Object::new_with_seq(ns.to_string(), req, self.last_seq(ns)? + 1);
+        let mut batch = WriteBatch::default();
+        self.stage_put(&mut batch, &obj, prev.as_ref())?;
+        self.stage_cap(&mut batch, &obj)?;
+        self.commit(batch)?;
+        Ok(obj)
+    }
+
+    // MAX_VERSIONS_PER_OBJECT, counting the version `obj` adds
+    fn stage_cap(&self, batch: &mut WriteBatch, obj: &Object) -> Result<()> {
+        let max = *MAX_VERSIONS_PER_OBJECT;
+        if max == 0 {
+            return Ok(());
+        }
+        let mut prefix = key(&[&obj.ns, &obj.id]);
+        prefix.push(0);
+        let held = self.scan(CF_OBJECTS, &prefix)?;
+        let over = (held.len() + 1).saturating_sub(max);
+        for (k, _) in held.into_iter().take(over) {
+            batch.delete_cf(self.cf(CF_OBJECTS), k);
+        }
+        Ok(())
+    }
+
+    fn select(&self, ns: &str, req: &QueryRequest) -> Result<Vec<Object>> {
+        if !req.any_of.is_empty() {
+            return Err(StateError::Invalid(
+                "any_of is not supported by the rocksdb engine".into(),
+            ));
+        }
+        if req.locked.is_some() {
+            return Err(StateError::Invalid(
+                "locked is not supported by the rocksdb engine".into(),
+            ));
+        }
+        let mut lookups: Vec<(&str, Option<&str>)> = Vec::new();
+        if let Some(tf) = &req.tag_filter {
+            lookups.extend(tf.0.iter().map(|(k, v)| (k.as_str(), Some(v.as_str()))));
+        }
+        lookups.extend(req.has_tag_keys.iter().map(|k| (k.as_str(), None)));
+        let ids: Vec<String> = if lookups.is_empty() {
+            self.live_ids(Some(ns))?
+                .into_iter()
+                .map(|(_, id)| id)
+                .collect()
+        } else {
+            let mut candidates: Option<BTreeSet<String>> = None;
+            for (k, v) in lookups {
+                let ids = self.tagged(ns, k, v)?;
+                candidates = Some(match candidates.take() {
+                    None => ids,
+                    Some(prev) => prev.intersection(&ids).cloned().collect(),
+                });
+            }
+            candidates.unwrap_or_default().into_iter().collect()
+        };
+        check_candidates(ids.len())?;
+        let mut out = Vec::new();
+        for (i, id) in ids.iter().enumerate() {
+            if i % SCAN_CHECK_EVERY == 0 && !req.deadline.keep_going()? {
+                break;
+            }
+            if let Some(o) = self.latest(ns, id)? {
+                let type_ok = req.r#type.as_ref().map_or(true, |t| o.r#type == *t);
+                if type_ok && !Self::is_expired(&o) && Self::matches_jsonpath(&o, req) {
+                    out.push(o);
+                }
+            }
+        }
+        if let Some(since) = req.since_commit_seq {
+            out.retain(|o| o.commit_seq > since);
+        }
+        // Same order as the in-memory engine, rather than key order
+        out.sort_by_key(|o| o.commit_seq);
+        Ok(out)
+    }
+
+    fn delete(&self, ns: &str, id: &str) -> Result<u64> {
+        let _w = self.write.lock();
+        let cur = self.latest(ns, id)?.ok_or(StateError::NotFound)?;
+        let mut batch = WriteBatch::default();
+        self.stage_remove(&mut batch, &cur)?;
+        // The tombstone takes a seq too, matching what watchers are told
+        let seq = self.last_seq(ns)? + 1;
+        self.stage_seq(&mut batch, ns, seq);
+        self.commit(batch)?;
+        Ok(seq)
+    }
+
+    fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>> {
+        let versions: Vec<Object> = self
+            .versions_of(ns, id)?
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        if versions.is_empty() {
+            return Err(StateError::NotFound);
+        }
+        Ok(versions)
+    }
+
+    fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize> {
+        if keep == 0 {
+            return Err(StateError::Invalid("keep must be at least 1".into()));
+        }
+        let _w = self.write.lock();
+        let versions = self.versions_of(ns, id)?;
+        if versions.is_empty() {
+            return Err(StateError::NotFound);
+        }
+        let drop = versions.len().saturating_sub(keep);
+        let mut batch = WriteBatch::default();
+        for (k, _) in versions.into_iter().take(drop) {
+            batch.delete_cf(self.cf(CF_OBJECTS), k);
+        }
+        self.commit(batch)?;
+        Ok(drop)
+    }
+
+    fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object> {
+        if new_id.is_empty() || new_id == id {
+            return Err(StateError::Invalid(
+                "new_id must be non-empty and differ from id".into(),
+            ));
+        }
+        let _w = self.write.lock();
+        if self.latest(ns, new_id)?.is_some() {
+            return Err(StateError::Conflict(format!(
+                "object {} already exists",
+                new_id
+            )));
+        }
+        let cur = self
+            .latest(ns, id)?
+            .filter(|o| !Self::is_expired(o))
+            .ok_or(StateError::NotFound)?;
+        let mut batch = WriteBatch::default();
+        self.stage_remove(&mut batch, &cur)?;
+        // seq n+1 is the old id's tombstone, n+2 the new object
+        let obj = Object::new_with_seq(
+            ns.to_string(),
+            PutRequest {
+                r#type: cur.r#type.clone(),
+                body: cur.body.clone(),
+                tags: cur.tags.clone(),
+                ttl_seconds: cur.ttl_seconds,
+                id: Some(new_id.to_string()),
+                parents: vec![cur.commit.clone()],
+                ts: None,
+                only_if_changed: false,
+                content_type: cur.content_type.clone(),
+            },
+            self.last_seq(ns)? + 2,
+        );
+        self.stage_put(&mut batch, &obj, None)?;
+        self.commit(batch)?;
+        Ok(obj)
+    }
+
+    // Each removal takes the next seq in its ns, as a delete's tombstone does
+    fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<(Object, u64)>> {
+        let _w = self.write.lock();
+        let cutoff = Utc::now() - Duration::seconds(retention_secs as i64);
+        let mut batch = WriteBatch::default();
+        let mut seqs: HashMap<String, u64> = HashMap::new();
+        let mut removed = Vec::new();
+        for (ns, id) in self.live_ids(None)? {
+            if let Some(o) = self.latest(&ns, &id)?.filter(|o| past_grace(o, cutoff)) {
+                self.stage_remove(&mut batch, &o)?;
+                let seq = match seqs.get(&ns) {
+                    Some(s) => s + 1,
+                    None => self.last_seq(&ns)? + 1,
+                };
+                seqs.insert(ns.clone(), seq);
+                self.stage_seq(&mut batch, &ns, seq);
+                removed.push((o, seq));
+            }
+        }
+        self.commit(batch)?;
+        Ok(removed)
+    }
+
+    fn all_objects(&self) -> Vec<Object> {
+        self.live_ids(None)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(ns, id)| self.latest(&ns, &id).ok().flatten())
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for RocksStore {
+    async fn put(&self, ns: &str, req: PutRequest) -> Result<Object> {
+        let ns = ns.to_string();
+        self.blocking(move |e| e.put(&ns, req)).await
+    }
+
+    async fn get(&self, ns: &str, id: &str, opts: GetOptions) -> Result<Object> {
+        let (ns, id) = (ns.to_string(), id.to_string());
+        self.blocking(move |e| e.newest_readable(&ns, &id, opts.at_ts))
+            .await?
+            .ok_or(StateError::NotFound)
+    }
+
+    async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>> {
+        let owned = ns.to_string();
+        let (mut out, req) = self
+            .blocking(move |e| Ok((e.select(&owned, &req)?, req)))
+            .await?;
+        if let Some(vq) = &req.vector {
+            let vf = self
+                .engine
+                .vec_fields
+                .read()
+                .get(&(ns.to_string(), vq.field.clone()))
+                .cloned();
+            let mut ranked = rank_by_vector(out, vq, vf, None, &req.deadline).await?;
+            if let Some(path) = &req.distinct_by {
+                keep_first_distinct(&mut ranked, path);
+            }
+            project_fields(&mut ranked, req.fields.as_ref());
+            return Ok(ranked);
+        }
+        if let Some(path) = &req.distinct_by {
+            keep_first_distinct(&mut out, path);
+        }
+        if let Some(limit) = req.limit {
+            out.truncate(limit);
+        }
+        project_fields(&mut out, req.fields.as_ref());
+        Ok(out)
+    }
+
+    async fn delete(&self, ns: &str, id: &str) -> Result<u64> {
+        let (ns, id) = (ns.to_string(), id.to_string());
+        self.blocking(move |e| e.delete(&ns, &id)).await
+    }
+
+    async fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>> {
+        let (ns, id) = (ns.to_string(), id.to_string());
+        self.blocking(move |e| e.versions(&ns, &id)).await
+    }
+
+    async fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize> {
+        let (ns, id) = (ns.to_string(), id.to_string());
+        self.blocking(move |e| e.trim_versions(&ns, &id, keep)).await
+    }
+
+    async fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object> {
+        let (ns, id, new_id) = (ns.to_string(), id.to_string(), new_id.to_string());
+        self.blocking(move |e| e.rename(&ns, &id, &new_id)).await
+    }
+
+    async fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<Object>> {
+        let swept = self.sweep_expired_seqs(retention_secs).await?;
+        Ok(swept.into_iter().map(|(o, _)| o).collect())
+    }
+
+    async fn sweep_expired_seqs(&self, retention_secs: u64) -> Result<Vec<(Object, u64)>> {
+        self.blocking(move |e| e.sweep_expired(retention_secs)).await
+    }
+
+    fn register_vec_field(&self, ns: &str, field: VecField) -> Result<()> {
+        if field.name.is_empty() || field.dims == 0 {
+            return Err(StateError::Invalid(
+                "vec field needs a name and dims > 0".into(),
+            ));
+        }
+        self.engine
+            .vec_fields
+            .write()
+            .insert((ns.to_string(), field.name.clone()), field);
+        Ok(())
+    }
+
+    fn register_composite_index(&self, _ns: &str, _keys: Vec<String>) -> Result<()> {
+        Err(StateError::Invalid(
+            "composite indexes are not supported by the rocksdb engine".into(),
+        ))
+    }
+
+    // The admin dump is synchronous; it walks the whole keyspace on the caller's thread
+    fn all_objects(&self) -> Vec<Object> {
+        self.engine.all_objects()
+    }
+}
+
+fn key(parts: &[&str]) -> Vec<u8> {
+    parts.join("\0").into_bytes()
+}
+
+fn version_key(ns: &str, id: &str, seq: u64) -> Vec<u8> {
+    let mut k = key(&[ns, id]);
+    k.push(0);
+    k.extend_from_slice(&seq.to_be_bytes());
+    k
+}
+
+fn decode_seq(b: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&b[..8]);
+    u64::from_be_bytes(buf)
+}
+
+fn decode_obj(b: &[u8]) -> Result<Object> {
+    serde_json::from_slice(b).map_err(|e| StateError::Internal(e.to_string()))
+}
+
+fn internal(e: rocksdb::Error) -> StateError {
+    StateError::Internal(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::{doc, query};
+    use crate::Composed;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn passes_the_shared_object_store_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        crate::conformance::run(&RocksStore::open(dir.path()).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn passes_them_composed_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Composed::new(RocksStore::open(dir.path()).unwrap());
+        crate::conformance::run(&store).await;
+    }
+
+    #[tokio::test]
+    async fn versions_and_seqs_survive_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = RocksStore::open(dir.path()).unwrap();
+            store.put("ns", doc("a", json!({"v": 1}))).await.unwrap();
+            let mut tagged = doc("a", json!({"v": 2}));
+            tagged.tags = serde_json::from_value(json!({"status": "open"})).unwrap();
+            store.put("ns", tagged).await.unwrap();
+            store.put("ns", doc("b", json!({}))).await.unwrap();
+            store.delete("ns", "b").await.unwrap();
+        }
+        let store = RocksStore::open(dir.path()).unwrap();
+        let got = store.get("ns", "a", GetOptions { at_ts: None }).await.unwrap();
+        assert_eq!((got.body, got.commit_seq), (json!({"v": 2}), 2));
+        assert_eq!(store.versions("ns", "a").await.unwrap().len(), 2);
+        let open = store
+            .query("ns", query(json!({"tag_filter": {"status": "open"}})))
+            .await
+            .unwrap();
+        assert_eq!(open.len(), 1);
+        // b's tombstone took seq 4
+        let next = store.put("ns", doc("c", json!({}))).await.unwrap();
+        assert_eq!(next.commit_seq, 5);
+    }
+
+    #[tokio::test]
+    async fn queries_rank_vectors_and_match_body_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RocksStore::open(dir.path()).unwrap();
+        for (id, emb, kind) in [
+            ("near", [1.0, 0.0], "a"),
+            ("far", [0.0, 1.0], "a"),
+            ("mid", [1.0, 1.0], "b"),
+        ] {
+            store
+                .put("ns", doc(id, json!({ "emb": emb, "kind": kind })))
+                .await
+                .unwrap();
+        }
+        let ranked = store
+            .query(
+                "ns",
+                query(json!({"vector": {"field": "emb", "top_k": 2, "embedding": [1.0, 0.0]}})),
+            )
+            .await
+            .unwrap();
+        let ids: Vec<_> = ranked.iter().map(|o| o.id.as_str()).collect();
+        assert_eq!(ids, ["near", "mid"]);
+        let kind_a = query(json!({"jsonpath": {"equals": {"kind": "a"}}}));
+        assert_eq!(store.query("ns", kind_a).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sweeps_number_their_removals() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RocksStore::open(dir.path()).unwrap();
+        for id in ["x", "y"] {
+            let mut req = doc(id, json!({}));
+            req.ts = Some(Utc::now() - Duration::seconds(60));
+            req.ttl_seconds = Some(10);
+            store.put("ns", req).await.unwrap();
+        }
+        let mut seqs: Vec<u64> = store
+            .sweep_expired_seqs(0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, seq)| seq)
+            .collect();
+        seqs.sort();
+        assert_eq!(seqs, [3, 4]);
+        let next = store.put("ns", doc("z", json!({}))).await.unwrap();
+        assert_eq!(next.commit_seq, 5);
+    }
+}
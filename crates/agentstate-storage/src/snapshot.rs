@@ -40,11 +40,9 @@ pub fn read_snapshot(path: PathBuf) -> std::io::Result<Vec<serde_json::Value>> {
     let fh = File::open(path)?;
     let br = BufReader::new(fh);
     let mut out = Vec::new();
-    for line in br.lines() {
-        if let Ok(l) = line {
-            if let Ok(v) = serde_json::from_str(&l) {
-                out.push(v);
-            }
+    for l in br.lines().map_while(|line| line.ok()) {
+        if let Ok(v) = serde_json::from_str(&l) {
+            out.push(v);
         }
     }
     Ok(out)
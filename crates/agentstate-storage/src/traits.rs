@@ -1,15 +1,182 @@
-use agentstate_core::{Object, PutRequest, QueryRequest, Result};
+use agentstate_core::{Object, PutRequest, QueryRequest, Result, TransformRule, TxnOp, VecField};
 use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
 pub struct GetOptions {
     pub at_ts: Option<DateTime<Utc>>, // time-travel
 }
 
+/// Read activity for one object, kept apart from its version history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessStats {
+    pub access_count: u64,
+    pub last_access: DateTime<Utc>,
+}
+
+/// Rows and time per stage of one executed query, for EXPLAIN ANALYZE. Stages are only
+/// kept when the trace is enabled, so ordinary queries pay nothing for them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryTrace {
+    pub stages: Vec<QueryStage>,
+    // Objects the metadata stage scanned, counted whether or not the trace is enabled
+    #[serde(skip)]
+    pub candidates: usize,
+    // Rows the metadata stage visited, kept up to date while it runs so a stopped scan
+    // still reports how far it got
+    #[serde(skip)]
+    pub scanned: usize,
+    #[serde(skip)]
+    enabled: bool,
+    #[serde(skip)]
+    mark: Option<std::time::Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryStage {
+    pub stage: &'static str,
+    // The tag, path or field the stage worked on, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on: Option<String>,
+    // Index of the any_of group the stage ran in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<usize>,
+    // Candidates or results left after the stage
+    pub rows: usize,
+    pub micros: u64,
+}
+
+impl QueryTrace {
+    pub fn enabled() -> Self {
+        Self {
+            enabled: true,
+            mark: Some(std::time::Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    /// A trace for a sub-query, enabled if this one is; see [`QueryTrace::absorb`].
+    pub fn child(&self) -> Self {
+        if self.enabled {
+            Self::enabled()
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Records a stage ending now, timed from the end of the previous one.
+    pub fn record(&mut self, stage: &'static str, on: impl std::fmt::Display, rows: usize) {
+        if !self.enabled {
+            return;
+        }
+        let now = std::time::Instant::now();
+        let micros = self.mark.map_or(0, |m| now.duration_since(m).as_micros() as u64);
+        self.mark = Some(now);
+        let on = on.to_string();
+        self.stages.push(QueryStage {
+            stage,
+            on: (!on.is_empty()).then_some(on),
+            group: None,
+            rows,
+            micros,
+        });
+    }
+
+    /// Appends an any_of group's stages, tagged with the group's index.
+    pub fn absorb(&mut self, group: usize, sub: QueryTrace) {
+        self.candidates += sub.candidates;
+        self.scanned += sub.scanned;
+        if !self.enabled {
+            return;
+        }
+        self.stages.extend(sub.stages.into_iter().map(|mut s| {
+            s.group.get_or_insert(group);
+            s
+        }));
+        self.mark = Some(std::time::Instant::now());
+    }
+}
+
+/// A cooperative "working on this" marker on an object. Writes never check it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdvisoryLock {
+    pub owner: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Per-namespace write limits; a limit left out is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct NsQuota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_objects: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// What a namespace holds: live objects and the serialized size of their latest versions.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct NsUsage {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// A secondary index entry whose object no longer carries the indexed tag or value.
 #[derive(Debug, Clone)]
+pub struct IndexMismatch {
+    pub index: &'static str,
+    pub ns: String,
+    pub id: String,
+    pub detail: String,
+}
+
+/// Which events a subscriber gets. Puts must match every set field; deletes carry no type
+/// or tags, so they always pass.
+#[derive(Debug, Clone, Default)]
 pub struct WatchFilter {
     pub ns: String,
+    pub type_equals: Option<String>,
+    pub tag_equals: BTreeMap<String, String>,
+}
+
+/// How much one subscription may queue before it overflows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchLimits {
+    pub max_events: usize,
+    pub max_bytes: usize,
+}
+
+// WATCH_BUFFER_EVENTS (default 10000) and WATCH_BUFFER_BYTES (default 64 MiB), read once
+static WATCH_LIMITS: Lazy<WatchLimits> = Lazy::new(|| {
+    let env = |k: &str, d: usize| {
+        std::env::var(k)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(d)
+    };
+    WatchLimits {
+        max_events: env("WATCH_BUFFER_EVENTS", 10_000),
+        max_bytes: env("WATCH_BUFFER_BYTES", 64 * 1024 * 1024),
+    }
+});
+
+impl Default for WatchLimits {
+    fn default() -> Self {
+        *WATCH_LIMITS
+    }
+}
+
+impl WatchFilter {
+    pub fn matches(&self, ev: &WatchEvent) -> bool {
+        match ev {
+            WatchEvent::Put(o) => {
+                self.type_equals.as_ref().is_none_or(|t| &o.r#type == t)
+                    && self.tag_equals.iter().all(|(k, v)| o.tags.0.get(k) == Some(v))
+            }
+            WatchEvent::Delete { .. } => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,18 +189,305 @@ pub enum WatchEvent {
     },
 }
 
+impl WatchEvent {
+    pub fn commit_seq(&self) -> u64 {
+        match self {
+            WatchEvent::Put(o) => o.commit_seq,
+            WatchEvent::Delete { commit_seq, .. } => *commit_seq,
+        }
+    }
+}
+
+/// Object persistence: the only part a new engine has to provide itself.
 #[async_trait::async_trait]
-pub trait Storage: Send + Sync + 'static {
+pub trait ObjectStore: Send + Sync + 'static {
     async fn put(&self, ns: &str, req: PutRequest) -> Result<Object>;
+    // Creates `req.id` only if it has no live version; returns (object, created)
+    async fn put_if_absent(&self, _ns: &str, _req: PutRequest) -> Result<(Object, bool)> {
+        Err(agentstate_core::StateError::Invalid(
+            "put_if_absent not supported by this engine".into(),
+        ))
+    }
+    // Writes `req` only if its body or tags differ from `req.id`'s live version;
+    // returns (object, changed), the existing version when unchanged
+    async fn put_if_changed(&self, _ns: &str, _req: PutRequest) -> Result<(Object, bool)> {
+        Err(agentstate_core::StateError::Invalid(
+            "only_if_changed not supported by this engine".into(),
+        ))
+    }
     async fn get(&self, ns: &str, id: &str, opts: GetOptions) -> Result<Object>;
     async fn query(&self, ns: &str, req: QueryRequest) -> Result<Vec<Object>>;
-    async fn delete(&self, ns: &str, id: &str) -> Result<()>;
-    async fn sweep_expired(&self, retention_secs: u64) -> Result<u64>; // returns removed count
+    async fn delete(&self, ns: &str, id: &str) -> Result<u64>; // returns the delete's commit_seq
+    // Full retained version history of an object, oldest first
+    async fn versions(&self, ns: &str, id: &str) -> Result<Vec<Object>>;
+    // Keep only the newest `keep` versions of an object; returns the number dropped
+    async fn trim_versions(&self, ns: &str, id: &str, keep: usize) -> Result<usize>;
+    // Move the current version of `id` to `new_id`: old id is tombstoned, new id created
+    async fn rename(&self, ns: &str, id: &str, new_id: &str) -> Result<Object>;
+    // Removes objects `retention_secs` past their TTL and grace; returns the removed objects
+    async fn sweep_expired(&self, retention_secs: u64) -> Result<Vec<Object>>;
+    // The same sweep, with the commit_seq each removal took in its ns as a delete's would.
+    // `Composed` tells watchers with these, so its engine has to provide it.
+    async fn sweep_expired_seqs(&self, _retention_secs: u64) -> Result<Vec<(Object, u64)>> {
+        Err(agentstate_core::StateError::Invalid(
+            "numbered sweeps not supported by this engine".into(),
+        ))
+    }
+    // Applies all ops or none; returns the committed events in order
+    async fn txn(&self, _ns: &str, _ops: Vec<TxnOp>) -> Result<Vec<WatchEvent>> {
+        Err(agentstate_core::StateError::Invalid(
+            "transactions not supported by this engine".into(),
+        ))
+    }
+
+    // Vector fields: register expected dims (and coercion mode) for an embedding field
+    fn register_vec_field(&self, ns: &str, field: VecField) -> Result<()>;
+
+    // ANN_WARMUP: page the engine's ANN graphs in; returns the synthetic searches run
+    fn warm_ann(&self) -> usize {
+        0
+    }
+
+    // True while a warm-up runs, at startup or after a vec field registration
+    fn ann_warming(&self) -> bool {
+        false
+    }
+
+    // Composite tag index: one lookup for tag filters over exactly these keys
+    fn register_composite_index(&self, ns: &str, keys: Vec<String>) -> Result<()>;
+
+    // JSONPath equality index over these body paths; returns every path indexed in the ns
+    fn register_index_paths(&self, _ns: &str, _paths: Vec<String>) -> Result<Vec<String>> {
+        Err(agentstate_core::StateError::Invalid(
+            "JSONPath indexes not supported by this engine".into(),
+        ))
+    }
+
+    // Body paths with a JSONPath index in the ns, in registration order
+    fn index_paths(&self, _ns: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Write quota for the ns, replacing any earlier one; an empty quota removes it. Puts
+    // and txns that would take the ns past a limit fail with `quota_exceeded`
+    fn set_quota(&self, _ns: &str, _quota: NsQuota) -> Result<()> {
+        Err(agentstate_core::StateError::Invalid(
+            "quotas not supported by this engine".into(),
+        ))
+    }
+
+    fn quota(&self, _ns: &str) -> Option<NsQuota> {
+        None
+    }
+
+    // Objects and bytes the ns holds, counting each object's latest version
+    fn usage(&self, _ns: &str) -> Option<NsUsage> {
+        None
+    }
+
+    // Live objects in the ns whose TTL runs out by `until`, soonest first. A listing, not
+    // a read: access stats stay as they were
+    fn expiring(&self, _ns: &str, _until: DateTime<Utc>) -> Result<Vec<Object>> {
+        Err(agentstate_core::StateError::Invalid(
+            "expiry listing not supported by this engine".into(),
+        ))
+    }
+
+    // Verifies up to `sample` tag and JSONPath index entries against the latest version of
+    // the object they point at; successive calls move on through the indexes
+    fn check_indexes(&self, _sample: usize) -> Vec<IndexMismatch> {
+        Vec::new()
+    }
 
+    // Write-time body transform, appended to the ns's ordered rule list
+    fn register_transform(&self, _ns: &str, _rule: TransformRule) -> Result<()> {
+        Err(agentstate_core::StateError::Invalid(
+            "body transforms not supported by this engine".into(),
+        ))
+    }
+
+    // Export all objects (for admin dump); the latest version of each. Required, since an
+    // engine that listed nothing would export and snapshot as if it were empty
+    fn all_objects(&self) -> Vec<Object>;
+
+    // Point-in-time export, step 1: each ns's last commit_seq, taken atomically, so every
+    // write at or below it is visible and none above it is
+    fn export_watermark(&self) -> Result<BTreeMap<String, u64>> {
+        Err(agentstate_core::StateError::Invalid(
+            "consistent export not supported by this engine".into(),
+        ))
+    }
+
+    // Step 2: every (ns, id) holding an object, sorted, for the export to walk in order
+    fn export_keys(&self) -> Result<Vec<(String, String)>> {
+        Err(agentstate_core::StateError::Invalid(
+            "consistent export not supported by this engine".into(),
+        ))
+    }
+
+    // Step 3: for each key, its newest retained version at or below its ns's watermark.
+    // Keys with none are skipped: written only since, or deleted or trimmed meanwhile.
+    fn export_batch(
+        &self,
+        _keys: &[(String, String)],
+        _watermark: &BTreeMap<String, u64>,
+    ) -> Vec<Object> {
+        Vec::new()
+    }
+
+    // Namespaces holding at least one object, sorted
+    fn namespaces(&self) -> Vec<String> {
+        let mut out: Vec<String> = self.all_objects().into_iter().map(|o| o.ns).collect();
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    // Ids whose latest version lists one of this object's commits in `parents`
+    fn children(&self, _ns: &str, _id: &str) -> Result<Vec<String>> {
+        Err(agentstate_core::StateError::Invalid(
+            "object references not supported by this engine".into(),
+        ))
+    }
+
+    // Objects referencing `id` transitively, breadth first, as (id, depth) with direct
+    // children at depth 1; stops below `depth` levels or at `max` results, and reports
+    // whether it stopped early because of `max`. Each object appears once, at its
+    // shallowest depth, so cycles end.
+    fn descendants(
+        &self,
+        ns: &str,
+        id: &str,
+        depth: usize,
+        max: usize,
+    ) -> Result<(Vec<(String, usize)>, bool)> {
+        let mut seen: std::collections::HashSet<String> = [id.to_string()].into();
+        let mut level = vec![id.to_string()];
+        let mut out = Vec::new();
+        for d in 1..=depth {
+            let mut next = Vec::new();
+            for parent in level.iter() {
+                let children = match self.children(ns, parent) {
+                    Ok(c) => c,
+                    // Deleted since it was found; the root must exist
+                    Err(agentstate_core::StateError::NotFound) if d > 1 => continue,
+                    Err(e) => return Err(e),
+                };
+                for child in children {
+                    if !seen.insert(child.clone()) {
+                        continue;
+                    }
+                    if out.len() >= max {
+                        return Ok((out, true));
+                    }
+                    out.push((child.clone(), d));
+                    next.push(child);
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            level = next;
+        }
+        Ok((out, false))
+    }
+
+    // Deletes `id` and everything referencing it, transitively; returns (id, commit_seq)
+    // in delete order, root first. The graph is walked before anything is deleted, so
+    // one with more than `max` objects is refused whole. Deletes are not atomic.
+    async fn delete_cascade(&self, ns: &str, id: &str, max: usize) -> Result<Vec<(String, u64)>> {
+        let (found, truncated) = self.descendants(ns, id, usize::MAX, max.saturating_sub(1))?;
+        if truncated {
+            return Err(agentstate_core::StateError::Invalid(format!(
+                "cascade_too_large: more than {} objects",
+                max
+            )));
+        }
+        let order = std::iter::once(id.to_string()).chain(found.into_iter().map(|(c, _)| c));
+        let mut deleted = Vec::new();
+        for (i, target) in order.enumerate() {
+            match self.delete(ns, &target).await {
+                Ok(seq) => deleted.push((target, seq)),
+                // Deleted by someone else since the walk
+                Err(agentstate_core::StateError::NotFound) if i > 0 => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(deleted)
+    }
+
+    // Runs the query like `query`, filling in `trace`; a disabled trace only counts candidates
+    async fn query_traced(
+        &self,
+        ns: &str,
+        req: QueryRequest,
+        _trace: &mut QueryTrace,
+    ) -> Result<Vec<Object>> {
+        self.query(ns, req).await
+    }
+
+    // Runs the query like `query`, also returning what each stage did
+    async fn query_analyze(
+        &self,
+        _ns: &str,
+        _req: QueryRequest,
+    ) -> Result<(Vec<Object>, QueryTrace)> {
+        Err(agentstate_core::StateError::Invalid(
+            "analyze not supported by this engine".into(),
+        ))
+    }
+
+    // Get/query hits on an object; None if never read or the engine doesn't count reads
+    fn access_stats(&self, _ns: &str, _id: &str) -> Option<AccessStats> {
+        None
+    }
+
+    // Takes or extends an advisory lock on an existing object; Conflict while another
+    // owner's lock is live
+    fn advisory_lock(
+        &self,
+        _ns: &str,
+        _id: &str,
+        _owner: &str,
+        _ttl_secs: u64,
+    ) -> Result<AdvisoryLock> {
+        Err(agentstate_core::StateError::Invalid(
+            "advisory locks not supported by this engine".into(),
+        ))
+    }
+
+    // Drops `owner`'s advisory lock; Conflict if another owner's lock is live
+    fn advisory_unlock(&self, _ns: &str, _id: &str, _owner: &str) -> Result<()> {
+        Err(agentstate_core::StateError::Invalid(
+            "advisory locks not supported by this engine".into(),
+        ))
+    }
+
+    // The object's live advisory lock, if any
+    fn advisory_lock_state(&self, _ns: &str, _id: &str) -> Option<AdvisoryLock> {
+        None
+    }
+}
+
+/// Change feed over committed writes.
+pub trait WatchSource: Send + Sync + 'static {
     // Subscribe from an optional resume token (commit_seq)
-    fn subscribe(&self, filter: WatchFilter, from_commit: Option<u64>) -> Box<dyn WatchHandle>;
+    // `limits` overrides the env defaults for this subscription's buffer
+    fn subscribe(
+        &self,
+        filter: WatchFilter,
+        from_commit: Option<u64>,
+        limits: Option<WatchLimits>,
+    ) -> Box<dyn WatchHandle>;
 
-    // Leases
+    // Backlog monitoring
+    fn backlog_map(&self) -> std::collections::HashMap<String, u64> {
+        Default::default()
+    }
+}
+
+#[async_trait::async_trait]
+pub trait LeaseStore: Send + Sync + 'static {
     async fn lease_acquire(&self, ns: &str, key: &str, owner: &str, ttl_secs: u64)
         -> Result<Lease>;
     async fn lease_renew(
@@ -46,7 +500,12 @@ pub trait Storage: Send + Sync + 'static {
     ) -> Result<Lease>;
     async fn lease_release(&self, ns: &str, key: &str, owner: &str, token: u64) -> Result<()>;
 
-    // Idempotency
+    // Fence validation for writes
+    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+pub trait IdempotencyStore: Send + Sync + 'static {
     async fn idempotency_lookup(
         &self,
         ns: &str,
@@ -62,31 +521,48 @@ pub trait Storage: Send + Sync + 'static {
         commit_seq: u64,
         expires_at: DateTime<Utc>,
     ) -> Result<()>;
+}
 
-    // Fence validation for writes
-    async fn validate_fence(&self, ns: &str, resource: &str, fence: u64) -> Result<()>;
-
-    // Admin
+#[async_trait::async_trait]
+pub trait AdminOps: Send + Sync + 'static {
     async fn admin_snapshot(&self) -> Result<(String, u64)>;
     async fn admin_manifest(&self) -> Result<serde_json::Value>;
     async fn admin_trim_wal(&self, snapshot_id: &str) -> Result<Vec<String>>;
-    
-    // Backlog monitoring
-    fn backlog_map(&self) -> std::collections::HashMap<String, u64> {
-        Default::default()
+
+    // Hot backup: pause WAL segment rotation (and trims) and report the segments to copy
+    async fn admin_wal_freeze(&self, _frozen: bool) -> Result<serde_json::Value> {
+        Err(agentstate_core::StateError::Invalid("wal not supported by this engine".into()))
+    }
+
+    // Deep health: Err while the engine's WAL can't persist writes
+    async fn wal_health(&self) -> Result<()> {
+        Ok(())
     }
-    
-    // Export all objects (for admin dump)
-    fn all_objects(&self) -> Vec<Object> {
+
+    // Startup recovery: what the engine skipped to come up (e.g. unreadable WAL segments)
+    fn recovery_issues(&self) -> Vec<String> {
         Vec::new()
     }
 
+    // Replication: apply one record, or one whole transaction, from a leader's WAL stream
+    // logged by the leader at `ts` (unix seconds)
+    async fn apply_replicated(&self, _ts: i64, _recs: Vec<crate::walbin::RecBody>) -> Result<()> {
+        Err(agentstate_core::StateError::Invalid("replication not supported".into()))
+    }
 }
 
+/// Everything the server needs; implemented for any type providing all the parts.
+/// Engines that only persist objects can wrap themselves in [`crate::Composed`].
+pub trait Storage: ObjectStore + WatchSource + LeaseStore + IdempotencyStore + AdminOps {}
+
+impl<T> Storage for T where T: ObjectStore + WatchSource + LeaseStore + IdempotencyStore + AdminOps {}
+
 pub trait WatchHandle: Send {
     fn try_next(&mut self) -> Option<WatchEvent>;
     fn last_commit(&self) -> u64;
     fn overflow_meta(&self) -> Option<(u64, u32)>; // (last_commit, retry_after_ms)
+    // Stops queueing events for this handle; later calls to try_next drain what's left
+    fn unsubscribe(&mut self) {}
 }
 
 #[derive(Debug, Clone)]
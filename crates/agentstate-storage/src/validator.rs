@@ -0,0 +1,168 @@
+// Optional write validation by a WASM module, for deployment-specific business rules.
+//
+// WASM_VALIDATOR: path to the .wasm module (needs the `wasm` build feature)
+// WASM_VALIDATOR_TIMEOUT_MS: per-call wall-clock limit (default 10)
+// WASM_VALIDATOR_MAX_MEMORY: linear memory cap in bytes (default 16 MiB)
+//
+// Module ABI: export `memory`, `alloc(len: i32) -> i32` and
+// `validate(ptr: i32, len: i32) -> i64`. The object JSON is copied into the buffer
+// `alloc` returned. `validate` returns 0 to accept. Any other value rejects, packed as
+// (reason_ptr << 32 | reason_len) of a UTF-8 reason in memory; a zero len gives none.
+// No host functions are linked, and every call runs in a fresh instance.
+use agentstate_core::{PutRequest, Result, StateError};
+
+/// Loads the module named by WASM_VALIDATOR, if any. Call once at startup.
+pub fn init() -> std::result::Result<(), String> {
+    let Ok(path) = std::env::var("WASM_VALIDATOR") else {
+        return Ok(());
+    };
+    if path.is_empty() {
+        return Ok(());
+    }
+    imp::load(&path)
+}
+
+/// Runs the loaded validator on an object about to be written; a no-op when none is set.
+/// Rejections are Invalid; traps, timeouts and ABI mismatches are Internal.
+pub(crate) fn check(ns: &str, req: &PutRequest) -> Result<()> {
+    if !imp::loaded() {
+        return Ok(());
+    }
+    let mut doc = serde_json::to_value(req).map_err(|e| StateError::Internal(e.to_string()))?;
+    doc["ns"] = serde_json::Value::String(ns.to_string());
+    let json = serde_json::to_vec(&doc).map_err(|e| StateError::Internal(e.to_string()))?;
+    match imp::call(&json) {
+        Ok(None) => Ok(()),
+        Ok(Some(reason)) if reason.is_empty() => {
+            Err(StateError::Invalid("rejected by validator".into()))
+        }
+        Ok(Some(reason)) => Err(StateError::Invalid(format!(
+            "rejected by validator: {}",
+            reason
+        ))),
+        Err(e) => Err(StateError::Internal(format!("validator failed: {}", e))),
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod imp {
+    use once_cell::sync::OnceCell;
+    use std::time::Duration;
+    use wasmtime::{
+        Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap,
+    };
+
+    struct Validator {
+        engine: Engine,
+        pre: InstancePre<StoreLimits>,
+        timeout_ticks: u64,
+        max_memory: usize,
+    }
+
+    static VALIDATOR: OnceCell<Validator> = OnceCell::new();
+
+    // The engine's epoch advances once per tick; a call may run for timeout_ticks of them
+    const TICK: Duration = Duration::from_millis(1);
+
+    pub(super) fn load(path: &str) -> Result<(), String> {
+        let timeout_ms = std::env::var("WASM_VALIDATOR_TIMEOUT_MS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(10);
+        let max_memory = std::env::var("WASM_VALIDATOR_MAX_MEMORY")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(16 * 1024 * 1024);
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+        let module = Module::from_file(&engine, path).map_err(|e| format!("{}: {}", path, e))?;
+        // An empty linker: modules that import anything fail here, not at the first put
+        let pre = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        for export in ["memory", "alloc", "validate"] {
+            if module.get_export(export).is_none() {
+                return Err(format!("{}: missing export `{}`", path, export));
+            }
+        }
+        let ticker = engine.clone();
+        std::thread::Builder::new()
+            .name("wasm-epoch".into())
+            .spawn(move || loop {
+                std::thread::sleep(TICK);
+                ticker.increment_epoch();
+            })
+            .map_err(|e| e.to_string())?;
+        VALIDATOR
+            .set(Validator {
+                engine,
+                pre,
+                timeout_ticks: timeout_ms,
+                max_memory,
+            })
+            .map_err(|_| "validator already loaded".to_string())?;
+        tracing::info!("wasm validator loaded from {}", path);
+        Ok(())
+    }
+
+    pub(super) fn loaded() -> bool {
+        VALIDATOR.get().is_some()
+    }
+
+    pub(super) fn call(json: &[u8]) -> Result<Option<String>, String> {
+        let v = VALIDATOR.get().ok_or("no validator loaded")?;
+        let limits = StoreLimitsBuilder::new().memory_size(v.max_memory).build();
+        let mut store = Store::new(&v.engine, limits);
+        store.limiter(|l| l);
+        store.set_epoch_deadline(v.timeout_ticks);
+        let timed_out = |e: wasmtime::Error| match e.downcast_ref::<Trap>() {
+            Some(Trap::Interrupt) => "timed out".to_string(),
+            _ => e.to_string(),
+        };
+        let instance = v.pre.instantiate(&mut store).map_err(timed_out)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or("`memory` is not a memory")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(|e| e.to_string())?;
+        let validate = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, "validate")
+            .map_err(|e| e.to_string())?;
+        let len = i32::try_from(json.len()).map_err(|_| "object too large for validator")?;
+        let ptr = alloc.call(&mut store, len).map_err(timed_out)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, json)
+            .map_err(|e| e.to_string())?;
+        let res = validate.call(&mut store, (ptr, len)).map_err(timed_out)?;
+        if res == 0 {
+            return Ok(None);
+        }
+        let (rptr, rlen) = (
+            (res as u64 >> 32) as usize,
+            (res as u64 & 0xffff_ffff) as usize,
+        );
+        let mut reason = vec![0u8; rlen.min(1024)];
+        memory
+            .read(&store, rptr, &mut reason)
+            .map_err(|e| e.to_string())?;
+        Ok(Some(String::from_utf8_lossy(&reason).into_owned()))
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod imp {
+    pub(super) fn load(_path: &str) -> Result<(), String> {
+        Err("WASM_VALIDATOR is set but this build lacks the `wasm` feature".into())
+    }
+
+    pub(super) fn loaded() -> bool {
+        false
+    }
+
+    pub(super) fn call(_json: &[u8]) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+}
@@ -20,7 +20,6 @@ pub enum WalRecord {
 }
 
 pub struct Wal {
-    dir: PathBuf,
     file: File,
 }
 
@@ -29,7 +28,7 @@ impl Wal {
         std::fs::create_dir_all(&dir).ok();
         let path = dir.join(format!("wal-{}.log", Utc::now().timestamp()));
         let file = OpenOptions::new().create(true).append(true).open(&path)?;
-        Ok(Self { dir, file })
+        Ok(Self { file })
     }
 
     pub fn append(&mut self, rec: &WalRecord) -> std::io::Result<()> {
@@ -52,11 +51,9 @@ impl Wal {
                 }
                 let fh = File::open(&p)?;
                 let br = BufReader::new(fh);
-                for line in br.lines() {
-                    if let Ok(l) = line {
-                        if let Ok(rec) = serde_json::from_str::<WalRecord>(&l) {
-                            out.push(rec);
-                        }
+                for l in br.lines().map_while(|line| line.ok()) {
+                    if let Ok(rec) = serde_json::from_str::<WalRecord>(&l) {
+                        out.push(rec);
                     }
                 }
             }
@@ -7,14 +7,45 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{
+    collections::BTreeMap,
     fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 use tokio::sync::{mpsc, oneshot};
 
 const MAGIC: [u8; 4] = *b"ASTW";
-const VER: u8 = 1;
+// The header version byte also names the body encoding, so segments may mix both
+const VER: u8 = 1; // CBOR
+const VER_MSGPACK: u8 = 2;
+
+// WAL_ENCODING=msgpack writes new records as MessagePack; replay reads either
+static WAL_ENCODING: Lazy<u8> = Lazy::new(|| match std::env::var("WAL_ENCODING").as_deref() {
+    Ok("msgpack") => VER_MSGPACK,
+    _ => VER,
+});
+
+// WAL_PREALLOCATE=1 reserves each new segment's full size up front, so appends
+// fill allocated space instead of growing the file; the zeroed tail reads as clean
+static WAL_PREALLOCATE: Lazy<bool> = Lazy::new(|| {
+    matches!(
+        std::env::var("WAL_PREALLOCATE").as_deref(),
+        Ok("1") | Ok("true")
+    )
+});
+
+// WAL_DIRS=/disk1/wal,/disk2/wal spreads new segments across directories: segment N
+// goes to entry (N - 1) % len, so listing a directory twice doubles its share. Unset,
+// segments stay in DATA_DIR/wal. The manifest records where each segment went.
+static WAL_DIRS: Lazy<Vec<PathBuf>> = Lazy::new(|| {
+    std::env::var("WAL_DIRS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| std::path::absolute(s).unwrap_or_else(|_| PathBuf::from(s)))
+        .collect()
+});
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -25,6 +56,9 @@ pub enum RecType {
     LeaseRenew = 4,
     LeaseRelease = 5,
     Idempotency = 6,
+    TrimVersions = 7,
+    TxnBegin = 8,
+    TxnCommit = 9,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -36,6 +70,9 @@ pub enum RecBody {
     Delete {
         ns: String,
         id: String,
+        // 0 in records written before deletes carried their seq
+        #[serde(default)]
+        commit_seq: u64,
     },
     LeaseAcquire {
         ns: String,
@@ -63,12 +100,65 @@ pub enum RecBody {
         response: serde_json::Value,
         expires_ts: i64,
     },
+    TrimVersions {
+        ns: String,
+        id: String,
+        keep: usize,
+    },
+    // Markers around the records of a multi-object transaction; replay applies
+    // the records in between only once it reaches the commit
+    TxnBegin {
+        id: String,
+    },
+    TxnCommit {
+        id: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct WalSegmentMeta {
     pub name: String,
     pub max_seq: u64,
+    // Directory the segment was placed in by WAL_DIRS; None is DATA_DIR/wal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+}
+
+impl WalSegmentMeta {
+    fn next(prev: Option<&WalSegmentMeta>, max_seq: u64) -> Self {
+        let name = WalWriter::new_segment_name(prev);
+        let dir = name
+            .trim_end_matches(".wal")
+            .parse::<u64>()
+            .ok()
+            .filter(|_| !WAL_DIRS.is_empty())
+            .map(|n| WAL_DIRS[((n.max(1) - 1) % WAL_DIRS.len() as u64) as usize].clone());
+        Self { name, max_seq, dir }
+    }
+
+    /// The segment file, for a WAL whose manifest lives in `data_dir`.
+    pub fn path(&self, data_dir: &Path) -> PathBuf {
+        match &self.dir {
+            Some(d) => d.join(&self.name),
+            None => data_dir.join("wal").join(&self.name),
+        }
+    }
+}
+
+/// Bytes this process has appended to the WAL so far.
+pub fn bytes_written() -> u64 {
+    WAL_BYTES_TOTAL.get()
+}
+
+/// Every directory that may hold segments: DATA_DIR/wal, then WAL_DIRS.
+pub fn wal_dirs(data_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![data_dir.join("wal")];
+    for d in WAL_DIRS.iter() {
+        if !dirs.contains(d) {
+            dirs.push(d.clone());
+        }
+    }
+    dirs
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -88,8 +178,6 @@ pub struct WalSegment {
 }
 
 pub struct WalWriter {
-    dir: PathBuf,
-    seg_size: u64,
     inner: Arc<RwLock<WalInner>>,
     tx: mpsc::Sender<Enq>,
 }
@@ -102,16 +190,18 @@ struct WalHandle {
 }
 
 struct WalInner {
-    pub current_seq: u64,
     pub segment: WalSegment,
     pub manifest: Manifest,
+    // Last write/sync failure; cleared once a batch reaches disk again
+    pub last_error: Option<String>,
+    // Set while a backup copies the segments: the current one grows instead of rotating
+    pub frozen: bool,
 }
 
 struct Enq {
     rec: Vec<u8>,
-    size: usize,
     seq: u64,
-    ack: oneshot::Sender<()>,
+    ack: oneshot::Sender<std::result::Result<(), String>>,
 }
 
 static WAL_RECORDS_TOTAL: Lazy<IntCounter> =
@@ -123,14 +213,18 @@ static WAL_FSYNC_TOTAL: Lazy<IntCounter> =
 static WAL_BATCH_BYTES: Lazy<Histogram> = Lazy::new(|| {
     Histogram::with_opts(HistogramOpts::new("wal_batch_bytes", "wal batch sizes")).unwrap()
 });
+static WAL_WRITE_ERRORS_TOTAL: Lazy<IntCounter> =
+    Lazy::new(|| IntCounter::new("wal_write_errors_total", "wal write/sync errors").unwrap());
 static WAL_FSYNC_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     Histogram::with_opts(HistogramOpts::new("wal_fsync_seconds", "wal fsync time")).unwrap()
 });
 
 impl WalWriter {
-    pub fn open(dir: impl AsRef<Path>, seg_size: u64, start_seq: u64) -> std::io::Result<Self> {
+    pub fn open(dir: impl AsRef<Path>, seg_size: u64, _start_seq: u64) -> std::io::Result<Self> {
         let dir = dir.as_ref().to_path_buf();
-        std::fs::create_dir_all(dir.join("wal"))?;
+        for d in wal_dirs(&dir) {
+            std::fs::create_dir_all(d)?;
+        }
         std::fs::create_dir_all(dir.join("snapshots"))?;
         let manifest_path = dir.join("manifest.json");
         let mut manifest: Manifest = if manifest_path.exists() {
@@ -146,47 +240,70 @@ impl WalWriter {
                 segments: vec![],
             }
         };
-        let seg_name = if manifest.current_segment.is_empty() {
-            Self::new_segment_name(manifest.segments.last())
+        let seg = if manifest.current_segment.is_empty() {
+            WalSegmentMeta::next(manifest.segments.last(), 0)
         } else {
-            manifest.current_segment.clone()
+            let cur = &manifest.current_segment;
+            manifest
+                .segments
+                .iter()
+                .find(|m| m.name == *cur)
+                .cloned()
+                .unwrap_or(WalSegmentMeta {
+                    name: cur.clone(),
+                    ..Default::default()
+                })
         };
-        let seg_path = dir.join("wal").join(&seg_name);
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(&seg_path)?;
-        let bytes = file.metadata()?.len();
+        let opened = open_segment_file(&seg.path(&dir), seg_size).and_then(|f| {
+            match clean_end(&seg.path(&dir)) {
+                Err(why) => Err(std::io::Error::other(why)),
+                Ok(end) => Ok((f, end)),
+            }
+        });
+        let (seg, (mut file, bytes)) = match opened {
+            Ok(f) => (seg, f),
+            // Don't let one bad segment take the whole store down, and never append
+            // after a torn record or an unfinished transaction, where replay would
+            // lose the new records: leave it in the manifest and continue in a fresh one
+            Err(e) if !manifest.current_segment.is_empty() => {
+                let next = WalSegmentMeta::next(manifest.segments.last(), manifest.last_seq);
+                tracing::warn!("wal segment {} unusable ({}), using {}", seg.name, e, next.name);
+                let f = open_segment_file(&next.path(&dir), seg_size)?;
+                manifest.segments.push(next.clone());
+                manifest.current_segment = next.name.clone();
+                persist_manifest_at(&dir, &manifest)?;
+                (next, (f, 0))
+            }
+            Err(e) => return Err(e),
+        };
+        let seg_path = seg.path(&dir);
+        // Not opened for append: a preallocated segment's end is past its last record
+        file.seek(SeekFrom::Start(bytes))?;
         let segment = WalSegment {
             path: seg_path,
             file,
             bytes,
         };
+        manifest.current_segment = seg.name.clone();
         if manifest.segments.is_empty() {
-            manifest.segments.push(WalSegmentMeta {
-                name: seg_name.clone(),
-                max_seq: 0,
-            });
+            manifest.segments.push(seg);
         }
-        manifest.current_segment = seg_name;
         let (tx, mut rx) = mpsc::channel::<Enq>(1024);
-        // register metrics in default registry
-        let reg = prometheus::default_registry();
+        let reg = &crate::metrics::REGISTRY;
         let _ = reg.register(Box::new(WAL_RECORDS_TOTAL.clone()));
         let _ = reg.register(Box::new(WAL_BYTES_TOTAL.clone()));
         let _ = reg.register(Box::new(WAL_FSYNC_TOTAL.clone()));
         let _ = reg.register(Box::new(WAL_BATCH_BYTES.clone()));
         let _ = reg.register(Box::new(WAL_FSYNC_SECONDS.clone()));
+        let _ = reg.register(Box::new(WAL_WRITE_ERRORS_TOTAL.clone()));
 
         let inner = Arc::new(RwLock::new(WalInner {
-            current_seq: start_seq,
             segment,
             manifest,
+            last_error: None,
+            frozen: false,
         }));
         let me = Self {
-            dir: dir.clone(),
-            seg_size,
             inner: inner.clone(),
             tx,
         };
@@ -214,14 +331,59 @@ impl WalWriter {
         self.inner.read().manifest.clone()
     }
 
+    /// Pauses (or resumes) segment rotation and returns the manifest at that moment.
+    /// Rotation happens under the same lock, so the segment list can't change while frozen.
+    pub fn set_frozen(&self, frozen: bool) -> Manifest {
+        let mut inner = self.inner.write();
+        inner.frozen = frozen;
+        inner.manifest.clone()
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.inner.read().frozen
+    }
+
+    /// Last write/sync error from the fsync worker, if the WAL is currently failing.
+    pub fn last_error(&self) -> Option<String> {
+        self.inner.read().last_error.clone()
+    }
+
     pub async fn append(&self, seq: u64, ts: i64, body: &RecBody) -> std::io::Result<()> {
-        let mut v = Vec::new();
-        ser::into_writer(body, &mut v).unwrap();
+        self.enqueue(Self::frame(seq, ts, body)?, seq).await
+    }
+
+    /// Appends records back to back in one write, so no other writer's records land
+    /// between them and they never straddle a segment rotation.
+    pub async fn append_all(&self, ts: i64, recs: &[(u64, RecBody)]) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        for (seq, body) in recs {
+            buf.extend_from_slice(&Self::frame(*seq, ts, body)?);
+        }
+        let seq = recs.iter().map(|(s, _)| *s).max().unwrap_or(0);
+        self.enqueue(buf, seq).await
+    }
+
+    fn frame(seq: u64, ts: i64, body: &RecBody) -> std::io::Result<Vec<u8>> {
+        let ver = *WAL_ENCODING;
+        let v = if ver == VER_MSGPACK {
+            rmp_serde::to_vec_named(body).unwrap()
+        } else {
+            let mut v = Vec::new();
+            ser::into_writer(body, &mut v).unwrap();
+            v
+        };
+        // Replay refuses anything longer, so it is never written
+        if v.len() > MAX_RECORD_BYTES {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("record of {} bytes exceeds {}", v.len(), MAX_RECORD_BYTES),
+            ));
+        }
         let len = v.len() as u32;
         let ns_hash = 0u64; // reserved
         let mut rec = Vec::with_capacity(4 + 1 + 1 + 8 + 8 + 8 + 4 + v.len() + 4);
         rec.extend_from_slice(&MAGIC);
-        rec.push(VER);
+        rec.push(ver);
         rec.push(Self::rectype(body) as u8);
         rec.extend_from_slice(&ns_hash.to_be_bytes());
         rec.extend_from_slice(&seq.to_be_bytes());
@@ -232,18 +394,24 @@ impl WalWriter {
         rec.extend_from_slice(&(crc.to_be_bytes()));
         WAL_RECORDS_TOTAL.inc();
         WAL_BYTES_TOTAL.inc_by(rec.len() as u64);
+        Ok(rec)
+    }
+
+    async fn enqueue(&self, rec: Vec<u8>, seq: u64) -> std::io::Result<()> {
         let (tx, rx) = oneshot::channel();
-        let _ = self
-            .tx
+        self.tx
             .send(Enq {
                 rec,
-                size: len as usize,
                 seq,
                 ack: tx,
             })
-            .await;
-        let _ = rx.await; // wait fsync
-        Ok(())
+            .await
+            .map_err(|_| std::io::Error::other("wal writer stopped"))?;
+        // wait fsync
+        match rx.await {
+            Ok(res) => res.map_err(std::io::Error::other),
+            Err(_) => Err(std::io::Error::other("wal writer stopped")),
+        }
     }
 
     fn rectype(b: &RecBody) -> RecType {
@@ -254,6 +422,9 @@ impl WalWriter {
             RecBody::LeaseRenew { .. } => RecType::LeaseRenew,
             RecBody::LeaseRelease { .. } => RecType::LeaseRelease,
             RecBody::Idempotency { .. } => RecType::Idempotency,
+            RecBody::TrimVersions { .. } => RecType::TrimVersions,
+            RecBody::TxnBegin { .. } => RecType::TxnBegin,
+            RecBody::TxnCommit { .. } => RecType::TxnCommit,
         }
     }
 }
@@ -282,59 +453,98 @@ impl WalHandle {
             let t0 = std::time::Instant::now();
             {
                 let mut inner = self.inner.write();
+                let start = inner.segment.bytes;
+                let mut failed: Option<String> = None;
                 for enq in &batch {
-                    let _ = inner.segment.file.write_all(&enq.rec);
+                    if let Err(e) = inner.segment.file.write_all(&enq.rec) {
+                        failed = Some(format!("wal write: {}", e));
+                        break;
+                    }
                 }
-                inner.segment.bytes += bytes as u64;
-                let last_seq = batch
-                    .last()
-                    .map(|e| e.seq)
-                    .unwrap_or(inner.manifest.last_seq);
-                inner.manifest.last_seq = inner.manifest.last_seq.max(last_seq);
-                if let Some(meta) = inner.manifest.segments.last_mut() {
-                    meta.max_seq = meta.max_seq.max(last_seq);
+                if failed.is_none() {
+                    // Use sync_data instead of sync_all for better performance
+                    // sync_data only syncs file data, not metadata, which is faster
+                    if let Err(e) = inner
+                        .segment
+                        .file
+                        .flush()
+                        .and_then(|_| inner.segment.file.sync_data())
+                    {
+                        failed = Some(format!("wal sync: {}", e));
+                    }
+                }
+                if failed.is_none() {
+                    inner.segment.bytes += bytes as u64;
+                    // Concurrent writers can enqueue out of seq order
+                    let last_seq = batch
+                        .iter()
+                        .map(|e| e.seq)
+                        .max()
+                        .unwrap_or(inner.manifest.last_seq);
+                    inner.manifest.last_seq = inner.manifest.last_seq.max(last_seq);
+                    if let Some(meta) = inner.manifest.segments.last_mut() {
+                        meta.max_seq = meta.max_seq.max(last_seq);
+                    }
+                } else if let Err(e) = self.rewind_locked(&mut inner, start) {
+                    // Replay stops at a torn record, so nothing may follow it in this segment
+                    tracing::error!("wal rewind to {}: {}", start, e);
+                    if let Err(e) = self.rotate_locked(&mut inner) {
+                        failed = failed.map(|f| format!("{}; wal rotate: {}", f, e));
+                    }
                 }
-                let _ = inner.segment.file.flush();
-                // Use sync_data instead of sync_all for better performance
-                // sync_data only syncs file data, not metadata, which is faster
-                let _ = inner.segment.file.sync_data();
                 WAL_FSYNC_TOTAL.inc();
                 WAL_FSYNC_SECONDS.observe(t0.elapsed().as_secs_f64());
                 WAL_BATCH_BYTES.observe(bytes as f64);
-                let _ = persist_manifest_at(&self.dir, &inner.manifest);
+                if let Err(e) = persist_manifest_at(&self.dir, &inner.manifest) {
+                    failed.get_or_insert(format!("wal manifest: {}", e));
+                }
                 // rotation
-                let rotate_at = std::env::var("WAL_SEGMENT_BYTES")
-                    .ok()
-                    .and_then(|s| s.parse::<u64>().ok())
-                    .unwrap_or(self.seg_size);
-                if inner.segment.bytes >= rotate_at {
-                    let _ = self.rotate_locked(&mut inner);
+                if failed.is_none()
+                    && !inner.frozen
+                    && inner.segment.bytes >= segment_bytes(self.seg_size)
+                {
+                    if let Err(e) = self.rotate_locked(&mut inner) {
+                        failed.get_or_insert(format!("wal rotate: {}", e));
+                    }
                 }
+                if let Some(e) = &failed {
+                    WAL_WRITE_ERRORS_TOTAL.inc();
+                    tracing::error!("{}", e);
+                }
+                // Any failure is reported to every writer in the batch, never acked as success
+                let ack = failed.clone().map_or(Ok(()), Err);
+                inner.last_error = failed;
                 for enq in batch {
-                    let _ = enq.ack.send(());
+                    let _ = enq.ack.send(ack.clone());
                 }
             }
         }
     }
 
+    // Cuts a failed batch's partial records off the segment, so the next batch lands where
+    // it would have and replay never meets them
+    fn rewind_locked(&self, inner: &mut WalInner, offset: u64) -> std::io::Result<()> {
+        let file = &mut inner.segment.file;
+        file.set_len(offset)?;
+        let want = segment_bytes(self.seg_size);
+        if *WAL_PREALLOCATE && offset < want {
+            preallocate(file, want)?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.sync_data()
+    }
+
     fn rotate_locked(&self, inner: &mut WalInner) -> std::io::Result<()> {
-        let name = WalWriter::new_segment_name(inner.manifest.segments.last());
-        let seg_path = self.dir.join("wal").join(&name);
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(&seg_path)?;
+        let meta = WalSegmentMeta::next(inner.manifest.segments.last(), inner.manifest.last_seq);
+        let seg_path = meta.path(&self.dir);
+        let file = open_segment_file(&seg_path, self.seg_size)?;
         inner.segment = WalSegment {
-            path: seg_path.clone(),
+            path: seg_path,
             file,
             bytes: 0,
         };
-        inner.manifest.current_segment = name.clone();
-        inner.manifest.segments.push(WalSegmentMeta {
-            name,
-            max_seq: inner.manifest.last_seq,
-        });
+        inner.manifest.current_segment = meta.name.clone();
+        inner.manifest.segments.push(meta);
         persist_manifest_at(&self.dir, &inner.manifest)
     }
 }
@@ -345,54 +555,944 @@ fn persist_manifest_at(dir: &Path, m: &Manifest) -> std::io::Result<()> {
     std::fs::rename(tmp, dir.join("manifest.json"))
 }
 
-pub fn replay(dir: impl AsRef<Path>) -> std::io::Result<Vec<RecBody>> {
-    let dir = dir.as_ref().to_path_buf();
+static WAL_SEGMENT_BYTES: Lazy<Option<u64>> = Lazy::new(|| {
+    std::env::var("WAL_SEGMENT_BYTES")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+});
+
+// Rotation threshold: WAL_SEGMENT_BYTES, else the size the writer was opened with
+fn segment_bytes(default: u64) -> u64 {
+    WAL_SEGMENT_BYTES.unwrap_or(default)
+}
+
+// Opens (creating if needed) a segment for writing, preallocated when WAL_PREALLOCATE is set
+fn open_segment_file(path: &Path, seg_size: u64) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .read(true)
+        .open(path)?;
+    let want = segment_bytes(seg_size);
+    if *WAL_PREALLOCATE && file.metadata()?.len() < want {
+        preallocate(&file, want)?;
+    }
+    Ok(file)
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // Mode 0 allocates blocks and extends the size; the new range reads as zeros
+    let rc = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if rc == 0 {
+        return Ok(());
+    }
+    match std::io::Error::last_os_error() {
+        // e.g. tmpfs without fallocate: a sparse file still gives the zeroed tail
+        e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => file.set_len(len),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    file.set_len(len)
+}
+
+// Whether everything in `path` from `offset` on is zero: unused preallocated space
+fn zero_tail(path: &Path, offset: u64) -> bool {
+    let Ok(mut f) = File::open(path) else {
+        return false;
+    };
+    if f.seek(SeekFrom::Start(offset)).is_err() {
+        return false;
+    }
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(n) if buf[..n].iter().all(|b| *b == 0) => {}
+            _ => return false,
+        }
+    }
+}
+
+const HDR_LEN: usize = 4 + 1 + 1 + 8 + 8 + 8 + 4;
+// Largest record body; a length field past it is damage, not a record
+const MAX_RECORD_BYTES: usize = 256 << 20;
+
+// Where the next record goes in a segment left by a previous run, or why it can't be
+// appended to after a crash
+fn clean_end(path: &Path) -> std::result::Result<u64, String> {
+    let Ok(f) = File::open(path) else {
+        return Ok(0);
+    };
+    let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+    let mut f = std::io::BufReader::new(f);
+    let (mut offset, mut open_txn) = (0u64, None);
+    while let Some(raw) = read_record(&mut f) {
+        match decode_body(raw.ver, &raw.body) {
+            Ok(RecBody::TxnBegin { .. }) => open_txn = Some(offset),
+            Ok(RecBody::TxnCommit { .. }) => open_txn = None,
+            _ => {}
+        }
+        offset += raw.len;
+    }
+    if offset < size && !zero_tail(path, offset) {
+        return Err(format!("unreadable record at byte {}", offset));
+    }
+    match open_txn {
+        Some(at) => Err(format!("unfinished transaction at byte {}", at)),
+        None => Ok(offset),
+    }
+}
+
+fn read_manifest(dir: &Path) -> std::io::Result<Manifest> {
     let manifest_path = dir.join("manifest.json");
-    let manifest: Manifest = if manifest_path.exists() {
+    if manifest_path.exists() {
         let s = std::fs::read_to_string(&manifest_path)?;
-        serde_json::from_str(&s).unwrap_or_default()
+        Ok(serde_json::from_str(&s).unwrap_or_default())
     } else {
-        Manifest::default()
-    };
+        Ok(Manifest::default())
+    }
+}
+
+struct RawRecord {
+    ver: u8,
+    rtype: u8,
+    seq: u64,
+    ts: i64,
+    body: Vec<u8>,
+    len: u64, // bytes on disk including header and crc
+}
+
+// Reads one framed record; None on EOF, short read, bad magic or CRC mismatch.
+fn read_record(f: &mut impl Read) -> Option<RawRecord> {
+    let mut hdr = [0u8; HDR_LEN];
+    if f.read_exact(&mut hdr).is_err() {
+        return None;
+    }
+    if &hdr[0..4] != MAGIC.as_ref() {
+        return None;
+    }
+    let seq = u64::from_be_bytes(hdr[14..22].try_into().unwrap());
+    let ts = u64::from_be_bytes(hdr[22..30].try_into().unwrap()) as i64;
+    let len = u32::from_be_bytes(hdr[30..34].try_into().unwrap()) as usize;
+    if len > MAX_RECORD_BYTES {
+        return None;
+    }
+    // Read through `take`, so a damaged length allocates only what the file still holds
+    let mut body = Vec::new();
+    if f.take(len as u64).read_to_end(&mut body).ok()? != len {
+        return None;
+    }
+    let mut crcbuf = [0u8; 4];
+    if f.read_exact(&mut crcbuf).is_err() {
+        return None;
+    }
+    let mut rec = hdr.to_vec();
+    rec.extend_from_slice(&body);
+    if crc32c(&rec) != u32::from_be_bytes(crcbuf) {
+        return None;
+    }
+    Some(RawRecord {
+        ver: hdr[4],
+        rtype: hdr[5],
+        seq,
+        ts,
+        body,
+        len: (HDR_LEN + len + 4) as u64,
+    })
+}
+
+fn decode_body(ver: u8, body: &[u8]) -> std::result::Result<RecBody, String> {
+    match ver {
+        VER => ciborium::de::from_reader(body).map_err(|e| e.to_string()),
+        VER_MSGPACK => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+        v => Err(format!("unknown record version {}", v)),
+    }
+}
+
+pub fn replay(dir: impl AsRef<Path>) -> std::io::Result<Vec<RecBody>> {
     let mut out = Vec::new();
+    replay_with(dir, |rec| out.push(rec))?;
+    Ok(out)
+}
+
+/// What a replay applied and where it had to stop reading.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    pub records_applied: u64,
+    // Segments not read to their end, by the byte offset reading stopped at
+    pub truncated_at_offset: BTreeMap<String, u64>,
+    // Stops with valid records after them, or anywhere in a finished segment: a bad
+    // record rather than a write torn by a crash
+    pub crc_failures: u64,
+    // Everything lost to damage, one line each; empty for a clean recovery
+    pub issues: Vec<String>,
+}
+
+/// Streams every record in manifest order to `apply` as it is decoded, so
+/// recovery memory doesn't grow with the WAL size. A transaction's records are
+/// held back until its commit marker; one cut off by a crash is dropped whole.
+///
+/// A segment is read up to its first bad record, then replay goes on with the next
+/// segment rather than failing; the report says where and why so callers can tell a
+/// partial recovery from a clean one. A torn record at the end of the current
+/// segment is an unacknowledged write, not data loss: it shows up in
+/// `truncated_at_offset` but not as an issue or CRC failure.
+pub fn replay_with(
+    dir: impl AsRef<Path>,
+    mut apply: impl FnMut(RecBody),
+) -> std::io::Result<ReplayReport> {
+    replay_timed(dir, |_, rec| apply(rec))
+}
+
+/// [`replay_with`], also passing each record's append time in unix seconds.
+pub fn replay_timed(
+    dir: impl AsRef<Path>,
+    mut apply: impl FnMut(i64, RecBody),
+) -> std::io::Result<ReplayReport> {
+    let dir = dir.as_ref().to_path_buf();
+    let manifest = read_manifest(&dir)?;
+    let mut report = ReplayReport::default();
+    let issues = &mut report.issues;
+    let mut applied = 0u64;
+    let mut apply = |ts, rec| {
+        applied += 1;
+        apply(ts, rec)
+    };
     for meta in manifest.segments.iter() {
-        let name = &meta.name;
-        let p = dir.join("wal").join(name);
-        if let Ok(mut f) = File::open(&p) {
-            loop {
-                let mut hdr = [0u8; 4 + 1 + 1 + 8 + 8 + 8 + 4];
-                if let Err(_) = f.read_exact(&mut hdr) {
-                    break;
-                }
-                if &hdr[0..4] != MAGIC.as_ref() {
-                    break;
-                }
-                let _ver = hdr[4];
-                let _typ = hdr[5];
-                let _ns_hash = u64::from_be_bytes(hdr[6..14].try_into().unwrap());
-                let _seq = u64::from_be_bytes(hdr[14..22].try_into().unwrap());
-                let _ts = u64::from_be_bytes(hdr[22..30].try_into().unwrap());
-                let len = u32::from_be_bytes(hdr[30..34].try_into().unwrap()) as usize;
-                let mut body = vec![0u8; len];
-                if let Err(_) = f.read_exact(&mut body) {
-                    break;
+        let p = meta.path(&dir);
+        let f = match File::open(&p) {
+            Ok(f) => f,
+            Err(e) => {
+                issues.push(format!("{}: unreadable: {}", meta.name, e));
+                continue;
+            }
+        };
+        let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+        let mut f = std::io::BufReader::new(f);
+        let (mut offset, mut undecodable) = (0u64, 0usize);
+        // Transactions are written in one piece, so one never spans segments
+        let mut txn: Option<(String, Vec<(i64, RecBody)>)> = None;
+        while let Some(raw) = read_record(&mut f) {
+            offset += raw.len;
+            match decode_body(raw.ver, &raw.body) {
+                Ok(RecBody::TxnBegin { id }) => txn = Some((id, Vec::new())),
+                Ok(RecBody::TxnCommit { id }) => {
+                    if let Some((_, recs)) = txn.take_if(|(open, _)| *open == id) {
+                        recs.into_iter().for_each(|(ts, rec)| apply(ts, rec));
+                    }
                 }
-                let mut crcbuf = [0u8; 4];
-                if let Err(_) = f.read_exact(&mut crcbuf) {
-                    break;
+                Ok(v) => match txn.as_mut() {
+                    Some((_, recs)) => recs.push((raw.ts, v)),
+                    None => apply(raw.ts, v),
+                },
+                Err(_) => undecodable += 1,
+            }
+        }
+        if let Some((id, recs)) = txn {
+            tracing::warn!(
+                "{}: dropped incomplete transaction {} ({} records)",
+                meta.name,
+                id,
+                recs.len()
+            );
+        }
+        if offset < size && !zero_tail(&p, offset) {
+            report.truncated_at_offset.insert(meta.name.clone(), offset);
+            if is_corruption(&p, offset, meta.name == manifest.current_segment) {
+                report.crc_failures += 1;
+                issues.push(format!(
+                    "{}: bad record at byte {} of {}, remaining records skipped",
+                    meta.name, offset, size
+                ));
+            }
+        }
+        if undecodable > 0 {
+            issues.push(format!(
+                "{}: {} undecodable records skipped",
+                meta.name, undecodable
+            ));
+        }
+    }
+    report.records_applied = applied;
+    Ok(report)
+}
+
+// Whether reading a segment stopping short at `offset` lost data: anywhere in a finished
+// segment, or in the current one when intact records follow (else it's a torn last write)
+fn is_corruption(path: &Path, offset: u64, current: bool) -> bool {
+    !current || valid_record_after(path, offset)
+}
+
+fn valid_record_after(path: &Path, offset: u64) -> bool {
+    let Ok(buf) = std::fs::read(path) else {
+        return false;
+    };
+    let mut pos = offset as usize + 1;
+    while let Some(skip) = buf.get(pos..).and_then(|b| b.windows(4).position(|w| w == MAGIC)) {
+        pos += skip;
+        if read_record(&mut &buf[pos..]).is_some() {
+            return true;
+        }
+        pos += 1;
+    }
+    false
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentCheck {
+    pub name: String,
+    pub exists: bool,
+    pub records: u64,
+    pub max_seq: u64,
+    // What the manifest says the segment's highest seq is
+    pub manifest_max_seq: u64,
+    // Where reading stopped, when short of the end (a torn tail or a bad record)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped_at: Option<u64>,
+    pub crc_ok: bool,
+    pub undecodable: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    // From the manifest, against the highest seq found in any segment
+    pub last_seq: u64,
+    pub max_seq_seen: u64,
+    pub last_seq_ok: bool,
+    pub segments: Vec<SegmentCheck>,
+    pub missing_segments: u64,
+    // Segments that exist but hold a bad record or can't be read
+    pub crc_failures: u64,
+    pub undecodable: u64,
+    // No missing segment, CRC failure, undecodable record or last_seq mismatch
+    pub ok: bool,
+}
+
+/// Checks a data directory without changing it: every segment in the manifest must
+/// exist and read cleanly to its end, as replay would read it. A torn last record in the
+/// current segment is left by a crash mid-write and isn't a failure.
+pub fn verify(dir: impl AsRef<Path>) -> std::io::Result<VerifyReport> {
+    let dir = dir.as_ref();
+    if !dir.join("manifest.json").exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no manifest.json in {}", dir.display()),
+        ));
+    }
+    let manifest = read_manifest(dir)?;
+    let mut segments = Vec::new();
+    for meta in manifest.segments.iter() {
+        let p = meta.path(dir);
+        let mut check = SegmentCheck {
+            name: meta.name.clone(),
+            exists: true,
+            records: 0,
+            max_seq: 0,
+            manifest_max_seq: meta.max_seq,
+            stopped_at: None,
+            crc_ok: true,
+            undecodable: 0,
+            error: None,
+        };
+        let f = match File::open(&p) {
+            Ok(f) => f,
+            Err(e) => {
+                check.exists = e.kind() != std::io::ErrorKind::NotFound;
+                check.crc_ok = false;
+                check.error = Some(format!("unreadable: {}", e));
+                segments.push(check);
+                continue;
+            }
+        };
+        let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+        let mut f = std::io::BufReader::new(f);
+        let mut offset = 0u64;
+        while let Some(raw) = read_record(&mut f) {
+            offset += raw.len;
+            check.records += 1;
+            check.max_seq = check.max_seq.max(raw.seq);
+            if decode_body(raw.ver, &raw.body).is_err() {
+                check.undecodable += 1;
+            }
+        }
+        if offset < size && !zero_tail(&p, offset) {
+            check.stopped_at = Some(offset);
+            if is_corruption(&p, offset, meta.name == manifest.current_segment) {
+                check.crc_ok = false;
+                check.error = Some(format!("bad record at byte {} of {}", offset, size));
+            }
+        }
+        segments.push(check);
+    }
+    // A segment opened by a rotation starts out empty, carrying the seq it began at
+    let max_seq_seen = segments
+        .iter()
+        .map(|s| match s.exists && s.records == 0 {
+            true => s.manifest_max_seq,
+            false => s.max_seq,
+        })
+        .max()
+        .unwrap_or(0);
+    let last_seq_ok = max_seq_seen == manifest.last_seq;
+    let missing_segments = segments.iter().filter(|s| !s.exists).count() as u64;
+    let crc_failures = segments.iter().filter(|s| s.exists && !s.crc_ok).count() as u64;
+    let undecodable = segments.iter().map(|s| s.undecodable).sum();
+    Ok(VerifyReport {
+        last_seq: manifest.last_seq,
+        max_seq_seen,
+        last_seq_ok,
+        segments,
+        missing_segments,
+        crc_failures,
+        undecodable,
+        ok: missing_segments == 0 && crc_failures == 0 && undecodable == 0 && last_seq_ok,
+    })
+}
+
+/// Every record in manifest order as JSON (`segment`, `seq`, `type`, `ts`, `body`), for
+/// diagnosing replay differences. Unlike replay this is raw: transaction markers and
+/// incomplete transactions are included, and undecodable records or unreadable segments
+/// show up in place with an `error` instead of `body`.
+pub fn dump_wal(dir: impl AsRef<Path>) -> std::io::Result<Vec<serde_json::Value>> {
+    Ok(dump_since(dir, &WalCursor::default())?.0)
+}
+
+/// [`dump_wal`] from `from` on, with the cursor just past the last complete record read,
+/// so a caller polling with it sees each record once, as `tail -f` would. A segment that
+/// can't be opened is reported once, when the cursor first moves onto it.
+pub fn dump_since(
+    dir: impl AsRef<Path>,
+    from: &WalCursor,
+) -> std::io::Result<(Vec<serde_json::Value>, WalCursor)> {
+    use std::io::{Seek, SeekFrom};
+    let dir = dir.as_ref();
+    let manifest = read_manifest(dir)?;
+    let mut out = Vec::new();
+    let mut cursor = from.clone();
+    for meta in manifest.segments.iter() {
+        if meta.name < from.segment {
+            continue;
+        }
+        let offset = if meta.name == from.segment {
+            from.offset
+        } else {
+            0
+        };
+        let mut f = match File::open(meta.path(dir)) {
+            Ok(f) => f,
+            Err(e) => {
+                if meta.name != from.segment {
+                    out.push(serde_json::json!({
+                        "segment": meta.name,
+                        "error": format!("unreadable: {}", e),
+                    }));
                 }
-                let mut rec = hdr.to_vec();
-                rec.extend_from_slice(&body);
-                let crc = crc32c(&rec);
-                let got = u32::from_be_bytes(crcbuf);
-                if crc != got {
-                    break;
+                cursor = WalCursor {
+                    segment: meta.name.clone(),
+                    offset: 0,
+                };
+                continue;
+            }
+        };
+        f.seek(SeekFrom::Start(offset))?;
+        let mut f = std::io::BufReader::new(f);
+        cursor = WalCursor {
+            segment: meta.name.clone(),
+            offset,
+        };
+        while let Some(raw) = read_record(&mut f) {
+            cursor.offset += raw.len;
+            let mut v = serde_json::json!({
+                "segment": meta.name,
+                "seq": raw.seq,
+                "type": rectype_name(raw.rtype),
+                "ts": raw.ts,
+            });
+            match decode_body(raw.ver, &raw.body) {
+                Ok(body) => v["body"] = serde_json::to_value(&body).unwrap_or_default(),
+                Err(e) => v["error"] = format!("undecodable body: {}", e).into(),
+            }
+            out.push(v);
+        }
+    }
+    Ok((out, cursor))
+}
+
+/// Position in the WAL: a segment name and the byte offset of the next record in it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WalCursor {
+    pub segment: String,
+    pub offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub seq: u64,
+    pub ts: i64,
+    pub body: RecBody,
+    // cursor positioned just after this record
+    pub next: WalCursor,
+}
+
+/// Reads up to `max` complete records after `from`, following segments in manifest order.
+/// An empty cursor starts at the oldest segment; a cursor into a trimmed segment resumes at
+/// the next surviving one. A partially written tail record is left for the next call.
+pub fn read_since(
+    dir: impl AsRef<Path>,
+    from: &WalCursor,
+    max: usize,
+) -> std::io::Result<Vec<WalEntry>> {
+    use std::io::{Seek, SeekFrom};
+    let dir = dir.as_ref().to_path_buf();
+    let manifest = read_manifest(&dir)?;
+    let mut out = Vec::new();
+    for meta in manifest.segments.iter() {
+        if meta.name < from.segment {
+            continue;
+        }
+        let mut offset = if meta.name == from.segment {
+            from.offset
+        } else {
+            0
+        };
+        let Ok(mut f) = File::open(meta.path(&dir)) else {
+            continue;
+        };
+        f.seek(SeekFrom::Start(offset))?;
+        let mut f = std::io::BufReader::new(f);
+        // A batch never ends inside a transaction: it runs past `max` to the
+        // commit, or stops before the begin if the commit isn't written yet
+        let mut open_txn: Option<usize> = None;
+        while out.len() < max || open_txn.is_some() {
+            let Some(raw) = read_record(&mut f) else {
+                if let Some(begin) = open_txn.take() {
+                    out.truncate(begin);
+                    // Still being written; in an older segment it was cut off by a crash
+                    if meta.name == manifest.current_segment {
+                        return Ok(out);
+                    }
                 }
-                if let Ok(v) = ciborium::de::from_reader::<RecBody, _>(&body[..]) {
-                    out.push(v);
+                break;
+            };
+            offset += raw.len;
+            if let Ok(body) = decode_body(raw.ver, &raw.body) {
+                match &body {
+                    RecBody::TxnBegin { .. } => open_txn = Some(out.len()),
+                    RecBody::TxnCommit { .. } => open_txn = None,
+                    _ => {}
                 }
+                out.push(WalEntry {
+                    seq: raw.seq,
+                    ts: raw.ts,
+                    body,
+                    next: WalCursor {
+                        segment: meta.name.clone(),
+                        offset,
+                    },
+                });
             }
         }
+        if out.len() >= max {
+            break;
+        }
     }
     Ok(out)
 }
+
+// Characters of a record body kept in a segment inspection preview
+const PREVIEW_CHARS: usize = 256;
+
+#[derive(Debug, Serialize)]
+pub struct SegmentRecord {
+    pub offset: u64,
+    pub rec_type: String,
+    pub seq: u64,
+    pub ts: i64,
+    pub len: u64,
+    // JSON of the decoded body, cut to PREVIEW_CHARS
+    pub preview: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentIssue {
+    pub offset: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentReport {
+    pub name: String,
+    pub bytes: u64,
+    pub records: Vec<SegmentRecord>,
+    // CRC, framing and decode failures, with the offset they were found at
+    pub errors: Vec<SegmentIssue>,
+    // more than `limit` good records; the rest are only checked for errors
+    pub truncated: bool,
+}
+
+/// Decodes one segment for debugging. Unlike replay, a bad record doesn't end the
+/// scan: CRC failures skip by the framed length, bad magic resyncs on the next one.
+pub fn inspect_segment(
+    dir: impl AsRef<Path>,
+    name: &str,
+    limit: usize,
+) -> std::io::Result<SegmentReport> {
+    let dir = dir.as_ref();
+    let path = read_manifest(dir)
+        .ok()
+        .and_then(|m| m.segments.into_iter().find(|s| s.name == name))
+        .map_or_else(|| dir.join("wal").join(name), |s| s.path(dir));
+    let buf = std::fs::read(path)?;
+    let mut report = SegmentReport {
+        name: name.to_string(),
+        bytes: buf.len() as u64,
+        records: Vec::new(),
+        errors: Vec::new(),
+        truncated: false,
+    };
+    let mut pos = 0usize;
+    while pos < buf.len() {
+        let issue = |error: &str| SegmentIssue {
+            offset: pos as u64,
+            error: error.to_string(),
+        };
+        // Preallocated space not yet written to
+        if buf[pos..].iter().all(|b| *b == 0) {
+            break;
+        }
+        if buf.len() - pos < HDR_LEN {
+            report.errors.push(issue("partial header at end of segment"));
+            break;
+        }
+        let hdr = &buf[pos..pos + HDR_LEN];
+        if hdr[0..4] != MAGIC {
+            report.errors.push(issue("bad magic"));
+            match buf[pos + 1..].windows(4).position(|w| w == MAGIC) {
+                Some(skip) => pos += 1 + skip,
+                None => break,
+            }
+            continue;
+        }
+        let len = u32::from_be_bytes(hdr[30..34].try_into().unwrap()) as usize;
+        let end = pos + HDR_LEN + len + 4;
+        if end > buf.len() {
+            report.errors.push(issue("record runs past end of segment"));
+            break;
+        }
+        let crc = u32::from_be_bytes(buf[end - 4..end].try_into().unwrap());
+        if crc32c(&buf[pos..end - 4]) != crc {
+            report.errors.push(issue("crc mismatch"));
+            pos = end;
+            continue;
+        }
+        let body = &buf[pos + HDR_LEN..end - 4];
+        match decode_body(buf[pos + 4], body) {
+            Ok(rec) if report.records.len() < limit => {
+                let json = serde_json::to_string(&rec).unwrap_or_default();
+                report.records.push(SegmentRecord {
+                    offset: pos as u64,
+                    rec_type: rectype_name(hdr[5]).to_string(),
+                    seq: u64::from_be_bytes(hdr[14..22].try_into().unwrap()),
+                    ts: u64::from_be_bytes(hdr[22..30].try_into().unwrap()) as i64,
+                    len: (end - pos) as u64,
+                    preview: json.chars().take(PREVIEW_CHARS).collect(),
+                });
+            }
+            Ok(_) => report.truncated = true,
+            Err(e) => report.errors.push(issue(&format!("undecodable body: {}", e))),
+        }
+        pos = end;
+    }
+    Ok(report)
+}
+
+fn rectype_name(t: u8) -> &'static str {
+    match t {
+        1 => "put",
+        2 => "delete",
+        3 => "lease_acquire",
+        4 => "lease_renew",
+        5 => "lease_release",
+        6 => "idempotency",
+        7 => "trim_versions",
+        8 => "txn_begin",
+        9 => "txn_commit",
+        _ => "unknown",
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl WalWriter {
+    // Swaps the segment for a read-only handle, so the next batch fails to write
+    pub(crate) fn fail_next_write(&self) {
+        let mut inner = self.inner.write();
+        inner.segment.file = File::open(&inner.segment.path).unwrap();
+    }
+
+    // Undoes `fail_next_write`, as if the disk came back
+    pub(crate) fn heal_writes(&self) {
+        let mut inner = self.inner.write();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&inner.segment.path)
+            .unwrap();
+        file.seek(SeekFrom::Start(inner.segment.bytes)).unwrap();
+        inner.segment.file = file;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trim(id: &str) -> RecBody {
+        RecBody::TrimVersions {
+            ns: "ns".into(),
+            id: id.into(),
+            keep: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_cut_off_before_the_next_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WalWriter::open(dir.path(), 1 << 20, 0).unwrap();
+        wal.append(1, 0, &trim("a")).await.unwrap();
+        // A write that failed partway: half a record left at the end of the segment
+        let handle = WalHandle {
+            dir: dir.path().to_path_buf(),
+            seg_size: 1 << 20,
+            inner: wal.inner.clone(),
+        };
+        {
+            let mut inner = wal.inner.write();
+            let start = inner.segment.bytes;
+            let torn = WalWriter::frame(2, 0, &trim("b")).unwrap();
+            inner
+                .segment
+                .file
+                .write_all(&torn[..torn.len() / 2])
+                .unwrap();
+            handle.rewind_locked(&mut inner, start).unwrap();
+        }
+        wal.append(3, 0, &trim("c")).await.unwrap();
+        let recs = replay(dir.path()).unwrap();
+        assert_eq!(recs.len(), 2);
+        assert!(matches!(&recs[1], RecBody::TrimVersions { id, .. } if id == "c"));
+        assert_eq!(wal.manifest().last_seq, 3);
+    }
+
+    #[tokio::test]
+    async fn a_write_that_fails_is_reported_to_the_appender() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WalWriter::open(dir.path(), 1 << 20, 0).unwrap();
+        wal.append(1, 0, &trim("a")).await.unwrap();
+        let errors = WAL_WRITE_ERRORS_TOTAL.get();
+        wal.fail_next_write();
+        assert!(wal.append(2, 0, &trim("b")).await.is_err());
+        assert!(wal.last_error().is_some());
+        assert!(WAL_WRITE_ERRORS_TOTAL.get() > errors);
+        assert_eq!(wal.manifest().last_seq, 1);
+        wal.heal_writes();
+        wal.append(3, 0, &trim("c")).await.unwrap();
+        assert!(wal.last_error().is_none());
+        let ids: Vec<String> = replay(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|rec| match rec {
+                RecBody::TrimVersions { id, .. } => id,
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(ids, ["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn batch_seqs_count_by_their_max() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WalWriter::open(dir.path(), 1 << 20, 0).unwrap();
+        // Enqueued together, so one batch whose last record has the lower seq
+        let (ra, rb) = (trim("a"), trim("b"));
+        let (a, b) = tokio::join!(wal.append(5, 0, &ra), wal.append(3, 0, &rb));
+        a.unwrap();
+        b.unwrap();
+        let m = wal.manifest();
+        assert_eq!(m.last_seq, 5);
+        assert_eq!(m.segments.last().unwrap().max_seq, 5);
+        assert!(verify(dir.path()).unwrap().ok);
+    }
+
+    #[tokio::test]
+    async fn verify_fails_when_last_seq_disagrees_with_the_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WalWriter::open(dir.path(), 1 << 20, 0).unwrap();
+        wal.append(1, 0, &trim("a")).await.unwrap();
+        wal.append(2, 0, &trim("b")).await.unwrap();
+        drop(wal);
+        let path = dir.path().join("manifest.json");
+        let mut m: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        m["last_seq"] = serde_json::json!(1);
+        std::fs::write(&path, serde_json::to_vec(&m).unwrap()).unwrap();
+        let report = verify(dir.path()).unwrap();
+        assert!(!report.last_seq_ok);
+        assert!(!report.ok);
+    }
+
+    #[tokio::test]
+    async fn streamed_replay_matches_the_batch_reader_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every batch fills its segment, so each lands in a segment of its own
+        let wal = WalWriter::open(dir.path(), 1, 0).unwrap();
+        wal.append(1, 0, &trim("a")).await.unwrap();
+        let txn = [
+            (0, RecBody::TxnBegin { id: "t".into() }),
+            (2, trim("b")),
+            (3, trim("c")),
+            (0, RecBody::TxnCommit { id: "t".into() }),
+        ];
+        wal.append_all(0, &txn).await.unwrap();
+        wal.append(4, 0, &trim("d")).await.unwrap();
+        assert!(wal.manifest().segments.len() >= 3);
+        drop(wal);
+
+        let mut streamed = Vec::new();
+        let report = replay_with(dir.path(), |rec| streamed.push(format!("{:?}", rec))).unwrap();
+        assert_eq!(report.records_applied, 4);
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        let ids = ["a", "b", "c", "d"].map(|id| format!("{:?}", trim(id)));
+        assert_eq!(streamed, ids);
+
+        let start = WalCursor {
+            segment: String::new(),
+            offset: 0,
+        };
+        let batch: Vec<String> = read_since(dir.path(), &start, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .filter(|e| !matches!(e.body, RecBody::TxnBegin { .. } | RecBody::TxnCommit { .. }))
+            .map(|e| format!("{:?}", e.body))
+            .collect();
+        assert_eq!(batch, streamed);
+    }
+
+    #[tokio::test]
+    async fn replay_drops_a_transaction_without_its_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WalWriter::open(dir.path(), 1 << 20, 0).unwrap();
+        wal.append(1, 0, &trim("a")).await.unwrap();
+        // A crash between the begin marker and the commit
+        let cut = [
+            (0, RecBody::TxnBegin { id: "t".into() }),
+            (2, trim("b")),
+            (3, trim("c")),
+        ];
+        wal.append_all(0, &cut).await.unwrap();
+        drop(wal);
+
+        let mut applied = Vec::new();
+        let report = replay_with(dir.path(), |rec| applied.push(format!("{:?}", rec))).unwrap();
+        assert_eq!(applied, [format!("{:?}", trim("a"))]);
+        assert_eq!(report.records_applied, 1);
+        let start = WalCursor {
+            segment: String::new(),
+            offset: 0,
+        };
+        // The follower's reader holds it back too
+        assert_eq!(read_since(dir.path(), &start, usize::MAX).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn damaged_lengths_are_not_records() {
+        let rec = WalWriter::frame(1, 0, &trim("a")).unwrap();
+        assert!(read_record(&mut rec.as_slice()).is_some());
+        // Claims more than the cap, and more than the bytes that follow
+        for len in [u32::MAX, (rec.len() - HDR_LEN) as u32 + 1] {
+            let mut bad = rec.clone();
+            bad[30..34].copy_from_slice(&len.to_be_bytes());
+            assert!(read_record(&mut bad.as_slice()).is_none());
+        }
+        let huge = RecBody::TrimVersions {
+            ns: "ns".into(),
+            id: "x".repeat(MAX_RECORD_BYTES),
+            keep: 1,
+        };
+        let err = WalWriter::frame(1, 0, &huge).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn inspecting_a_segment_reports_records_and_bad_ones_by_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WalWriter::open(dir.path(), 1 << 20, 0).unwrap();
+        for (seq, id) in [(1, "a"), (2, "b"), (3, "c")] {
+            wal.append(seq, 10 * seq as i64, &trim(id)).await.unwrap();
+        }
+        let name = wal.manifest().current_segment.clone();
+        let path = wal.manifest().segments.last().unwrap().path(dir.path());
+        drop(wal);
+
+        let report = inspect_segment(dir.path(), &name, 10).unwrap();
+        assert!(report.errors.is_empty());
+        let seen: Vec<_> = report
+            .records
+            .iter()
+            .map(|r| (r.seq, r.ts, r.rec_type.as_str()))
+            .collect();
+        let trims = [1, 2, 3].map(|seq| (seq, 10 * seq as i64, "trim_versions"));
+        assert_eq!(seen, trims);
+        assert!(report.records[1].preview.contains("\"b\""));
+
+        // A flipped byte in the middle record: its offset is reported and the scan goes on
+        let bad = report.records[1].offset;
+        let mut buf = std::fs::read(&path).unwrap();
+        buf[bad as usize + HDR_LEN] ^= 0xff;
+        std::fs::write(&path, buf).unwrap();
+        let report = inspect_segment(dir.path(), &name, 10).unwrap();
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|e| (e.offset, e.error.as_str()))
+            .collect();
+        assert_eq!(errors, [(bad, "crc mismatch")]);
+        let seqs: Vec<u64> = report.records.iter().map(|r| r.seq).collect();
+        assert_eq!(seqs, [1, 3]);
+
+        let limited = inspect_segment(dir.path(), &name, 1).unwrap();
+        assert_eq!((limited.records.len(), limited.truncated), (1, true));
+        let err = inspect_segment(dir.path(), "missing", 10).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn a_frozen_wal_grows_its_segment_instead_of_rotating() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every record is past a 1-byte threshold, so each append would normally rotate
+        let wal = WalWriter::open(dir.path(), 1, 0).unwrap();
+        wal.append(1, 0, &trim("a")).await.unwrap();
+        let frozen = wal.set_frozen(true);
+        for (seq, id) in (2..).zip(["b", "c", "d", "e"]) {
+            wal.append(seq, 0, &trim(id)).await.unwrap();
+        }
+        let during = wal.manifest();
+        assert_eq!(during.current_segment, frozen.current_segment);
+        assert_eq!(during.segments.len(), frozen.segments.len());
+        assert!(wal.is_frozen());
+
+        wal.set_frozen(false);
+        wal.append(6, 0, &trim("f")).await.unwrap();
+        let after = wal.manifest();
+        assert_ne!(after.current_segment, frozen.current_segment);
+        assert!(after.segments.len() > frozen.segments.len());
+        assert_eq!(replay(dir.path()).unwrap().len(), 6);
+    }
+}
@@ -0,0 +1,56 @@
+// Filtered vector queries served by the HNSW index. The size a field needs before the
+// index serves it is read from the environment once per process, so these tests get a
+// binary of their own.
+use agentstate_core::{PutRequest, QueryRequest, VecField};
+use agentstate_storage::metrics::{unprefixed, REGISTRY};
+use agentstate_storage::{InMemoryStore, ObjectStore};
+use serde_json::json;
+
+fn hnsw_queries() -> u64 {
+    REGISTRY
+        .gather()
+        .iter()
+        .filter(|mf| unprefixed(mf.get_name()) == "vector_query_algo_total")
+        .flat_map(|mf| mf.get_metric())
+        .filter(|m| m.get_label().iter().any(|l| l.get_value() == "hnsw"))
+        .map(|m| m.get_counter().get_value() as u64)
+        .sum()
+}
+
+#[tokio::test]
+async fn a_filtered_ann_query_still_returns_k_from_the_filtered_set() {
+    std::env::set_var("HNSW_MIN_VECTORS", "100");
+    let store = InMemoryStore::new();
+    let field = VecField {
+        name: "emb".into(),
+        dims: 2,
+        mode: Default::default(),
+    };
+    store.register_vec_field("ns", field).unwrap();
+    // Around the unit circle, one in four in project x
+    let n = 1200;
+    for i in 0..n {
+        let a = i as f64 / n as f64 * std::f64::consts::TAU;
+        let project = if i % 4 == 0 { "x" } else { "y" };
+        let req = PutRequest {
+            r#type: "doc".into(),
+            body: json!({"emb": [a.cos(), a.sin()]}),
+            id: Some(format!("p{i}")),
+            tags: serde_json::from_value(json!({"project": project})).unwrap(),
+            ..Default::default()
+        };
+        store.put("ns", req).await.unwrap();
+    }
+    let before = hnsw_queries();
+    let q: QueryRequest = serde_json::from_value(json!({
+        "tag_filter": {"project": "x"},
+        "vector": {"field": "emb", "top_k": 5, "embedding": [1.0, 0.0]}
+    }))
+    .unwrap();
+    let got = store.query("ns", q).await.unwrap();
+    assert!(hnsw_queries() > before, "the index didn't serve the query");
+    let mut ids: Vec<_> = got.iter().map(|o| o.id.as_str()).collect();
+    ids.sort();
+    // The five x points nearest angle 0
+    assert_eq!(ids, ["p0", "p1192", "p1196", "p4", "p8"]);
+}
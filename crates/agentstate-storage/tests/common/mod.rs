@@ -0,0 +1,25 @@
+// Request builders shared by the integration test binaries. Each binary uses only some of
+// them, so the rest would otherwise warn as dead code there.
+#![allow(dead_code)]
+use agentstate_core::{PutRequest, QueryRequest};
+
+pub fn doc(id: &str, body: serde_json::Value) -> PutRequest {
+    PutRequest {
+        r#type: "doc".into(),
+        body,
+        id: Some(id.into()),
+        ..Default::default()
+    }
+}
+
+// A doc whose id the store's ID_STRATEGY assigns
+pub fn unnamed(body: serde_json::Value) -> PutRequest {
+    PutRequest {
+        id: None,
+        ..doc("", body)
+    }
+}
+
+pub fn query(q: serde_json::Value) -> QueryRequest {
+    serde_json::from_value(q).unwrap()
+}
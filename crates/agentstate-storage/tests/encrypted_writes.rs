@@ -0,0 +1,71 @@
+// Writes into an encrypted namespace. Encryption and the id strategy are read from the
+// environment once per process, so these tests get a binary of their own.
+mod common;
+
+use agentstate_core::TxnOp;
+use agentstate_storage::{InMemoryStore, ObjectStore};
+use common::{doc, unnamed};
+use serde_json::json;
+use std::sync::Once;
+
+fn store() -> InMemoryStore {
+    static ENV: Once = Once::new();
+    ENV.call_once(|| {
+        // base64 of 32 bytes of 7
+        std::env::set_var(
+            "FIELD_ENCRYPTION_KEY",
+            "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=",
+        );
+        std::env::set_var("ENCRYPTED_FIELDS", r#"{"sealed":["ssn"]}"#);
+        std::env::set_var("ID_STRATEGY", "content_hash");
+    });
+    InMemoryStore::new()
+}
+
+#[tokio::test]
+async fn only_if_changed_compares_plaintext() {
+    let store = store();
+    let body = json!({"name": "ann", "ssn": "123-45-6789"});
+    let first = store.put("sealed", doc("p", body.clone())).await.unwrap();
+    assert!(first.body["ssn"].as_str().unwrap().starts_with("enc:v1:"));
+
+    let (same, changed) = store
+        .put_if_changed("sealed", doc("p", body))
+        .await
+        .unwrap();
+    assert!(!changed);
+    assert_eq!(same.commit_seq, first.commit_seq);
+
+    let edited = json!({"name": "ann", "ssn": "987-65-4321"});
+    let (_, changed) = store
+        .put_if_changed("sealed", doc("p", edited))
+        .await
+        .unwrap();
+    assert!(changed);
+    assert_eq!(store.versions("sealed", "p").await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn content_hash_ids_hash_the_plaintext() {
+    let store = store();
+    let body = json!({"name": "bob", "ssn": "111-22-3333"});
+    let a = store.put("sealed", unnamed(body.clone())).await.unwrap();
+    let b = store.put("sealed", unnamed(body.clone())).await.unwrap();
+    assert_eq!(a.id, b.id);
+    assert_eq!(store.versions("sealed", &a.id).await.unwrap().len(), 2);
+    // The same id as the body gets where nothing is sealed
+    let plain = store.put("plain", unnamed(body.clone())).await.unwrap();
+    assert_eq!(plain.id, a.id);
+
+    let events = store
+        .txn("sealed", vec![TxnOp::Put(unnamed(body))])
+        .await
+        .unwrap();
+    assert_eq!(events[0].commit_seq(), b.commit_seq + 1);
+    let other = store
+        .put("sealed", unnamed(json!({"name": "bob", "ssn": "000-00-0000"})))
+        .await
+        .unwrap();
+    assert_ne!(other.id, a.id);
+    assert_eq!(store.versions("sealed", &a.id).await.unwrap().len(), 3);
+}
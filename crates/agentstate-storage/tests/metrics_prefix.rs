@@ -0,0 +1,37 @@
+// Metric names under METRICS_PREFIX. The prefix is read from the environment once per
+// process, so these tests get a binary of their own.
+use agentstate_core::{PutRequest, QueryRequest};
+use agentstate_storage::metrics::{unprefixed, REGISTRY};
+use agentstate_storage::{ObjectStore, PersistentStore};
+use serde_json::json;
+
+#[tokio::test]
+async fn every_family_carries_the_prefix() {
+    std::env::set_var("METRICS_PREFIX", "agentstate_");
+    // Touch the store and the WAL so their metrics register
+    let dir = tempfile::tempdir().unwrap();
+    let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+    let req = PutRequest {
+        r#type: "doc".into(),
+        body: json!({"v": [1.0, 0.0]}),
+        id: Some("a".into()),
+        ..Default::default()
+    };
+    store.put("ns", req).await.unwrap();
+    let q: QueryRequest = serde_json::from_value(json!({
+        "vector": {"field": "v", "top_k": 1, "embedding": [1.0, 0.0]}
+    }))
+    .unwrap();
+    store.query("ns", q).await.unwrap();
+    store.snapshot().unwrap();
+
+    let families = REGISTRY.gather();
+    assert!(families.len() >= 3, "{}", families.len());
+    for mf in &families {
+        let name = mf.get_name();
+        assert!(name.starts_with("agentstate_"), "{name}");
+        assert_eq!(format!("agentstate_{}", unprefixed(name)), name);
+    }
+    let names: Vec<_> = families.iter().map(|mf| unprefixed(mf.get_name())).collect();
+    assert!(names.contains(&"wal_records_total"), "{names:?}");
+}
@@ -0,0 +1,42 @@
+// Queries refused under QUERY_MAX_CANDIDATES. The cap is read from the environment once
+// per process, so these tests get a binary of their own.
+mod common;
+
+use agentstate_core::{PutRequest, StateError};
+use agentstate_storage::{InMemoryStore, ObjectStore};
+use common::query;
+use serde_json::json;
+
+#[tokio::test]
+async fn queries_matching_more_than_the_cap_are_refused() {
+    std::env::set_var("QUERY_MAX_CANDIDATES", "3");
+    let store = InMemoryStore::new();
+    let kinds = [("a", "rare"), ("b", "rare")]
+        .into_iter()
+        .chain(["c", "d", "e", "f"].map(|id| (id, "common")));
+    for (id, kind) in kinds {
+        let req = PutRequest {
+            r#type: "doc".into(),
+            body: json!({}),
+            id: Some(id.into()),
+            tags: serde_json::from_value(json!({"kind": kind})).unwrap(),
+            ..Default::default()
+        };
+        store.put("ns", req).await.unwrap();
+    }
+    for broad in [json!({}), json!({"tag_filter": {"kind": "common"}})] {
+        let err = store.query("ns", query(broad.clone())).await.unwrap_err();
+        assert!(
+            matches!(&err, StateError::Invalid(m) if m.starts_with("query_too_broad")),
+            "{broad}: {err:?}"
+        );
+    }
+    // The cap counts candidates, not results: a limit doesn't get a broad query through
+    let err = store.query("ns", query(json!({"limit": 1}))).await.unwrap_err();
+    assert!(matches!(err, StateError::Invalid(_)));
+    let narrow = store
+        .query("ns", query(json!({"tag_filter": {"kind": "rare"}})))
+        .await
+        .unwrap();
+    assert_eq!(narrow.len(), 2);
+}
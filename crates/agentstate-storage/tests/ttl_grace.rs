@@ -0,0 +1,43 @@
+// Expired objects inside TTL_GRACE_SECS. The grace is read from the environment once per
+// process, so these tests get a binary of their own.
+use agentstate_core::{PutRequest, StateError};
+use agentstate_storage::traits::GetOptions;
+use agentstate_storage::{InMemoryStore, ObjectStore};
+use chrono::{Duration, Utc};
+use serde_json::json;
+
+// Written `age_secs` ago with a one second TTL
+fn aged(id: &str, age_secs: i64) -> PutRequest {
+    PutRequest {
+        r#type: "doc".into(),
+        body: json!({}),
+        id: Some(id.into()),
+        ttl_seconds: Some(1),
+        ts: Some(Utc::now() - Duration::seconds(age_secs)),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn expired_objects_are_served_and_kept_until_the_grace_runs_out() {
+    std::env::set_var("TTL_GRACE_SECS", "600");
+    let store = InMemoryStore::new();
+    store.put("ns", aged("in_grace", 60)).await.unwrap();
+    store.put("ns", aged("past_grace", 3600)).await.unwrap();
+
+    let got = store
+        .get("ns", "in_grace", GetOptions { at_ts: None })
+        .await
+        .unwrap();
+    assert!(got.expires_at().unwrap() < Utc::now());
+    let get = store.get("ns", "past_grace", GetOptions { at_ts: None }).await;
+    assert!(matches!(get, Err(StateError::NotFound)), "{get:?}");
+
+    let swept = store.sweep_expired(0).await.unwrap();
+    let swept: Vec<_> = swept.iter().map(|o| o.id.as_str()).collect();
+    assert_eq!(swept, ["past_grace"]);
+    assert!(store
+        .get("ns", "in_grace", GetOptions { at_ts: None })
+        .await
+        .is_ok());
+}
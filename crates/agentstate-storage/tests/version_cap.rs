@@ -0,0 +1,54 @@
+// MAX_VERSIONS_PER_OBJECT is read from the environment once per process, so these tests
+// get a binary of their own.
+mod common;
+
+use agentstate_storage::{InMemoryStore, ObjectStore, PersistentStore};
+use common::doc;
+use serde_json::json;
+use std::sync::Once;
+
+fn cap_at_three() {
+    static ENV: Once = Once::new();
+    ENV.call_once(|| std::env::set_var("MAX_VERSIONS_PER_OBJECT", "3"));
+}
+
+async fn kept(store: &impl ObjectStore) -> Vec<serde_json::Value> {
+    let versions = store.versions("ns", "a").await.unwrap();
+    versions.into_iter().map(|o| o.body["v"].clone()).collect()
+}
+
+#[tokio::test]
+async fn puts_past_the_cap_drop_the_oldest() {
+    cap_at_three();
+    let store = InMemoryStore::new();
+    for v in 0..5 {
+        store.put("ns", doc("a", json!({ "v": v }))).await.unwrap();
+    }
+    assert_eq!(kept(&store).await, [2, 3, 4]);
+}
+
+#[tokio::test]
+async fn replay_applies_the_cap_too() {
+    cap_at_three();
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+        for v in 0..5 {
+            store.put("ns", doc("a", json!({ "v": v }))).await.unwrap();
+        }
+    }
+    let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+    assert_eq!(kept(&store).await, [2, 3, 4]);
+}
+
+#[cfg(feature = "rocksdb")]
+#[tokio::test]
+async fn the_rocksdb_engine_enforces_it() {
+    cap_at_three();
+    let dir = tempfile::tempdir().unwrap();
+    let store = agentstate_storage::RocksStore::open(dir.path()).unwrap();
+    for v in 0..5 {
+        store.put("ns", doc("a", json!({ "v": v }))).await.unwrap();
+    }
+    assert_eq!(kept(&store).await, [2, 3, 4]);
+}
@@ -0,0 +1,45 @@
+// Segments striped across WAL_DIRS. The directory list is read from the environment once
+// per process, so these tests get a binary of their own.
+use agentstate_storage::walbin::{replay, RecBody, WalWriter};
+
+fn trim(id: &str) -> RecBody {
+    RecBody::TrimVersions {
+        ns: "ns".into(),
+        id: id.into(),
+        keep: 1,
+    }
+}
+
+#[tokio::test]
+async fn segments_alternate_between_dirs_and_replay_in_order() {
+    let (disk1, disk2) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let dirs = format!("{},{}", disk1.path().display(), disk2.path().display());
+    std::env::set_var("WAL_DIRS", dirs);
+    let data = tempfile::tempdir().unwrap();
+    // Every record fills its segment, so each lands in a segment of its own
+    let wal = WalWriter::open(data.path(), 1, 0).unwrap();
+    let ids = ["a", "b", "c", "d", "e"];
+    for (seq, id) in ids.iter().enumerate() {
+        wal.append(seq as u64 + 1, 0, &trim(id)).await.unwrap();
+    }
+    let manifest = wal.manifest();
+    drop(wal);
+
+    assert!(manifest.segments.len() >= 4);
+    let on = |disk: &tempfile::TempDir| {
+        let placed = manifest.segments.iter().filter(|s| s.dir.as_deref() == Some(disk.path()));
+        placed.count()
+    };
+    assert_eq!(on(&disk1) + on(&disk2), manifest.segments.len());
+    assert!(on(&disk1).abs_diff(on(&disk2)) <= 1);
+    for (prev, next) in manifest.segments.iter().zip(&manifest.segments[1..]) {
+        assert_ne!(prev.dir, next.dir);
+    }
+    for seg in &manifest.segments {
+        assert!(seg.path(data.path()).exists(), "{}", seg.name);
+    }
+
+    let replayed: Vec<_> = replay(data.path()).unwrap().iter().map(|r| format!("{r:?}")).collect();
+    let want: Vec<_> = ids.iter().map(|id| format!("{:?}", trim(id))).collect();
+    assert_eq!(replayed, want);
+}
@@ -0,0 +1,39 @@
+// WAL records written as MessagePack. The encoding is read from the environment once per
+// process, so these tests get a binary of their own.
+mod common;
+
+use agentstate_core::{PutRequest, StateError};
+use agentstate_storage::traits::GetOptions;
+use agentstate_storage::walbin::WalWriter;
+use agentstate_storage::{AdminOps, ObjectStore, PersistentStore};
+use common::doc;
+use serde_json::json;
+
+#[tokio::test]
+async fn msgpack_records_replay_to_the_same_objects() {
+    std::env::set_var("WAL_ENCODING", "msgpack");
+    let dir = tempfile::tempdir().unwrap();
+    let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+    let body = json!({"text": "héllo", "n": 3, "nested": {"list": [1.5, null, true]}});
+    let tags: agentstate_core::Tags = serde_json::from_value(json!({"k": "v"})).unwrap();
+    let a = PutRequest { tags, ..doc("a", body) };
+    let a = store.put("ns", a).await.unwrap();
+    store.put("ns", doc("b", json!({}))).await.unwrap();
+    store.delete("ns", "b").await.unwrap();
+    drop(store);
+
+    // Each record's header names MessagePack as its body encoding
+    let manifest = WalWriter::open(dir.path(), 1 << 20, 0).unwrap().manifest();
+    let seg = std::fs::read(manifest.segments[0].path(dir.path())).unwrap();
+    assert_eq!((&seg[..4], seg[4]), (&b"ASTW"[..], 2));
+
+    let reopened = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+    let got = reopened
+        .get("ns", "a", GetOptions { at_ts: None })
+        .await
+        .unwrap();
+    assert_eq!(serde_json::to_value(&got).unwrap(), serde_json::to_value(&a).unwrap());
+    let get = reopened.get("ns", "b", GetOptions { at_ts: None }).await;
+    assert!(matches!(get, Err(StateError::NotFound)), "{get:?}");
+    assert!(reopened.recovery_issues().is_empty());
+}
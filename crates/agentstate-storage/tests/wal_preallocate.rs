@@ -0,0 +1,43 @@
+// Segments preallocated with WAL_PREALLOCATE. The option is read from the environment
+// once per process, so these tests get a binary of their own.
+use agentstate_storage::walbin::{replay_with, RecBody, WalWriter};
+
+fn trim(id: &str) -> RecBody {
+    RecBody::TrimVersions {
+        ns: "ns".into(),
+        id: id.into(),
+        keep: 1,
+    }
+}
+
+#[tokio::test]
+async fn a_preallocated_segment_replays_past_its_zeroed_tail() {
+    std::env::set_var("WAL_PREALLOCATE", "1");
+    let dir = tempfile::tempdir().unwrap();
+    let seg_size = 1 << 20;
+    let wal = WalWriter::open(dir.path(), seg_size, 0).unwrap();
+    wal.append(1, 0, &trim("a")).await.unwrap();
+    wal.append(2, 0, &trim("b")).await.unwrap();
+    let manifest = wal.manifest();
+    drop(wal);
+    assert_eq!(manifest.segments.len(), 1);
+    let seg = manifest.segments[0].path(dir.path());
+    assert_eq!(std::fs::metadata(&seg).unwrap().len(), seg_size);
+
+    let replayed = |dir: &std::path::Path| {
+        let mut recs = Vec::new();
+        let report = replay_with(dir, |r| recs.push(format!("{:?}", r))).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(report.crc_failures, 0);
+        recs
+    };
+    let want = |ids: &[&str]| ids.iter().map(|id| format!("{:?}", trim(id))).collect::<Vec<_>>();
+    assert_eq!(replayed(dir.path()), want(&["a", "b"]));
+
+    // Reopened, appends land after the last record rather than after the zeros
+    let wal = WalWriter::open(dir.path(), seg_size, 0).unwrap();
+    wal.append(3, 0, &trim("c")).await.unwrap();
+    drop(wal);
+    assert_eq!(std::fs::metadata(&seg).unwrap().len(), seg_size);
+    assert_eq!(replayed(dir.path()), want(&["a", "b", "c"]));
+}
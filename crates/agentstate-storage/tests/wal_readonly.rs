@@ -0,0 +1,46 @@
+// WAL_FAILURE_MODE is read from the environment once per process, so these tests get a
+// binary of their own.
+mod common;
+
+use agentstate_core::StateError;
+use agentstate_storage::traits::GetOptions;
+use agentstate_storage::{AdminOps, ObjectStore, PersistentStore};
+use common::doc;
+use serde_json::json;
+use std::sync::Once;
+
+fn readonly_mode() {
+    static ENV: Once = Once::new();
+    ENV.call_once(|| std::env::set_var("WAL_FAILURE_MODE", "readonly"));
+}
+
+#[tokio::test]
+async fn a_wal_failure_latches_the_store_read_only_until_restart() {
+    readonly_mode();
+    let dir = tempfile::tempdir().unwrap();
+    let store = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+    store.put("ns", doc("a", json!({ "id": "a" }))).await.unwrap();
+    store.fail_next_wal_write().await;
+    let err = store.put("ns", doc("b", json!({ "id": "b" }))).await.unwrap_err();
+    assert!(matches!(err, StateError::Unavailable(_)), "{err:?}");
+    // The disk coming back doesn't lift the latch
+    store.heal_wal_writes().await;
+    for _ in 0..2 {
+        let err = store.put("ns", doc("c", json!({ "id": "c" }))).await.unwrap_err();
+        assert!(matches!(err, StateError::Unavailable(m) if m.contains("read-only")));
+    }
+    assert!(store.delete("ns", "a").await.is_err());
+    let got = store.get("ns", "a", GetOptions { at_ts: None }).await.unwrap();
+    assert_eq!(got.body["id"], "a");
+    assert!(store.wal_health().await.is_err());
+    drop(store);
+
+    let reopened = PersistentStore::open(dir.path().to_path_buf()).unwrap();
+    reopened.wal_health().await.unwrap();
+    reopened.put("ns", doc("c", json!({ "id": "c" }))).await.unwrap();
+    for id in ["a", "c"] {
+        assert!(reopened.get("ns", id, GetOptions { at_ts: None }).await.is_ok());
+    }
+    let get = reopened.get("ns", "b", GetOptions { at_ts: None }).await;
+    assert!(matches!(get, Err(StateError::NotFound)));
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "wasm")]
+// The WASM validation hook on each write path, using examples/wasm_validator. The module
+// is loaded once per process, so these tests get a binary of their own.
+mod common;
+
+use agentstate_core::{StateError, TxnOp};
+use agentstate_storage::{InMemoryStore, ObjectStore};
+use common::doc;
+use serde_json::json;
+use std::sync::Once;
+
+fn store() -> InMemoryStore {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        std::env::set_var(
+            "FIELD_ENCRYPTION_KEY",
+            "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=",
+        );
+        std::env::set_var("ENCRYPTED_FIELDS", r#"{"sealed":["owner"]}"#);
+        let module = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../examples/wasm_validator/require_owner.wasm"
+        );
+        std::env::set_var("WASM_VALIDATOR", module);
+        agentstate_storage::validator::init().unwrap();
+    });
+    InMemoryStore::new()
+}
+
+fn assert_rejected(err: StateError) {
+    match err {
+        StateError::Invalid(m) => assert!(m.contains("body.owner is required"), "{m}"),
+        other => panic!("expected a rejection, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn puts_txn_puts_and_patches_are_all_validated() {
+    let store = store();
+    for ns in ["plain", "sealed"] {
+        assert_rejected(store.put(ns, doc("t", json!({"title": "x"}))).await.unwrap_err());
+        store
+            .put(ns, doc("t", json!({"title": "x", "owner": "ann"})))
+            .await
+            .unwrap();
+
+        let put = TxnOp::Put(doc("u", json!({"title": "y"})));
+        assert_rejected(store.txn(ns, vec![put]).await.unwrap_err());
+
+        // Same verdict for a patch as for a put of the merged body, sealed field or not
+        let keep = TxnOp::Patch {
+            id: "t".into(),
+            patch: json!({"title": "z"}),
+        };
+        store.txn(ns, vec![keep]).await.unwrap();
+        let drop_owner = TxnOp::Patch {
+            id: "t".into(),
+            patch: json!({"owner": null}),
+        };
+        assert_rejected(store.txn(ns, vec![drop_owner]).await.unwrap_err());
+        let versions = store.versions(ns, "t").await.unwrap();
+        assert_eq!(versions.len(), 2, "{ns}");
+        // Sealed again after the merge
+        let owner = versions[1].body["owner"].as_str().unwrap();
+        assert_eq!(owner.starts_with("enc:v1:"), ns == "sealed", "{owner}");
+    }
+}
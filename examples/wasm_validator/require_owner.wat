@@ -0,0 +1,32 @@
+;; Rejects objects whose JSON has no "owner" key (a plain substring check, enough for a demo).
+;; Build: wat2wasm require_owner.wat   Run: WASM_VALIDATOR=require_owner.wasm agentstate-server
+;; require_owner.wasm is this module built; the storage crate's validator tests load it.
+(module
+  (memory (export "memory") 2)
+  (data (i32.const 0) "\22owner\22")          ;; needle, 7 bytes
+  (data (i32.const 16) "body.owner is required")  ;; reason, 22 bytes
+  (global $heap (mut i32) (i32.const 1024))
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $p i32)
+    (local.set $p (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (local.get $len)))
+    (local.get $p))
+  (func (export "validate") (param $ptr i32) (param $len i32) (result i64)
+    (local $i i32) (local $j i32) (local $end i32)
+    (local.set $end (i32.sub (i32.add (local.get $ptr) (local.get $len)) (i32.const 7)))
+    (local.set $i (local.get $ptr))
+    (block $notfound
+      (loop $outer
+        (br_if $notfound (i32.gt_s (local.get $i) (local.get $end)))
+        (local.set $j (i32.const 0))
+        (block $mismatch
+          (loop $inner
+            (br_if $mismatch (i32.ne (i32.load8_u (i32.add (local.get $i) (local.get $j)))
+                                     (i32.load8_u (local.get $j))))
+            (local.set $j (i32.add (local.get $j) (i32.const 1)))
+            (if (i32.eq (local.get $j) (i32.const 7)) (then (return (i64.const 0))))
+            (br $inner)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $outer)))
+    (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 22)))
+)